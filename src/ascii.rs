@@ -0,0 +1,142 @@
+use std::sync::OnceLock;
+
+use crate::normalize;
+
+// Some tools can't take file names that aren't ASCII. With --ascii-names
+// the names are shown transliterated: letters lose their accents, Greek and
+// Cyrillic are spelled in Latin letters, and what has no spelling (CJK,
+// symbols) is left out, the listing adding the start of the uuid so that
+// such names stay apart. The metadata keeps the real names, and a rename
+// stores the new name as it was given.
+
+static ENABLED: OnceLock<bool> = OnceLock::new();
+
+pub fn configure(enabled: bool) {
+    ENABLED.get_or_init(|| enabled);
+}
+
+pub fn enabled() -> bool {
+    ENABLED.get().copied().unwrap_or(false)
+}
+
+// The letters that don't decompose into an ASCII one and a mark
+fn spelling(c: char) -> Option<&'static str> {
+    Some(match c {
+        'ß' => "ss",
+        'æ' => "ae",
+        'œ' => "oe",
+        'ø' => "o",
+        'ł' => "l",
+        'đ' | 'ð' => "d",
+        'þ' => "th",
+        'ı' => "i",
+        'ŋ' => "n",
+        'ħ' => "h",
+        'ŧ' => "t",
+        'ſ' => "s",
+        'а' => "a",
+        'б' => "b",
+        'в' => "v",
+        'г' | 'ґ' => "g",
+        'д' => "d",
+        'е' | 'э' => "e",
+        'ё' => "yo",
+        'ж' => "zh",
+        'з' => "z",
+        'и' | 'і' => "i",
+        'й' | 'ы' => "y",
+        'ї' => "yi",
+        'є' => "ye",
+        'к' => "k",
+        'л' => "l",
+        'м' => "m",
+        'н' => "n",
+        'о' => "o",
+        'п' => "p",
+        'р' => "r",
+        'с' => "s",
+        'т' => "t",
+        'у' => "u",
+        'ф' => "f",
+        'х' => "kh",
+        'ц' => "ts",
+        'ч' => "ch",
+        'ш' => "sh",
+        'щ' => "shch",
+        'ъ' | 'ь' => "",
+        'ю' => "yu",
+        'я' => "ya",
+        'α' => "a",
+        'β' => "v",
+        'γ' => "g",
+        'δ' => "d",
+        'ε' => "e",
+        'ζ' => "z",
+        'η' | 'ι' => "i",
+        'θ' => "th",
+        'κ' => "k",
+        'λ' => "l",
+        'μ' => "m",
+        'ν' => "n",
+        'ξ' => "x",
+        'ο' | 'ω' => "o",
+        'π' => "p",
+        'ρ' => "r",
+        'σ' | 'ς' => "s",
+        'τ' => "t",
+        'υ' => "y",
+        'φ' => "f",
+        'χ' => "ch",
+        'ψ' => "ps",
+        '‘' | '’' | '‚' | '′' => "'",
+        '“' | '”' | '„' | '«' | '»' | '″' => "\"",
+        '‐' | '‑' | '‒' | '–' | '—' | '−' => "-",
+        '…' => "...",
+        '×' => "x",
+        '\u{a0}' | '\u{2009}' | '\u{202f}' => " ",
+        _ => return None,
+    })
+}
+
+// Capitalized for capitals, like Zh for Ж
+fn spelled(c: char) -> Option<String> {
+    let mut lower = c.to_lowercase();
+    let (first, rest) = (lower.next()?, lower.next());
+    let spelling = spelling(first).filter(|_| rest.is_none())?;
+    if first == c {
+        return Some(spelling.to_string());
+    }
+    let mut chars = spelling.chars();
+    Some(chars.next().map_or(String::new(), |initial| {
+        initial.to_uppercase().chain(chars).collect()
+    }))
+}
+
+// The combining diacritical marks, and those of Cyrillic
+fn is_mark(c: char) -> bool {
+    matches!(c, '\u{300}'..='\u{36f}' | '\u{483}'..='\u{489}')
+}
+
+/// `name` in ASCII, and whether characters without a spelling were left out
+pub fn transliterate(name: &str) -> (String, bool) {
+    let mut res = String::with_capacity(name.len());
+    let mut lossy = false;
+    for c in name.chars() {
+        if c.is_ascii() {
+            res.push(c);
+        } else if let Some(spelling) = spelled(c) {
+            res.push_str(&spelling);
+        } else {
+            for c in normalize::nfd(c.encode_utf8(&mut [0; 4])).chars() {
+                if c.is_ascii() {
+                    res.push(c);
+                } else if let Some(spelling) = spelled(c) {
+                    res.push_str(&spelling);
+                } else if !is_mark(c) {
+                    lossy = true;
+                }
+            }
+        }
+    }
+    (res, lossy)
+}
@@ -0,0 +1,434 @@
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::{Component, Path, PathBuf};
+
+use crate::direntry::{DirEntry, EntryType, ORPHANS_INO};
+use crate::export::copy_recursive;
+use crate::paths::PathResolver;
+use crate::rmxfs::list_dir_metadata;
+use crate::sha256;
+use crate::timeconv;
+
+// A copy of the whole library, to keep or to restore from. The metadata is
+// listed once at the start and every document copied as it was listed: a
+// data file that changed size since fails that document instead of ending
+// up in the backup half old and half new. The manifest in the backup has
+// the uuid, visible path, hash and lastModified of every document, written
+// last, so that an interrupted backup leaves the previous one.
+//
+// Documents are copied to their visible path, or with --raw the files
+// xochitl keeps for them (and for the folders) under their uuid names.
+// Notebooks and documents without a data file are left out.
+
+argwerk::define! {
+    /// Copy every document of a xochitl directory, with a manifest.
+    #[usage = "fuse-rm backup [--raw] [--incremental] source dest"]
+    pub struct BackupArgs {
+        pub help: bool,
+        pub raw: bool,
+        pub incremental: bool,
+        pub positional: Option<(String, String)>,
+    }
+    /// Copy the raw uuid.* files and page directories instead of the
+    /// visible tree.
+    ["--raw"] => {
+        raw = true;
+    }
+    /// Skip the documents unchanged since the backup in dest, and remove
+    /// those gone from source.
+    ["--incremental"] => {
+        incremental = true;
+    }
+    /// Print this help.
+    ["-h" | "--help"] => {
+        println!("{}", HELP);
+        help = true;
+    }
+    /// <source> dir, <dest> dir
+    [source, dest] if positional.is_none() => {
+        positional = Some((source, dest))
+    }
+}
+
+pub const MANIFEST: &str = ".fuse-rm-manifest.json";
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct Document {
+    pub uuid: String,
+    // as shown in the mount
+    pub path: String,
+    // where the data is in the backup, the visible path or uuid.ext
+    pub file: String,
+    pub sha256: String,
+    pub size: u64,
+    // milliseconds, as in the metadata
+    pub last_modified: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, Default)]
+pub struct Manifest {
+    pub raw: bool,
+    pub documents: Vec<Document>,
+}
+
+#[derive(Debug)]
+pub enum BackupError {
+    // unreadable, or of a backup made with(out) --raw
+    BadManifest(PathBuf, String),
+    Io(io::Error),
+}
+
+impl BackupError {
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            BackupError::BadManifest(..) => 50,
+            BackupError::Io(_) => 51,
+        }
+    }
+}
+
+impl fmt::Display for BackupError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BackupError::BadManifest(path, why) => {
+                write!(f, "Bad manifest {:?}: {}", path, why)
+            }
+            BackupError::Io(e) => write!(f, "IO error: {}", e),
+        }
+    }
+}
+
+impl std::convert::From<io::Error> for BackupError {
+    fn from(err: io::Error) -> BackupError {
+        BackupError::Io(err)
+    }
+}
+
+/// The manifest of the backup in `dir`
+pub fn read_manifest(dir: &Path) -> Result<Manifest, BackupError> {
+    let path = dir.join(MANIFEST);
+    let data = fs::read(&path)?;
+    serde_json::from_slice(&data)
+        .map_err(|e| BackupError::BadManifest(path, e.to_string()))
+}
+
+#[derive(Default)]
+pub struct Summary {
+    pub copied: usize,
+    pub unchanged: usize,
+    pub removed: usize,
+    // notebooks and documents without data
+    pub left_out: usize,
+    // with the visible path
+    pub failed: Vec<(String, io::Error)>,
+}
+
+impl Summary {
+    // some documents failed, the others are in the backup
+    pub fn exit_code(&self) -> i32 {
+        match self.failed.is_empty() {
+            true => 0,
+            false => 52,
+        }
+    }
+}
+
+/// Where `file` of a manifest is in the backup in `dest`. Only paths of
+/// plain names are, a manifest can't point out of the backup.
+pub fn in_backup(dest: &Path, file: &str) -> io::Result<PathBuf> {
+    let path = Path::new(file);
+    let plain = path.components().all(|c| matches!(c, Component::Normal(_)));
+    if file.is_empty() || !plain {
+        let why = format!("{:?} of the manifest is not in the backup", file);
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, why));
+    }
+    Ok(dest.join(path))
+}
+
+fn changed_error(what: &str) -> io::Error {
+    io::Error::other(format!("{} changed during the backup", what))
+}
+
+// The data file and the other uuid.* files of a document as they are, the
+// metadata as it was read at the start
+fn copy_raw(entry: &DirEntry, metadata: &[u8], dest: &Path) -> io::Result<()> {
+    let meta_path = entry.metadata_file_name();
+    for path in entry.associated_paths() {
+        if path.path == meta_path {
+            continue;
+        }
+        if let Some(name) = path.path.file_name().filter(|_| path.path.exists())
+        {
+            copy_recursive(&path.path, &dest.join(name))?;
+        }
+    }
+    let name = meta_path.file_name().unwrap_or_default();
+    fs::write(dest.join(name), metadata)
+}
+
+// Copies the document and checks it is the size it was listed with
+fn copy_document(
+    entry: &DirEntry,
+    metadata: &[u8],
+    dest: &Path,
+    file: &str,
+) -> io::Result<u64> {
+    let copy = in_backup(dest, file)?;
+    if metadata.is_empty() {
+        if let Some(dir) = copy.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        fs::copy(entry.source_file_path(), &copy)?;
+    } else {
+        copy_raw(entry, metadata, dest)?;
+    }
+    let size = fs::metadata(&copy)?.len();
+    if size != entry.attr.size {
+        return Err(changed_error("Size"));
+    }
+    Ok(size)
+}
+
+// Whether `doc` of the previous manifest is the document still, and in
+// place in the backup
+fn unchanged(doc: &Document, now: &Document, dest: &Path) -> bool {
+    doc.path == now.path
+        && doc.file == now.file
+        && doc.size == now.size
+        && doc.last_modified == now.last_modified
+        && in_backup(dest, &doc.file)
+            .and_then(fs::metadata)
+            .is_ok_and(|m| m.len() == doc.size)
+}
+
+// Removes what the backup had of a document no longer in it, and the dirs
+// of the tree left empty that aren't those of folders
+fn remove_stale(
+    doc: &Document,
+    raw: bool,
+    dest: &Path,
+    folders: &HashSet<PathBuf>,
+) -> io::Result<()> {
+    if raw {
+        // the files of no document but the manifest have no uuid
+        if uuid::Uuid::parse_str(&doc.uuid).is_err() {
+            let why = format!("{:?} of the manifest is no uuid", doc.uuid);
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, why));
+        }
+        for file in fs::read_dir(dest)? {
+            let file = file?;
+            let name = file.file_name();
+            let prefix = name.to_string_lossy();
+            if prefix.split('.').next() != Some(doc.uuid.as_str()) {
+                continue;
+            }
+            if file.file_type()?.is_dir() {
+                fs::remove_dir_all(file.path())?;
+            } else {
+                fs::remove_file(file.path())?;
+            }
+        }
+        return Ok(());
+    }
+    let copy = in_backup(dest, &doc.file)?;
+    match fs::remove_file(&copy) {
+        Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+        _ => (),
+    }
+    for dir in copy.ancestors().skip(1) {
+        if dir == dest || folders.contains(dir) || fs::remove_dir(dir).is_err()
+        {
+            break;
+        }
+    }
+    Ok(())
+}
+
+pub fn backup(
+    source: &Path,
+    dest: &Path,
+    raw: bool,
+    incremental: bool,
+) -> Result<Summary, BackupError> {
+    let previous = match incremental {
+        true => match read_manifest(dest) {
+            Ok(manifest) => manifest,
+            Err(BackupError::Io(e)) if e.kind() == io::ErrorKind::NotFound => {
+                Manifest::default()
+            }
+            Err(e) => return Err(e),
+        },
+        false => Manifest::default(),
+    };
+    if incremental && !previous.documents.is_empty() && previous.raw != raw {
+        let why = match previous.raw {
+            true => "the backup was made with --raw",
+            false => "the backup was made without --raw",
+        };
+        return Err(BackupError::BadManifest(dest.join(MANIFEST), why.into()));
+    }
+
+    let entries = list_dir_metadata(&source.to_path_buf())?;
+    let resolver = PathResolver::new(&entries);
+    let kept: Vec<&DirEntry> = entries
+        .iter()
+        .filter(|e| e.attr.ino > ORPHANS_INO && !e.rendition)
+        .filter(|e| !resolver.is_deleted(e))
+        .collect();
+    // the metadata as it is now, for --raw, before anything is copied
+    let mut metadata = HashMap::new();
+    if raw {
+        for entry in &kept {
+            match fs::read(entry.metadata_file_name()) {
+                Ok(data) => {
+                    metadata.insert(entry.prefix.clone(), data);
+                }
+                // removed since it was listed
+                Err(e) if e.kind() == io::ErrorKind::NotFound => (),
+                Err(e) => return Err(e.into()),
+            }
+        }
+    }
+    fs::create_dir_all(dest)?;
+
+    let previous: HashMap<&str, &Document> = previous
+        .documents
+        .iter()
+        .map(|doc| (doc.uuid.as_str(), doc))
+        .collect();
+    let mut summary = Summary::default();
+    let mut documents = Vec::new();
+    let mut folders = HashSet::new();
+    for entry in &kept {
+        let data = metadata.get(&entry.prefix);
+        if raw && data.is_none() {
+            continue;
+        }
+        if entry.entry_type == EntryType::NONE {
+            match data {
+                Some(data) => copy_raw(entry, data, dest)?,
+                // the documents of a folder that has no path fail with it
+                None => {
+                    if let Ok(path) = resolver.file_path(entry) {
+                        let dir = dest.join(path);
+                        fs::create_dir_all(&dir)?;
+                        folders.insert(dir);
+                    }
+                }
+            }
+            continue;
+        }
+        if !matches!(
+            entry.entry_type,
+            EntryType::PDF | EntryType::EPUB | EntryType::OTHER(_)
+        ) {
+            summary.left_out += 1;
+            continue;
+        }
+        // never outside dest, whatever the names
+        let path = match resolver.file_path(entry) {
+            Ok(path) => path.to_string_lossy().into_owned(),
+            Err(e) => {
+                summary.failed.push((resolver.describe(entry), e));
+                continue;
+            }
+        };
+        let data_path = entry.source_file_path();
+        let file = match raw {
+            true => data_path.file_name().unwrap_or_default(),
+            false => path.as_ref(),
+        };
+        let mut doc = Document {
+            uuid: entry.prefix.to_string_lossy().into_owned(),
+            file: file.to_string_lossy().into_owned(),
+            path,
+            sha256: String::new(),
+            size: entry.attr.size,
+            last_modified: entry
+                .last_modified()
+                .map(timeconv::to_millis_str)
+                .unwrap_or_default(),
+        };
+        if let Some(before) = previous.get(doc.uuid.as_str()) {
+            if unchanged(before, &doc, dest) {
+                documents.push((*before).clone());
+                summary.unchanged += 1;
+                continue;
+            }
+        }
+        let copied = copy_document(
+            entry,
+            data.map_or(&[][..], Vec::as_slice),
+            dest,
+            &doc.file,
+        )
+        .and_then(|_| sha256::hash_file(&in_backup(dest, &doc.file)?));
+        match copied {
+            Ok(hash) => {
+                doc.sha256 = hash;
+                documents.push(doc);
+                summary.copied += 1;
+            }
+            // nothing is left of it half copied
+            Err(e) => {
+                if let Err(err) = remove_stale(&doc, raw, dest, &folders) {
+                    debug!("backup: can't remove {:?}: {}", doc.file, err);
+                }
+                summary.failed.push((doc.path, e));
+            }
+        }
+    }
+
+    let files: HashSet<&str> =
+        documents.iter().map(|doc| doc.file.as_str()).collect();
+    let uuids: HashSet<&str> =
+        documents.iter().map(|doc| doc.uuid.as_str()).collect();
+    for doc in previous.values() {
+        let gone = match raw {
+            true => !uuids.contains(doc.uuid.as_str()),
+            false => !files.contains(doc.file.as_str()),
+        };
+        if gone {
+            match remove_stale(doc, raw, dest, &folders) {
+                Ok(()) => summary.removed += 1,
+                Err(e) => summary.failed.push((doc.path.clone(), e)),
+            }
+        }
+    }
+
+    let manifest = Manifest { raw, documents };
+    let json = serde_json::to_vec_pretty(&manifest).map_err(io::Error::from)?;
+    let temp = dest.join(format!("{}.tmp", MANIFEST));
+    fs::write(&temp, json)?;
+    fs::rename(&temp, dest.join(MANIFEST))?;
+    Ok(summary)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse() {
+        let args = BackupArgs::parse(["src", "dest"]).unwrap();
+        assert!(!args.raw && !args.incremental);
+        assert_eq!(
+            args.positional,
+            Some(("src".to_string(), "dest".to_string()))
+        );
+        let args = BackupArgs::parse(["--incremental", "--raw", "src", "dest"])
+            .unwrap();
+        assert!(args.raw && args.incremental);
+    }
+
+    #[test]
+    fn parse_errors() {
+        assert!(BackupArgs::parse(["src"]).is_err());
+        assert!(BackupArgs::parse(["src", "dest", "more"]).is_err());
+        assert!(BackupArgs::parse(["--full", "src", "dest"]).is_err());
+    }
+}
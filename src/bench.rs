@@ -0,0 +1,351 @@
+use std::fs;
+use std::io::{self, Seek, Write};
+use std::path::{Path, PathBuf};
+use std::time::Instant;
+
+use crate::allocs;
+use crate::bufpool::BufPool;
+use crate::direntry::DirEntry;
+use crate::options::Options;
+use crate::rmxfs::{
+    self, dir_snapshot, list_dir_metadata, list_dir_metadata_with, Snapshot,
+};
+use crate::scanpool;
+use crate::writebuf::BufferedFile;
+use std::sync::Arc;
+
+argwerk::define! {
+    /// Measure listing time and memory use on a (generated) library.
+    #[usage = "fuse-rm bench [--docs n] [--flat] [--max-rss kib] \
+               [--read-mb n] [--write-mb n] [--write-kib n] [source]"]
+    pub struct BenchArgs {
+        pub help: bool,
+        pub docs: usize = 5000,
+        pub flat: bool,
+        pub max_rss: Option<u64>,
+        pub read_mb: Option<u64>,
+        pub write_mb: Option<u64>,
+        pub write_kib: usize = 4,
+        pub source: Option<String>,
+    }
+    /// Documents in the generated library (default: 5000).
+    ["--docs", n] => {
+        docs = str::parse(&n)?;
+    }
+    /// Generate every document at the top level, none in folders.
+    ["--flat"] => {
+        flat = true;
+    }
+    /// Fail if the peak resident memory exceeds this many KiB.
+    ["--max-rss", kib] => {
+        max_rss = Some(str::parse(&kib)?);
+    }
+    /// Also read a generated file of this many MiB the way the mount does.
+    ["--read-mb", n] => {
+        read_mb = Some(str::parse(&n)?);
+    }
+    /// Also write a file of this many MiB the way the mount writes new
+    /// documents, and one request at a time.
+    ["--write-mb", n] => {
+        write_mb = Some(str::parse(&n)?);
+    }
+    /// The size of the write requests in KiB (default: 4, the kernel's
+    /// without big writes).
+    ["--write-kib", n] => {
+        write_kib = str::parse(&n)?;
+    }
+    /// Print this help.
+    ["-h" | "--help"] => {
+        println!("{}", HELP);
+        help = true;
+    }
+    /// Existing xochitl dir to measure instead of a generated one
+    [dir] if source.is_none() => {
+        source = Some(dir);
+    }
+}
+
+// Open directory handles kept while measuring, like a couple of file
+// manager windows would
+const SNAPSHOTS: usize = 4;
+
+// Folders in the generated library, documents are spread over them
+const FOLDERS: usize = 50;
+
+// what the kernel asks for at a time
+const READ_SIZE: u32 = 128 * 1024;
+
+pub struct BenchResult {
+    pub entries: usize,
+    pub scan_millis: u128,
+    // again, from the page cache, by the threads and by one
+    pub threads: usize,
+    pub parallel_millis: u128,
+    pub serial_millis: u128,
+    // whether both listings are the same, in the same order
+    pub same_order: bool,
+    pub rss_kib: u64,
+    pub peak_rss_kib: u64,
+    // what the snapshots of the open top level take, by entry and handle
+    pub snapshot_bytes: usize,
+    // the allocations of opening the top level, with the listing shared
+    // and with a copy of it
+    pub open_allocs: u64,
+    pub copied_allocs: u64,
+}
+
+fn status_kib(field: &str) -> u64 {
+    fs::read_to_string("/proc/self/status")
+        .unwrap_or_default()
+        .lines()
+        .find(|l| l.starts_with(field))
+        .and_then(|l| l.split_whitespace().nth(1))
+        .and_then(|kib| kib.parse().ok())
+        .unwrap_or(0)
+}
+
+fn write_doc(
+    dir: &Path,
+    uuid: &str,
+    parent: &str,
+    name: &str,
+    folder: bool,
+) -> io::Result<()> {
+    // the fields a device-written metadata file has
+    let metadata = json!({
+        "deleted": false,
+        "lastModified": "1629658253392",
+        "lastOpened": "1629658253392",
+        "lastOpenedPage": 0,
+        "metadatamodified": false,
+        "modified": false,
+        "parent": parent,
+        "pinned": false,
+        "synced": true,
+        "type": if folder { "CollectionType" } else { "DocumentType" },
+        "version": 1,
+        "visibleName": name,
+    });
+    let path = dir.join(uuid);
+    fs::write(
+        path.with_extension("metadata"),
+        serde_json::to_vec_pretty(&metadata)?,
+    )?;
+    if !folder {
+        fs::write(path.with_extension("content"), b"{\"fileType\":\"pdf\"}")?;
+        fs::write(path.with_extension("pdf"), b"%PDF-1.4\n%%EOF\n")?;
+    }
+    Ok(())
+}
+
+pub fn generate(dir: &Path, docs: usize, flat: bool) -> io::Result<()> {
+    fs::create_dir_all(dir)?;
+    let folders: Vec<String> = (0..FOLDERS)
+        .map(|_| uuid::Uuid::new_v4().to_hyphenated().to_string())
+        .collect();
+    for (i, folder) in folders.iter().enumerate() {
+        write_doc(dir, folder, "", &format!("Folder {}", i), true)?;
+    }
+    for i in 0..docs {
+        let uuid = uuid::Uuid::new_v4().to_hyphenated().to_string();
+        // a fifth at the top level, the rest in folders
+        let parent = if flat || i % 5 == 0 {
+            ""
+        } else {
+            &folders[i % FOLDERS]
+        };
+        write_doc(dir, &uuid, parent, &format!("Document {}", i), false)?;
+    }
+    Ok(())
+}
+
+pub fn run(source: &Path) -> io::Result<BenchResult> {
+    let source = source.to_path_buf();
+    let start = Instant::now();
+    let entries = list_dir_metadata(&source)?;
+    let scan_millis = start.elapsed().as_millis();
+    let count = entries.len();
+
+    // every operation lists the library, keep a listing alive next to the
+    // snapshots of open dirs like a busy mount does
+    let (root, options) = (DirEntry::make_root(&source), Options::default());
+    let mut snapshots: Vec<Snapshot> = Vec::new();
+    for _ in 0..SNAPSHOTS {
+        let listing = list_dir_metadata(&source)?;
+        let snapshot = rmxfs::listed(dir_snapshot(&listing, &root, &options));
+        snapshots.push(rmxfs::share(snapshot, snapshots.iter()));
+    }
+    let (rss_kib, peak_rss_kib) = (status_kib("VmRSS:"), status_kib("VmHWM:"));
+    let shared: usize = snapshots
+        .iter()
+        .enumerate()
+        .filter(|(i, s)| !snapshots[..*i].iter().any(|o| Arc::ptr_eq(o, s)))
+        .map(|(_, s)| rmxfs::snapshot_size(s))
+        .sum();
+    let snapshot_bytes = shared / (SNAPSHOTS * snapshots[0].len()).max(1);
+
+    // --snapshot shares one listing between the calls, every call had a
+    // copy of its own before
+    let listing: Arc<[DirEntry]> = entries.into();
+    let (_, open_allocs) = allocs::counted(|| {
+        rmxfs::listed(dir_snapshot(&listing, &root, &options))
+    });
+    let (_, copied_allocs) = allocs::counted(|| {
+        let copy = listing.to_vec();
+        rmxfs::listed(dir_snapshot(&copy, &root, &options))
+    });
+    drop((listing, snapshots));
+
+    // the same again from the page cache, by the threads and by one
+    let threads = scanpool::threads();
+    let start = Instant::now();
+    let parallel = list_dir_metadata_with(&source, threads)?;
+    let parallel_millis = start.elapsed().as_millis();
+    let start = Instant::now();
+    let serial = list_dir_metadata_with(&source, 1)?;
+    let serial_millis = start.elapsed().as_millis();
+    let same_order = parallel.len() == serial.len()
+        && parallel.iter().zip(&serial).all(|(a, b)| {
+            a.prefix == b.prefix && a.file_name() == b.file_name()
+        });
+    drop((parallel, serial));
+    Ok(BenchResult {
+        entries: count,
+        scan_millis,
+        threads,
+        parallel_millis,
+        serial_millis,
+        same_order,
+        rss_kib,
+        peak_rss_kib,
+        snapshot_bytes,
+        open_allocs,
+        copied_allocs,
+    })
+}
+
+pub struct ReadResult {
+    pub reads: u64,
+    pub read_millis: u128,
+    pub buffers: u64,
+}
+
+/// Reads a file of `mib` MiB in requests as large as the kernel's
+pub fn read(dir: &Path, mib: u64) -> io::Result<ReadResult> {
+    let path = dir.join(format!("fuse-rm-read-{}", std::process::id()));
+    let mut file = fs::File::create(&path)?;
+    let chunk = vec![0xa5; 1 << 20];
+    for _ in 0..mib {
+        file.write_all(&chunk)?;
+    }
+    drop(file);
+
+    let (file, pool) = (fs::File::open(&path)?, BufPool::new());
+    let (mut reads, mut offset) = (0, 0);
+    let start = Instant::now();
+    loop {
+        let data = pool.read_at(&file, offset, READ_SIZE)?;
+        if data.is_empty() {
+            break;
+        }
+        offset += data.len() as u64;
+        reads += 1;
+    }
+    let res = ReadResult {
+        reads,
+        read_millis: start.elapsed().as_millis(),
+        buffers: pool.allocated(),
+    };
+    fs::remove_file(path)?;
+    Ok(res)
+}
+
+pub struct WriteResult {
+    pub buffered_millis: u128,
+    pub unbuffered_millis: u128,
+}
+
+/// Writes a file of `mib` MiB in requests of `kib` KiB, through the buffer
+/// of pending files and with a seek and write per request
+pub fn write(dir: &Path, mib: u64, kib: usize) -> io::Result<WriteResult> {
+    let path = dir.join(format!("fuse-rm-write-{}", std::process::id()));
+    let size = kib.max(1) << 10;
+    let chunk = vec![0xa5; size];
+    let chunks = mib * (1 << 20) / size as u64;
+
+    let start = Instant::now();
+    let mut file = BufferedFile::new(fs::File::create(&path)?);
+    for i in 0..chunks {
+        file.write_at(i * size as u64, &chunk)?;
+    }
+    file.flush()?;
+    drop(file);
+    let buffered_millis = start.elapsed().as_millis();
+
+    let start = Instant::now();
+    let mut file = fs::File::create(&path)?;
+    for i in 0..chunks {
+        file.seek(io::SeekFrom::Start(i * size as u64))?;
+        file.write_all(&chunk)?;
+    }
+    drop(file);
+    let unbuffered_millis = start.elapsed().as_millis();
+
+    fs::remove_file(path)?;
+    Ok(WriteResult {
+        buffered_millis,
+        unbuffered_millis,
+    })
+}
+
+pub fn temp_library() -> PathBuf {
+    std::env::temp_dir().join(format!("fuse-rm-bench-{}", std::process::id()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_defaults() {
+        let args = BenchArgs::parse(Vec::<&str>::new()).unwrap();
+        assert_eq!((args.docs, args.flat, args.write_kib), (5000, false, 4));
+        assert_eq!(
+            (args.max_rss, args.read_mb, args.write_mb),
+            (None, None, None)
+        );
+        assert_eq!(args.source, None);
+    }
+
+    #[test]
+    fn parse_all() {
+        let args = BenchArgs::parse([
+            "--docs",
+            "10",
+            "--flat",
+            "--max-rss",
+            "1024",
+            "--read-mb",
+            "2",
+            "--write-mb",
+            "3",
+            "--write-kib",
+            "128",
+            "/data/xochitl",
+        ])
+        .unwrap();
+        assert_eq!((args.docs, args.flat, args.write_kib), (10, true, 128));
+        assert_eq!(
+            (args.max_rss, args.read_mb, args.write_mb),
+            (Some(1024), Some(2), Some(3))
+        );
+        assert_eq!(args.source.as_deref(), Some("/data/xochitl"));
+    }
+
+    #[test]
+    fn parse_errors() {
+        assert!(BenchArgs::parse(["--docs", "many"]).is_err());
+        assert!(BenchArgs::parse(["--docs"]).is_err());
+        assert!(BenchArgs::parse(["one", "two"]).is_err());
+    }
+}
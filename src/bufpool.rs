@@ -0,0 +1,104 @@
+use std::fs;
+use std::io;
+use std::ops::Deref;
+use std::os::unix::fs::FileExt;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+
+use crate::retry::retry;
+
+// A big copy is one read request per 128 KiB. Instead of allocating and
+// zeroing a buffer for each, reads take one from a pool and put it back
+// once replied. The pool is shared by the I/O threads, it keeps at most a
+// buffer per thread or so, more are only made while reads overlap.
+
+const MAX_FREE: usize = 8;
+
+pub struct BufPool {
+    free: Mutex<Vec<Vec<u8>>>,
+    // buffers made or grown, for the summary at unmount and the bench
+    allocated: AtomicU64,
+}
+
+/// Read data in a pooled buffer, which goes back to the pool when dropped
+pub struct PoolBuf<P: Deref<Target = BufPool>> {
+    pool: P,
+    buf: Vec<u8>,
+    len: usize,
+}
+
+impl<P: Deref<Target = BufPool>> Deref for PoolBuf<P> {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        &self.buf[..self.len]
+    }
+}
+
+impl<P: Deref<Target = BufPool>> Drop for PoolBuf<P> {
+    fn drop(&mut self) {
+        let mut free = self.pool.free.lock().unwrap();
+        if free.len() < MAX_FREE {
+            free.push(std::mem::take(&mut self.buf));
+        }
+    }
+}
+
+impl BufPool {
+    pub fn new() -> BufPool {
+        BufPool {
+            free: Mutex::new(Vec::new()),
+            allocated: AtomicU64::new(0),
+        }
+    }
+
+    pub fn allocated(&self) -> u64 {
+        self.allocated.load(Ordering::Relaxed)
+    }
+
+    // Holds at least `len` bytes, left over from earlier reads
+    fn take(&self, len: usize) -> Vec<u8> {
+        let mut buf = self.free.lock().unwrap().pop().unwrap_or_default();
+        if buf.len() < len {
+            // zeroed once, reads overwrite what they return
+            buf.resize(len, 0);
+            self.allocated.fetch_add(1, Ordering::Relaxed);
+        }
+        buf
+    }
+
+    /// Up to `size` bytes at `offset`, fewer at the end of the file
+    pub fn read_at(
+        &self,
+        file: &fs::File,
+        offset: u64,
+        size: u32,
+    ) -> io::Result<PoolBuf<&BufPool>> {
+        read(self, file, offset, size)
+    }
+
+    /// read_at with the buffer holding on to the pool, for a read on a
+    /// thread that may outlive the request
+    pub fn read_shared(
+        self: &Arc<Self>,
+        file: &fs::File,
+        offset: u64,
+        size: u32,
+    ) -> io::Result<PoolBuf<Arc<BufPool>>> {
+        read(Arc::clone(self), file, offset, size)
+    }
+}
+
+fn read<P: Deref<Target = BufPool>>(
+    pool: P,
+    file: &fs::File,
+    offset: u64,
+    size: u32,
+) -> io::Result<PoolBuf<P>> {
+    let file_size = retry(|| file.metadata())?.len();
+    let len = size.min(file_size.saturating_sub(offset) as u32) as usize;
+    let buf = pool.take(len);
+    let mut res = PoolBuf { pool, buf, len };
+    retry(|| file.read_exact_at(&mut res.buf[..len], offset))?;
+    Ok(res)
+}
@@ -0,0 +1,58 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+use crate::xochitl;
+
+// xochitl is confused by the document it has open changing or going away
+// under it. It keeps the uuid of that document as LastOpen in its config,
+// and removing, renaming or retagging it is refused with EBUSY. The file is
+// read again at most once a second. Off the tablet there is no such file,
+// and nothing is refused.
+
+pub const DEFAULT_STATE: &str = "/home/root/.config/remarkable/xochitl.conf";
+const KEY: &str = "LastOpen";
+const CACHE_TIME: Duration = Duration::from_secs(1);
+
+pub struct BusyGuard {
+    path: PathBuf,
+    // the config of the tablet only counts while xochitl runs, a file given
+    // with --busy-state always does
+    needs_xochitl: bool,
+    cached: Option<(Instant, Option<String>)>,
+}
+
+// The value of LastOpen in any section, empty when none is open
+fn last_open(conf: &str) -> Option<String> {
+    conf.lines()
+        .filter_map(|line| line.split_once('='))
+        .find(|(key, _)| key.trim() == KEY)
+        .map(|(_, uuid)| uuid.trim().trim_matches('"').to_string())
+        .filter(|uuid| !uuid.is_empty())
+}
+
+impl BusyGuard {
+    pub fn new(path: Option<&Path>) -> BusyGuard {
+        BusyGuard {
+            path: path.map_or(PathBuf::from(DEFAULT_STATE), Path::to_path_buf),
+            needs_xochitl: path.is_none(),
+            cached: None,
+        }
+    }
+
+    /// The uuid of the document xochitl has open
+    pub fn open_document(&mut self) -> Option<&str> {
+        let stale = self
+            .cached
+            .as_ref()
+            .is_none_or(|(read, _)| read.elapsed() >= CACHE_TIME);
+        if stale {
+            let uuid = fs::read_to_string(&self.path)
+                .ok()
+                .filter(|_| !self.needs_xochitl || xochitl::is_running())
+                .and_then(|conf| last_open(&conf));
+            self.cached = Some((Instant::now(), uuid));
+        }
+        self.cached.as_ref().and_then(|(_, uuid)| uuid.as_deref())
+    }
+}
@@ -0,0 +1,133 @@
+use std::fmt::Write;
+
+// Shell completion scripts, made from the help of the argument parsers so
+// they list the same switches and subcommands as --help does. Words are
+// taken from the usage of each switch: `-l, --limit <int>` completes -l and
+// --limit, `bench <rest..>` the subcommand bench.
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Shell {
+    Bash,
+    // through zsh's bash completion support
+    Zsh,
+    Fish,
+}
+
+impl std::str::FromStr for Shell {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Shell, String> {
+        match s {
+            "bash" => Ok(Shell::Bash),
+            "zsh" => Ok(Shell::Zsh),
+            "fish" => Ok(Shell::Fish),
+            _ => Err(format!("Unknown shell: {} (bash, zsh or fish)", s)),
+        }
+    }
+}
+
+struct Word {
+    word: &'static str,
+    takes_value: bool,
+    doc: &'static str,
+}
+
+fn words(help: &argwerk::Help) -> (Vec<Word>, Vec<&'static str>) {
+    let (mut switches, mut subcommands) = (Vec::new(), Vec::new());
+    for switch in help.switches {
+        let doc = switch.docs.first().copied().unwrap_or("").trim();
+        for alternative in switch.usage.split(", ") {
+            let mut parts = alternative.split(' ');
+            let word = parts.next().unwrap_or("");
+            let takes_value = parts.next().is_some();
+            if word.starts_with('-') {
+                switches.push(Word {
+                    word,
+                    takes_value,
+                    doc,
+                });
+            } else if !word.is_empty() && !word.starts_with('<') {
+                subcommands.push(word);
+            }
+        }
+    }
+    (switches, subcommands)
+}
+
+fn bash(
+    main: &argwerk::Help,
+    subcommands: &[(&str, &argwerk::Help)],
+) -> String {
+    let list = |help| {
+        let (switches, subcommands) = words(help);
+        let mut res: Vec<&str> = switches.iter().map(|w| w.word).collect();
+        res.extend(subcommands);
+        res.join(" ")
+    };
+    let mut res = String::from(
+        "_fuse_rm() {\n    local cur=${COMP_WORDS[COMP_CWORD]} words\n    \
+         case \"${COMP_WORDS[1]}\" in\n",
+    );
+    for (name, help) in subcommands {
+        let _ = writeln!(res, "        {}) words=\"{}\" ;;", name, list(help));
+    }
+    let _ = writeln!(res, "        *) words=\"{}\" ;;", list(main));
+    res.push_str(
+        "    esac\n    if [[ $cur == -* || $COMP_CWORD -eq 1 ]]; then\n        \
+         COMPREPLY=($(compgen -W \"$words\" -- \"$cur\"))\n    fi\n    \
+         [[ ${#COMPREPLY[@]} -eq 0 ]] && COMPREPLY=($(compgen -f -- \
+         \"$cur\"))\n}\ncomplete -o filenames -F _fuse_rm fuse-rm\n",
+    );
+    res
+}
+
+fn fish(
+    main: &argwerk::Help,
+    subcommands: &[(&str, &argwerk::Help)],
+) -> String {
+    let mut res = String::new();
+    let mut add = |condition: &str, help: &argwerk::Help| {
+        for switch in words(help).0 {
+            let (flag, name) = match switch.word.strip_prefix("--") {
+                Some(long) => ("-l", long),
+                None => ("-s", &switch.word[1..]),
+            };
+            let _ = writeln!(
+                res,
+                "complete -c fuse-rm -n '{}' {} {}{} -d '{}'",
+                condition,
+                flag,
+                name,
+                if switch.takes_value { " -r" } else { "" },
+                switch.doc.replace('\'', "\\'")
+            );
+        }
+    };
+    add("__fish_use_subcommand", main);
+    for (name, help) in subcommands {
+        add(&format!("__fish_seen_subcommand_from {}", name), help);
+    }
+    let _ = writeln!(
+        res,
+        "complete -c fuse-rm -n '__fish_use_subcommand' -a '{}'",
+        words(main).1.join(" ")
+    );
+    res
+}
+
+/// The completion script for `shell`, of the switches of `main` and those
+/// of the `subcommands` after their name
+pub fn script(
+    shell: Shell,
+    main: &argwerk::Help,
+    subcommands: &[(&str, &argwerk::Help)],
+) -> String {
+    match shell {
+        Shell::Bash => bash(main, subcommands),
+        Shell::Zsh => format!(
+            "autoload -U +X bashcompinit && bashcompinit\n{}",
+            bash(main, subcommands)
+        ),
+        Shell::Fish => fish(main, subcommands),
+    }
+}
@@ -0,0 +1,45 @@
+use std::ffi::OsStr;
+use std::time::SystemTime;
+
+use crate::direntry::{CONTROL_INO, REFRESH_INO};
+use crate::timeconv;
+
+// A shell on a machine without fuse-rm can't send the ioctls of
+// `fuse-rm ctl`. Instead, writing anything to `.fuse-rm/refresh` at the root
+// of the mount drops the caches, lists the source again and counts the
+// library as `ctl rescan` does; reading it gives when the source was last
+// listed. The dir is only listed with --expose-control, and nothing in it
+// can be created, removed or renamed. It hides an entry of the same name at
+// the root.
+
+pub const DIR_NAME: &str = ".fuse-rm";
+pub const REFRESH_NAME: &str = "refresh";
+
+pub fn is_control(ino: u64) -> bool {
+    ino == CONTROL_INO || ino == REFRESH_INO
+}
+
+/// The inode of `name` in `parent` if it is the control dir or its file
+pub fn child(parent: u64, name: &OsStr) -> Option<u64> {
+    match parent {
+        1 if name == DIR_NAME => Some(CONTROL_INO),
+        CONTROL_INO if name == REFRESH_NAME => Some(REFRESH_INO),
+        _ => None,
+    }
+}
+
+/// Whether `name` in `parent` is, or would be, in the control dir
+pub fn is_reserved(parent: u64, name: &OsStr) -> bool {
+    parent == CONTROL_INO || child(parent, name).is_some()
+}
+
+/// What reading the refresh file gives: the time of the last listing in
+/// seconds since the epoch, 0 before the first
+pub fn last_scan(time: Option<SystemTime>) -> String {
+    let millis = time.map_or(0, timeconv::to_millis);
+    format!(
+        "{}.{:03}\n",
+        millis.div_euclid(1000),
+        millis.rem_euclid(1000)
+    )
+}
@@ -0,0 +1,172 @@
+use std::convert::TryInto;
+use std::ffi::CString;
+use std::io;
+use std::os::unix::ffi::OsStrExt;
+use std::path::Path;
+
+// Scripts poke a live mount through ioctls on its root: an open handle of
+// the root is the mount itself, so a command can't reach another mount or
+// a file that happens to be named like a control file.
+//
+// Every command is a read and write ioctl of a message of the same size,
+// with fields of fixed size only so that it has the same layout for the
+// 32-bit ARM of the tablet and on a 64-bit machine:
+//
+//     u32 version, u32 len, [u8; DATA_LEN] data
+//
+// in native byte order. The argument of a command goes in the data, and the
+// answer comes back in it. Commands this version doesn't know are ENOTTY.
+
+argwerk::define! {
+    /// Send a command to a mounted fuse-rm.
+    #[usage = "fuse-rm ctl mountpoint flush-caches|stats|log-level LEVEL|\
+               rescan"]
+    pub struct CtlArgs {
+        pub help: bool,
+        pub mountpoint: Option<String>,
+        pub command: Option<String>,
+        pub arg: Option<String>,
+    }
+    /// Print this help.
+    ["-h" | "--help"] => {
+        println!("{}", HELP);
+        help = true;
+    }
+    /// <mountpoint> of the mount, and the <command>: flush-caches, stats
+    /// (printed as JSON), log-level with one of off, error, warn, info,
+    /// debug and trace, or rescan (to count the library again)
+    [dir] if mountpoint.is_none() => {
+        mountpoint = Some(dir);
+    }
+    [name] if command.is_none() => {
+        command = Some(name);
+    }
+    [value] if arg.is_none() => {
+        arg = Some(value);
+    }
+}
+
+pub const VERSION: u32 = 1;
+const HEADER_LEN: usize = 8;
+// the size of the ioctl is 13 bits on the BSDs, 14 on Linux
+const MESSAGE_LEN: usize = 4096;
+pub const DATA_LEN: usize = MESSAGE_LEN - HEADER_LEN;
+
+const MAGIC: u32 = b'R' as u32;
+// _IOC_READ | _IOC_WRITE, which is IOC_INOUT on the BSDs too
+const INOUT: u32 = 3 << 30;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Command {
+    FlushCaches,
+    GetStats,
+    SetLogLevel,
+    TriggerRescan,
+}
+
+const COMMANDS: [(Command, u32, &str); 4] = [
+    (Command::FlushCaches, 1, "flush-caches"),
+    (Command::GetStats, 2, "stats"),
+    (Command::SetLogLevel, 3, "log-level"),
+    (Command::TriggerRescan, 4, "rescan"),
+];
+
+const fn ioc(nr: u32) -> u32 {
+    INOUT | ((MESSAGE_LEN as u32) << 16) | (MAGIC << 8) | nr
+}
+
+impl Command {
+    /// The ioctl number of the command
+    pub fn cmd(self) -> u32 {
+        let (_, nr, _) = COMMANDS.iter().find(|(c, ..)| *c == self).unwrap();
+        ioc(*nr)
+    }
+
+    /// The command of an ioctl number, None for those of other versions
+    /// and other programs
+    pub fn from_cmd(cmd: u32) -> Option<Command> {
+        COMMANDS
+            .iter()
+            .find(|(_, nr, _)| ioc(*nr) == cmd)
+            .map(|(c, ..)| *c)
+    }
+}
+
+impl std::str::FromStr for Command {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Command, String> {
+        match COMMANDS.iter().find(|(.., name)| *name == s) {
+            Some((command, ..)) => Ok(*command),
+            None => Err(format!("Unknown command: {}", s)),
+        }
+    }
+}
+
+/// A message of `data`, None if it doesn't fit
+pub fn encode(data: &[u8]) -> Option<Vec<u8>> {
+    if data.len() > DATA_LEN {
+        return None;
+    }
+    let mut res = Vec::with_capacity(MESSAGE_LEN);
+    res.extend_from_slice(&VERSION.to_ne_bytes());
+    res.extend_from_slice(&(data.len() as u32).to_ne_bytes());
+    res.extend_from_slice(data);
+    res.resize(MESSAGE_LEN, 0);
+    Some(res)
+}
+
+/// The data of a message of this version
+pub fn decode(message: &[u8]) -> Option<&[u8]> {
+    let field = |at: usize| {
+        let bytes = message.get(at..at + 4)?;
+        Some(u32::from_ne_bytes(bytes.try_into().ok()?))
+    };
+    if field(0)? != VERSION {
+        return None;
+    }
+    message.get(HEADER_LEN..HEADER_LEN + field(4)? as usize)
+}
+
+/// Sends `command` with `arg` to the mount at `mountpoint`, and returns the
+/// data of the answer
+pub fn send(
+    mountpoint: &Path,
+    command: Command,
+    arg: &[u8],
+) -> io::Result<Vec<u8>> {
+    let too_long = || io::Error::from_raw_os_error(libc::E2BIG);
+    let mut message = encode(arg).ok_or_else(too_long)?;
+    let path = CString::new(mountpoint.as_os_str().as_bytes())?;
+    let fd = unsafe {
+        libc::open(path.as_ptr(), libc::O_RDONLY | libc::O_DIRECTORY)
+    };
+    if fd < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let res =
+        unsafe { libc::ioctl(fd, command.cmd() as _, message.as_mut_ptr()) };
+    let err = io::Error::last_os_error();
+    unsafe { libc::close(fd) };
+    if res < 0 {
+        return Err(err);
+    }
+    let invalid = || io::Error::from_raw_os_error(libc::EPROTO);
+    decode(&message).map(<[u8]>::to_vec).ok_or_else(invalid)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse() {
+        let args = CtlArgs::parse(["/mnt/rm", "log-level", "debug"]).unwrap();
+        assert_eq!(args.mountpoint.as_deref(), Some("/mnt/rm"));
+        assert_eq!(args.command.as_deref(), Some("log-level"));
+        assert_eq!(args.arg.as_deref(), Some("debug"));
+        let args = CtlArgs::parse(["/mnt/rm", "stats"]).unwrap();
+        assert_eq!((args.command.as_deref(), args.arg), (Some("stats"), None));
+        assert!(CtlArgs::parse(["/mnt/rm", "log-level", "debug", "x"]).is_err());
+    }
+}
@@ -0,0 +1,64 @@
+use std::fs;
+use std::io::{self, Read, Write};
+use std::os::unix::io::{AsRawFd, FromRawFd};
+
+// mount(8) waits for its helper to return, so as a helper the mount keeps
+// running in a forked child. The parent exits with the status the child
+// sends through a pipe: 0 once mounted, the exit code if mounting failed,
+// and 1 if the child ended without saying.
+
+pub struct Daemon {
+    pipe: Option<fs::File>,
+}
+
+fn detach_stdio() -> io::Result<()> {
+    let null = fs::OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open("/dev/null")?;
+    for fd in 0..3 {
+        if unsafe { libc::dup2(null.as_raw_fd(), fd) } < 0 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+    Ok(())
+}
+
+/// Forks, returns in the child only. Has to run before any threads are
+/// started, they aren't forked along.
+pub fn daemonize() -> io::Result<Daemon> {
+    let mut fds = [0; 2];
+    if unsafe { libc::pipe(fds.as_mut_ptr()) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let (mut read, write) = unsafe {
+        (fs::File::from_raw_fd(fds[0]), fs::File::from_raw_fd(fds[1]))
+    };
+    match unsafe { libc::fork() } {
+        -1 => Err(io::Error::last_os_error()),
+        0 => {
+            drop(read);
+            unsafe { libc::setsid() };
+            detach_stdio()?;
+            Ok(Daemon { pipe: Some(write) })
+        }
+        _ => {
+            drop(write);
+            let mut status = [0u8];
+            let code = match read.read_exact(&mut status) {
+                Ok(_) => i32::from(status[0]),
+                Err(_) => 1,
+            };
+            std::process::exit(code);
+        }
+    }
+}
+
+impl Daemon {
+    /// Lets the parent exit with `code`, 0 once mounted
+    pub fn report(&mut self, code: i32) {
+        if let Some(mut pipe) = self.pipe.take() {
+            pipe.write_all(&[code as u8]).unwrap_or(());
+        }
+    }
+}
@@ -0,0 +1,110 @@
+use std::collections::HashSet;
+use std::io;
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::sync::{Mutex, OnceLock};
+use std::thread;
+use std::time::Duration;
+
+// A hung network source (NFS, sshfs) blocks every request that touches it,
+// and the mount looks dead. With --io-timeout, listings, opens and reads of
+// the source run on a thread of their own, and the request gives up with
+// ETIMEDOUT once the timeout has passed. The thread is left to finish, and
+// what it comes back with is dropped.
+//
+// Writes aren't given up halfway. A handle with an operation that timed out
+// refuses the writes after it instead, the source is unlikely to take them.
+
+static TIMEOUT: OnceLock<Option<Duration>> = OnceLock::new();
+// handles with an operation that timed out, until released
+static TIMED_OUT: Mutex<Option<HashSet<u64>>> = Mutex::new(None);
+
+// Debug builds sleep this many milliseconds in each timed operation, so the
+// tests can check that it is given up on
+#[cfg(debug_assertions)]
+const INJECT_VAR: &str = "FUSE_RM_INJECT_SOURCE_DELAY_MS";
+
+#[cfg(debug_assertions)]
+fn injected_delay() {
+    static DELAY: OnceLock<u64> = OnceLock::new();
+    let delay = DELAY.get_or_init(|| {
+        let millis = std::env::var(INJECT_VAR).ok();
+        millis.and_then(|m| m.parse().ok()).unwrap_or(0)
+    });
+    if *delay > 0 {
+        thread::sleep(Duration::from_millis(*delay));
+    }
+}
+
+#[cfg(not(debug_assertions))]
+fn injected_delay() {}
+
+pub fn configure(timeout: Option<Duration>) {
+    TIMEOUT.get_or_init(|| timeout);
+}
+
+pub fn enabled() -> bool {
+    TIMEOUT.get().copied().flatten().is_some()
+}
+
+/// `op` on the source, or ETIMEDOUT if it doesn't finish in time
+pub fn run<T: Send + 'static>(
+    what: &str,
+    op: impl FnOnce() -> io::Result<T> + Send + 'static,
+) -> io::Result<T> {
+    let timeout = match TIMEOUT.get().copied().flatten() {
+        Some(timeout) => timeout,
+        None => return op(),
+    };
+    let (tx, rx) = mpsc::sync_channel(1);
+    thread::Builder::new()
+        .name("fuse-rm-deadline".to_string())
+        .spawn(move || {
+            injected_delay();
+            // nobody waits any more if it timed out
+            let _ = tx.send(op());
+        })?;
+    match rx.recv_timeout(timeout) {
+        Ok(res) => res,
+        Err(RecvTimeoutError::Timeout) => {
+            warn!("{} took longer than {:?}, giving up", what, timeout);
+            Err(io::Error::from_raw_os_error(libc::ETIMEDOUT))
+        }
+        // it panicked
+        Err(RecvTimeoutError::Disconnected) => {
+            Err(io::Error::from_raw_os_error(libc::EIO))
+        }
+    }
+}
+
+/// `run` for an operation of the handle `fh`, which is marked if it times
+/// out
+pub fn run_for<T: Send + 'static>(
+    fh: u64,
+    what: &str,
+    op: impl FnOnce() -> io::Result<T> + Send + 'static,
+) -> io::Result<T> {
+    let res = run(what, op);
+    if matches!(&res, Err(e) if e.raw_os_error() == Some(libc::ETIMEDOUT)) {
+        TIMED_OUT
+            .lock()
+            .unwrap()
+            .get_or_insert_with(HashSet::new)
+            .insert(fh);
+    }
+    res
+}
+
+/// Whether an operation of the handle `fh` timed out
+pub fn timed_out(fh: u64) -> bool {
+    let timed_out = TIMED_OUT.lock().unwrap();
+    timed_out
+        .as_ref()
+        .is_some_and(|handles| handles.contains(&fh))
+}
+
+/// Forgets a released handle
+pub fn forget(fh: u64) {
+    if let Some(handles) = TIMED_OUT.lock().unwrap().as_mut() {
+        handles.remove(&fh);
+    }
+}
@@ -0,0 +1,198 @@
+use serde_json::Value;
+use std::collections::HashMap;
+use std::ffi::{OsStr, OsString};
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use crate::control;
+use crate::direntry::DirEntry;
+use crate::sha256;
+use crate::timeconv;
+
+// Dropping the same pdf again makes another document of it on the tablet.
+// With --dedup, a new document is compared when it is closed with those of
+// the same size outside the trash, and one with the same data isn't
+// created: the close fails with EEXIST, or succeeds with the copy
+// discarded. The hashes are kept with the size and mtime of the data in
+// .fuse-rm/dedup.json in the source, so that only what changed is hashed
+// again at the next mount.
+
+const INDEX_FILE: &str = "dedup.json";
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Dedup {
+    Off,
+    // close(2) fails with EEXIST
+    Fail,
+    // close(2) succeeds, nothing is created
+    Discard,
+}
+
+impl std::str::FromStr for Dedup {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Dedup, String> {
+        match s {
+            "off" => Ok(Dedup::Off),
+            "fail" => Ok(Dedup::Fail),
+            "discard" => Ok(Dedup::Discard),
+            _ => Err(format!("Unknown dedup mode: {}", s)),
+        }
+    }
+}
+
+struct Hashed {
+    size: u64,
+    mtime: i64,
+    sha256: String,
+}
+
+/// The hashes of the data of the documents, by uuid
+pub struct Index {
+    path: PathBuf,
+    // read from the file when first needed
+    hashes: Option<HashMap<OsString, Hashed>>,
+    // of the new documents compared, until they are finalized
+    staged: HashMap<OsString, String>,
+}
+
+impl Index {
+    pub fn new(source: &Path) -> Index {
+        Index {
+            path: source.join(control::DIR_NAME).join(INDEX_FILE),
+            hashes: None,
+            staged: HashMap::new(),
+        }
+    }
+
+    fn load(&mut self) -> &mut HashMap<OsString, Hashed> {
+        let path = &self.path;
+        self.hashes.get_or_insert_with(|| {
+            let value: Value = fs::read(path)
+                .ok()
+                .and_then(|data| serde_json::from_slice(&data).ok())
+                .unwrap_or(Value::Null);
+            let entries = value.as_object().into_iter().flatten();
+            entries
+                .filter_map(|(uuid, hashed)| {
+                    let hashed = Hashed {
+                        size: hashed.get("size")?.as_u64()?,
+                        mtime: hashed.get("mtime")?.as_i64()?,
+                        sha256: hashed.get("sha256")?.as_str()?.to_string(),
+                    };
+                    Some((OsString::from(uuid), hashed))
+                })
+                .collect()
+        })
+    }
+
+    fn save(&self) -> io::Result<()> {
+        let hashes = match &self.hashes {
+            Some(hashes) => hashes,
+            None => return Ok(()),
+        };
+        let value: serde_json::Map<String, Value> = hashes
+            .iter()
+            .map(|(uuid, hashed)| {
+                let hashed = json!({
+                    "size": hashed.size,
+                    "mtime": hashed.mtime,
+                    "sha256": hashed.sha256,
+                });
+                (uuid.to_string_lossy().to_string(), hashed)
+            })
+            .collect();
+        fs::create_dir_all(self.path.parent().unwrap_or(Path::new("")))?;
+        let tmp = self.path.with_extension("tmp");
+        fs::write(&tmp, serde_json::to_vec(&value)?)?;
+        fs::rename(&tmp, &self.path)
+    }
+
+    // The hash of the data of `doc`, hashed again if it changed
+    fn hash(&mut self, doc: &DirEntry) -> io::Result<String> {
+        let path = doc.source_file_path();
+        let meta = fs::metadata(&path)?;
+        let (size, mtime) = (meta.len(), timeconv::to_millis(meta.modified()?));
+        let hashes = self.load();
+        if let Some(hashed) = hashes.get(&doc.prefix) {
+            if hashed.size == size && hashed.mtime == mtime {
+                return Ok(hashed.sha256.clone());
+            }
+        }
+        let sha256 = sha256::hash_file(&path)?;
+        let hashed = Hashed {
+            size,
+            mtime,
+            sha256: sha256.clone(),
+        };
+        hashes.insert(doc.prefix.clone(), hashed);
+        Ok(sha256)
+    }
+
+    /// The uuid of the document of `docs` with the same data as the new
+    /// document `uuid` staged at `path`
+    pub fn find(
+        &mut self,
+        docs: &[&DirEntry],
+        uuid: &OsStr,
+        path: &Path,
+    ) -> io::Result<Option<OsString>> {
+        let size = fs::metadata(path)?.len();
+        let mut sha256 = None;
+        let mut res = None;
+        for doc in docs.iter().filter(|doc| doc.attr.size == size) {
+            let staged = match &sha256 {
+                Some(staged) => staged,
+                None => sha256.insert(sha256::hash_file(path)?),
+            };
+            match self.hash(doc) {
+                Ok(hash) if hash == *staged => {
+                    res = Some(doc.prefix.clone());
+                    break;
+                }
+                Ok(_) => (),
+                Err(e) => debug!("dedup: can't hash {:?}: {}", doc.prefix, e),
+            }
+        }
+        if let Some(sha256) = sha256 {
+            self.staged.insert(uuid.to_os_string(), sha256);
+        }
+        if let Err(e) = self.save() {
+            warn!("dedup: can't write {:?}: {}", self.path, e);
+        }
+        Ok(res)
+    }
+
+    /// Adds a document once it is finalized, with the hash of its data when
+    /// it was compared
+    pub fn add(&mut self, doc: &DirEntry) {
+        // a new document of a size no other has wasn't hashed
+        let sha256 = match self.staged.remove(&doc.prefix) {
+            Some(sha256) => sha256,
+            None => return,
+        };
+        let res = fs::metadata(doc.source_file_path()).and_then(|meta| {
+            let hashed = Hashed {
+                size: meta.len(),
+                mtime: timeconv::to_millis(meta.modified()?),
+                sha256,
+            };
+            self.load().insert(doc.prefix.clone(), hashed);
+            self.save()
+        });
+        if let Err(e) = res {
+            warn!("dedup: can't add {:?}: {}", doc.prefix, e);
+        }
+    }
+
+    /// Forgets a document removed, or a new one discarded
+    pub fn remove(&mut self, uuid: &OsStr) {
+        self.staged.remove(uuid);
+        if self.load().remove(uuid).is_some() {
+            if let Err(e) = self.save() {
+                warn!("dedup: can't write {:?}: {}", self.path, e);
+            }
+        }
+    }
+}
@@ -1,23 +1,39 @@
 use fuser::{FileAttr, FileType};
+use std::collections::BTreeMap;
 use std::ffi::{OsStr, OsString};
 use std::fs;
-use std::io;
+use std::io::{self, Read};
 use std::os::unix::fs::MetadataExt;
 use std::path::{Path, PathBuf};
-use std::time::{Duration, UNIX_EPOCH};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Mutex, OnceLock};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
-use crate::jsonmetadata::JsonMetadata;
+use crate::control;
+use crate::filetypes;
+use crate::jsonmetadata::{to_device_json, DocType, JsonMetadata};
+use crate::normalize;
+use crate::options::Rendition;
+use crate::staging;
+use crate::tags;
 
+#[allow(clippy::upper_case_acronyms)]
 #[derive(Eq, Hash, Debug, Copy, Clone, PartialEq)]
 pub enum EntryType {
     PDF,
     EPUB,
     RMLINES,
     PENDING,
+    // read-only view of a document's highlights, a file or a dir of pages
+    HIGHLIGHTS,
+    // a document whose data file is gone, shown as an empty file
+    MISSING,
+    // none of the above, stored with --allow-any-type under this extension
+    OTHER(&'static str),
     NONE,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct DirEntry {
     pub root_path: PathBuf,
     pub prefix: OsString,
@@ -26,41 +42,326 @@ pub struct DirEntry {
     pub parent: OsString,
     pub attr: FileAttr,
 
-    json_metadata: JsonMetadata,
+    // The rest of the metadata is only needed when changing it, so it is
+    // read from the file then instead of being kept for every entry
+    last_modified: Option<SystemTime>,
+    // of a type xochitl has but no file shows, like a template
+    pub hidden: bool,
+    // the read-only pdf rendition of an epub, shown next to it
+    pub rendition: bool,
+    // marked deleted by xochitl, gone from the tablet but not yet removed
+    pub deleted: bool,
+    // synced to the cloud with no local changes since, None without
+    // metadata
+    pub synced: Option<bool>,
 }
 
-const ENTRYMAP: &'static [(EntryType, &'static str)] = &[
+/// A file or dir xochitl keeps for an entry, named after its uuid
+#[derive(Debug, Clone, PartialEq)]
+pub struct AssociatedPath {
+    pub path: PathBuf,
+    pub is_dir: bool,
+    // the entry is broken without it, the others come and go
+    pub required: bool,
+}
+
+// What a document may have besides its data and metadata, by suffix of the
+// uuid: the pages of notebooks and annotations, caches xochitl rebuilds, the
+// file a template is made of
+const DOCUMENT_PATHS: &[(&str, bool)] = &[
+    (".content", false),
+    (".pagedata", false),
+    (".local", false),
+    (".bookm", false),
+    (".epubindex", false),
+    (".template", false),
+    ("", true),
+    (".thumbnails", true),
+    (".highlights", true),
+    (".cache", true),
+    (".textconversion", true),
+];
+
+const ENTRYMAP: &[(EntryType, &str)] = &[
     (EntryType::EPUB, "epub"),
     (EntryType::PDF, "pdf"),
     (EntryType::RMLINES, "rm"),
 ];
 
-pub fn entry_type_ext(e: &EntryType) -> &str {
-    ENTRYMAP
-        .iter()
-        .find(|x| x.0 == *e)
-        .unwrap_or(&(EntryType::NONE, ""))
-        .1
+/// The extensions of the supported types, as the device names the data
+/// files or as the mount shows them. `--map rm=lines` shows notebooks as
+/// .lines, and takes .lines for a notebook in new names; other types keep
+/// their own extension.
+#[derive(Debug, Clone)]
+pub struct ExtensionMap {
+    exts: Vec<(EntryType, String)>,
+}
+
+impl Default for ExtensionMap {
+    fn default() -> ExtensionMap {
+        ExtensionMap {
+            exts: ENTRYMAP
+                .iter()
+                .map(|(tp, ext)| (*tp, ext.to_string()))
+                .collect(),
+        }
+    }
+}
+
+static DEVICE_EXTENSIONS: OnceLock<ExtensionMap> = OnceLock::new();
+static SHOWN_EXTENSIONS: OnceLock<ExtensionMap> = OnceLock::new();
+
+impl ExtensionMap {
+    /// The default map with the `from=to` entries of --map. Only the
+    /// supported types can be mapped, and to an extension no other type
+    /// shows.
+    pub fn new(maps: &[String]) -> Result<ExtensionMap, String> {
+        let mut res = ExtensionMap::default();
+        for map in maps {
+            let (from, to) = match map.split_once('=') {
+                Some((from, to)) if filetypes::is_valid_ext(to) => (from, to),
+                _ => return Err(format!("Invalid mapping: {}", map)),
+            };
+            let i = match res.exts.iter().position(|(tp, _)| {
+                *tp == *ExtensionMap::device().entry_type(from)
+            }) {
+                Some(i) => i,
+                None => return Err(format!("Unknown type: {}", from)),
+            };
+            res.exts[i].1 = to.to_string();
+        }
+        for (i, (_, ext)) in res.exts.iter().enumerate() {
+            if res.exts[..i].iter().any(|(_, other)| other == ext) {
+                return Err(format!("Extension mapped twice: {}", ext));
+            }
+        }
+        Ok(res)
+    }
+
+    /// The extensions of the data files
+    pub fn device() -> &'static ExtensionMap {
+        DEVICE_EXTENSIONS.get_or_init(ExtensionMap::default)
+    }
+
+    /// The extensions in the names of the mount
+    pub fn shown() -> &'static ExtensionMap {
+        SHOWN_EXTENSIONS.get().unwrap_or_else(ExtensionMap::device)
+    }
+
+    pub fn ext<'a>(&'a self, e: &'a EntryType) -> &'a str {
+        if let EntryType::OTHER(ext) = e {
+            return ext;
+        }
+        self.exts
+            .iter()
+            .find(|x| x.0 == *e)
+            .map_or("", |(_, ext)| ext)
+    }
+
+    pub fn entry_type(&self, ext: &str) -> &EntryType {
+        self.exts
+            .iter()
+            .find(|x| x.1 == ext)
+            .map_or(&EntryType::NONE, |(tp, _)| tp)
+    }
+}
+
+pub fn set_extensions(map: ExtensionMap) {
+    SHOWN_EXTENSIONS.get_or_init(|| map);
+}
+
+/// `name` without the extension of a known type, the name a document is
+/// shown under on the tablet
+pub fn bare_name(name: &OsStr) -> &OsStr {
+    let path = Path::new(name);
+    let known = path.extension().and_then(OsStr::to_str).is_some_and(|ext| {
+        ExtensionMap::shown().entry_type(ext) != &EntryType::NONE
+    });
+    match path.file_stem() {
+        Some(stem) if known => stem,
+        _ => name,
+    }
+}
+
+// A document named with a shown extension is stored under the device's, so
+// the tablet shows the usual name
+fn stored_name(name: &OsStr) -> OsString {
+    let path = Path::new(name);
+    let tp = path
+        .extension()
+        .and_then(OsStr::to_str)
+        .map(|ext| ExtensionMap::shown().entry_type(ext));
+    match tp {
+        Some(tp) if *tp != EntryType::NONE => path
+            .with_extension(ExtensionMap::device().ext(tp))
+            .into_os_string(),
+        _ => name.to_os_string(),
+    }
+}
+
+fn content_file_type(path: &Path) -> Option<String> {
+    fs::read(path.with_extension("content"))
+        .ok()
+        .and_then(|c| serde_json::from_slice::<serde_json::Value>(&c).ok())
+        .and_then(|c| c["fileType"].as_str().map(String::from))
+}
+
+// Notebooks keep their pages in a dir named like the document
+fn is_notebook(path: &Path) -> bool {
+    path.with_extension("").is_dir()
+        || content_file_type(path).as_deref() == Some("notebook")
+}
+
+// The .rm pages in the page dir of a document and their bytes, as of the
+// mtime of the dir. Pages are written to a temp file and renamed over,
+// which changes the mtime of the dir.
+struct Pages {
+    dir_mtime: SystemTime,
+    files: Vec<PathBuf>,
+    size: u64,
 }
 
-pub fn ext_entry_type(ext: &str) -> &EntryType {
-    &ENTRYMAP
+static PAGES: Mutex<BTreeMap<PathBuf, Pages>> = Mutex::new(BTreeMap::new());
+
+/// Forgets the pages of every notebook, they are listed again when needed
+pub fn clear_pages() {
+    PAGES.lock().unwrap().clear();
+}
+
+/// Forgets the pages of the notebook of `path`
+pub fn forget_pages(path: &Path) {
+    PAGES.lock().unwrap().remove(&path.with_extension(""));
+}
+
+// `f` of the pages of the document of `path`, None without a page dir.
+// Only stats the pages.
+fn with_pages<T>(path: &Path, f: impl FnOnce(&Pages) -> T) -> Option<T> {
+    let dir = path.with_extension("");
+    let dir_mtime = fs::metadata(&dir).and_then(|m| m.modified()).ok()?;
+    let mut cache = PAGES.lock().unwrap();
+    if cache.get(&dir).is_none_or(|p| p.dir_mtime != dir_mtime) {
+        let mut pages = Pages {
+            dir_mtime,
+            files: Vec::new(),
+            size: 0,
+        };
+        for page in fs::read_dir(&dir).into_iter().flatten().flatten() {
+            let path = page.path();
+            match page.metadata() {
+                Ok(meta)
+                    if meta.is_file()
+                        && path.extension() == Some(OsStr::new("rm")) =>
+                {
+                    pages.size += meta.len();
+                    pages.files.push(path);
+                }
+                _ => (),
+            }
+        }
+        cache.insert(dir.clone(), pages);
+    }
+    cache.get(&dir).map(f)
+}
+
+// The bytes in the pages of a notebook, 0 without a page dir
+fn notebook_size(path: &Path) -> u64 {
+    with_pages(path, |pages| pages.size).unwrap_or(0)
+}
+
+// Set once at startup, it takes a stat per page in every scan
+static DEEP_MTIME: AtomicBool = AtomicBool::new(false);
+
+pub fn set_deep_mtime(deep: bool) {
+    DEEP_MTIME.store(deep, Ordering::Relaxed);
+}
+
+// When the newest page was modified. The pages are stat'ed every time,
+// touching one in place doesn't change the mtime of the dir.
+fn newest_page(path: &Path) -> Option<SystemTime> {
+    with_pages(path, |pages| pages.files.clone())?
         .iter()
-        .find(|x| x.1 == ext)
-        .unwrap_or(&(EntryType::NONE, ""))
-        .0
+        .filter_map(|page| fs::metadata(page).and_then(|m| m.modified()).ok())
+        .max()
+}
+
+// Set once at startup like the file types, so that every scan of a document
+// finds the same data file for its size and its reads
+static RENDITION: OnceLock<Rendition> = OnceLock::new();
+
+pub fn set_rendition(rendition: Rendition) {
+    RENDITION.get_or_init(|| rendition);
 }
 
 fn determine_entry_type(path: &Path) -> (EntryType, u64) {
     let mut p = PathBuf::from(path);
-    for (tp, ext) in ENTRYMAP {
+    // the pdf a document was rendered to is looked for before its epub
+    let rendition = ENTRYMAP
+        .iter()
+        .filter(|(tp, _)| *tp == EntryType::PDF)
+        .filter(|_| RENDITION.get() == Some(&Rendition::Pdf));
+    for (tp, ext) in rendition.chain(ENTRYMAP) {
         p.set_extension(ext);
-        if p.exists() {
-            let size = fs::File::open(p).unwrap().metadata().unwrap().len();
-            return (*tp, size);
+        // one stat tells that it is there and its size
+        if let Ok(meta) = fs::metadata(&p) {
+            return (*tp, meta.len());
+        }
+    }
+    // other types are only looked for under the type in the content
+    let other = content_file_type(path)
+        .and_then(|ft| filetypes::stored_ext(&ft))
+        .and_then(|ext| {
+            Some((ext, fs::metadata(path.with_extension(ext)).ok()?))
+        });
+    match other {
+        Some((ext, meta)) if meta.is_file() => {
+            (EntryType::OTHER(ext), meta.len())
+        }
+        _ => (EntryType::NONE, 0),
+    }
+}
+
+// Paths that aren't required may be missing
+fn remove_path(path: &AssociatedPath) -> io::Result<()> {
+    let res = if path.is_dir {
+        fs::remove_dir_all(&path.path)
+    } else {
+        fs::remove_file(&path.path)
+    };
+    match res {
+        Err(e) if e.kind() == io::ErrorKind::NotFound && !path.required => {
+            Ok(())
         }
+        res => res,
     }
-    return (EntryType::NONE, 0);
+}
+
+fn new_uuid() -> OsString {
+    OsString::from(uuid::Uuid::new_v4().to_hyphenated().to_string())
+}
+
+// st_blocks is in 512 byte units, whatever the block size of the fs
+pub fn blocks(size: u64) -> u64 {
+    size.div_ceil(512)
+}
+
+// Virtual dirs use reserved inodes, everything else has the inode of its
+// metadata file in the source
+pub const TRASH_INO: u64 = 2;
+pub const RECENT_INO: u64 = 3;
+pub const DELETED_INO: u64 = 4;
+pub const ORPHANS_INO: u64 = 5;
+// the control dir and its file, never listed with the others
+pub const CONTROL_INO: u64 = 6;
+pub const REFRESH_INO: u64 = 7;
+
+// In a union mount, the entries of the extra sources have the number of their
+// source in the top bits of the inode, so inodes are unique across sources.
+// Inodes with these bits set in a source aren't told apart.
+pub const SOURCE_SHIFT: u32 = 56;
+
+/// The source bits of `ino`, 0 for the first source
+pub fn source_bits(ino: u64) -> u64 {
+    ino & !((1 << SOURCE_SHIFT) - 1)
 }
 
 pub const DEFAULT_TTL: Duration = Duration::from_secs(1); // 1 second
@@ -76,8 +377,9 @@ pub const ROOT_DIR_ATTR: FileAttr = FileAttr {
     kind: FileType::Directory,
     perm: 0o755,
     nlink: 2,
-    uid: 501,
-    gid: 20,
+    // RMXFS gives the virtual dirs and new entries the mounting user
+    uid: 0,
+    gid: 0,
     rdev: 0,
     flags: 0,
     blksize: 512,
@@ -89,26 +391,71 @@ impl DirEntry {
         attr: &FileAttr,
         json_data: &JsonMetadata,
     ) -> DirEntry {
-        let (tp, sz) = determine_entry_type(file_path);
+        // The type in the metadata tells folders from documents, the data
+        // file only gives a document its extension and size. A stray data
+        // file next to a folder's metadata is ignored. Other types are shown
+        // as documents, when they are shown at all. Folders aren't looked
+        // for data files, the kind of every entry comes from the metadata.
+        let is_document = json_data.doc_type() != &DocType::CollectionType;
+        let found = if is_document {
+            determine_entry_type(file_path)
+        } else {
+            (EntryType::NONE, 0)
+        };
+        let (tp, sz) = match found {
+            _ if !is_document => (EntryType::NONE, 0),
+            (EntryType::NONE, _) if is_notebook(file_path) => {
+                (EntryType::RMLINES, notebook_size(file_path))
+            }
+            (EntryType::NONE, _) => (EntryType::MISSING, 0),
+            found => found,
+        };
+        // The metadata file is written again on every change, so its birth
+        // time is only of use where createdTime is missing
+        let last_modified = json_data.last_modified();
+        let crtime = json_data
+            .created_time()
+            // folders of 3.x have "0"
+            .filter(|t| *t != UNIX_EPOCH)
+            .or_else(|| Some(attr.crtime).filter(|t| *t != UNIX_EPOCH))
+            .or(last_modified)
+            .unwrap_or(attr.crtime);
+        // handwriting may only change the pages until the firmware bumps
+        // lastModified, if it does
+        let mut mtime = attr.mtime;
+        if DEEP_MTIME.load(Ordering::Relaxed) && is_document {
+            mtime = newest_page(file_path)
+                .into_iter()
+                .chain(last_modified)
+                .fold(mtime, SystemTime::max);
+        }
         DirEntry {
             root_path: PathBuf::from(
                 file_path.parent().unwrap_or(Path::new("")),
             ),
             prefix: file_path.file_stem().unwrap().to_os_string(),
             entry_type: tp,
-            name: OsString::from(&json_data.visible_name),
+            name: OsString::from(&*normalize::name(&json_data.visible_name)),
             parent: OsString::from(&json_data.parent),
             attr: FileAttr {
                 size: sz,
-                kind: if tp == EntryType::NONE {
-                    FileType::Directory
-                } else {
+                // the data file, not the metadata the attr was taken from
+                blocks: blocks(sz),
+                kind: if is_document {
                     FileType::RegularFile
+                } else {
+                    FileType::Directory
                 },
                 perm: ROOT_DIR_ATTR.perm,
+                mtime,
+                crtime,
                 ..*attr
             },
-            json_metadata: json_data.clone(),
+            last_modified,
+            hidden: matches!(json_data.doc_type(), DocType::Other(_)),
+            rendition: false,
+            deleted: json_data.is_deleted(),
+            synced: Some(json_data.is_synced()),
         }
     }
 
@@ -121,8 +468,11 @@ impl DirEntry {
             name: OsString::from(""),
             parent: OsString::from(""),
             attr: ROOT_DIR_ATTR,
-
-            json_metadata: JsonMetadata::new_file("", ""),
+            last_modified: None,
+            hidden: false,
+            rendition: false,
+            deleted: false,
+            synced: None,
         }
     }
 
@@ -134,25 +484,229 @@ impl DirEntry {
             name: OsString::from("trash"),
             parent: OsString::from(""),
             attr: FileAttr {
-                ino: 2,
-                ..*&ROOT_DIR_ATTR
+                ino: TRASH_INO,
+                ..ROOT_DIR_ATTR
+            },
+            last_modified: None,
+            hidden: false,
+            rendition: false,
+            deleted: false,
+            synced: None,
+        }
+    }
+
+    pub fn make_recent(dir_path: &Path) -> DirEntry {
+        DirEntry {
+            root_path: PathBuf::from(dir_path),
+            prefix: OsString::from("recent"),
+            entry_type: EntryType::NONE,
+            name: OsString::from("Recent"),
+            parent: OsString::from(""),
+            attr: FileAttr {
+                ino: RECENT_INO,
+                perm: 0o555,
+                ..ROOT_DIR_ATTR
+            },
+            last_modified: None,
+            hidden: false,
+            rendition: false,
+            deleted: false,
+            synced: None,
+        }
+    }
+
+    pub fn make_deleted(dir_path: &Path) -> DirEntry {
+        DirEntry {
+            root_path: PathBuf::from(dir_path),
+            prefix: OsString::from("deleted"),
+            entry_type: EntryType::NONE,
+            name: OsString::from(".deleted"),
+            parent: OsString::from(""),
+            attr: FileAttr {
+                ino: DELETED_INO,
+                perm: 0o555,
+                ..ROOT_DIR_ATTR
+            },
+            last_modified: None,
+            hidden: false,
+            rendition: false,
+            deleted: false,
+            synced: None,
+        }
+    }
+
+    pub fn make_orphans(dir_path: &Path) -> DirEntry {
+        DirEntry {
+            root_path: PathBuf::from(dir_path),
+            prefix: OsString::from("orphans"),
+            entry_type: EntryType::NONE,
+            name: OsString::from(".orphans"),
+            parent: OsString::from(""),
+            attr: FileAttr {
+                ino: ORPHANS_INO,
+                ..ROOT_DIR_ATTR
+            },
+            last_modified: None,
+            hidden: false,
+            rendition: false,
+            deleted: false,
+            synced: None,
+        }
+    }
+
+    pub fn make_control(dir_path: &Path) -> DirEntry {
+        DirEntry {
+            root_path: PathBuf::from(dir_path),
+            prefix: OsString::from("control"),
+            entry_type: EntryType::NONE,
+            name: OsString::from(control::DIR_NAME),
+            parent: OsString::from(""),
+            attr: FileAttr {
+                ino: CONTROL_INO,
+                perm: 0o555,
+                ..ROOT_DIR_ATTR
+            },
+            last_modified: None,
+            hidden: false,
+            rendition: false,
+            deleted: false,
+            synced: None,
+        }
+    }
+
+    /// The refresh file of the control dir, `size` long when read
+    pub fn make_refresh(dir_path: &Path, size: u64) -> DirEntry {
+        DirEntry {
+            root_path: PathBuf::from(dir_path),
+            prefix: OsString::from("refresh"),
+            entry_type: EntryType::NONE,
+            name: OsString::from(control::REFRESH_NAME),
+            parent: OsString::from("control"),
+            attr: FileAttr {
+                ino: REFRESH_INO,
+                size,
+                blocks: blocks(size),
+                kind: FileType::RegularFile,
+                perm: 0o644,
+                nlink: 1,
+                ..ROOT_DIR_ATTR
+            },
+            last_modified: None,
+            hidden: false,
+            rendition: false,
+            deleted: false,
+            synced: None,
+        }
+    }
+
+    /// A data file without metadata, shown under its raw name in .orphans
+    pub fn make_orphan(
+        file_path: &Path,
+        attr: &FileAttr,
+        entry_type: EntryType,
+    ) -> DirEntry {
+        let stem = file_path.file_stem().unwrap().to_os_string();
+        DirEntry {
+            root_path: PathBuf::from(
+                file_path.parent().unwrap_or(Path::new("")),
+            ),
+            prefix: stem.clone(),
+            entry_type,
+            name: stem,
+            parent: OsString::from("orphans"),
+            attr: FileAttr {
+                blocks: blocks(attr.size),
+                kind: FileType::RegularFile,
+                perm: 0o644,
+                ..*attr
+            },
+            last_modified: None,
+            hidden: false,
+            rendition: false,
+            deleted: false,
+            synced: None,
+        }
+    }
+
+    /// The highlights companion of `doc`, `Book.pdf.highlights.json` or with
+    /// `raw` the dir `.Book.pdf.highlights` for the pages
+    pub fn make_highlights(
+        doc: &DirEntry,
+        attr: &FileAttr,
+        size: u64,
+        raw: bool,
+    ) -> DirEntry {
+        let mut name = OsString::from(if raw { "." } else { "" });
+        name.push(doc.file_name());
+        name.push(if raw {
+            ".highlights"
+        } else {
+            ".highlights.json"
+        });
+        let mut prefix = doc.prefix.clone();
+        prefix.push(".highlights");
+        DirEntry {
+            root_path: doc.root_path.clone(),
+            prefix,
+            entry_type: EntryType::HIGHLIGHTS,
+            name,
+            parent: doc.parent.clone(),
+            attr: FileAttr {
+                size,
+                blocks: blocks(size),
+                kind: if raw {
+                    FileType::Directory
+                } else {
+                    FileType::RegularFile
+                },
+                perm: if raw { 0o555 } else { 0o444 },
+                ..*attr
             },
+            last_modified: None,
+            hidden: false,
+            rendition: false,
+            deleted: false,
+            synced: None,
+        }
+    }
 
-            json_metadata: JsonMetadata::new_file("trash", ""),
+    /// A page file in a raw highlights dir
+    pub fn make_highlights_page(
+        dir: &DirEntry,
+        file_name: &OsStr,
+        attr: &FileAttr,
+    ) -> DirEntry {
+        DirEntry {
+            root_path: dir.root_path.clone(),
+            prefix: Path::new(&dir.prefix).join(file_name).into_os_string(),
+            entry_type: EntryType::HIGHLIGHTS,
+            name: file_name.to_os_string(),
+            parent: dir.prefix.clone(),
+            attr: FileAttr {
+                blocks: blocks(attr.size),
+                kind: FileType::RegularFile,
+                perm: 0o444,
+                ..*attr
+            },
+            last_modified: None,
+            hidden: false,
+            rendition: false,
+            deleted: false,
+            synced: None,
         }
     }
 
     pub fn create_entry(
         parent_dir: &DirEntry,
         name: &OsStr,
+        prefix: OsString,
         mode: u32,
         umask: u32,
         is_dir: bool,
     ) -> io::Result<DirEntry> {
-        let uid = uuid::Uuid::new_v4();
         let mut entry = DirEntry {
             root_path: PathBuf::from(&parent_dir.root_path),
-            prefix: OsString::from(uid.to_hyphenated().to_string()),
+            prefix,
             entry_type: if is_dir {
                 EntryType::NONE
             } else {
@@ -168,22 +722,32 @@ impl DirEntry {
                 } else {
                     FileType::RegularFile
                 },
-                ..*&ROOT_DIR_ATTR
-            },
-            json_metadata: if is_dir {
-                JsonMetadata::new_dir(
-                    name.to_str().unwrap(),
-                    parent_dir.prefix.to_str().unwrap(),
-                )
-            } else {
-                JsonMetadata::new_file(
-                    name.to_str().unwrap(),
-                    parent_dir.prefix.to_str().unwrap(),
-                )
+                ..ROOT_DIR_ATTR
             },
+            last_modified: None,
+            hidden: false,
+            rendition: false,
+            deleted: false,
+            synced: None,
         };
+        let json_metadata = if is_dir {
+            JsonMetadata::new_dir(
+                name.to_str().unwrap(),
+                parent_dir.prefix.to_str().unwrap(),
+            )
+        } else {
+            JsonMetadata::new_file(
+                stored_name(name).to_str().unwrap(),
+                parent_dir.prefix.to_str().unwrap(),
+            )
+        };
+        entry.last_modified = json_metadata.last_modified();
+        entry.attr.crtime = json_metadata
+            .created_time()
+            .or(entry.last_modified)
+            .unwrap_or(entry.attr.crtime);
         let ino = if is_dir {
-            entry.json_metadata.save_file(entry.metadata_file_name())?
+            json_metadata.save_file(entry.metadata_file_name())?
         } else {
             // We rely on the inode not changing on mv
             let mut temp_file = PathBuf::from(&entry.root_path);
@@ -193,7 +757,7 @@ impl DirEntry {
             }
             temp_file.push(&entry.prefix);
             temp_file.set_extension("metadata");
-            entry.json_metadata.save_file(temp_file)?
+            json_metadata.save_file(temp_file)?
         };
         entry.attr.ino = ino;
         Ok(entry)
@@ -205,7 +769,7 @@ impl DirEntry {
         mode: u32,
         umask: u32,
     ) -> io::Result<DirEntry> {
-        DirEntry::create_entry(parent_dir, name, mode, umask, true)
+        DirEntry::create_entry(parent_dir, name, new_uuid(), mode, umask, true)
     }
 
     pub fn make_file(
@@ -214,98 +778,371 @@ impl DirEntry {
         mode: u32,
         umask: u32,
     ) -> io::Result<DirEntry> {
-        DirEntry::create_entry(parent_dir, name, mode, umask, false)
+        DirEntry::make_file_as(parent_dir, name, new_uuid(), mode, umask)
+    }
+
+    /// A new document with the uuid it had before, for restoring it
+    pub fn make_file_as(
+        parent_dir: &DirEntry,
+        name: &OsStr,
+        prefix: OsString,
+        mode: u32,
+        umask: u32,
+    ) -> io::Result<DirEntry> {
+        DirEntry::create_entry(parent_dir, name, prefix, mode, umask, false)
     }
 
     pub fn forget_pending(&self) {
-        let data_file_path = self.source_file_path();
-        if data_file_path.exists() {
-            fs::remove_file(data_file_path).unwrap();
+        // either may not have been written yet
+        for mut path in self.associated_paths() {
+            path.required = false;
+            if let Err(e) = remove_path(&path) {
+                error!("can't remove {:?}: {}", path.path, e);
+            }
         }
-        let metadata_path = self.metadata_file_name();
-        if metadata_path.exists() {
-            fs::remove_file(metadata_path).unwrap();
+    }
+
+    /// Removes a new document that won't be finalized, its type known or not
+    pub fn discard_pending(&self) {
+        for path in [self.pending_path(), self.pending_metadata_path()] {
+            match fs::remove_file(&path) {
+                Err(e) if e.kind() != io::ErrorKind::NotFound => {
+                    error!("can't remove {:?}: {}", path, e)
+                }
+                _ => (),
+            }
         }
     }
 
-    pub fn finalize_pending(&self) -> io::Result<()> {
+    /// Every path of this entry in the source, data and metadata first. A
+    /// folder has its metadata and maybe a content file, an orphan only its
+    /// data. The virtual dirs and companions have none of their own.
+    pub fn associated_paths(&self) -> Vec<AssociatedPath> {
+        let at = |path, is_dir, required| AssociatedPath {
+            path,
+            is_dir,
+            required,
+        };
+        let mut res = Vec::new();
+        if self.attr.ino <= ORPHANS_INO
+            || self.entry_type == EntryType::HIGHLIGHTS
+            || self.rendition
+        {
+            return res;
+        }
+        match self.entry_type {
+            EntryType::NONE | EntryType::MISSING => (),
+            // notebooks keep their pages in the page dir instead
+            EntryType::RMLINES => {
+                res.push(at(self.source_file_path(), false, false))
+            }
+            _ => res.push(at(self.source_file_path(), false, true)),
+        }
+        if self.parent == "orphans" {
+            return res;
+        }
+        res.push(at(self.metadata_file_name(), false, true));
+        if self.entry_type == EntryType::PENDING {
+            return res;
+        }
+        // the pdf rendition of an epub, or the epub of one shown as its pdf
+        let rendition = match self.entry_type {
+            EntryType::EPUB => Some(EntryType::PDF),
+            EntryType::PDF => Some(EntryType::EPUB),
+            _ => None,
+        };
+        if let Some(tp) = rendition {
+            let path = self.root_path.join(&self.prefix);
+            res.push(at(
+                path.with_extension(ExtensionMap::device().ext(&tp)),
+                false,
+                false,
+            ));
+        }
+        let suffixes = match self.entry_type {
+            EntryType::NONE => &DOCUMENT_PATHS[..1],
+            _ => DOCUMENT_PATHS,
+        };
+        for (suffix, is_dir) in suffixes {
+            let mut name = self.prefix.clone();
+            name.push(suffix);
+            res.push(at(self.root_path.join(name), *is_dir, false));
+        }
+        res
+    }
+
+    /// The metadata of a new document, always in the source's `.pending` so
+    /// the final rename keeps its inode
+    fn pending_metadata_path(&self) -> PathBuf {
+        let mut path = PathBuf::from(&self.root_path);
+        path.push(".pending");
+        path.push(&self.prefix);
+        path.set_extension("metadata");
+        path
+    }
+
+    /// Data is written here first, in `.pending` or the staging dir
+    pub fn pending_path(&self) -> PathBuf {
+        let mut path = staging::data_dir(&self.root_path);
+        path.push(&self.prefix);
+        path
+    }
+
+    /// Moves the pending data in place, for new documents as well as when
+    /// replacing the data of an existing one
+    pub fn finalize_data(&self) -> io::Result<()> {
         if self.entry_type == EntryType::NONE
             || self.entry_type == EntryType::PENDING
         {
             return Err(io::Error::from_raw_os_error(libc::EPERM));
         }
-        let mut source_path = PathBuf::from(&self.root_path);
-        source_path.push(".pending");
-        source_path.push(&self.prefix);
-        fs::rename(&source_path, self.source_file_path())?;
-        source_path.set_extension("metadata");
-        fs::rename(&source_path, self.metadata_file_name())?;
-
-        // The file type is stored in "*.content" (worked without it before)
-        let mut content_path = self.metadata_file_name();
-        content_path.set_extension("content");
-        let content_data = json!({
-            "fileType": entry_type_ext(&self.entry_type)
-        });
-        fs::write(content_path, serde_json::to_vec(&content_data)?)?;
+        staging::move_into(&self.pending_path(), &self.source_file_path())?;
+
+        // The file type is stored in "*.content" (worked without it before),
+        // keep whatever else xochitl put there
+        let content_path = self.content_path();
+        let mut content_data = fs::read(&content_path)
+            .ok()
+            .and_then(|c| serde_json::from_slice(&c).ok())
+            .filter(serde_json::Value::is_object)
+            .unwrap_or_else(|| json!({}));
+        content_data["fileType"] =
+            json!(ExtensionMap::device().ext(&self.entry_type));
+        fs::write(content_path, to_device_json(&content_data)?)?;
+        if let EntryType::OTHER(ext) = self.entry_type {
+            info!("stored {:?} as {}, xochitl won't show it", self.name, ext);
+        }
+
+        Ok(())
+    }
+
+    pub fn finalize_pending(&self) -> io::Result<()> {
+        // The metadata goes last, lookups only see the document once its
+        // data is in place
+        self.finalize_data()?;
+        fs::rename(self.pending_metadata_path(), self.metadata_file_name())
+    }
+
+    /// Completes a new document a crash left in `.pending`. False if its
+    /// data is missing or of no supported type.
+    pub fn recover_pending(root: &Path, prefix: &OsStr) -> io::Result<bool> {
+        let mut entry = DirEntry {
+            root_path: PathBuf::from(root),
+            prefix: prefix.to_os_string(),
+            entry_type: EntryType::PENDING,
+            name: OsString::new(),
+            parent: OsString::new(),
+            attr: ROOT_DIR_ATTR,
+            last_modified: None,
+            hidden: false,
+            rendition: false,
+            deleted: false,
+            synced: None,
+        };
+        // the type of other data may come from the name
+        if let Ok(json_data) =
+            JsonMetadata::from_file(entry.pending_metadata_path())
+        {
+            entry.name =
+                OsString::from(&*normalize::name(&json_data.visible_name));
+        }
+        // stopped between moving the data and the metadata in place
+        let (tp, _) = determine_entry_type(&root.join(prefix));
+        if tp != EntryType::NONE && !entry.pending_path().exists() {
+            entry.entry_type = tp;
+            fs::rename(
+                entry.pending_metadata_path(),
+                entry.metadata_file_name(),
+            )?;
+            return Ok(true);
+        }
+        let mut head = vec![0; 8192];
+        let n = match fs::File::open(entry.pending_path()) {
+            Ok(mut data) => data.read(&mut head)?,
+            Err(_) => return Ok(false),
+        };
+        if n == 0 || entry.update_type(&head[..n]).is_err() {
+            return Ok(false);
+        }
+        entry.finalize_pending()?;
+        Ok(true)
+    }
+
+    /// Marks the document as changed after replacing its data
+    pub fn touch(&mut self) -> io::Result<()> {
+        let mut json_data = JsonMetadata::from_file(self.metadata_file_name())?;
+        json_data.touch();
+        json_data.save_file(self.metadata_file_name())?;
+        self.last_modified = json_data.last_modified();
+        Ok(())
+    }
+
+    // The content file, if there is one holding a json object
+    fn read_content(&self) -> Option<serde_json::Value> {
+        fs::read(self.content_path())
+            .ok()
+            .and_then(|c| serde_json::from_slice(&c).ok())
+            .filter(serde_json::Value::is_object)
+    }
+
+    /// The pdf the device rendered the epub `doc` to, with
+    /// --prefer-rendition both. It shares the uuid and metadata of `doc` but
+    /// has the inode of the pdf.
+    pub fn make_rendition(doc: &DirEntry) -> Option<DirEntry> {
+        if RENDITION.get() != Some(&Rendition::Both)
+            || doc.entry_type != EntryType::EPUB
+        {
+            return None;
+        }
+        let path = doc.root_path.join(&doc.prefix).with_extension("pdf");
+        let meta = fs::metadata(path).ok().filter(|meta| meta.is_file())?;
+        Some(DirEntry {
+            root_path: doc.root_path.clone(),
+            prefix: doc.prefix.clone(),
+            entry_type: EntryType::PDF,
+            name: doc.name.clone(),
+            parent: doc.parent.clone(),
+            attr: FileAttr {
+                ino: meta.ino(),
+                size: meta.len(),
+                blocks: blocks(meta.len()),
+                perm: 0o444,
+                ..doc.attr
+            },
+            last_modified: doc.last_modified,
+            hidden: false,
+            rendition: true,
+            deleted: doc.deleted,
+            synced: doc.synced,
+        })
+    }
 
+    /// The names of the tags, from the metadata if it has them and else from
+    /// the content file
+    pub fn tags(&self) -> io::Result<Vec<String>> {
+        let json_data = JsonMetadata::from_file(self.metadata_file_name())?;
+        if json_data.tags().is_some() {
+            return Ok(tags::names(json_data.tags()));
+        }
+        let content = self.read_content();
+        Ok(tags::names(content.as_ref().and_then(|c| c.get("tags"))))
+    }
+
+    /// Replaces the tags where `tags` reads them, in the content file if
+    /// neither has any (as firmware 3.x does), and marks the entry changed
+    pub fn set_tags(&mut self, names: &[String]) -> io::Result<()> {
+        // the same tags again change nothing, not even lastModified
+        if self.tags()? == names {
+            return Ok(());
+        }
+        let mut json_data = JsonMetadata::from_file(self.metadata_file_name())?;
+        match self.read_content() {
+            Some(mut content) if json_data.tags().is_none() => {
+                content["tags"] =
+                    tags::to_value(names, content.get("tags"), true);
+                fs::write(self.content_path(), to_device_json(&content)?)?;
+            }
+            _ => {
+                let value = tags::to_value(names, json_data.tags(), false);
+                json_data.set_tags(value);
+            }
+        }
+        json_data.touch();
+        json_data.save_file(self.metadata_file_name())?;
+        self.last_modified = json_data.last_modified();
+        Ok(())
+    }
+
+    /// Moves the data of this new document into `target`, which keeps its
+    /// uuid and annotations. The metadata of `target` goes into this entry's
+    /// metadata file first, so the inode the kernel saw renamed stays valid.
+    pub fn replace_content(&self, target: &DirEntry) -> io::Result<()> {
+        let mut json_data =
+            JsonMetadata::from_file(target.metadata_file_name())?;
+        json_data.touch();
+        json_data.save_file(self.metadata_file_name())?;
+        fs::rename(self.source_file_path(), target.source_file_path())?;
+        fs::rename(self.metadata_file_name(), target.metadata_file_name())?;
+        // whatever else was written for the new uuid
+        for path in self.associated_paths().iter().filter(|p| !p.required) {
+            remove_path(path)?;
+        }
+        Ok(())
+    }
+
+    /// Removes every path of the entry: the data, metadata and annotations
+    /// of a document, the metadata of a folder
+    pub fn remove(&self) -> io::Result<()> {
+        for path in self.associated_paths() {
+            remove_path(&path)?;
+        }
         Ok(())
     }
 
+    pub fn content_path(&self) -> PathBuf {
+        self.root_path.join(&self.prefix).with_extension("content")
+    }
+
     pub fn source_file_path(&self) -> PathBuf {
-        let mut path = PathBuf::from(&self.root_path);
+        // the highlights dir, or a page in it
+        if self.entry_type == EntryType::HIGHLIGHTS {
+            return self.root_path.join(&self.prefix);
+        }
         if self.entry_type == EntryType::PENDING {
-            path.push(".pending");
+            return self.pending_path();
         }
+        let mut path = PathBuf::from(&self.root_path);
         path.push(&self.prefix);
-        path.set_extension(entry_type_ext(&self.entry_type));
+        path.set_extension(ExtensionMap::device().ext(&self.entry_type));
         path
     }
 
     pub fn file_name(&self) -> OsString {
+        if self.entry_type == EntryType::HIGHLIGHTS {
+            return self.name.clone();
+        }
         let mut path = PathBuf::from(&self.name);
-        path.set_extension(entry_type_ext(&self.entry_type));
+        path.set_extension(ExtensionMap::shown().ext(&self.entry_type));
         path.into_os_string()
     }
 
     pub fn metadata_file_name(&self) -> PathBuf {
-        let mut path = PathBuf::from(&self.root_path);
         if self.entry_type == EntryType::PENDING {
-            path.push(".pending");
+            return self.pending_metadata_path();
         }
+        let mut path = PathBuf::from(&self.root_path);
         path.push(&self.prefix);
         path.set_extension("metadata");
         path
     }
 
-    pub fn is_parent(&self, parent: &DirEntry) -> bool {
-        (parent.name == "." && self.parent == "")
-            || self.parent == parent.prefix
+    pub fn last_modified(&self) -> Option<SystemTime> {
+        self.last_modified
     }
 
-    pub fn parent_inode(&self) -> io::Result<u64> {
-        if self.parent == "trash" {
-            return Ok(2);
-        }
-        let mut path = PathBuf::from(&self.root_path);
-        path.push(&self.parent);
-        path.set_extension("metadata");
-        Ok(fs::File::open(path)?.metadata()?.ino())
+    /// The root and the virtual dirs are shared by the sources of a union
+    /// mount, folders only hold entries from their own source
+    pub fn is_parent(&self, parent: &DirEntry) -> bool {
+        (parent.name == "." && self.parent.is_empty())
+            || (self.parent == parent.prefix
+                && (parent.attr.ino <= ORPHANS_INO
+                    || self.root_path == parent.root_path))
     }
 
+    /// Without `newparent` the entry stays in its folder on the device
     pub fn rename(
         &self,
-        newparent: &DirEntry,
+        newparent: Option<&OsStr>,
         newname: &OsStr,
     ) -> io::Result<DirEntry> {
-        let mut json_data = self.json_metadata.clone();
-        json_data.visible_name = newname.to_string_lossy().to_string();
-        json_data.parent = newparent.prefix.to_string_lossy().to_string();
-        json_data.save_file(self.metadata_file_name())?;
+        let stored = match self.entry_type {
+            EntryType::NONE => newname.to_os_string(),
+            _ => stored_name(newname),
+        };
+        self.move_as(newparent, &stored.to_string_lossy())?;
         let res = DirEntry {
             name: OsString::from(newname),
-            parent: newparent.prefix.clone(),
-            json_metadata: json_data,
+            parent: newparent.map_or(self.parent.clone(), OsString::from),
             root_path: self.root_path.clone(),
             prefix: self.prefix.clone(),
             ..*self
@@ -314,16 +1151,247 @@ impl DirEntry {
         Ok(res)
     }
 
-    pub fn update_type(&mut self, buf: &[u8]) -> Result<(), &str> {
-        match infer::get(buf) {
-            Some(tp) => {
-                if ext_entry_type(tp.extension()) != &EntryType::NONE {
-                    Ok(self.entry_type = *ext_entry_type(tp.extension()))
+    /// The visibleName in the metadata file, without what the listing adds
+    /// to tell entries apart
+    pub fn stored_name(&self) -> io::Result<String> {
+        Ok(JsonMetadata::from_file(self.metadata_file_name())?.visible_name)
+    }
+
+    /// Moves the entry to `newparent`, with `stored` as the visibleName
+    pub fn move_as(
+        &self,
+        newparent: Option<&OsStr>,
+        stored: &str,
+    ) -> io::Result<()> {
+        let mut json_data = JsonMetadata::from_file(self.metadata_file_name())?;
+        json_data.visible_name = stored.to_string();
+        if let Some(newparent) = newparent {
+            json_data.parent = newparent.to_string_lossy().to_string();
+        }
+        json_data.save_file(self.metadata_file_name())?;
+        Ok(())
+    }
+
+    /// Sets the type from the first bytes of the data, the error is the
+    /// refused type
+    pub fn update_type(&mut self, buf: &[u8]) -> Result<(), String> {
+        let sniffed = infer::get(buf).map(|tp| tp.extension());
+        let device = ExtensionMap::device();
+        self.entry_type = match sniffed.map(|ext| device.entry_type(ext)) {
+            Some(tp) if tp != &EntryType::NONE => *tp,
+            _ => EntryType::OTHER(filetypes::other_ext(sniffed, &self.name)?),
+        };
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rmxfs::list_dir_metadata;
+    use std::collections::BTreeSet;
+
+    // (uuid, type, fileType, data files)
+    const LIBRARY: &[(&str, &str, &str, &[&str])] = &[
+        ("folder", "CollectionType", "", &[]),
+        ("paper", "DocumentType", "pdf", &["pdf"]),
+        ("book", "DocumentType", "epub", &["epub", "pdf"]),
+        ("notes", "DocumentType", "notebook", &[]),
+        ("marked", "DocumentType", "pdf", &["pdf"]),
+    ];
+
+    // what xochitl may keep of a document besides its data and metadata,
+    // (suffix, is_dir)
+    const EXTRAS: &[(&str, bool)] = &[
+        (".content", false),
+        (".pagedata", false),
+        (".local", false),
+        (".bookm", false),
+        (".epubindex", false),
+        (".template", false),
+        ("", true),
+        (".thumbnails", true),
+        (".highlights", true),
+        (".cache", true),
+        (".textconversion", true),
+    ];
+
+    // Every file a document can have, a page in each of its dirs
+    fn fixture(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!(
+            "fuse-rm-direntry-{}-{}",
+            std::process::id(),
+            name
+        ));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        for (uuid, tp, file_type, data) in LIBRARY {
+            let metadata = serde_json::json!({
+                "parent": "",
+                "visibleName": uuid,
+                "type": tp,
+                "lastModified": "1707566400000",
+                "deleted": false,
+            });
+            fs::write(
+                dir.join(format!("{}.metadata", uuid)),
+                metadata.to_string(),
+            )
+            .unwrap();
+            let content = serde_json::json!({ "fileType": file_type });
+            fs::write(
+                dir.join(format!("{}.content", uuid)),
+                content.to_string(),
+            )
+            .unwrap();
+            if *tp == "CollectionType" {
+                continue;
+            }
+            for ext in *data {
+                fs::write(dir.join(format!("{}.{}", uuid, ext)), ext).unwrap();
+            }
+            for (suffix, is_dir) in &EXTRAS[1..] {
+                let path = dir.join(format!("{}{}", uuid, suffix));
+                if *is_dir {
+                    fs::create_dir(&path).unwrap();
+                    fs::write(path.join("page"), "").unwrap();
                 } else {
-                    Err(tp.extension())
+                    fs::write(&path, "").unwrap();
                 }
             }
-            None => Err("unknown"),
         }
+        dir
+    }
+
+    fn with_library(name: &str, test: impl FnOnce(&Path, &[DirEntry])) {
+        let dir = fixture(name);
+        let entries = list_dir_metadata(&dir).unwrap();
+        test(&dir, &entries);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    fn entry<'a>(entries: &'a [DirEntry], uuid: &str) -> &'a DirEntry {
+        entries.iter().find(|e| e.prefix == uuid).unwrap()
+    }
+
+    // (name in the source, is_dir, required)
+    fn names(dir: &Path, entry: &DirEntry) -> Vec<(String, bool, bool)> {
+        entry
+            .associated_paths()
+            .into_iter()
+            .map(|at| {
+                let name = at.path.strip_prefix(dir).unwrap();
+                (name.to_string_lossy().into_owned(), at.is_dir, at.required)
+            })
+            .collect()
+    }
+
+    // the data files, the metadata and then every extra
+    fn expected(
+        uuid: &str,
+        files: &[(&str, bool)],
+    ) -> Vec<(String, bool, bool)> {
+        let mut res: Vec<_> = files
+            .iter()
+            .map(|(ext, required)| {
+                (format!("{}.{}", uuid, ext), false, *required)
+            })
+            .collect();
+        res.extend(EXTRAS.iter().map(|(suffix, is_dir)| {
+            (format!("{}{}", uuid, suffix), *is_dir, false)
+        }));
+        res
+    }
+
+    #[test]
+    fn folder() {
+        with_library("folder", |dir, entries| {
+            assert_eq!(
+                names(dir, entry(entries, "folder")),
+                vec![
+                    ("folder.metadata".to_string(), false, true),
+                    ("folder.content".to_string(), false, false),
+                ]
+            );
+        });
+    }
+
+    #[test]
+    fn pdf() {
+        with_library("pdf", |dir, entries| {
+            assert_eq!(
+                names(dir, entry(entries, "paper")),
+                expected(
+                    "paper",
+                    &[("pdf", true), ("metadata", true), ("epub", false)]
+                )
+            );
+        });
+    }
+
+    #[test]
+    fn epub() {
+        with_library("epub", |dir, entries| {
+            assert_eq!(
+                names(dir, entry(entries, "book")),
+                expected(
+                    "book",
+                    &[("epub", true), ("metadata", true), ("pdf", false)]
+                )
+            );
+        });
+    }
+
+    #[test]
+    fn notebook() {
+        with_library("notebook", |dir, entries| {
+            let notes = entry(entries, "notes");
+            assert_eq!(notes.entry_type, EntryType::RMLINES);
+            // the pages are in the page dir, there's no data file
+            assert_eq!(
+                names(dir, notes),
+                expected("notes", &[("rm", false), ("metadata", true)])
+            );
+        });
+    }
+
+    #[test]
+    fn highlights_and_extras() {
+        with_library("highlights", |dir, entries| {
+            let marked = entry(entries, "marked");
+            let names = names(dir, marked);
+            assert!(names.contains(&(
+                "marked.highlights".to_string(),
+                true,
+                false
+            )));
+            assert_eq!(
+                names,
+                expected(
+                    "marked",
+                    &[("pdf", true), ("metadata", true), ("epub", false)]
+                )
+            );
+            // the highlights shown next to it are a view of the document's
+            // files, they have none of their own
+            let highlights =
+                DirEntry::make_highlights(marked, &marked.attr, 0, true);
+            assert!(highlights.associated_paths().is_empty());
+        });
+    }
+
+    #[test]
+    fn nothing_left_behind() {
+        with_library("complete", |dir, entries| {
+            let claimed: BTreeSet<PathBuf> = entries
+                .iter()
+                .flat_map(|e| e.associated_paths())
+                .map(|at| at.path)
+                .collect();
+            for file in fs::read_dir(dir).unwrap() {
+                let path = file.unwrap().path();
+                assert!(claimed.contains(&path), "{:?} not claimed", path);
+            }
+        });
     }
 }
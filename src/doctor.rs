@@ -0,0 +1,326 @@
+use std::ffi::CString;
+use std::fs;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::{FileTypeExt, MetadataExt};
+use std::path::{Path, PathBuf};
+use std::thread;
+use std::time::Duration;
+
+use crate::unmount;
+use crate::validate;
+
+// Most failures to mount are about the machine rather than fuse-rm: no
+// /dev/fuse, a fusermount that isn't setuid, a user outside the fuse group.
+// Each check is a function of its own returning what it found, `doctor`
+// runs them in order and prints a line per check.
+
+argwerk::define! {
+    /// Check that fuse-rm can mount here, and the source and target if
+    /// given.
+    #[usage = "fuse-rm doctor [source [target]]"]
+    pub struct DoctorArgs {
+        pub help: bool,
+        pub source: Option<String>,
+        pub target: Option<String>,
+    }
+    /// Print this help.
+    ["-h" | "--help"] => {
+        println!("{}", HELP);
+        help = true;
+    }
+    /// <source> dir and <target> dir to check as for mounting
+    [dir] if source.is_none() => {
+        source = Some(dir);
+    }
+    [dir] if target.is_none() => {
+        target = Some(dir);
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub enum Outcome {
+    Pass(String),
+    // what is wrong and how to fix it
+    Fail(String, String),
+    Skip(String),
+}
+
+#[derive(Debug)]
+pub struct Check {
+    pub name: &'static str,
+    // a failure of a critical check means mounting won't work
+    pub critical: bool,
+    pub outcome: Outcome,
+}
+
+impl Check {
+    fn new(name: &'static str, critical: bool, outcome: Outcome) -> Check {
+        Check {
+            name,
+            critical,
+            outcome,
+        }
+    }
+
+    pub fn failed(&self) -> bool {
+        self.critical && matches!(self.outcome, Outcome::Fail(..))
+    }
+
+    /// A line, and one more with the hint for a failure
+    pub fn report(&self) -> String {
+        match &self.outcome {
+            Outcome::Pass(what) => format!("PASS  {}: {}", self.name, what),
+            Outcome::Fail(what, hint) => format!(
+                "{}  {}: {}\n      hint: {}",
+                if self.critical { "FAIL" } else { "WARN" },
+                self.name,
+                what,
+                hint
+            ),
+            Outcome::Skip(why) => format!("SKIP  {}: {}", self.name, why),
+        }
+    }
+}
+
+fn is_root() -> bool {
+    unsafe { libc::geteuid() == 0 }
+}
+
+fn accessible(path: &Path, mode: libc::c_int) -> bool {
+    match CString::new(path.as_os_str().as_bytes()) {
+        Ok(path) => unsafe { libc::access(path.as_ptr(), mode) == 0 },
+        Err(_) => false,
+    }
+}
+
+fn fail(what: impl Into<String>, hint: impl Into<String>) -> Outcome {
+    Outcome::Fail(what.into(), hint.into())
+}
+
+pub fn check_dev_fuse() -> Check {
+    let dev = Path::new("/dev/fuse");
+    let outcome = match fs::metadata(dev) {
+        Err(e) => fail(
+            format!("{}", e),
+            "load the fuse module (`modprobe fuse`), in a container pass \
+             --device /dev/fuse",
+        ),
+        Ok(meta) if !meta.file_type().is_char_device() => fail(
+            "not a character device",
+            "remove it and load the fuse module (`modprobe fuse`)",
+        ),
+        Ok(_) if !accessible(dev, libc::R_OK | libc::W_OK) => fail(
+            "no read and write access",
+            "add the user to the group of /dev/fuse, or mount as root",
+        ),
+        Ok(_) => Outcome::Pass("usable".to_string()),
+    };
+    Check::new("/dev/fuse", true, outcome)
+}
+
+fn find_in_path(names: &[&str]) -> Option<PathBuf> {
+    let path = std::env::var_os("PATH")?;
+    std::env::split_paths(&path)
+        .flat_map(|dir| names.iter().map(move |name| dir.join(name)))
+        .find(|candidate| candidate.is_file())
+}
+
+// root mounts and unmounts without it
+pub fn check_fusermount() -> Check {
+    let outcome = match find_in_path(&["fusermount3", "fusermount"]) {
+        None => fail(
+            "not found in PATH",
+            "install fuse3 (or fuse), it is how users other than root mount \
+             and unmount",
+        ),
+        Some(path) => match fs::metadata(&path) {
+            Ok(meta) if meta.uid() != 0 || meta.mode() & 0o4000 == 0 => fail(
+                format!("{:?} is not setuid root", path),
+                format!(
+                    "reinstall fuse, or `chown root {0:?} && chmod u+s \
+                         {0:?}`",
+                    path
+                ),
+            ),
+            Ok(_) => Outcome::Pass(format!("{:?}", path)),
+            Err(e) => fail(format!("{:?}: {}", path, e), "reinstall fuse"),
+        },
+    };
+    Check::new("fusermount", !is_root(), outcome)
+}
+
+fn user_groups() -> Vec<libc::gid_t> {
+    let n = unsafe { libc::getgroups(0, std::ptr::null_mut()) };
+    let mut groups = vec![0; n.max(0) as usize];
+    let n = unsafe { libc::getgroups(n, groups.as_mut_ptr()) };
+    groups.truncate(n.max(0) as usize);
+    groups.push(unsafe { libc::getegid() });
+    groups
+}
+
+// the gid of `name` in an /etc/group
+fn group_id(etc_group: &str, name: &str) -> Option<libc::gid_t> {
+    etc_group.lines().find_map(|line| {
+        let fields: Vec<&str> = line.split(':').collect();
+        match fields.as_slice() {
+            [group, _, gid, ..] if *group == name => gid.parse().ok(),
+            _ => None,
+        }
+    })
+}
+
+// Only some distributions restrict /dev/fuse to a fuse group
+pub fn check_fuse_group() -> Check {
+    let etc_group = fs::read_to_string("/etc/group").unwrap_or_default();
+    let outcome = if is_root() {
+        Outcome::Skip("running as root".to_string())
+    } else {
+        match group_id(&etc_group, "fuse") {
+            None => Outcome::Skip("there is no fuse group".to_string()),
+            Some(gid) if user_groups().contains(&gid) => {
+                Outcome::Pass("member".to_string())
+            }
+            Some(_) => fail(
+                "not a member",
+                "`usermod -aG fuse $USER` if /dev/fuse belongs to the group, \
+                 then log in again",
+            ),
+        }
+    };
+    Check::new("fuse group", false, outcome)
+}
+
+// allow_other is refused to users unless fuse.conf allows it
+pub fn check_user_allow_other() -> Check {
+    let conf = fs::read_to_string("/etc/fuse.conf").unwrap_or_default();
+    let allowed = conf.lines().any(|line| {
+        line.split('#').next().unwrap_or("").trim() == "user_allow_other"
+    });
+    let outcome = if is_root() {
+        Outcome::Skip("running as root".to_string())
+    } else if allowed {
+        Outcome::Pass("enabled in /etc/fuse.conf".to_string())
+    } else {
+        fail(
+            "disabled, users can't mount with -o allow_other",
+            "add user_allow_other to /etc/fuse.conf",
+        )
+    };
+    Check::new("user_allow_other", false, outcome)
+}
+
+pub fn check_source(source: Option<&Path>) -> Check {
+    let outcome = match source {
+        None => Outcome::Skip("no source given".to_string()),
+        Some(source) => match validate::check_source(source, false) {
+            Ok(()) => Outcome::Pass(format!("{:?}", source)),
+            Err(e) => fail(
+                e.to_string(),
+                "pass the xochitl dir, .local/share/remarkable/xochitl on \
+                 the tablet",
+            ),
+        },
+    };
+    Check::new("source", true, outcome)
+}
+
+// The source only matters for a target inside it
+pub fn check_target(source: Option<&Path>, target: Option<&Path>) -> Check {
+    let source = source.unwrap_or_else(|| Path::new(""));
+    let outcome = match target {
+        None => Outcome::Skip("no target given".to_string()),
+        Some(target) => match validate::check_target(source, target, false) {
+            Ok(()) => Outcome::Pass(format!("{:?}", target)),
+            Err(e @ validate::MountError::AlreadyMounted(_)) => fail(
+                e.to_string(),
+                "unmount it, or stop the fuse-rm serving it",
+            ),
+            Err(e) => fail(e.to_string(), "pick an empty dir outside source"),
+        },
+    };
+    Check::new("target", true, outcome)
+}
+
+// A filesystem of nothing, for mounting once
+struct Probe;
+
+impl fuser::Filesystem for Probe {}
+
+/// Mounts an empty filesystem in a temp dir and unmounts it again
+pub fn check_mount() -> Check {
+    let dir = std::env::temp_dir()
+        .join(format!("fuse-rm-doctor-{}", std::process::id()));
+    let outcome = match fs::create_dir(&dir) {
+        Err(e) => Outcome::Skip(format!("can't create {:?}: {}", dir, e)),
+        Ok(()) => {
+            let options = [fuser::MountOption::FSName("fuse-rm".to_string())];
+            let outcome = match fuser::Session::new(Probe, &dir, &options)
+                .and_then(|session| session.spawn())
+            {
+                Err(e) => fail(
+                    format!("mounting {:?} failed: {}", dir, e),
+                    "see the checks above",
+                ),
+                Ok(session) => {
+                    let mounted = unmount::is_mounted(&dir);
+                    drop(session);
+                    // the unmount is done once the kernel let go
+                    let unmounted = (0..50).any(|_| {
+                        thread::sleep(Duration::from_millis(20));
+                        !unmount::is_mounted(&dir)
+                    });
+                    match (mounted, unmounted) {
+                        (false, _) => fail(
+                            format!("{:?} didn't show up in /proc/mounts", dir),
+                            "see the checks above",
+                        ),
+                        (true, false) => fail(
+                            format!("{:?} is still mounted", dir),
+                            format!("`fusermount -u {}`", dir.display()),
+                        ),
+                        (true, true) => Outcome::Pass(
+                            "mounted and unmounted a temp dir".to_string(),
+                        ),
+                    }
+                }
+            };
+            fs::remove_dir(&dir).unwrap_or(());
+            outcome
+        }
+    };
+    Check::new("mount", true, outcome)
+}
+
+/// Every check, the mount only once /dev/fuse is there
+pub fn run(source: Option<&Path>, target: Option<&Path>) -> Vec<Check> {
+    let dev_fuse = check_dev_fuse();
+    let mount = if dev_fuse.failed() {
+        Check::new("mount", true, Outcome::Skip("no /dev/fuse".to_string()))
+    } else {
+        check_mount()
+    };
+    vec![
+        dev_fuse,
+        check_fusermount(),
+        check_fuse_group(),
+        check_user_allow_other(),
+        check_source(source),
+        check_target(source, target),
+        mount,
+    ]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse() {
+        let args = DoctorArgs::parse(Vec::<&str>::new()).unwrap();
+        assert_eq!((args.source, args.target), (None, None));
+        let args = DoctorArgs::parse(["src", "mnt"]).unwrap();
+        assert_eq!(args.source.as_deref(), Some("src"));
+        assert_eq!(args.target.as_deref(), Some("mnt"));
+        assert!(DoctorArgs::parse(["src", "mnt", "more"]).is_err());
+    }
+}
@@ -0,0 +1,96 @@
+use std::io;
+use std::io::ErrorKind;
+
+// The errno replied for an error of the source. Errors of the OS keep their
+// number, the most specific there is. Those std and the parsers make up get
+// the one of their kind, and EIO is left for what has none (broken
+// metadata, a short read).
+
+const BY_KIND: &[(ErrorKind, libc::c_int)] = &[
+    (ErrorKind::NotFound, libc::ENOENT),
+    (ErrorKind::PermissionDenied, libc::EACCES),
+    (ErrorKind::AlreadyExists, libc::EEXIST),
+    (ErrorKind::InvalidInput, libc::EINVAL),
+    (ErrorKind::InvalidFilename, libc::ENAMETOOLONG),
+    (ErrorKind::NotADirectory, libc::ENOTDIR),
+    (ErrorKind::IsADirectory, libc::EISDIR),
+    (ErrorKind::DirectoryNotEmpty, libc::ENOTEMPTY),
+    (ErrorKind::ReadOnlyFilesystem, libc::EROFS),
+    (ErrorKind::StorageFull, libc::ENOSPC),
+    (ErrorKind::QuotaExceeded, libc::EDQUOT),
+    (ErrorKind::FileTooLarge, libc::EFBIG),
+    (ErrorKind::ResourceBusy, libc::EBUSY),
+    (ErrorKind::CrossesDevices, libc::EXDEV),
+    (ErrorKind::NotConnected, libc::ENOTCONN),
+    (ErrorKind::StaleNetworkFileHandle, libc::ESTALE),
+    (ErrorKind::TimedOut, libc::ETIMEDOUT),
+    (ErrorKind::WouldBlock, libc::EAGAIN),
+    (ErrorKind::Interrupted, libc::EINTR),
+    (ErrorKind::Unsupported, libc::ENOTSUP),
+    (ErrorKind::OutOfMemory, libc::ENOMEM),
+];
+
+/// The errno to reply for `e`
+pub fn from_io(e: &io::Error) -> libc::c_int {
+    if let Some(errno) = e.raw_os_error().filter(|errno| *errno > 0) {
+        return errno;
+    }
+    BY_KIND
+        .iter()
+        .find(|(kind, _)| *kind == e.kind())
+        .map_or(libc::EIO, |(_, errno)| *errno)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn os_errors_keep_their_number() {
+        for errno in &[
+            libc::EACCES,
+            libc::EPERM,
+            libc::ENOENT,
+            libc::ENOSPC,
+            libc::EDQUOT,
+            libc::EROFS,
+            libc::ENAMETOOLONG,
+            libc::ELOOP,
+            libc::EMFILE,
+            libc::EIO,
+        ] {
+            let e = io::Error::from_raw_os_error(*errno);
+            assert_eq!(from_io(&e), *errno, "{}", e);
+        }
+    }
+
+    #[test]
+    fn made_up_errors_get_the_errno_of_their_kind() {
+        for (kind, errno) in &[
+            (ErrorKind::NotFound, libc::ENOENT),
+            (ErrorKind::PermissionDenied, libc::EACCES),
+            (ErrorKind::StorageFull, libc::ENOSPC),
+            (ErrorKind::ReadOnlyFilesystem, libc::EROFS),
+            (ErrorKind::InvalidFilename, libc::ENAMETOOLONG),
+            (ErrorKind::AlreadyExists, libc::EEXIST),
+            (ErrorKind::Unsupported, libc::ENOTSUP),
+        ] {
+            let e = io::Error::new(*kind, "made up");
+            assert_eq!(from_io(&e), *errno, "{:?}", kind);
+        }
+    }
+
+    #[test]
+    fn the_rest_is_eio() {
+        for kind in &[
+            ErrorKind::InvalidData,
+            ErrorKind::UnexpectedEof,
+            ErrorKind::Other,
+        ] {
+            assert_eq!(from_io(&io::Error::new(*kind, "made up")), libc::EIO);
+        }
+        let invalid: io::Error =
+            serde_json::from_str::<u32>("lorem").unwrap_err().into();
+        assert_eq!(from_io(&invalid), libc::EIO);
+    }
+}
@@ -0,0 +1,225 @@
+use std::path::{Component, Path};
+
+// Documents and folders hidden with --exclude, matched against the path
+// shown in the mount ("Archive/Old.pdf"). The syntax is that of globset:
+// '*' and '?' match within a name, "**" as a whole name matches any number
+// of folders (none too, so "Archive/**" hides the folder along with what
+// is in it), "[a-z]" and "[!0-9]" match a character of a class, and "{a,b}"
+// either. A pattern without a '/' matches the name in any folder, one with
+// a '/' the whole path. What is in an excluded folder is excluded as well.
+//
+// Patterns are parsed once, when the options are.
+
+#[derive(Debug, Clone)]
+enum Token {
+    Char(char),
+    // '?'
+    One,
+    // '*'
+    Any,
+    // the ranges, and whether they are negated
+    Class(Vec<(char, char)>, bool),
+}
+
+#[derive(Debug, Clone)]
+enum Part {
+    Name(Vec<Token>),
+    // "**"
+    Folders,
+}
+
+#[derive(Debug, Clone)]
+pub struct Glob {
+    // one for each alternative of the braces
+    alternatives: Vec<Vec<Part>>,
+}
+
+// The patterns the braces of `pattern` stand for
+fn expand(pattern: &str) -> Result<Vec<String>, String> {
+    let open = match pattern.find('{') {
+        Some(open) => open,
+        None if pattern.contains('}') => {
+            return Err(format!("Unmatched '}}' in {:?}", pattern))
+        }
+        None => return Ok(vec![pattern.to_string()]),
+    };
+    let (mut depth, mut close, mut commas) = (0, None, Vec::new());
+    for (i, c) in pattern[open..].char_indices() {
+        match c {
+            '{' => depth += 1,
+            '}' => {
+                depth -= 1;
+                if depth == 0 {
+                    close = Some(open + i);
+                    break;
+                }
+            }
+            ',' if depth == 1 => commas.push(open + i),
+            _ => (),
+        }
+    }
+    let close =
+        close.ok_or_else(|| format!("Unmatched '{{' in {:?}", pattern))?;
+    let bounds = std::iter::once(open)
+        .chain(commas)
+        .chain(std::iter::once(close));
+    let bounds: Vec<usize> = bounds.collect();
+    let mut res = Vec::new();
+    for pair in bounds.windows(2) {
+        let alternative = format!(
+            "{}{}{}",
+            &pattern[..open],
+            &pattern[pair[0] + 1..pair[1]],
+            &pattern[close + 1..]
+        );
+        res.extend(expand(&alternative)?);
+    }
+    Ok(res)
+}
+
+fn parse_class(
+    chars: &mut std::iter::Peekable<std::str::Chars>,
+) -> Option<Token> {
+    let negated = matches!(chars.peek(), Some('!') | Some('^'));
+    if negated {
+        chars.next();
+    }
+    let mut ranges = Vec::new();
+    // a ']' first is one of the class
+    let mut first = true;
+    loop {
+        let c = chars.next()?;
+        if c == ']' && !first {
+            return Some(Token::Class(ranges, negated));
+        }
+        first = false;
+        let mut ahead = chars.clone();
+        match (ahead.next(), ahead.next()) {
+            (Some('-'), Some(end)) if end != ']' => {
+                chars.next();
+                chars.next();
+                ranges.push((c, end));
+            }
+            _ => ranges.push((c, c)),
+        }
+    }
+}
+
+fn parse_name(name: &str, pattern: &str) -> Result<Part, String> {
+    if name == "**" {
+        return Ok(Part::Folders);
+    }
+    let mut tokens = Vec::new();
+    let mut chars = name.chars().peekable();
+    while let Some(c) = chars.next() {
+        tokens.push(match c {
+            '?' => Token::One,
+            '*' => {
+                // "a**b" is "a*b"
+                while chars.peek() == Some(&'*') {
+                    chars.next();
+                }
+                Token::Any
+            }
+            '[' => parse_class(&mut chars)
+                .ok_or_else(|| format!("Unmatched '[' in {:?}", pattern))?,
+            '\\' => Token::Char(chars.next().unwrap_or('\\')),
+            c => Token::Char(c),
+        });
+    }
+    Ok(Part::Name(tokens))
+}
+
+fn parse(pattern: &str, whole: &str) -> Result<Vec<Part>, String> {
+    let mut parts = Vec::new();
+    // one without a '/' is for names anywhere
+    if !pattern.contains('/') {
+        parts.push(Part::Folders);
+    }
+    for name in pattern.split('/').filter(|name| !name.is_empty()) {
+        parts.push(parse_name(name, whole)?);
+    }
+    Ok(parts)
+}
+
+impl std::str::FromStr for Glob {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Glob, String> {
+        if s.trim_matches('/').is_empty() {
+            return Err(format!("Empty exclude pattern: {:?}", s));
+        }
+        let alternatives = expand(s)?
+            .iter()
+            .map(|pattern| parse(pattern, s))
+            .collect::<Result<_, _>>()?;
+        Ok(Glob { alternatives })
+    }
+}
+
+fn name_matches(tokens: &[Token], name: &[char]) -> bool {
+    match tokens.split_first() {
+        None => name.is_empty(),
+        Some((Token::Any, rest)) => {
+            (0..=name.len()).any(|i| name_matches(rest, &name[i..]))
+        }
+        Some((token, rest)) => {
+            let (c, name) = match name.split_first() {
+                Some(first) => first,
+                None => return false,
+            };
+            let matched = match token {
+                Token::Char(expected) => c == expected,
+                Token::One => true,
+                Token::Class(ranges, negated) => {
+                    ranges.iter().any(|(lo, hi)| (lo..=hi).contains(&c))
+                        != *negated
+                }
+                Token::Any => unreachable!(),
+            };
+            matched && name_matches(rest, name)
+        }
+    }
+}
+
+fn parts_match(parts: &[Part], names: &[Vec<char>]) -> bool {
+    match parts.split_first() {
+        None => names.is_empty(),
+        Some((Part::Folders, rest)) => {
+            (0..=names.len()).any(|i| parts_match(rest, &names[i..]))
+        }
+        Some((Part::Name(tokens), rest)) => match names.split_first() {
+            Some((name, names)) => {
+                name_matches(tokens, name) && parts_match(rest, names)
+            }
+            None => false,
+        },
+    }
+}
+
+impl Glob {
+    fn matches(&self, names: &[Vec<char>]) -> bool {
+        self.alternatives
+            .iter()
+            .any(|parts| parts_match(parts, names))
+    }
+}
+
+/// Whether the entry shown at `path`, or one of the folders it is in,
+/// matches one of `globs`
+pub fn is_excluded(globs: &[Glob], path: &Path) -> bool {
+    if globs.is_empty() {
+        return false;
+    }
+    let names: Vec<Vec<char>> = path
+        .components()
+        .filter_map(|c| match c {
+            Component::Normal(name) => {
+                Some(name.to_string_lossy().chars().collect())
+            }
+            _ => None,
+        })
+        .collect();
+    (1..=names.len())
+        .any(|len| globs.iter().any(|glob| glob.matches(&names[..len])))
+}
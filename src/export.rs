@@ -0,0 +1,177 @@
+use std::collections::HashSet;
+use std::ffi::OsString;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+use crate::direntry::{DirEntry, EntryType};
+use crate::paths::{self, PathResolver, ResolveError};
+use crate::rmxfs::list_dir_metadata;
+
+argwerk::define! {
+    /// Copy a document or folder out of a xochitl directory.
+    #[usage = "fuse-rm export [--raw] source document dest"]
+    pub struct ExportArgs {
+        pub help: bool,
+        pub raw: bool,
+        pub positional: Option<(String, String, String)>,
+    }
+    /// Also copy the raw uuid.* files and page directory.
+    ["--raw"] => {
+        raw = true;
+    }
+    /// Print this help.
+    ["-h" | "--help"] => {
+        println!("{}", HELP);
+        help = true;
+    }
+    /// <source> dir, visible path or uuid of the <document>, <dest> dir
+    [source, document, dest] if positional.is_none() => {
+        positional = Some((source, document, dest))
+    }
+}
+
+#[derive(Debug)]
+pub enum ExportError {
+    Resolve(ResolveError),
+    Io(io::Error),
+}
+
+impl ExportError {
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            ExportError::Resolve(e) => e.exit_code(),
+            ExportError::Io(_) => 32,
+        }
+    }
+}
+
+impl fmt::Display for ExportError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ExportError::Resolve(e) => e.fmt(f),
+            ExportError::Io(e) => write!(f, "IO error: {}", e),
+        }
+    }
+}
+
+impl std::convert::From<io::Error> for ExportError {
+    fn from(err: io::Error) -> ExportError {
+        ExportError::Io(err)
+    }
+}
+
+impl std::convert::From<ResolveError> for ExportError {
+    fn from(err: ResolveError) -> ExportError {
+        ExportError::Resolve(err)
+    }
+}
+
+pub fn copy_recursive(from: &Path, to: &Path) -> io::Result<()> {
+    if from.is_dir() {
+        fs::create_dir_all(to)?;
+        for entry in fs::read_dir(from)? {
+            let entry = entry?;
+            copy_recursive(&entry.path(), &to.join(entry.file_name()))?;
+        }
+    } else {
+        fs::copy(from, to)?;
+    }
+    Ok(())
+}
+
+// Everything xochitl keeps for the entry, under the names it has there
+fn copy_raw(entry: &DirEntry, dest: &Path) -> io::Result<()> {
+    for path in entry.associated_paths() {
+        if let Some(name) = path.path.file_name().filter(|_| path.path.exists())
+        {
+            copy_recursive(&path.path, &dest.join(name))?;
+        }
+    }
+    Ok(())
+}
+
+#[derive(Default)]
+pub struct Exported {
+    pub documents: usize,
+    // notebooks and documents without data, copied with --raw only
+    pub left_out: usize,
+}
+
+fn export_entry(
+    entries: &[DirEntry],
+    entry: &DirEntry,
+    dest: &Path,
+    raw: bool,
+    seen: &mut HashSet<OsString>,
+    res: &mut Exported,
+) -> io::Result<()> {
+    // broken metadata could make a folder its own ancestor
+    if !seen.insert(entry.prefix.clone()) {
+        return Ok(());
+    }
+    if raw {
+        copy_raw(entry, dest)?;
+    }
+    // never outside dest, whatever the name
+    let copy = dest.join(paths::file_name(&entry.file_name())?);
+    match entry.entry_type {
+        EntryType::NONE => (),
+        EntryType::PDF | EntryType::EPUB | EntryType::OTHER(_) => {
+            fs::copy(entry.source_file_path(), copy)?;
+            res.documents += 1;
+            return Ok(());
+        }
+        _ => {
+            res.left_out += 1;
+            return Ok(());
+        }
+    }
+
+    fs::create_dir_all(&copy)?;
+    for child in entries.iter().filter(|e| e.parent == entry.prefix) {
+        export_entry(entries, child, &copy, raw, seen, res)?;
+    }
+    Ok(())
+}
+
+pub fn export(
+    source: &Path,
+    what: &str,
+    dest: &Path,
+    raw: bool,
+) -> Result<Exported, ExportError> {
+    let entries = list_dir_metadata(&source.to_path_buf())?;
+    let entry = PathResolver::new(&entries).resolve(what)?;
+    fs::create_dir_all(dest)?;
+    let mut res = Exported::default();
+    export_entry(&entries, entry, dest, raw, &mut HashSet::new(), &mut res)?;
+    Ok(res)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn positional(args: &ExportArgs) -> Option<(&str, &str, &str)> {
+        let (source, document, dest) = args.positional.as_ref()?;
+        Some((source, document, dest))
+    }
+
+    #[test]
+    fn parse() {
+        let args = ExportArgs::parse(["src", "Work/Notes", "out"]).unwrap();
+        assert!(!args.raw && !args.help);
+        assert_eq!(positional(&args), Some(("src", "Work/Notes", "out")));
+        let args = ExportArgs::parse(["--raw", "src", "Notes", "out"]).unwrap();
+        assert!(args.raw);
+        assert_eq!(positional(&args), Some(("src", "Notes", "out")));
+    }
+
+    #[test]
+    fn parse_errors() {
+        assert!(ExportArgs::parse(["src", "Notes"]).is_err());
+        assert!(ExportArgs::parse(["src", "Notes", "out", "more"]).is_err());
+    }
+}
@@ -0,0 +1,103 @@
+use std::collections::HashSet;
+use std::ffi::OsStr;
+use std::path::Path;
+use std::sync::{Mutex, OnceLock};
+
+use crate::direntry::{EntryType, ExtensionMap};
+
+// xochitl only shows pdfs, epubs and notebooks, anything else is refused.
+// With --allow-any-type other files are stored as documents too, under the
+// extension their content or name gives, so they can be kept on the device
+// and copied back. xochitl ignores them.
+//
+// Set once at startup like the retry policy, the mount and the import
+// subcommand upload through the same DirEntry code.
+
+struct AnyType {
+    denied: Vec<String>,
+}
+
+static ANY_TYPE: OnceLock<AnyType> = OnceLock::new();
+
+// Extensions of the files xochitl keeps next to the data, a document stored
+// under one of them would overwrite its own metadata
+const RESERVED: &[&str] = &[
+    "metadata",
+    "content",
+    "pagedata",
+    "highlights",
+    "thumbnails",
+    "textconversion",
+    "cache",
+    "local",
+    "bookm",
+    "epubindex",
+    "tombstone",
+];
+
+// longer ones are more likely part of a name than an extension
+const MAX_EXT_LEN: usize = 16;
+
+/// Stores documents of any type but the `denied` extensions
+pub fn configure(allow_any_type: bool, denied: &[String]) {
+    if allow_any_type {
+        let denied = denied.iter().map(|ext| ext.to_lowercase()).collect();
+        ANY_TYPE.get_or_init(|| AnyType { denied });
+    }
+}
+
+/// An extension the data of a document can be stored under, other than
+/// those of the supported types
+pub fn is_valid_ext(ext: &str) -> bool {
+    !ext.is_empty()
+        && ext.len() <= MAX_EXT_LEN
+        && ext
+            .bytes()
+            .all(|b| b.is_ascii_lowercase() || b.is_ascii_digit())
+        && !RESERVED.contains(&ext)
+}
+
+// Types are Copy and compared all over, so the few extensions met are kept
+// for the lifetime of the mount
+fn intern(ext: &str) -> &'static str {
+    static EXTS: OnceLock<Mutex<HashSet<&'static str>>> = OnceLock::new();
+    let mut exts = EXTS.get_or_init(Default::default).lock().unwrap();
+    match exts.get(ext) {
+        Some(ext) => ext,
+        None => {
+            let ext: &'static str = Box::leak(ext.into());
+            exts.insert(ext);
+            ext
+        }
+    }
+}
+
+/// The extension an existing document of another type is stored under
+pub fn stored_ext(ext: &str) -> Option<&'static str> {
+    Some(ext).filter(|ext| is_valid_ext(ext)).map(intern)
+}
+
+/// The extension to store a document of no supported type under, from its
+/// sniffed type or else its name. The error names the refused type.
+pub fn other_ext(
+    sniffed: Option<&str>,
+    name: &OsStr,
+) -> Result<&'static str, String> {
+    let from_name = Path::new(name)
+        .extension()
+        .map(|ext| ext.to_string_lossy().to_lowercase());
+    let ext = match sniffed.map(String::from).or(from_name) {
+        Some(ext) => ext,
+        None => return Err("unknown".to_string()),
+    };
+    let allowed = ANY_TYPE.get().is_some_and(|any| !any.denied.contains(&ext));
+    // a name saying pdf on content that isn't would show xochitl a bad pdf
+    let supported = [ExtensionMap::device(), ExtensionMap::shown()]
+        .iter()
+        .any(|map| map.entry_type(&ext) != &EntryType::NONE);
+    if allowed && !supported && is_valid_ext(&ext) {
+        Ok(intern(&ext))
+    } else {
+        Err(ext)
+    }
+}
@@ -0,0 +1,87 @@
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+// Open files are only closed on release, which never comes if the kernel
+// loses track of a handle, e.g. after a forced unmount attempt. Handles that
+// see no I/O for long are logged and, with --handle-timeout, evicted: read
+// handles closed, pending files finalized or discarded as at unmount.
+
+// idle handles are logged after this long without a timeout
+const LONG_LIVED: Duration = Duration::from_secs(600);
+
+// the handles are checked at most this often
+const SWEEP_INTERVAL: Duration = Duration::from_secs(1);
+
+struct Times {
+    opened: Instant,
+    last_io: Instant,
+    reported: bool,
+}
+
+pub struct Handles {
+    times: HashMap<u64, Times>,
+    last_sweep: Instant,
+}
+
+impl Handles {
+    pub fn new() -> Handles {
+        Handles {
+            times: HashMap::new(),
+            last_sweep: Instant::now(),
+        }
+    }
+
+    /// Notes an open of `fh`, which counts as I/O if it is open already
+    pub fn opened(&mut self, fh: u64) {
+        let now = Instant::now();
+        self.times.entry(fh).or_insert(Times {
+            opened: now,
+            last_io: now,
+            reported: false,
+        });
+        self.used(fh);
+    }
+
+    /// Notes I/O on `fh`
+    pub fn used(&mut self, fh: u64) {
+        if let Some(times) = self.times.get_mut(&fh) {
+            times.last_io = Instant::now();
+            times.reported = false;
+        }
+    }
+
+    /// Forgets `fh` once its last reference is released
+    pub fn closed(&mut self, fh: u64) {
+        self.times.remove(&fh);
+    }
+
+    /// The handles idle for at least `timeout`, which are forgotten. Logs
+    /// the long idle ones that are kept. Empty unless a sweep is due.
+    pub fn sweep(&mut self, timeout: Option<Duration>) -> Vec<u64> {
+        let now = Instant::now();
+        if now.duration_since(self.last_sweep) < SWEEP_INTERVAL {
+            return Vec::new();
+        }
+        self.last_sweep = now;
+        let mut stale = Vec::new();
+        for (fh, times) in self.times.iter_mut() {
+            let idle = now.duration_since(times.last_io);
+            if timeout.is_some_and(|timeout| idle >= timeout) {
+                info!(
+                    "evicting handle {}, open for {}s and idle for {}s",
+                    fh,
+                    now.duration_since(times.opened).as_secs(),
+                    idle.as_secs()
+                );
+                stale.push(*fh);
+            } else if idle >= LONG_LIVED && !times.reported {
+                warn!("handle {} idle for {}s", fh, idle.as_secs());
+                times.reported = true;
+            }
+        }
+        for fh in &stale {
+            self.times.remove(fh);
+        }
+        stale
+    }
+}
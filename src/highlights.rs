@@ -0,0 +1,87 @@
+use std::fs;
+use std::io;
+use std::os::unix::fs::FileExt;
+use std::path::{Path, PathBuf};
+
+// xochitl keeps the smart highlights of a document as one json file per page
+// in <uuid>.highlights/. Merged they are shown as a json array of those files,
+// read piecewise from the pages instead of being assembled in memory.
+
+const OPEN: &[u8] = b"[\n";
+const SEPARATOR: &[u8] = b",\n";
+const CLOSE: &[u8] = b"\n]\n";
+
+enum Part {
+    Bytes(&'static [u8]),
+    Page(PathBuf, u64),
+}
+
+impl Part {
+    fn len(&self) -> u64 {
+        match self {
+            Part::Bytes(bytes) => bytes.len() as u64,
+            Part::Page(_, len) => *len,
+        }
+    }
+}
+
+/// The page files of a highlights dir, in name order
+pub fn pages(dir: &Path) -> io::Result<Vec<fs::DirEntry>> {
+    let mut pages = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        if entry.path().extension().is_some_and(|ext| ext == "json")
+            && entry.file_type()?.is_file()
+        {
+            pages.push(entry);
+        }
+    }
+    pages.sort_by_key(|e| e.file_name());
+    Ok(pages)
+}
+
+fn parts(pages: &[fs::DirEntry]) -> io::Result<Vec<Part>> {
+    let mut parts = vec![Part::Bytes(OPEN)];
+    for (i, page) in pages.iter().enumerate() {
+        if i > 0 {
+            parts.push(Part::Bytes(SEPARATOR));
+        }
+        parts.push(Part::Page(page.path(), page.metadata()?.len()));
+    }
+    parts.push(Part::Bytes(CLOSE));
+    Ok(parts)
+}
+
+pub fn merged_size(pages: &[fs::DirEntry]) -> io::Result<u64> {
+    Ok(parts(pages)?.iter().map(Part::len).sum())
+}
+
+/// Up to `size` bytes of the merged highlights at `offset`
+pub fn read_merged(
+    dir: &Path,
+    offset: u64,
+    size: usize,
+) -> io::Result<Vec<u8>> {
+    let mut out = Vec::with_capacity(size);
+    let mut start = 0;
+    for part in parts(&pages(dir)?)? {
+        let end = start + part.len();
+        let pos = offset + out.len() as u64;
+        if pos < end && out.len() < size {
+            let from = pos - start;
+            let n = (end - pos).min((size - out.len()) as u64) as usize;
+            match part {
+                Part::Bytes(bytes) => {
+                    out.extend_from_slice(&bytes[from as usize..][..n])
+                }
+                Part::Page(path, _) => {
+                    let mut buf = vec![0; n];
+                    fs::File::open(path)?.read_exact_at(&mut buf, from)?;
+                    out.extend_from_slice(&buf);
+                }
+            }
+        }
+        start = end;
+    }
+    Ok(out)
+}
@@ -0,0 +1,250 @@
+use std::ffi::{OsStr, OsString};
+use std::fmt;
+use std::fs;
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+
+use crate::direntry::{DirEntry, EntryType};
+use crate::paths::{PathResolver, ResolveError};
+use crate::rmxfs::list_dir_metadata;
+
+argwerk::define! {
+    /// Add pdf and epub files to a xochitl directory.
+    #[usage = "fuse-rm import [--into folder | --replace document] source file.."]
+    pub struct ImportArgs {
+        pub help: bool,
+        pub into: Option<String>,
+        pub replace: Option<String>,
+        pub source: Option<String>,
+        pub files: Vec<String>,
+    }
+    /// Visible path of the folder to import into (default: top level).
+    ["--into", folder] => {
+        into = Some(folder);
+    }
+    /// Replace the data of a document, keeping its uuid and annotations.
+    ["--replace", document] => {
+        replace = Some(document);
+    }
+    /// Print this help.
+    ["-h" | "--help"] => {
+        println!("{}", HELP);
+        help = true;
+    }
+    /// <source> dir followed by the files to import
+    [file] => {
+        if source.is_none() {
+            source = Some(file);
+        } else {
+            files.push(file);
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum ImportError {
+    Resolve(ResolveError),
+    NotAFolder(String),
+    NotADocument(String),
+    Exists(OsString),
+    UnsupportedType(PathBuf, String),
+    Io(io::Error),
+}
+
+impl ImportError {
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            ImportError::Resolve(e) => e.exit_code(),
+            ImportError::NotAFolder(_) => 40,
+            ImportError::NotADocument(_) => 41,
+            ImportError::Exists(_) => 42,
+            ImportError::UnsupportedType(..) => 43,
+            ImportError::Io(_) => 44,
+        }
+    }
+}
+
+impl fmt::Display for ImportError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ImportError::Resolve(e) => e.fmt(f),
+            ImportError::NotAFolder(what) => {
+                write!(f, "{:?} is not a folder", what)
+            }
+            ImportError::NotADocument(what) => {
+                write!(f, "{:?} is not a document", what)
+            }
+            ImportError::Exists(name) => write!(
+                f,
+                "{:?} already exists (use --replace to update it)",
+                name
+            ),
+            ImportError::UnsupportedType(path, ext) => {
+                write!(f, "{:?} has an unsupported type: {}", path, ext)
+            }
+            ImportError::Io(e) => write!(f, "IO error: {}", e),
+        }
+    }
+}
+
+impl std::convert::From<io::Error> for ImportError {
+    fn from(err: io::Error) -> ImportError {
+        ImportError::Io(err)
+    }
+}
+
+impl std::convert::From<ResolveError> for ImportError {
+    fn from(err: ResolveError) -> ImportError {
+        ImportError::Resolve(err)
+    }
+}
+
+// Copies the file to the entry's pending path, detecting the type from the
+// first chunk the same way the first FUSE write does
+fn stage(entry: &mut DirEntry, file: &Path) -> Result<(), ImportError> {
+    let mut input = fs::File::open(file)?;
+    let total = input.metadata()?.len();
+    let staged = entry.pending_path();
+    if let Some(pending_dir) = staged.parent() {
+        fs::create_dir_all(pending_dir)?;
+    }
+    let mut output = fs::File::create(&staged)?;
+
+    let mut buf = vec![0; 1 << 20];
+    let mut written = 0;
+    loop {
+        let n = input.read(&mut buf)?;
+        if n == 0 {
+            break;
+        }
+        if written == 0 {
+            if let Err(ext) = entry.update_type(&buf[..n]) {
+                fs::remove_file(&staged)?;
+                return Err(ImportError::UnsupportedType(file.into(), ext));
+            }
+        }
+        output.write_all(&buf[..n])?;
+        written += n as u64;
+        print!("\r{}: {}%", file.display(), written * 100 / total.max(1));
+        io::stdout().flush()?;
+    }
+    println!();
+    if written == 0 {
+        fs::remove_file(&staged)?;
+        return Err(ImportError::UnsupportedType(file.into(), "empty".into()));
+    }
+    Ok(())
+}
+
+pub fn import_file(
+    source: &Path,
+    into: Option<&str>,
+    file: &Path,
+) -> Result<DirEntry, ImportError> {
+    let entries = list_dir_metadata(&source.to_path_buf())?;
+    let root = DirEntry::make_root(source);
+    let parent = match into {
+        Some(folder) => {
+            let parent = PathResolver::new(&entries).resolve(folder)?;
+            if parent.entry_type != EntryType::NONE {
+                return Err(ImportError::NotAFolder(folder.to_string()));
+            }
+            parent
+        }
+        None => &root,
+    };
+
+    let name = file.file_name().unwrap_or(file.as_os_str());
+    if entries
+        .iter()
+        .any(|e| e.parent == parent.prefix && e.file_name() == name)
+    {
+        return Err(ImportError::Exists(name.to_os_string()));
+    }
+
+    let entry = DirEntry::make_file(parent, name, 0o644, 0o022)?;
+    finish(entry, file)
+}
+
+/// Adds `file` to the folder `parent` as `name`, under the uuid `prefix`. A
+/// document of that uuid left in `.pending` by an earlier try is replaced.
+pub fn import_as(
+    parent: &DirEntry,
+    name: &OsStr,
+    prefix: OsString,
+    file: &Path,
+) -> Result<DirEntry, ImportError> {
+    let entry = DirEntry::make_file_as(parent, name, prefix, 0o644, 0o022)?;
+    finish(entry, file)
+}
+
+// Stages and finalizes the new document `entry`, or removes it again
+fn finish(mut entry: DirEntry, file: &Path) -> Result<DirEntry, ImportError> {
+    let res = stage(&mut entry, file).and_then(|_| {
+        entry.finalize_pending()?;
+        Ok(())
+    });
+    if let Err(e) = res {
+        entry.forget_pending();
+        return Err(e);
+    }
+    Ok(entry)
+}
+
+pub fn replace_file(
+    source: &Path,
+    document: &str,
+    file: &Path,
+) -> Result<DirEntry, ImportError> {
+    let entries = list_dir_metadata(&source.to_path_buf())?;
+    let prefix = PathResolver::new(&entries)
+        .resolve(document)?
+        .prefix
+        .clone();
+    let entry = entries
+        .into_iter()
+        .find(|e| e.prefix == prefix)
+        .expect("resolved entry is listed");
+    if entry.entry_type == EntryType::NONE {
+        return Err(ImportError::NotADocument(document.to_string()));
+    }
+    replace_entry(entry, file)
+}
+
+/// Replaces the data of the document `entry` with `file`
+pub fn replace_entry(
+    mut entry: DirEntry,
+    file: &Path,
+) -> Result<DirEntry, ImportError> {
+    let old_data = entry.source_file_path();
+    stage(&mut entry, file)?;
+    entry.finalize_data()?;
+    // a pdf replaced by an epub leaves the old data file behind
+    if old_data != entry.source_file_path() {
+        fs::remove_file(old_data)?;
+    }
+    entry.touch()?;
+    Ok(entry)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse() {
+        let args = ImportArgs::parse(["src", "a.pdf", "b.epub"]).unwrap();
+        assert_eq!(args.source.as_deref(), Some("src"));
+        assert_eq!(args.files, ["a.pdf", "b.epub"]);
+        assert_eq!((args.into, args.replace), (None, None));
+
+        let args = ImportArgs::parse(["--into", "Work/Papers", "src", "a.pdf"])
+            .unwrap();
+        assert_eq!(args.into.as_deref(), Some("Work/Papers"));
+        let args =
+            ImportArgs::parse(["src", "--replace", "Notes", "a.pdf"]).unwrap();
+        assert_eq!(args.replace.as_deref(), Some("Notes"));
+        assert_eq!(args.files, ["a.pdf"]);
+        assert!(ImportArgs::parse(["--into"]).is_err());
+    }
+}
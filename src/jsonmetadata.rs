@@ -1,16 +1,79 @@
 use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
 use std::fs;
-use std::io;
+use std::io::{self, Read, Write};
 use std::os::unix::fs::MetadataExt;
 use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::SystemTime;
+
+use crate::retry::retry;
+use crate::timeconv;
 
 type JsonMap = HashMap<String, serde_json::Value>;
 
-#[derive(Serialize, Deserialize, Debug, Clone)]
-enum DocType {
+// xochitl writes its json with Qt: keys sorted, indented by 4 spaces and a
+// newline at the end. Files are written the same way so that they diff
+// cleanly against what the device wrote, unless --compact-metadata asks for
+// a single line.
+static COMPACT: AtomicBool = AtomicBool::new(false);
+
+pub fn set_compact(compact: bool) {
+    COMPACT.store(compact, Ordering::Relaxed);
+}
+
+/// `value` as the device would write it, its maps have sorted keys
+pub fn to_device_json(value: &serde_json::Value) -> io::Result<Vec<u8>> {
+    if COMPACT.load(Ordering::Relaxed) {
+        return Ok(serde_json::to_vec(value)?);
+    }
+    let mut out = Vec::new();
+    let formatter = serde_json::ser::PrettyFormatter::with_indent(b"    ");
+    let mut ser = serde_json::Serializer::with_formatter(&mut out, formatter);
+    value.serialize(&mut ser)?;
+    out.push(b'\n');
+    Ok(out)
+}
+
+// Templates and whatever later firmware adds have other types. They are
+// kept as read, so that writing their metadata back doesn't change them.
+#[derive(Debug, Clone, PartialEq)]
+pub enum DocType {
     CollectionType,
     DocumentType,
+    Other(String),
+}
+
+impl DocType {
+    pub fn as_str(&self) -> &str {
+        match self {
+            DocType::CollectionType => "CollectionType",
+            DocType::DocumentType => "DocumentType",
+            DocType::Other(s) => s,
+        }
+    }
+}
+
+impl Serialize for DocType {
+    fn serialize<S: serde::Serializer>(
+        &self,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(self.as_str())
+    }
+}
+
+impl<'de> Deserialize<'de> for DocType {
+    fn deserialize<D: serde::Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<DocType, D::Error> {
+        let s = String::deserialize(deserializer)?;
+        Ok(match s.as_str() {
+            "CollectionType" => DocType::CollectionType,
+            "DocumentType" => DocType::DocumentType,
+            _ => DocType::Other(s),
+        })
+    }
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
@@ -35,23 +98,20 @@ impl JsonMetadata {
             parent: parent.to_string(),
             visible_name: visible_name.to_string(),
             r#type: doctype,
-            extra: extra,
+            extra,
         }
     }
 
     pub fn new_file(visible_name: &str, parent: &str) -> JsonMetadata {
-        //use std::iter::Copied;
-        let modtime = std::time::SystemTime::now()
-            .duration_since(std::time::UNIX_EPOCH)
-            .unwrap()
-            .as_millis() as u64;
+        let modtime = timeconv::to_millis_str(SystemTime::now());
         JsonMetadata::new(
             visible_name,
             parent,
             DocType::DocumentType,
             vec![
+                ("createdTime".to_string(), json!(modtime)),
                 ("deleted".to_string(), json!(false)),
-                ("lastModified".to_string(), json!(modtime.to_string())),
+                ("lastModified".to_string(), json!(modtime)),
                 ("metadatamodified".to_string(), json!(false)),
                 ("modified".to_string(), json!(false)),
                 ("pinned".to_string(), json!(false)),
@@ -72,12 +132,187 @@ impl JsonMetadata {
         )
     }
 
+    pub fn doc_type(&self) -> &DocType {
+        &self.r#type
+    }
+
+    // Times are stored as strings of milliseconds since the epoch, some
+    // tools write a number
+    fn millis(&self, key: &str) -> Option<SystemTime> {
+        match self.extra.get(key)? {
+            serde_json::Value::String(s) => timeconv::from_millis_str(s),
+            v => v
+                .as_i64()
+                .or_else(|| v.as_u64().map(|_| i64::MAX))
+                .map(timeconv::from_millis),
+        }
+    }
+
+    pub fn last_modified(&self) -> Option<SystemTime> {
+        self.millis("lastModified")
+    }
+
+    /// `createdTime`, which firmware older than 3.x doesn't write
+    pub fn created_time(&self) -> Option<SystemTime> {
+        self.millis("createdTime")
+    }
+
+    /// The `tags` of metadata written by tools for older firmware
+    pub fn tags(&self) -> Option<&serde_json::Value> {
+        self.extra.get("tags")
+    }
+
+    pub fn set_tags(&mut self, tags: serde_json::Value) {
+        self.extra.insert("tags".to_string(), tags);
+    }
+
+    /// Marked for deletion, to be removed once synced
+    pub fn is_deleted(&self) -> bool {
+        self.extra.get("deleted").and_then(|v| v.as_bool()) == Some(true)
+    }
+
+    /// `synced`, with neither the metadata nor the data changed since
+    /// (`metadatamodified`, `modified`)
+    pub fn is_synced(&self) -> bool {
+        let flag = |key| self.extra.get(key).and_then(|v| v.as_bool());
+        flag("synced") == Some(true)
+            && flag("metadatamodified") != Some(true)
+            && flag("modified") != Some(true)
+    }
+
+    /// Bumps `lastModified` and `version` and sets `modified`, in the types
+    /// the file already has them in. `lastModified` is added as a string if
+    /// it's missing, `modified` only set where the firmware keeps it
+    pub fn touch(&mut self) {
+        let now = SystemTime::now();
+        let modtime = match self.extra.get("lastModified") {
+            Some(v) if v.is_number() => json!(timeconv::to_millis(now)),
+            _ => json!(timeconv::to_millis_str(now)),
+        };
+        self.extra.insert("lastModified".to_string(), modtime);
+        if let Some(modified) = self.extra.get_mut("modified") {
+            *modified = json!(true);
+        }
+        if let Some(version) =
+            self.extra.get("version").and_then(|v| v.as_u64())
+        {
+            self.extra.insert("version".to_string(), json!(version + 1));
+        }
+    }
+
     pub fn from_file<P: AsRef<Path>>(path: P) -> io::Result<JsonMetadata> {
-        Ok(serde_json::from_str(&fs::read_to_string(&path)?)?)
+        let json = retry(|| fs::read_to_string(&path))?;
+        Ok(serde_json::from_str(&json)?)
     }
 
+    /// Writes back every field read, with its value untouched unless
+    /// changed above, formatted as the device does. Returns the inode of
+    /// the file written, taken from the handle written through: opening the
+    /// path again could find another file renamed over it meanwhile. A file
+    /// that already reads the same is left alone, its mtime untouched.
     pub fn save_file<P: AsRef<Path>>(&self, path: P) -> io::Result<u64> {
-        fs::write(&path, serde_json::to_vec(&self)?)?;
-        Ok(fs::File::open(&path)?.metadata()?.ino())
+        self.save_with(path.as_ref(), |path, write| {
+            if write {
+                fs::File::create(path)
+            } else {
+                fs::File::open(path)
+            }
+        })
+    }
+
+    // save_file with the files opened by `open`, for writing or not
+    fn save_with(
+        &self,
+        path: &Path,
+        mut open: impl FnMut(&Path, bool) -> io::Result<fs::File>,
+    ) -> io::Result<u64> {
+        let json = to_device_json(&serde_json::to_value(self)?)?;
+        // writing it again would have sync tools and the cloud see a change
+        if let Ok(mut current) = open(path, false) {
+            let meta = current.metadata()?;
+            let mut data = Vec::with_capacity(json.len());
+            if meta.len() == json.len() as u64
+                && current.read_to_end(&mut data).is_ok()
+                && data == json
+            {
+                return Ok(meta.ino());
+            }
+        }
+        let mut file = open(path, true)?;
+        file.write_all(&json)?;
+        Ok(file.metadata()?.ino())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+
+    fn scratch(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!(
+            "fuse-rm-jsonmetadata-{}-{}",
+            std::process::id(),
+            name
+        ));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    // Saves `json` to `path` and returns the inode and the opens, true for
+    // the ones for writing
+    fn save(json: &JsonMetadata, path: &Path) -> (u64, Vec<bool>) {
+        let mut opens = Vec::new();
+        let ino = json
+            .save_with(path, |path, write| {
+                opens.push(write);
+                if write {
+                    fs::File::create(path)
+                } else {
+                    fs::File::open(path)
+                }
+            })
+            .unwrap();
+        (ino, opens)
+    }
+
+    #[test]
+    fn new_file() {
+        let dir = scratch("new");
+        let path = dir.join("doc.metadata");
+        let (ino, opens) = save(&JsonMetadata::new_file("Doc", ""), &path);
+        // the one open for writing, the inode isn't looked up again
+        assert_eq!(opens, vec![false, true]);
+        assert_eq!(ino, fs::metadata(&path).unwrap().ino());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn changed_file() {
+        let dir = scratch("changed");
+        let path = dir.join("doc.metadata");
+        fs::write(&path, "{}").unwrap();
+        let (ino, opens) = save(&JsonMetadata::new_dir("Folder", ""), &path);
+        assert_eq!(opens, vec![false, true]);
+        assert_eq!(ino, fs::metadata(&path).unwrap().ino());
+        assert_eq!(
+            JsonMetadata::from_file(&path).unwrap().doc_type(),
+            &DocType::CollectionType
+        );
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn same_file() {
+        let dir = scratch("same");
+        let path = dir.join("doc.metadata");
+        let json = JsonMetadata::new_file("Doc", "");
+        json.save_file(&path).unwrap();
+        let (ino, opens) = save(&json, &path);
+        // read and left alone
+        assert_eq!(opens, vec![false]);
+        assert_eq!(ino, fs::metadata(&path).unwrap().ino());
+        fs::remove_dir_all(&dir).unwrap();
     }
 }
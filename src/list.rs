@@ -0,0 +1,81 @@
+use std::path::{Path, PathBuf};
+
+use crate::direntry::EntryType;
+use crate::export::ExportError;
+use crate::paths::PathResolver;
+use crate::rmxfs::list_dir_metadata;
+
+argwerk::define! {
+    /// Print the paths the mount would show, without mounting.
+    #[usage = "fuse-rm list source [folder]"]
+    pub struct ListArgs {
+        pub help: bool,
+        pub source: Option<String>,
+        pub folder: Option<String>,
+    }
+    /// Print this help.
+    ["-h" | "--help"] => {
+        println!("{}", HELP);
+        help = true;
+    }
+    /// <source> dir, and the visible path or uuid of a <folder> to list
+    /// instead of the whole library
+    [dir] if source.is_none() => {
+        source = Some(dir);
+    }
+    [dir] if folder.is_none() => {
+        folder = Some(dir);
+    }
+}
+
+/// The visible paths of every entry in `folder` or the whole library,
+/// sorted, folders with a trailing slash. Failures are those of export.
+pub fn list(
+    source: &Path,
+    folder: Option<&str>,
+) -> Result<Vec<String>, ExportError> {
+    let entries = list_dir_metadata(&source.to_path_buf())?;
+    let resolver = PathResolver::new(&entries);
+    let (under, base) = match folder {
+        Some(folder) => {
+            let dir = resolver.resolve_folder(folder)?;
+            (
+                Some(&dir.prefix),
+                resolver.visible_path(dir).unwrap_or_default(),
+            )
+        }
+        None => (None, PathBuf::new()),
+    };
+    let mut res: Vec<String> = entries
+        .iter()
+        .filter(|e| under.is_none_or(|dir| resolver.is_under(e, dir)))
+        .filter_map(|e| {
+            let path = resolver.visible_path(e)?;
+            let mut path = path
+                .strip_prefix(&base)
+                .unwrap_or(&path)
+                .to_string_lossy()
+                .into_owned();
+            if e.entry_type == EntryType::NONE {
+                path.push('/');
+            }
+            Some(path)
+        })
+        .collect();
+    res.sort();
+    Ok(res)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse() {
+        let args = ListArgs::parse(["src"]).unwrap();
+        assert_eq!((args.source.as_deref(), args.folder), (Some("src"), None));
+        let args = ListArgs::parse(["src", "Work"]).unwrap();
+        assert_eq!(args.folder.as_deref(), Some("Work"));
+        assert!(ListArgs::parse(["src", "Work", "more"]).is_err());
+    }
+}
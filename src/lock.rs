@@ -0,0 +1,44 @@
+use std::fs;
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::os::unix::io::AsRawFd;
+use std::path::Path;
+
+use crate::validate::MountError;
+
+pub const LOCK_FILE: &str = ".fuse-rm.lock";
+
+// Advisory lock on the source dir, held for as long as this is alive. The
+// kernel drops the flock when the process exits, however that happens.
+pub struct SourceLock {
+    _file: fs::File,
+}
+
+impl SourceLock {
+    pub fn acquire(source: &Path) -> Result<SourceLock, MountError> {
+        let path = source.join(LOCK_FILE);
+        let mut file = fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(&path)
+            .map_err(|e| MountError::LockFailed(path.clone(), e))?;
+        if unsafe {
+            libc::flock(file.as_raw_fd(), libc::LOCK_EX | libc::LOCK_NB)
+        } != 0
+        {
+            let mut pid = String::new();
+            file.read_to_string(&mut pid).unwrap_or(0);
+            return Err(MountError::SourceLocked(
+                path,
+                pid.trim().parse().ok(),
+            ));
+        }
+        // the pid is only for diagnostics
+        file.set_len(0)
+            .and_then(|_| file.seek(SeekFrom::Start(0)))
+            .and_then(|_| writeln!(file, "{}", std::process::id()))
+            .map_err(|e| MountError::LockFailed(path, e))?;
+        Ok(SourceLock { _file: file })
+    }
+}
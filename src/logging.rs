@@ -0,0 +1,219 @@
+use std::cell::RefCell;
+use std::ffi::OsStr;
+use std::fs;
+use std::io::{self, Write};
+use std::path::Path;
+use std::sync::Mutex;
+use std::time::Instant;
+
+// Operations on different files interleave in the log. Each operation runs
+// in a span, the fields of which go on every line logged in it: the request
+// id the kernel gave it, the operation and the inode (and name) it is about.
+// The I/O threads take the span of the request their job came from, so the
+// lookup, open, reads and release of a file can be picked out by inode and
+// a request out of its reads by id.
+//
+// Filtering is env_logger's, by RUST_LOG or else by the -v flags. Reads and
+// writes come by the thousand when copying a file, their lines are only
+// logged at trace level (-vvv).
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum LogFormat {
+    Text,
+    // a json object per line, the span fields as fields
+    Json,
+}
+
+impl std::str::FromStr for LogFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<LogFormat, String> {
+        match s {
+            "text" => Ok(LogFormat::Text),
+            "json" => Ok(LogFormat::Json),
+            _ => Err(format!("Unknown log format: {}", s)),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Span {
+    req: u64,
+    op: &'static str,
+    ino: u64,
+    name: Option<String>,
+    started: Instant,
+    // set for the line logged when the operation is done
+    took_us: Option<u128>,
+}
+
+// operations whose lines would drown out the others at debug level
+const BULK_OPS: &[&str] = &["read", "write"];
+
+/// The level to log the lines of `op` at that are logged at debug level for
+/// other operations
+pub fn op_level(op: &str) -> log::Level {
+    if BULK_OPS.contains(&op) {
+        log::Level::Trace
+    } else {
+        log::Level::Debug
+    }
+}
+
+thread_local! {
+    static CURRENT: RefCell<Option<Span>> = const { RefCell::new(None) };
+}
+
+impl Span {
+    /// `req` is the unique id of the request, 0 for those without one
+    pub fn new(op: &'static str, req: u64, ino: u64) -> Span {
+        Span {
+            req,
+            op,
+            ino,
+            name: None,
+            started: Instant::now(),
+            took_us: None,
+        }
+    }
+
+    pub fn name(mut self, name: &OsStr) -> Span {
+        self.name = Some(name.to_string_lossy().into_owned());
+        self
+    }
+
+    pub fn op(&self) -> &'static str {
+        self.op
+    }
+
+    /// Makes this the span of the lines logged on this thread until the
+    /// returned guard is dropped
+    pub fn enter(self) -> Entered {
+        let previous = CURRENT.with(|c| c.replace(Some(self)));
+        Entered { previous }
+    }
+}
+
+/// The span of the running operation, for work it hands to other threads
+pub fn current() -> Option<Span> {
+    CURRENT.with(|c| c.borrow().clone())
+}
+
+pub struct Entered {
+    previous: Option<Span>,
+}
+
+impl Drop for Entered {
+    fn drop(&mut self) {
+        let op = CURRENT.with(|c| {
+            let mut current = c.borrow_mut();
+            let span = current.as_mut()?;
+            span.took_us = Some(span.started.elapsed().as_micros());
+            Some(span.op)
+        });
+        log!(op_level(op.unwrap_or("")), "done");
+        CURRENT.with(|c| c.replace(self.previous.take()));
+    }
+}
+
+fn text_fields(span: &Span) -> String {
+    let mut res = format!("req={} op={} ino={}", span.req, span.op, span.ino);
+    if let Some(name) = &span.name {
+        res.push_str(&format!(" name={:?}", name));
+    }
+    if let Some(took) = span.took_us {
+        res.push_str(&format!(" took_us={}", took));
+    }
+    res
+}
+
+fn json_line(record: &log::Record, timestamp: String) -> serde_json::Value {
+    let mut line = json!({
+        "ts": timestamp,
+        "level": record.level().to_string(),
+        "target": record.target(),
+        "msg": record.args().to_string(),
+    });
+    if let Some(span) = current() {
+        line["req"] = json!(span.req);
+        line["op"] = json!(span.op);
+        line["ino"] = json!(span.ino);
+        if let Some(name) = span.name {
+            line["name"] = json!(name);
+        }
+        if let Some(took) = span.took_us {
+            line["took_us"] = json!(took as u64);
+        }
+    }
+    line
+}
+
+// -v for info, -vv for debug and -vvv for trace, only errors without, as
+// env_logger does
+fn verbosity_filter(verbosity: u8) -> log::LevelFilter {
+    match verbosity {
+        0 => log::LevelFilter::Error,
+        1 => log::LevelFilter::Info,
+        2 => log::LevelFilter::Debug,
+        _ => log::LevelFilter::Trace,
+    }
+}
+
+/// Logs to stderr, or appended to `file`, as filtered by RUST_LOG if it is
+/// set and by `verbosity` if not. Returns the filter, for the banner.
+pub fn init(
+    file: Option<&Path>,
+    format: LogFormat,
+    verbosity: u8,
+) -> io::Result<String> {
+    // env_logger only writes to stdout and stderr (its pipe target is for
+    // tests), so the lines for a file are written by the format itself
+    let file = match file {
+        Some(file) => Some(Mutex::new(
+            fs::OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(file)?,
+        )),
+        None => None,
+    };
+    let mut builder = env_logger::Builder::from_default_env();
+    // without RUST_LOG, everything passes env_logger and the level is the
+    // max level of the log crate, which `set_level` changes
+    let (filter, level) = match std::env::var("RUST_LOG") {
+        Ok(spec) => (format!("RUST_LOG={}", spec), None),
+        Err(_) => {
+            let level = verbosity_filter(verbosity);
+            builder.filter_level(log::LevelFilter::Trace);
+            (level.to_string().to_lowercase(), Some(level))
+        }
+    };
+    builder.format(move |buf, record| {
+        let timestamp = buf.timestamp_millis().to_string();
+        let line = match format {
+            LogFormat::Json => json_line(record, timestamp).to_string(),
+            LogFormat::Text => format!(
+                "[{} {:5} {}] {}{}",
+                timestamp,
+                record.level(),
+                record.target(),
+                current().map_or(String::new(), |s| text_fields(&s) + " "),
+                record.args()
+            ),
+        };
+        match &file {
+            Some(file) => writeln!(file.lock().unwrap(), "{}", line),
+            None => writeln!(buf, "{}", line),
+        }
+    });
+    builder.init();
+    if let Some(level) = level {
+        log::set_max_level(level);
+    }
+    Ok(filter)
+}
+
+/// Logs up to `level` from now on, within what RUST_LOG allows if set
+pub fn set_level(level: log::LevelFilter) {
+    log::set_max_level(level);
+}
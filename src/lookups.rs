@@ -0,0 +1,115 @@
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+
+// The kernel counts every reply carrying an entry (lookup, create, mkdir)
+// as a reference to the inode, and tells with forget how many it dropped.
+// What is cached of an entry for the kernel is dropped with the last
+// reference: an inode the kernel forgot is looked up again before it is
+// used.
+
+pub struct Lookups {
+    // the count by ino, with the data file the entry had when looked up
+    counts: HashMap<u64, (u64, PathBuf)>,
+}
+
+impl Lookups {
+    pub fn new() -> Lookups {
+        Lookups {
+            counts: HashMap::new(),
+        }
+    }
+
+    /// Notes a reply with the entry of `ino`, whose data file is `path`
+    pub fn looked_up(&mut self, ino: u64, path: &Path) {
+        let (count, data) =
+            self.counts.entry(ino).or_insert((0, PathBuf::new()));
+        *count += 1;
+        if data != path {
+            *data = path.to_path_buf();
+        }
+    }
+
+    /// Drops `nlookup` references to `ino`. The data file once the last
+    /// one is gone, None while the kernel still knows the inode or never
+    /// did.
+    pub fn forget(&mut self, ino: u64, nlookup: u64) -> Option<PathBuf> {
+        let (count, _) = self.counts.get_mut(&ino)?;
+        *count = count.saturating_sub(nlookup);
+        match *count {
+            0 => self.counts.remove(&ino).map(|(_, path)| path),
+            _ => None,
+        }
+    }
+
+    /// The references the kernel holds to `ino`
+    #[cfg(test)]
+    pub fn count(&self, ino: u64) -> u64 {
+        self.counts.get(&ino).map_or(0, |(count, _)| *count)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn forgotten_with_the_last_reference() {
+        let mut lookups = Lookups::new();
+        let path = Path::new("/xochitl/lorem.pdf");
+        for _ in 0..3 {
+            lookups.looked_up(10, path);
+        }
+        assert_eq!(lookups.count(10), 3);
+        assert_eq!(lookups.forget(10, 1), None);
+        assert_eq!(lookups.forget(10, 1), None);
+        assert_eq!(lookups.count(10), 1);
+        assert_eq!(lookups.forget(10, 1), Some(path.to_path_buf()));
+        assert_eq!(lookups.count(10), 0);
+        // the kernel forgets an inode once
+        assert_eq!(lookups.forget(10, 1), None);
+    }
+
+    #[test]
+    fn batch_forget() {
+        let mut lookups = Lookups::new();
+        let path = Path::new("/xochitl/ipsum.epub");
+        for _ in 0..5 {
+            lookups.looked_up(11, path);
+        }
+        // as batch_forget passes the count of the kernel at once
+        assert_eq!(lookups.forget(11, 5), Some(path.to_path_buf()));
+        assert_eq!(lookups.count(11), 0);
+    }
+
+    #[test]
+    fn more_than_looked_up() {
+        let mut lookups = Lookups::new();
+        lookups.looked_up(12, Path::new("/xochitl/dolor.pdf"));
+        assert!(lookups.forget(12, u64::MAX).is_some());
+        assert_eq!(lookups.forget(13, 1), None);
+        assert_eq!(lookups.count(13), 0);
+    }
+
+    #[test]
+    fn looked_up_again() {
+        let mut lookups = Lookups::new();
+        let before = Path::new("/xochitl/lorem.pdf");
+        let after = Path::new("/xochitl/lorem.epub");
+        lookups.looked_up(14, before);
+        assert!(lookups.forget(14, 1).is_some());
+        // a new cycle, with the data file the entry has now
+        lookups.looked_up(14, before);
+        lookups.looked_up(14, after);
+        assert_eq!(lookups.forget(14, 1), None);
+        assert_eq!(lookups.forget(14, 1), Some(after.to_path_buf()));
+    }
+
+    #[test]
+    fn inodes_are_counted_apart() {
+        let mut lookups = Lookups::new();
+        lookups.looked_up(15, Path::new("/xochitl/a.pdf"));
+        lookups.looked_up(16, Path::new("/xochitl/b.pdf"));
+        assert!(lookups.forget(15, 1).is_some());
+        assert_eq!(lookups.count(16), 1);
+    }
+}
@@ -4,17 +4,72 @@ extern crate log;
 #[macro_use]
 extern crate serde_json;
 
+use std::fs;
 use std::io;
-use std::sync::{Arc, Condvar, Mutex};
+use std::path::{Path, PathBuf};
+use std::sync::{mpsc, Arc, Condvar, Mutex};
+use std::time::Duration;
 
 mod rmxfs;
 use rmxfs::RMXFS;
 
+mod allocs;
+mod ascii;
+mod backup;
+mod bench;
+mod bufpool;
+mod busyguard;
+mod completions;
+mod control;
+mod ctl;
+mod daemon;
+mod deadline;
+mod dedup;
 mod direntry;
+mod doctor;
+mod errno;
+mod exclude;
+mod export;
+mod filetypes;
+mod handles;
+mod highlights;
+mod import;
 mod jsonmetadata;
+mod list;
+mod lock;
+mod logging;
+mod lookups;
+mod mountopts;
+mod normalize;
+mod options;
+mod panicguard;
+mod paths;
+mod platform;
+mod prescan;
+mod purge;
+mod restore;
+mod retry;
+mod scanpool;
+mod sdnotify;
+mod sha256;
+mod size;
+mod space;
+mod staging;
+mod stats;
+mod syncguard;
+mod tags;
+mod timeconv;
+mod unmount;
+mod validate;
+mod workers;
+mod writebuf;
+mod xochitl;
+
+#[global_allocator]
+static ALLOCATOR: allocs::Counting = allocs::Counting;
 
 #[derive(Debug)]
-struct ProgError(String);
+struct ProgError(#[allow(dead_code)] String);
 
 impl std::convert::From<argwerk::Error> for ProgError {
     fn from(err: argwerk::Error) -> ProgError {
@@ -40,8 +95,8 @@ impl std::convert::From<argwerk::Error> for ProgError {
             argwerk::ErrorKind::InputError { error } => {
                 ProgError(format!("Input error {}", error))
             }
-            argwerk::ErrorKind::Error { name, .. } => {
-                ProgError(format!("Error: {}", name))
+            argwerk::ErrorKind::Error { name, error } => {
+                ProgError(format!("Error: {}: {}", name, error))
             }
         }
     }
@@ -53,43 +108,1076 @@ impl std::convert::From<io::Error> for ProgError {
     }
 }
 
+fn bench_main(args: bench::BenchArgs) -> Result<(), ProgError> {
+    if args.help {
+        return Ok(());
+    }
+    let (source, generated) = match &args.source {
+        Some(source) => (PathBuf::from(source), false),
+        None => {
+            let dir = bench::temp_library();
+            println!("Generating {} documents in {:?}", args.docs, dir);
+            bench::generate(&dir, args.docs, args.flat)?;
+            (dir, true)
+        }
+    };
+    let res = bench::run(&source);
+    if generated {
+        fs::remove_dir_all(&source)?;
+    }
+    let res = res?;
+    println!("entries: {}", res.entries);
+    println!("scan: {} ms", res.scan_millis);
+    println!(
+        "scan with {} threads: {} ms ({} ms with one)",
+        res.threads, res.parallel_millis, res.serial_millis
+    );
+    println!("rss: {} KiB", res.rss_kib);
+    println!("peak rss: {} KiB", res.peak_rss_kib);
+    println!(
+        "dir snapshots: {} bytes per entry and handle",
+        res.snapshot_bytes
+    );
+    println!(
+        "opening the top level: {} allocations, {} with a copy of the listing",
+        res.open_allocs, res.copied_allocs
+    );
+    if let Some(mib) = args.read_mb {
+        let read = bench::read(&std::env::temp_dir(), mib)?;
+        println!("read: {} MiB in {} ms", mib, read.read_millis);
+        println!("read buffers: {} for {} reads", read.buffers, read.reads);
+    }
+    if let Some(mib) = args.write_mb {
+        let write = bench::write(&std::env::temp_dir(), mib, args.write_kib)?;
+        println!(
+            "write: {} MiB in {} ms ({} ms a request at a time)",
+            mib, write.buffered_millis, write.unbuffered_millis
+        );
+    }
+    if !res.same_order {
+        println!("The listings of the threads and of one differ");
+        std::process::exit(1);
+    }
+    match args.max_rss {
+        Some(max) if res.peak_rss_kib > max => {
+            println!("Peak rss is over the budget of {} KiB", max);
+            std::process::exit(1);
+        }
+        _ => Ok(()),
+    }
+}
+
+fn doctor_main(args: doctor::DoctorArgs) -> Result<(), ProgError> {
+    if args.help {
+        return Ok(());
+    }
+    let checks = doctor::run(
+        args.source.as_deref().map(Path::new),
+        args.target.as_deref().map(Path::new),
+    );
+    checks
+        .iter()
+        .for_each(|check| println!("{}", check.report()));
+    let failed = checks.iter().filter(|check| check.failed()).count();
+    if failed > 0 {
+        println!("{} check(s) failed, mounting won't work", failed);
+        std::process::exit(1);
+    }
+    Ok(())
+}
+
+fn ctl_main(args: ctl::CtlArgs) -> Result<(), ProgError> {
+    if args.help {
+        return Ok(());
+    }
+    let (mountpoint, command) = match (&args.mountpoint, &args.command) {
+        (Some(mountpoint), Some(command)) => (mountpoint, command),
+        _ => {
+            println!("Mountpoint and command required");
+            return Err(ProgError("Missing positional args".to_string()));
+        }
+    };
+    let command: ctl::Command = match command.parse() {
+        Ok(command) => command,
+        Err(e) => {
+            println!("{}", e);
+            return Err(ProgError(e));
+        }
+    };
+    let arg = args.arg.unwrap_or_default();
+    if command == ctl::Command::SetLogLevel && arg.is_empty() {
+        println!(
+            "log-level needs a level: off, error, warn, info, debug or trace"
+        );
+        return Err(ProgError("Missing positional args".to_string()));
+    }
+    match ctl::send(Path::new(mountpoint), command, arg.as_bytes()) {
+        Ok(answer) => {
+            if !answer.is_empty() {
+                println!("{}", String::from_utf8_lossy(&answer));
+            }
+            Ok(())
+        }
+        Err(e) => {
+            println!("{}: {}", mountpoint, e);
+            std::process::exit(1);
+        }
+    }
+}
+
+fn export_main(args: export::ExportArgs) -> Result<(), ProgError> {
+    if args.help {
+        return Ok(());
+    }
+    let (source_dir, document, dest_dir) = match args.positional {
+        Some(positional) => positional,
+        None => {
+            println!("Source, document and destination required");
+            return Err(ProgError("Missing positional args".to_string()));
+        }
+    };
+
+    let source = Path::new(&source_dir);
+    if let Err(e) = validate::check_source(source, false) {
+        println!("{}", e);
+        std::process::exit(e.exit_code());
+    }
+    match export::export(source, &document, Path::new(&dest_dir), args.raw) {
+        Ok(exported) => {
+            println!(
+                "Exported {} document(s) to {}",
+                exported.documents, dest_dir
+            );
+            if exported.left_out > 0 {
+                let copied = if args.raw {
+                    ", their raw files copied"
+                } else {
+                    ""
+                };
+                println!(
+                    "Left out {} notebook(s) and document(s) without data{}",
+                    exported.left_out, copied
+                );
+            }
+            Ok(())
+        }
+        Err(e) => {
+            println!("{}", e);
+            std::process::exit(e.exit_code());
+        }
+    }
+}
+
+fn backup_main(args: backup::BackupArgs) -> Result<(), ProgError> {
+    if args.help {
+        return Ok(());
+    }
+    let (source_dir, dest_dir) = match args.positional {
+        Some(positional) => positional,
+        None => {
+            println!("Source and destination required");
+            return Err(ProgError("Missing positional args".to_string()));
+        }
+    };
+
+    let source = Path::new(&source_dir);
+    if let Err(e) = validate::check_source(source, false) {
+        println!("{}", e);
+        std::process::exit(e.exit_code());
+    }
+    let dest = Path::new(&dest_dir);
+    let summary = match backup::backup(source, dest, args.raw, args.incremental)
+    {
+        Ok(summary) => summary,
+        Err(e) => {
+            println!("{}", e);
+            std::process::exit(e.exit_code());
+        }
+    };
+    for (path, e) in &summary.failed {
+        println!("Couldn't back up {}: {}", path, e);
+    }
+    println!(
+        "Backed up {} document(s) to {}, {} unchanged, {} removed",
+        summary.copied, dest_dir, summary.unchanged, summary.removed
+    );
+    if summary.left_out > 0 {
+        println!(
+            "Left out {} notebook(s) and document(s) without data",
+            summary.left_out
+        );
+    }
+    if summary.exit_code() != 0 {
+        std::process::exit(summary.exit_code());
+    }
+    Ok(())
+}
+
+fn restore_main(args: restore::RestoreArgs) -> Result<(), ProgError> {
+    if args.help {
+        return Ok(());
+    }
+    let (source_dir, backup_dir) = match args.positional {
+        Some(positional) => positional,
+        None => {
+            println!("Source and backup required");
+            return Err(ProgError("Missing positional args".to_string()));
+        }
+    };
+
+    let source = Path::new(&source_dir);
+    if let Err(e) = validate::check_source(source, false) {
+        println!("{}", e);
+        std::process::exit(e.exit_code());
+    }
+    // a mount wouldn't show what is restored, and could be writing there
+    let _lock = match lock::SourceLock::acquire(source) {
+        Ok(lock) => Some(lock),
+        Err(validate::MountError::SourceLocked(..)) if args.dry_run => None,
+        Err(e) => {
+            println!("{}", e);
+            std::process::exit(e.exit_code());
+        }
+    };
+    if xochitl::is_running() && !args.dry_run {
+        println!(
+            "WARNING: xochitl is running and won't notice the restored \
+             documents until restarted"
+        );
+    }
+
+    let backup = Path::new(&backup_dir);
+    let planned =
+        match restore::restore(source, backup, args.dry_run, args.overwrite) {
+            Ok(planned) => planned,
+            Err(e) => {
+                println!("{}", e);
+                std::process::exit(e.exit_code());
+            }
+        };
+    let mut exit_code = 0;
+    for doc in &planned {
+        if let Err(e) = &doc.result {
+            println!("Couldn't restore {}: {}", doc.path, e);
+            if exit_code == 0 {
+                exit_code = e.exit_code();
+            }
+            continue;
+        }
+        match (&doc.action, args.dry_run) {
+            (restore::Action::Restore, false) => {
+                println!("Restored {}", doc.path)
+            }
+            (restore::Action::Restore, true) => {
+                println!("Would restore {}", doc.path)
+            }
+            (restore::Action::Overwrite, false) => {
+                println!("Overwrote {}", doc.path)
+            }
+            (restore::Action::Overwrite, true) => {
+                println!("Would overwrite {}", doc.path)
+            }
+            (restore::Action::Skip(what), _) => {
+                println!("Skipped {}, source has {} already", doc.path, what)
+            }
+            (restore::Action::Conflict, _) => {
+                println!(
+                    "Conflict: {} is another document in source (restore \
+                     with --overwrite to replace it)",
+                    doc.path
+                );
+                if exit_code == 0 {
+                    exit_code = 60;
+                }
+            }
+        }
+    }
+    if exit_code != 0 {
+        std::process::exit(exit_code);
+    }
+    Ok(())
+}
+
+fn import_main(args: import::ImportArgs) -> Result<(), ProgError> {
+    if args.help {
+        return Ok(());
+    }
+    let source_dir = match (&args.source, args.files.is_empty()) {
+        (Some(source_dir), false) => source_dir,
+        _ => {
+            println!("Source and files to import required");
+            return Err(ProgError("Missing positional args".to_string()));
+        }
+    };
+    if args.replace.is_some() && (args.files.len() > 1 || args.into.is_some()) {
+        println!("--replace takes a single file and no --into");
+        return Err(ProgError("Conflicting args".to_string()));
+    }
+
+    let source = Path::new(source_dir);
+    let checked = validate::check_source(source, false)
+        .and_then(|_| lock::SourceLock::acquire(source));
+    let _lock = match checked {
+        Ok(lock) => lock,
+        Err(e) => {
+            println!("{}", e);
+            std::process::exit(e.exit_code());
+        }
+    };
+    if xochitl::is_running() {
+        println!(
+            "WARNING: xochitl is running and won't notice the new documents \
+             until restarted"
+        );
+    }
+
+    let mut exit_code = 0;
+    for file in &args.files {
+        let res = match &args.replace {
+            Some(document) => {
+                import::replace_file(source, document, Path::new(file))
+            }
+            None => import::import_file(
+                source,
+                args.into.as_deref(),
+                Path::new(file),
+            ),
+        };
+        match res {
+            Ok(entry) => println!(
+                "Imported {} as {}",
+                file,
+                entry.prefix.to_string_lossy()
+            ),
+            Err(e) => {
+                println!("{}", e);
+                if exit_code == 0 {
+                    exit_code = e.exit_code();
+                }
+            }
+        }
+    }
+    // only removed when empty
+    fs::remove_dir(source.join(".pending")).unwrap_or(());
+    if exit_code != 0 {
+        std::process::exit(exit_code);
+    }
+    Ok(())
+}
+
+fn list_main(args: list::ListArgs) -> Result<(), ProgError> {
+    if args.help {
+        return Ok(());
+    }
+    let source_dir = match &args.source {
+        Some(source_dir) => source_dir,
+        None => {
+            println!("Source required");
+            return Err(ProgError("Missing positional args".to_string()));
+        }
+    };
+
+    let source = Path::new(source_dir);
+    if let Err(e) = validate::check_source(source, false) {
+        println!("{}", e);
+        std::process::exit(e.exit_code());
+    }
+    match list::list(source, args.folder.as_deref()) {
+        Ok(paths) => {
+            paths.iter().for_each(|path| println!("{}", path));
+            Ok(())
+        }
+        Err(e) => {
+            println!("{}", e);
+            std::process::exit(e.exit_code());
+        }
+    }
+}
+
+fn purge_main(args: purge::PurgeArgs) -> Result<(), ProgError> {
+    if args.help {
+        return Ok(());
+    }
+    let source_dir = match &args.source {
+        Some(source_dir) => source_dir,
+        None => {
+            println!("Source required");
+            return Err(ProgError("Missing positional args".to_string()));
+        }
+    };
+
+    let source = Path::new(source_dir);
+    if let Err(e) = validate::check_source(source, false) {
+        println!("{}", e);
+        std::process::exit(e.exit_code());
+    }
+    // a mount would still show what is gone, and could be writing it
+    let _lock = match lock::SourceLock::acquire(source) {
+        Ok(lock) => Some(lock),
+        Err(validate::MountError::SourceLocked(..))
+            if args.force || args.dry_run =>
+        {
+            None
+        }
+        Err(e @ validate::MountError::SourceLocked(..)) => {
+            println!("{}", e);
+            println!("Unmount it first, or pass --force to purge anyway");
+            std::process::exit(e.exit_code());
+        }
+        Err(e) => {
+            println!("{}", e);
+            std::process::exit(e.exit_code());
+        }
+    };
+
+    let older_than = args.older_than.map(|age| age.0);
+    let purged = purge::purge(source, older_than, args.dry_run)?;
+    let mut bytes = 0;
+    let mut failed = false;
+    for entry in &purged {
+        match &entry.result {
+            Ok(()) if args.dry_run => {
+                println!("Would remove {} ({} bytes)", entry.path, entry.bytes)
+            }
+            Ok(()) => {
+                println!("Removed {} ({} bytes)", entry.path, entry.bytes)
+            }
+            Err(e) => {
+                println!("Couldn't remove {}: {}", entry.path, e);
+                failed = true;
+                continue;
+            }
+        }
+        bytes += entry.bytes;
+    }
+    println!(
+        "{} {} entries, {} bytes",
+        if args.dry_run {
+            "Would reclaim"
+        } else {
+            "Reclaimed"
+        },
+        purged.iter().filter(|e| e.result.is_ok()).count(),
+        bytes
+    );
+    if failed {
+        std::process::exit(1);
+    }
+    Ok(())
+}
+
+argwerk::define! {
+    /// A FUSE fs for accessing xochitl data.
+    #[usage = "fuse-rm [opts] source target"]
+    struct Args {
+        help: bool,
+        help_txt: String,
+        limit: usize = 10,
+        nonempty: bool,
+        force: bool,
+        no_lock: bool,
+        options: options::Options = options::Options::default(),
+        custom_markers: bool,
+        stop_xochitl: bool,
+        helper: mountopts::HelperOptions,
+        helper_mode: bool,
+        foreground: bool,
+        staging_dir: Option<PathBuf>,
+        root: Option<String>,
+        log_file: Option<PathBuf>,
+        verbosity: u8,
+        stats_on_exit: bool,
+        prescan: bool,
+        prescan_sizes: bool = true,
+        log_format: logging::LogFormat = logging::LogFormat::Text,
+        bench: Option<bench::BenchArgs>,
+        export: Option<export::ExportArgs>,
+        backup: Option<backup::BackupArgs>,
+        restore: Option<restore::RestoreArgs>,
+        import: Option<import::ImportArgs>,
+        list: Option<list::ListArgs>,
+        doctor: Option<doctor::DoctorArgs>,
+        ctl: Option<ctl::CtlArgs>,
+        purge: Option<purge::PurgeArgs>,
+        completions: Option<String>,
+        positional: Option<(String, String)>,
+    }
+    /// The limit of the operation. (default: 10).
+    ["-l" | "--limit", int] => {
+        limit = str::parse(&int)?;
+    }
+    /// Allow mounting over a non-empty target.
+    ["--nonempty"] => {
+        nonempty = true;
+    }
+    /// Mount even if the source doesn't look like a xochitl directory.
+    ["--force"] => {
+        force = true;
+    }
+    /// Don't lock the source against other fuse-rm instances.
+    ["--no-lock"] => {
+        no_lock = true;
+    }
+    /// Changes while xochitl runs: off, warn (default) or readonly.
+    ["--xochitl-guard", mode] => {
+        options.xochitl_guard = str::parse(&mode)?;
+    }
+    /// Allow changes to the document open in xochitl.
+    ["--no-busy-guard"] => {
+        options.busy_guard = false;
+    }
+    /// The xochitl config telling which document is open (default: that
+    /// of the tablet, while xochitl runs).
+    ["--busy-state", path] => {
+        options.busy_state = Some(PathBuf::from(path));
+    }
+    /// Changes during a cloud sync: off (default), wait or fail.
+    ["--sync-guard", mode] => {
+        options.sync_guard = str::parse(&mode)?;
+    }
+    /// File name or pattern marking a sync (repeatable, replaces the
+    /// defaults).
+    ["--sync-marker", marker] => {
+        if !custom_markers {
+            options.sync_markers.clear();
+            custom_markers = true;
+        }
+        options.sync_markers.push(marker);
+    }
+    /// Seconds to wait for a sync with --sync-guard wait (default: 10).
+    ["--sync-timeout", secs] => {
+        options.sync_timeout = Duration::from_secs(str::parse(&secs)?);
+    }
+    /// Listing order: name (default), mtime or none.
+    ["--sort", order] => {
+        options.sort = str::parse(&order)?;
+    }
+    /// List folders before documents.
+    ["--folders-first"] => {
+        options.folders_first = true;
+    }
+    /// Add a read-only Recent folder with the <n> newest documents.
+    ["--recent", n] => {
+        options.recent = str::parse(&n)?;
+    }
+    /// Show data files without metadata in a .orphans folder.
+    ["--expose-orphans"] => {
+        options.expose_orphans = true;
+    }
+    /// Show the entries marked deleted, awaiting removal, in a read-only
+    /// .deleted folder.
+    ["--expose-deleted"] => {
+        options.expose_deleted = true;
+    }
+    /// List the .fuse-rm control dir, see the README.
+    ["--expose-control"] => {
+        options.expose_control = true;
+    }
+    /// Keep the uuid of a document an editor saves over with a new file.
+    ["--replace-preserves-uuid"] => {
+        options.replace_preserves_uuid = true;
+    }
+    /// Show highlights next to documents: off (default), merged or raw.
+    ["--highlights", mode] => {
+        options.highlights = str::parse(&mode)?;
+    }
+    /// List every document at the root, without folders.
+    ["--flat"] => {
+        options.layout = options::Layout::Flat;
+    }
+    /// Retries of a failed read or stat of the source (default: 3).
+    ["--io-retries", n] => {
+        options.io_retries = str::parse(&n)?;
+    }
+    /// Milliseconds before the first retry, doubled for each next one
+    /// (default: 10).
+    ["--io-backoff", millis] => {
+        options.io_backoff = Duration::from_millis(str::parse(&millis)?);
+    }
+    /// Seconds after which a listing, open or read of the source is
+    /// given up on with ETIMEDOUT (default: none).
+    ["--io-timeout", secs] => {
+        let secs = Duration::try_from_secs_f64(str::parse(&secs)?)?;
+        options.io_timeout = Some(secs);
+    }
+    /// Threads reading from the source, so a slow read doesn't hold up
+    /// other requests (default: 4, 0 for none).
+    ["--io-threads", n] => {
+        options.io_threads = str::parse(&n)?;
+    }
+    /// Sync every write to a new document, and the files of the document
+    /// once it is complete, before replying. For sources on network
+    /// mounts that may drop.
+    ["--sync-write"] => {
+        options.sync_write = true;
+    }
+    /// Close read handles and finish uploads left without I/O for this
+    /// many seconds, e.g. by a crashed client (default: never).
+    ["--handle-timeout", secs] => {
+        options.handle_timeout =
+            Some(Duration::from_secs(str::parse(&secs)?));
+    }
+    /// New files that can be written at once, more are refused with
+    /// EMFILE (default: 64).
+    ["--max-pending", n] => {
+        options.max_pending = str::parse(&n)?;
+    }
+    /// Bytes the new files being written can hold together, writes
+    /// beyond fail with ENOSPC (default: no limit).
+    ["--max-staging-bytes", n] => {
+        options.max_staging_bytes = Some(size::parse(&n)?);
+    }
+    /// Bytes of new documents accepted in this session, e.g. 2GiB;
+    /// creates and writes beyond fail with EDQUOT (default: no limit).
+    ["--quota", n] => {
+        options.quota = Some(size::parse(&n)?);
+    }
+    /// Hide documents and folders whose visible path matches, e.g.
+    /// 'Archive/**' or '*.epub' (repeatable).
+    ["--exclude", pattern] => {
+        options.excludes.push(str::parse(&pattern)?);
+    }
+    /// What chmod, chown and utimes do, nothing of them is stored:
+    /// ignore (default), check (chown to another owner fails) or refuse.
+    ["--attr-changes", mode] => {
+        options.attr_changes = str::parse(&mode)?;
+    }
+    /// Serve the library as scanned at mount, read-only: changes to the
+    /// source after that don't show. Reads are of the data files as they
+    /// are now.
+    ["--snapshot"] => {
+        options.snapshot = options::SnapshotMode::Listing;
+    }
+    /// Like --snapshot, with the data files pinned too: a read of one
+    /// changed since fails with ESTALE.
+    ["--snapshot=deep"] => {
+        options.snapshot = options::SnapshotMode::Deep;
+    }
+    /// Store files of types xochitl doesn't show, e.g. a zip of
+    /// references, instead of refusing them.
+    ["--allow-any-type"] => {
+        options.allow_any_type = true;
+    }
+    /// Extension still refused with --allow-any-type (repeatable).
+    ["--deny-type", ext] => {
+        options.denied_types.push(ext);
+    }
+    /// List entries of types other than documents and folders, like
+    /// templates, as files.
+    ["--show-other-types"] => {
+        options.show_other_types = true;
+    }
+    /// Show epubs the device rendered as their original (default), as
+    /// the pdf rendition or both, the pdf read-only.
+    ["--prefer-rendition", rendition] => {
+        options.rendition = str::parse(&rendition)?;
+    }
+    /// Show documents with pages as modified when their newest page
+    /// was, if that is later. Stats every page of every scan.
+    ["--deep-mtime"] => {
+        options.deep_mtime = true;
+    }
+    /// Write metadata on a single line instead of indented like the
+    /// device.
+    ["--compact-metadata"] => {
+        options.compact_metadata = true;
+    }
+    /// Show, match and store names in this Unicode form: nfc, nfd or
+    /// off (default), so that names typed with decomposed accents match.
+    ["--normalize", form] => {
+        options.normalize = str::parse(&form)?;
+    }
+    /// Show names transliterated to ASCII, for tools that take nothing
+    /// else. The metadata keeps the real names.
+    ["--ascii-names"] => {
+        options.ascii_names = true;
+    }
+    /// Mark the names listed of documents and folders with changes not
+    /// synced to the cloud with " •".
+    ["--mark-unsynced"] => {
+        options.mark_unsynced = true;
+    }
+    /// Show documents of a type with another extension, like rm=lines
+    /// for notebooks as .lines (repeatable). New names take it too.
+    ["--map", map] => {
+        options.extension_maps.push(map);
+    }
+    /// Don't create a document with the same data as one outside the
+    /// trash: closing it fails with EEXIST (fail) or it is discarded
+    /// (discard).
+    ["--dedup", mode] => {
+        options.dedup = str::parse(&mode)?;
+    }
+    /// Threads parsing the metadata of a listing (default: 2 on the
+    /// tablet, one per core elsewhere).
+    ["--scan-threads", n] => {
+        options.scan_threads = Some(str::parse(&n)?);
+    }
+    /// Mount this folder, a visible path or uuid, instead of the whole
+    /// library. The trash and other virtual dirs are left out.
+    ["--root", folder] => {
+        root = Some(folder);
+    }
+    /// Write new documents to this dir until they are closed (default:
+    /// .pending in source).
+    ["--staging-dir", dir] => {
+        staging_dir = Some(PathBuf::from(dir));
+    }
+    /// Append the log to this file instead of stderr.
+    ["--log-file", file] => {
+        log_file = Some(PathBuf::from(file));
+    }
+    /// Log info, -vv debug and -vvv trace lines (also of every read and
+    /// write) unless RUST_LOG is set. Repeatable.
+    ["-v" | "--verbose"] => {
+        verbosity = verbosity.saturating_add(1);
+    }
+    ["-vv"] => {
+        verbosity = verbosity.saturating_add(2);
+    }
+    ["-vvv"] => {
+        verbosity = verbosity.saturating_add(3);
+    }
+    /// Log lines as text (default) or as json objects.
+    ["--log-format", format] => {
+        log_format = str::parse(&format)?;
+    }
+    /// Read the whole library before the mount is ready, so the first
+    /// listing is fast.
+    ["--prescan"] => {
+        prescan = true;
+    }
+    /// Prescan only the metadata, without looking for the data files
+    /// and their sizes (faster over the network).
+    ["--prescan-no-sizes"] => {
+        prescan = true;
+        prescan_sizes = false;
+    }
+    /// Print what the mount did at unmount (to the log with
+    /// --log-file).
+    ["--stats-on-exit"] => {
+        stats_on_exit = true;
+    }
+    /// Stop xochitl while mounted and restart it at unmount.
+    ["--stop-xochitl"] => {
+        stop_xochitl = true;
+    }
+    /// Mount options as passed by mount(8), e.g. ro,allow_other,uid=0.
+    /// Mounts in the background unless --foreground is given.
+    ["-o", opts] => {
+        helper.add(&opts)?;
+        helper_mode = true;
+    }
+    /// Stay in the foreground when run by mount(8).
+    ["--foreground"] => {
+        foreground = true;
+    }
+    /// Flags of mount(8) for its helpers, they have no effect here.
+    ["-s" | "-n"] => {}
+    /// The filesystem type from mount(8), ignored.
+    ["-t", _fstype] => {}
+    /// Print this help.
+    ["-h" | "--help"] => {
+        println!("{}", HELP);
+        help = true;
+    }
+    /// Measure memory use, see `fuse-rm bench --help`.
+    ["bench", #[rest(os)] rest] if positional.is_none() => {
+        bench = Some(bench::BenchArgs::parse(rest)?);
+    }
+    /// Copy a document out of source, see `fuse-rm export --help`.
+    ["export", #[rest(os)] rest] if positional.is_none() => {
+        export = Some(export::ExportArgs::parse(rest)?);
+    }
+    /// Copy the whole library out of source, see
+    /// `fuse-rm backup --help`.
+    ["backup", #[rest(os)] rest] if positional.is_none() => {
+        backup = Some(backup::BackupArgs::parse(rest)?);
+    }
+    /// Put a backup back into source, see `fuse-rm restore --help`.
+    ["restore", #[rest(os)] rest] if positional.is_none() => {
+        restore = Some(restore::RestoreArgs::parse(rest)?);
+    }
+    /// Add documents to source, see `fuse-rm import --help`.
+    ["import", #[rest(os)] rest] if positional.is_none() => {
+        import = Some(import::ImportArgs::parse(rest)?);
+    }
+    /// Print the visible paths, see `fuse-rm list --help`.
+    ["list", #[rest(os)] rest] if positional.is_none() => {
+        list = Some(list::ListArgs::parse(rest)?);
+    }
+    /// Check what mounting needs, see `fuse-rm doctor --help`.
+    ["doctor", #[rest(os)] rest] if positional.is_none() => {
+        doctor = Some(doctor::DoctorArgs::parse(rest)?);
+    }
+    /// Send a command to a mount, see `fuse-rm ctl --help`.
+    ["ctl", #[rest(os)] rest] if positional.is_none() => {
+        ctl = Some(ctl::CtlArgs::parse(rest)?);
+    }
+    /// Empty the trash, see `fuse-rm purge-trash --help`.
+    ["purge-trash", #[rest(os)] rest] if positional.is_none() => {
+        purge = Some(purge::PurgeArgs::parse(rest)?);
+    }
+    /// Print the completion script for bash, zsh or fish, e.g.
+    /// `fuse-rm completions bash > /etc/bash_completion.d/fuse-rm`.
+    ["completions", shell] if positional.is_none() => {
+        completions = Some(completions::script(
+            str::parse(&shell)?,
+            HELP,
+            &[
+                ("bench", &bench::BenchArgs::HELP),
+                ("export", &export::ExportArgs::HELP),
+                ("backup", &backup::BackupArgs::HELP),
+                ("restore", &restore::RestoreArgs::HELP),
+                ("import", &import::ImportArgs::HELP),
+                ("list", &list::ListArgs::HELP),
+                ("doctor", &doctor::DoctorArgs::HELP),
+                ("ctl", &ctl::CtlArgs::HELP),
+                ("purge-trash", &purge::PurgeArgs::HELP),
+            ],
+        ));
+    }
+    /// <source> and <target> paths for mounting, more sources to show
+    /// read-only along with the first as source:source2:...
+    [source, target] if positional.is_none() => {
+        positional = Some((source, target))
+    }
+}
+
 fn main() -> Result<(), ProgError> {
-    env_logger::init();
-    let args = argwerk::args! {
-        /// A FUSE fs for accessing xochitl data.
-        "fuse-rm [opts] source target" {
-            help: bool,
-            help_txt: String,
-            limit: usize = 10,
-            positional: Option<(String, String)>,
-        }
-        /// The limit of the operation. (default: 10).
-        ["-l" | "--limit", int] => {
-            limit = str::parse(&int)?;
-        }
-        /// Print this help.
-        ["-h" | "--help"] => {
-            println!("{}", HELP);
-            help = true;
-        }
-        /// <source> and <target> paths for mounting
-        [source, target] if positional.is_none() => {
-            positional = Some((source, target))
-        }
-    }?;
+    let args = Args::args()?;
 
     if args.help {
         return Ok(());
     }
+    if let Some(script) = &args.completions {
+        print!("{}", script);
+        return Ok(());
+    }
+    let log_filter = match logging::init(
+        args.log_file.as_deref(),
+        args.log_format,
+        args.verbosity,
+    ) {
+        Ok(filter) => filter,
+        Err(e) => {
+            println!("Can't open log file: {}", e);
+            std::process::exit(1);
+        }
+    };
+    retry::configure(args.options.io_retries, args.options.io_backoff);
+    deadline::configure(args.options.io_timeout);
+    jsonmetadata::set_compact(args.options.compact_metadata);
+    direntry::set_rendition(args.options.rendition);
+    direntry::set_deep_mtime(args.options.deep_mtime);
+    normalize::configure(args.options.normalize);
+    ascii::configure(args.options.ascii_names);
+    scanpool::configure(args.options.scan_threads);
+    match direntry::ExtensionMap::new(&args.options.extension_maps) {
+        Ok(map) => direntry::set_extensions(map),
+        Err(e) => {
+            println!("{}", e);
+            return Err(ProgError(e));
+        }
+    }
+    filetypes::configure(
+        args.options.allow_any_type,
+        &args.options.denied_types,
+    );
+
+    if let Some(bench_args) = args.bench {
+        return bench_main(bench_args);
+    }
+    if let Some(export_args) = args.export {
+        return export_main(export_args);
+    }
+    if let Some(backup_args) = args.backup {
+        return backup_main(backup_args);
+    }
+    if let Some(restore_args) = args.restore {
+        return restore_main(restore_args);
+    }
+    if let Some(import_args) = args.import {
+        return import_main(import_args);
+    }
+    if let Some(list_args) = args.list {
+        return list_main(list_args);
+    }
+    if let Some(doctor_args) = args.doctor {
+        return doctor_main(doctor_args);
+    }
+    if let Some(ctl_args) = args.ctl {
+        return ctl_main(ctl_args);
+    }
+    if let Some(purge_args) = args.purge {
+        return purge_main(purge_args);
+    }
 
     if args.positional.is_none() {
         println!("Source and target paths required");
-        return Err(ProgError(format!("Missing positional args")));
+        return Err(ProgError("Missing positional args".to_string()));
+    }
+
+    let (sources, target_dir) = &args.positional.unwrap();
+
+    let mut options = args.options;
+    // a path with a colon in it is still one source
+    let source_dir = if Path::new(sources).exists() {
+        sources.as_str()
+    } else {
+        let mut sources = sources.split(':');
+        let source_dir = sources.next().unwrap_or_default();
+        options.mirrors = sources.map(PathBuf::from).collect();
+        source_dir
+    };
+    options.uid = args.helper.uid.unwrap_or(options.uid);
+    options.gid = args.helper.gid.unwrap_or(options.gid);
+    let nonempty = args.nonempty || args.helper.nonempty;
+
+    let (source, target) = (Path::new(source_dir), Path::new(target_dir));
+    let force = args.force;
+    let checked = std::iter::once(source)
+        .chain(options.mirrors.iter().map(PathBuf::as_path))
+        .try_for_each(|source| validate::check_source(source, force))
+        .and_then(|_| validate::check_target(source, target, nonempty));
+    if let Err(e) = checked {
+        println!("{}", e);
+        std::process::exit(e.exit_code());
+    }
+
+    if let Some(folder) = &args.root {
+        if options.layout == options::Layout::Flat {
+            println!("--root and --flat can't be combined");
+            return Err(ProgError("Conflicting args".to_string()));
+        }
+        let entries = rmxfs::list_dir_metadata(&source.to_path_buf())?;
+        match paths::PathResolver::new(&entries).resolve_folder(folder) {
+            Ok(dir) => options.root = Some(dir.prefix.clone()),
+            Err(e) => {
+                println!("{}", e);
+                std::process::exit(e.exit_code());
+            }
+        }
     }
 
-    let (source_dir, target_dir) = &args.positional.unwrap();
+    if let Some(dir) = &args.staging_dir {
+        if let Err(e) = staging::configure(dir) {
+            println!("Can't use staging dir {}: {}", dir.display(), e);
+            std::process::exit(1);
+        }
+    }
+
+    // mount(8) waits for its helper, so mount in the background
+    let helper_mode = args.helper_mode || mountopts::invoked_as_helper();
+    let mut daemon = if helper_mode && !args.foreground {
+        Some(daemon::daemonize()?)
+    } else {
+        None
+    };
+
+    let _lock = if args.no_lock {
+        None
+    } else {
+        match lock::SourceLock::acquire(source) {
+            Ok(lock) => Some(lock),
+            Err(e) => {
+                println!("{}", e);
+                if let Some(daemon) = daemon.as_mut() {
+                    daemon.report(e.exit_code());
+                }
+                std::process::exit(e.exit_code());
+            }
+        }
+    };
+
+    // the kernel refuses every change to a snapshot with EROFS
+    let snapshot = options.snapshot != options::SnapshotMode::Off;
+    let read_only =
+        snapshot || args.helper.mount.contains(&fuser::MountOption::RO);
+
+    // with the lock held, no other instance is uploading
+    if !read_only {
+        match staging::reclaim(source) {
+            Ok(res) if res.completed + res.removed > 0 => println!(
+                "Leftover uploads in .pending: {} completed, {} removed \
+                 ({} bytes)",
+                res.completed, res.removed, res.bytes
+            ),
+            Ok(_) => (),
+            Err(e) => println!("Couldn't clean up .pending: {}", e),
+        }
+    }
+
+    // before the daemon reports, so failures reach mount(8)
+    if args.prescan {
+        let sources = std::iter::once(source)
+            .chain(options.mirrors.iter().map(PathBuf::as_path));
+        for source in sources {
+            match prescan::prescan(source, args.prescan_sizes) {
+                Ok(summary) => {
+                    println!("Prescanned {}: {}", source.display(), summary);
+                    prescan::report(source, &summary);
+                }
+                Err(e) => {
+                    println!("Can't prescan {}: {}", source.display(), e);
+                    if let Some(daemon) = daemon.as_mut() {
+                        daemon.report(1);
+                    }
+                    std::process::exit(1);
+                }
+            }
+        }
+    }
+
+    // declared before the unmount guard, so xochitl is started after it
+    let mut _restart = None;
+    if xochitl::is_running() {
+        if args.stop_xochitl {
+            println!("Stopping xochitl for the duration of the mount");
+            if xochitl::stop() {
+                _restart = Some(xochitl::RestartGuard);
+            }
+        } else {
+            match options.xochitl_guard {
+                options::XochitlGuard::Off => (),
+                options::XochitlGuard::Warn => println!(
+                    "WARNING: xochitl is running, changing its library \
+                     through the mount may corrupt its state"
+                ),
+                options::XochitlGuard::ReadOnly => println!(
+                    "xochitl is running, changes will be refused with EROFS"
+                ),
+            }
+        }
+    }
 
-    let _sesh = fuser::spawn_mount(RMXFS::new(source_dir), target_dir, &[])?;
+    let mut mount_options = platform::mount_options(
+        &fs::canonicalize(source_dir)?.to_string_lossy(),
+    );
+    mount_options.extend(args.helper.mount);
+    if snapshot {
+        mount_options.push(fuser::MountOption::RO);
+    }
+    // declared before the session, so it is dropped after it
+    let _unmount = unmount::UnmountGuard::new(target);
+    let sesh = fuser::Session::new(
+        panicguard::PanicGuard::new(RMXFS::new(source_dir, options)),
+        target_dir.as_ref(),
+        &mount_options,
+    )?
+    .spawn()?;
+    if let Some(daemon) = daemon.as_mut() {
+        daemon.report(0);
+    }
+    let status = format!("Mounted {} at {}", source_dir, target_dir);
+    if let Err(e) = sdnotify::notify(&[("READY", "1"), ("STATUS", &status)]) {
+        error!("sd_notify failed: {}", e);
+    }
+    sdnotify::spawn_watchdog();
+    #[cfg(debug_assertions)]
+    if std::env::var(panicguard::INJECT_VAR).as_deref() == Ok("main") {
+        panic!("injected by {}", panicguard::INJECT_VAR);
+    }
     let pair = Arc::new((Mutex::new(false), Condvar::new()));
     let pair2 = Arc::clone(&pair);
     ctrlc::set_handler(move || {
@@ -103,11 +1191,138 @@ fn main() -> Result<(), ProgError> {
 
     // TODO: use auto-unmount option instead?
 
+    println!("Log level: {}", log_filter);
     println!("Waiting for Ctrl-C...");
     let (lock, cvar) = &*pair;
     let mut started = lock.lock().unwrap();
     while !*started {
-        started = cvar.wait(started).unwrap();
+        // unmounted from outside, e.g. by umount(8)
+        if sesh.guard.is_finished() {
+            println!("Unmounted, stopping");
+            break;
+        }
+        started = cvar
+            .wait_timeout(started, Duration::from_secs(1))
+            .unwrap()
+            .0;
+    }
+
+    sdnotify::notify(&[("STOPPING", "1")]).unwrap_or(false);
+
+    // Joining unmounts and lets the session run destroy(), but the unmount
+    // is refused while the mount is busy, so don't wait for it forever
+    let (tx, rx) = mpsc::channel();
+    std::thread::spawn(move || {
+        sesh.join();
+        tx.send(()).unwrap_or(());
+    });
+    if rx.recv_timeout(Duration::from_secs(5)).is_err() {
+        println!("Unmount timed out, is {} still in use?", target_dir);
+    }
+    // after destroy(), which finalizes the last uploads
+    if args.stats_on_exit {
+        let summary = stats::summary();
+        match args.log_file {
+            Some(_) => summary.lines().for_each(|line| info!("{}", line)),
+            None => print!("{}", summary),
+        }
     }
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use options::SnapshotMode;
+
+    fn parse(args: &[&str]) -> Args {
+        match Args::parse(args.iter().copied()) {
+            Ok(args) => args,
+            Err(e) => panic!("{:?}: {}", args, e),
+        }
+    }
+
+    #[test]
+    fn mount() {
+        let args = parse(&["src", "mnt"]);
+        assert_eq!(args.positional, Some(("src".into(), "mnt".into())));
+        assert_eq!(args.options.snapshot, SnapshotMode::Off);
+        assert!(!args.nonempty && !args.helper_mode);
+        let args = parse(&["--nonempty", "--recent", "5", "src", "mnt"]);
+        assert!(args.nonempty);
+        assert_eq!(args.options.recent, 5);
+    }
+
+    #[test]
+    fn snapshot() {
+        let args = parse(&["--snapshot", "src", "mnt"]);
+        assert_eq!(args.options.snapshot, SnapshotMode::Listing);
+        // a switch of its own, not --snapshot with a value
+        let args = parse(&["--snapshot=deep", "src", "mnt"]);
+        assert_eq!(args.options.snapshot, SnapshotMode::Deep);
+        let args = parse(&["src", "mnt", "--snapshot=deep"]);
+        assert_eq!(args.options.snapshot, SnapshotMode::Deep);
+        assert!(Args::parse(["--snapshot=shallow", "src", "mnt"]).is_err());
+    }
+
+    #[test]
+    fn helper() {
+        let args = parse(&["src", "mnt", "-o", "ro,uid=1000", "-t", "fuse"]);
+        assert!(args.helper_mode);
+        assert_eq!(args.helper.uid, Some(1000));
+        assert!(args.helper.mount.contains(&fuser::MountOption::RO));
+    }
+
+    #[test]
+    fn verbosity() {
+        assert_eq!(parse(&["-v", "-vv", "src", "mnt"]).verbosity, 3);
+    }
+
+    #[test]
+    fn bad_values() {
+        assert!(Args::parse(["--recent", "many", "src", "mnt"]).is_err());
+        assert!(Args::parse(["--sort", "size", "src", "mnt"]).is_err());
+        assert!(Args::parse(["--recent"]).is_err());
+    }
+
+    // the rest of the line goes to the parser of the subcommand
+    #[test]
+    fn subcommands() {
+        let args = parse(&["bench", "--docs", "10", "--flat"]);
+        let bench = args.bench.unwrap();
+        assert_eq!((bench.docs, bench.flat), (10, true));
+        assert!(args.positional.is_none());
+
+        let export = parse(&["export", "--raw", "src", "Notes", "out"]).export;
+        assert!(export.is_some_and(|export| export.raw));
+        let backup = parse(&["backup", "--incremental", "src", "out"]).backup;
+        assert!(backup.is_some_and(|backup| backup.incremental));
+        let restore = parse(&["restore", "--dry-run", "src", "out"]).restore;
+        assert!(restore.is_some_and(|restore| restore.dry_run));
+        let import = parse(&["import", "src", "a.pdf", "b.pdf"]).import;
+        assert_eq!(import.unwrap().files, ["a.pdf", "b.pdf"]);
+        let list = parse(&["list", "src", "Work"]).list.unwrap();
+        assert_eq!(list.folder.as_deref(), Some("Work"));
+        let doctor = parse(&["doctor", "src"]).doctor.unwrap();
+        assert_eq!(doctor.source.as_deref(), Some("src"));
+        let ctl = parse(&["ctl", "mnt", "flush-caches"]).ctl.unwrap();
+        assert_eq!(ctl.command.as_deref(), Some("flush-caches"));
+        let purge = parse(&["purge-trash", "--dry-run", "src"]).purge.unwrap();
+        assert!(purge.dry_run);
+
+        // errors of the subcommand are errors of the line
+        assert!(Args::parse(["bench", "--docs", "many"]).is_err());
+        assert!(
+            Args::parse(["purge-trash", "--older-than", "x", "src"]).is_err()
+        );
+    }
+
+    #[test]
+    fn subcommand_names_as_paths() {
+        // only the first argument can be a subcommand
+        assert!(Args::parse(["src", "list"]).is_ok_and(|args| {
+            args.list.is_none()
+                && args.positional == Some(("src".into(), "list".into()))
+        }));
+    }
+}
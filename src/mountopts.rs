@@ -0,0 +1,180 @@
+use fuser::MountOption;
+use std::num::ParseIntError;
+
+// mount(8) runs a helper as `mount.fuse-rm <source> <target> -o opt,...`
+// (an fstab entry of type fuse.fuse-rm goes through mount.fuse to the same
+// form). The options are those of mount and fuse, the ones that don't apply
+// here are skipped with a warning as mount helpers do.
+
+// Options for mount(8) itself or for other tools reading the fstab
+const QUIET: &[&str] = &[
+    "defaults", "auto", "noauto", "user", "users", "nouser", "owner", "group",
+    "_netdev", "nofail",
+];
+
+#[derive(Debug, Default)]
+pub struct HelperOptions {
+    pub mount: Vec<MountOption>,
+    pub uid: Option<u32>,
+    pub gid: Option<u32>,
+    pub nonempty: bool,
+}
+
+fn flag(opt: &str) -> Option<MountOption> {
+    Some(match opt {
+        "ro" => MountOption::RO,
+        "rw" => MountOption::RW,
+        "allow_other" => MountOption::AllowOther,
+        "allow_root" => MountOption::AllowRoot,
+        "auto_unmount" => MountOption::AutoUnmount,
+        "default_permissions" => MountOption::DefaultPermissions,
+        "dev" => MountOption::Dev,
+        "nodev" => MountOption::NoDev,
+        "suid" => MountOption::Suid,
+        "nosuid" => MountOption::NoSuid,
+        "exec" => MountOption::Exec,
+        "noexec" => MountOption::NoExec,
+        "atime" => MountOption::Atime,
+        "noatime" => MountOption::NoAtime,
+        "dirsync" => MountOption::DirSync,
+        "sync" => MountOption::Sync,
+        "async" => MountOption::Async,
+        _ => return None,
+    })
+}
+
+// Flags that undo one another, the last one given wins as with mount(8)
+const OPPOSITES: &[(&str, &str)] = &[
+    ("ro", "rw"),
+    ("dev", "nodev"),
+    ("suid", "nosuid"),
+    ("exec", "noexec"),
+    ("atime", "noatime"),
+    ("sync", "async"),
+];
+
+fn opposite(opt: &str) -> Option<&'static str> {
+    OPPOSITES.iter().find_map(|&(a, b)| {
+        if opt == a {
+            Some(b)
+        } else if opt == b {
+            Some(a)
+        } else {
+            None
+        }
+    })
+}
+
+impl HelperOptions {
+    fn set(&mut self, key: &str) {
+        let undone = [flag(key), opposite(key).and_then(flag)];
+        self.mount.retain(|o| !undone.contains(&Some(o.clone())));
+        self.mount.extend(flag(key));
+    }
+
+    /// Adds the options of one `-o`, a bad uid or gid is an error
+    pub fn add(&mut self, opts: &str) -> Result<(), ParseIntError> {
+        for opt in opts.split(',').filter(|o| !o.is_empty()) {
+            let (key, value) = match opt.split_once('=') {
+                Some((key, value)) => (key, Some(value)),
+                None => (opt, None),
+            };
+            match (key, value) {
+                ("uid", Some(uid)) => self.uid = Some(uid.parse()?),
+                ("gid", Some(gid)) => self.gid = Some(gid.parse()?),
+                ("nonempty", None) => self.nonempty = true,
+                (key, None) if flag(key).is_some() => self.set(key),
+                (key, _) if QUIET.contains(&key) || key.starts_with("x-") => {}
+                // comment= is where systemd and others keep their options
+                ("comment", Some(_)) => {}
+                _ => println!("Ignoring unknown mount option: {}", opt),
+            }
+        }
+        Ok(())
+    }
+}
+
+/// True when run through a mount.fuse-rm link, as mount(8) does
+pub fn invoked_as_helper() -> bool {
+    std::env::args_os()
+        .next()
+        .and_then(|arg0| {
+            std::path::Path::new(&arg0)
+                .file_name()
+                .map(|name| name.to_string_lossy().starts_with("mount."))
+        })
+        .unwrap_or(false)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(opts: &[&str]) -> HelperOptions {
+        let mut helper = HelperOptions::default();
+        for o in opts {
+            helper.add(o).unwrap();
+        }
+        helper
+    }
+
+    #[test]
+    fn fstab_options() {
+        let helper = parse(&["ro,allow_other"]);
+        assert_eq!(helper.mount, [MountOption::RO, MountOption::AllowOther]);
+        assert_eq!(
+            (helper.uid, helper.gid, helper.nonempty),
+            (None, None, false)
+        );
+
+        let helper = parse(&["rw,nosuid,nodev,uid=1000,gid=100,nonempty"]);
+        assert_eq!(
+            helper.mount,
+            [MountOption::RW, MountOption::NoSuid, MountOption::NoDev]
+        );
+        assert_eq!((helper.uid, helper.gid), (Some(1000), Some(100)));
+        assert!(helper.nonempty);
+    }
+
+    #[test]
+    fn mount_and_unknown_options_are_skipped() {
+        let helper = parse(&[
+            "defaults,noauto,user,_netdev,nofail,x-systemd.automount",
+            "comment=systemd.automount,lorem,ipsum=dolor,,",
+        ]);
+        assert!(helper.mount.is_empty());
+        assert_eq!(
+            (helper.uid, helper.gid, helper.nonempty),
+            (None, None, false)
+        );
+    }
+
+    #[test]
+    fn flags_without_their_value() {
+        // ro takes no value, nonempty neither
+        let helper = parse(&["ro=1,nonempty=yes,uid=5"]);
+        assert!(helper.mount.is_empty());
+        assert!(!helper.nonempty);
+        assert_eq!(helper.uid, Some(5));
+    }
+
+    #[test]
+    fn bad_ids_are_errors() {
+        for opts in &["uid=root", "gid=-1", "uid=", "gid=99999999999"] {
+            assert!(HelperOptions::default().add(opts).is_err(), "{}", opts);
+        }
+    }
+
+    #[test]
+    fn last_one_wins() {
+        assert_eq!(parse(&["ro,rw"]).mount, [MountOption::RW]);
+        assert_eq!(parse(&["rw,ro,ro"]).mount, [MountOption::RO]);
+        assert_eq!(parse(&["ro", "rw", "ro"]).mount, [MountOption::RO]);
+        assert_eq!(
+            parse(&["noexec,allow_other,exec"]).mount,
+            [MountOption::AllowOther, MountOption::Exec]
+        );
+        let helper = parse(&["uid=1,gid=2,uid=3"]);
+        assert_eq!((helper.uid, helper.gid), (Some(3), Some(2)));
+    }
+}
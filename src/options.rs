@@ -0,0 +1,302 @@
+// Runtime options of the filesystem, set from the command line
+
+use std::ffi::OsString;
+use std::path::PathBuf;
+use std::time::Duration;
+
+use crate::dedup::Dedup;
+use crate::exclude::Glob;
+use crate::normalize::Normalization;
+use crate::syncguard;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum XochitlGuard {
+    Off,
+    // log a warning on the first modification while xochitl is running
+    Warn,
+    // refuse modifications with EROFS while xochitl is running
+    ReadOnly,
+}
+
+impl std::str::FromStr for XochitlGuard {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<XochitlGuard, String> {
+        match s {
+            "off" => Ok(XochitlGuard::Off),
+            "warn" => Ok(XochitlGuard::Warn),
+            "readonly" => Ok(XochitlGuard::ReadOnly),
+            _ => Err(format!("Unknown xochitl guard: {}", s)),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SyncGuard {
+    Off,
+    // hold changes back (up to sync_timeout) while a sync marker exists
+    Wait,
+    // refuse changes with EBUSY while a sync marker exists
+    Fail,
+}
+
+impl std::str::FromStr for SyncGuard {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<SyncGuard, String> {
+        match s {
+            "off" => Ok(SyncGuard::Off),
+            "wait" => Ok(SyncGuard::Wait),
+            "fail" => Ok(SyncGuard::Fail),
+            _ => Err(format!("Unknown sync guard: {}", s)),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SortOrder {
+    // by the bytes of the visible name, independent of the locale
+    Name,
+    // newest lastModified first
+    Mtime,
+    // as enumerated from the source dir
+    None,
+}
+
+impl std::str::FromStr for SortOrder {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<SortOrder, String> {
+        match s {
+            "name" => Ok(SortOrder::Name),
+            "mtime" => Ok(SortOrder::Mtime),
+            "none" => Ok(SortOrder::None),
+            _ => Err(format!("Unknown sort order: {}", s)),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Highlights {
+    Off,
+    // Book.pdf.highlights.json next to the document, the pages as an array
+    Merged,
+    // a hidden .Book.pdf.highlights dir with the page files as they are
+    Raw,
+}
+
+impl std::str::FromStr for Highlights {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Highlights, String> {
+        match s {
+            "off" => Ok(Highlights::Off),
+            "merged" => Ok(Highlights::Merged),
+            "raw" => Ok(Highlights::Raw),
+            _ => Err(format!("Unknown highlights mode: {}", s)),
+        }
+    }
+}
+
+// The device renders the epubs it opened to a pdf next to them, which has
+// the pages the annotations are on
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Rendition {
+    // the epub as it was added
+    Original,
+    // the pdf the device made of it
+    Pdf,
+    // both, the pdf read-only
+    Both,
+}
+
+impl std::str::FromStr for Rendition {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Rendition, String> {
+        match s {
+            "original" => Ok(Rendition::Original),
+            "pdf" => Ok(Rendition::Pdf),
+            "both" => Ok(Rendition::Both),
+            _ => Err(format!("Unknown rendition: {}", s)),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Layout {
+    // folders as on the device
+    Tree,
+    // every document outside the trash at the root, without folders
+    Flat,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum AttrChanges {
+    // chmod, chown and utimes succeed, nothing is stored
+    Ignore,
+    // as Ignore, but a chown to another owner than the one shown fails
+    // with EPERM
+    Check,
+    // all of them fail with EPERM
+    Refuse,
+}
+
+impl std::str::FromStr for AttrChanges {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<AttrChanges, String> {
+        match s {
+            "ignore" => Ok(AttrChanges::Ignore),
+            "check" => Ok(AttrChanges::Check),
+            "refuse" => Ok(AttrChanges::Refuse),
+            _ => Err(format!("Unknown attr changes mode: {}", s)),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SnapshotMode {
+    Off,
+    // the listing is the one scanned at mount, reads are of the data files
+    // as they are now
+    Listing,
+    // the data files are pinned too, reads of one that changed since fail
+    // with ESTALE
+    Deep,
+}
+
+#[derive(Debug, Clone)]
+pub struct Options {
+    pub xochitl_guard: XochitlGuard,
+    pub sync_guard: SyncGuard,
+    // file names (or '*' patterns) in the source marking a running sync
+    pub sync_markers: Vec<String>,
+    pub sync_timeout: Duration,
+    // refuse changes to the document open in xochitl, as told by busy_state
+    // or else the config of the tablet
+    pub busy_guard: bool,
+    pub busy_state: Option<PathBuf>,
+    pub sort: SortOrder,
+    pub folders_first: bool,
+    // size of the virtual Recent folder, 0 to leave it out
+    pub recent: usize,
+    // show data files without metadata in .orphans
+    pub expose_orphans: bool,
+    // show the entries marked deleted in .deleted instead of leaving them out
+    pub expose_deleted: bool,
+    // list the .fuse-rm control dir in the root
+    pub expose_control: bool,
+    // a new document renamed over one of the same type replaces its data
+    pub replace_preserves_uuid: bool,
+    // read-only companions of documents with smart highlights
+    pub highlights: Highlights,
+    pub layout: Layout,
+    // uuid of the folder shown as the root, None for the whole library
+    pub root: Option<OsString>,
+    // read-only sources shown along with the source, by priority
+    pub mirrors: Vec<PathBuf>,
+    // retries of transient source errors, the first after io_backoff
+    pub io_retries: u32,
+    pub io_backoff: Duration,
+    // listings, opens and reads of the source given up on after this long
+    pub io_timeout: Option<Duration>,
+    // store documents of types xochitl doesn't show, except the denied ones
+    pub allow_any_type: bool,
+    pub denied_types: Vec<String>,
+    // list templates and other types that aren't documents or folders
+    pub show_other_types: bool,
+    // the data file shown of an epub with a pdf rendition
+    pub rendition: Rendition,
+    // mtime of documents with pages that of the newest page if later
+    pub deep_mtime: bool,
+    // metadata on one line rather than indented as the device writes it
+    pub compact_metadata: bool,
+    // the Unicode form names are shown, matched and stored in
+    pub normalize: Normalization,
+    // names shown transliterated to ASCII
+    pub ascii_names: bool,
+    // " •" after the names listed of what isn't synced to the cloud
+    pub mark_unsynced: bool,
+    // from=to extensions shown for the supported types
+    pub extension_maps: Vec<String>,
+    // what closing a new document with the data of another one does
+    pub dedup: Dedup,
+    // threads parsing the metadata of a listing, None for one per core
+    pub scan_threads: Option<usize>,
+    // fsync what is written before replying, for sources on network mounts
+    pub sync_write: bool,
+    // threads for reads and hashing, 0 to do them in the session
+    pub io_threads: usize,
+    // open handles without I/O for this long are dropped
+    pub handle_timeout: Option<Duration>,
+    // new files being written at once, and the bytes they may hold together
+    pub max_pending: usize,
+    pub max_staging_bytes: Option<u64>,
+    // bytes of the documents created in this session and those being
+    // written, less those removed again
+    pub quota: Option<u64>,
+    // documents and folders hidden, and that nothing can be made as
+    pub excludes: Vec<Glob>,
+    // what mode, owner and time changes do, the metadata has no place for
+    // them
+    pub attr_changes: AttrChanges,
+    // a read-only view of the library as it was at mount
+    pub snapshot: SnapshotMode,
+    // owner of the root, the virtual dirs and new entries
+    pub uid: u32,
+    pub gid: u32,
+}
+
+impl Default for Options {
+    fn default() -> Options {
+        Options {
+            xochitl_guard: XochitlGuard::Warn,
+            sync_guard: SyncGuard::Off,
+            sync_markers: syncguard::DEFAULT_MARKERS
+                .iter()
+                .map(|m| m.to_string())
+                .collect(),
+            sync_timeout: Duration::from_secs(10),
+            busy_guard: true,
+            busy_state: None,
+            sort: SortOrder::Name,
+            folders_first: false,
+            recent: 0,
+            expose_orphans: false,
+            expose_deleted: false,
+            expose_control: false,
+            replace_preserves_uuid: false,
+            highlights: Highlights::Off,
+            layout: Layout::Tree,
+            root: None,
+            mirrors: Vec::new(),
+            io_retries: 3,
+            io_backoff: Duration::from_millis(10),
+            io_timeout: None,
+            allow_any_type: false,
+            denied_types: Vec::new(),
+            show_other_types: false,
+            rendition: Rendition::Original,
+            deep_mtime: false,
+            compact_metadata: false,
+            normalize: Normalization::Off,
+            ascii_names: false,
+            mark_unsynced: false,
+            extension_maps: Vec::new(),
+            dedup: Dedup::Off,
+            scan_threads: None,
+            sync_write: false,
+            io_threads: 4,
+            handle_timeout: None,
+            max_pending: 64,
+            max_staging_bytes: None,
+            quota: None,
+            excludes: Vec::new(),
+            attr_changes: AttrChanges::Ignore,
+            snapshot: SnapshotMode::Off,
+            uid: unsafe { libc::geteuid() },
+            gid: unsafe { libc::getegid() },
+        }
+    }
+}
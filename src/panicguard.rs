@@ -0,0 +1,473 @@
+use fuser::{
+    Filesystem, KernelConfig, ReplyAttr, ReplyCreate, ReplyData,
+    ReplyDirectory, ReplyEmpty, ReplyEntry, ReplyIoctl, ReplyOpen, ReplyStatfs,
+    ReplyWrite, ReplyXattr, Request, TimeOrNow,
+};
+use std::any::Any;
+use std::ffi::OsStr;
+use std::panic::{self, AssertUnwindSafe};
+use std::path::Path;
+use std::time::{Instant, SystemTime};
+
+use crate::logging::Span;
+use crate::stats;
+
+// A panic in an operation would end the session and leave a dead mount
+// point behind. The guard catches it, the reply dropped while unwinding
+// answers EIO (fuser sends that for any reply that wasn't sent).
+//
+// The filesystem isn't unwind safe, an operation may stop halfway through
+// updating its maps. At worst that leaves a stale refcount or a pending
+// file that is finalized at unmount, which beats losing the mount.
+
+// Debug builds panic in the operation named by this variable (or in main
+// after mounting), so the tests can check that the mount survives
+#[cfg(debug_assertions)]
+pub const INJECT_VAR: &str = "FUSE_RM_INJECT_PANIC";
+
+pub struct PanicGuard<FS> {
+    fs: FS,
+    // operations that panicked, reported at unmount
+    panics: u64,
+    #[cfg(debug_assertions)]
+    inject: Option<String>,
+}
+
+fn payload_str(payload: &(dyn Any + Send)) -> &str {
+    if let Some(s) = payload.downcast_ref::<&str>() {
+        s
+    } else if let Some(s) = payload.downcast_ref::<String>() {
+        s
+    } else {
+        "(no message)"
+    }
+}
+
+impl<FS: Filesystem> PanicGuard<FS> {
+    pub fn new(fs: FS) -> PanicGuard<FS> {
+        PanicGuard {
+            fs,
+            panics: 0,
+            #[cfg(debug_assertions)]
+            inject: std::env::var(INJECT_VAR).ok(),
+        }
+    }
+
+    fn guard(&mut self, span: Span, f: impl FnOnce(&mut FS)) {
+        let (op, started) = (span.op(), Instant::now());
+        let _entered = span.enter();
+        #[cfg(debug_assertions)]
+        let inject = self.inject.as_deref() == Some(op);
+        let fs = &mut self.fs;
+        let res = panic::catch_unwind(AssertUnwindSafe(|| {
+            #[cfg(debug_assertions)]
+            if inject {
+                panic!("injected by {}", INJECT_VAR);
+            }
+            f(fs)
+        }));
+        if let Err(payload) = res {
+            self.panics += 1;
+            error!("{}: panicked: {}", op, payload_str(&*payload));
+        }
+        stats::timed(op, started.elapsed());
+    }
+}
+
+impl<FS: Filesystem> Filesystem for PanicGuard<FS> {
+    fn init(
+        &mut self,
+        req: &Request<'_>,
+        config: &mut KernelConfig,
+    ) -> Result<(), libc::c_int> {
+        // one that panicked fails the mount
+        let mut res = Err(libc::EIO);
+        self.guard(Span::new("init", 0, 0), |fs| res = fs.init(req, config));
+        res
+    }
+
+    fn destroy(&mut self) {
+        self.guard(Span::new("destroy", 0, 0), |fs| fs.destroy());
+        if self.panics > 0 {
+            error!("destroy: {} operations panicked", self.panics);
+        }
+    }
+
+    fn lookup(
+        &mut self,
+        req: &Request,
+        parent: u64,
+        name: &OsStr,
+        reply: ReplyEntry,
+    ) {
+        self.guard(
+            Span::new("lookup", req.unique(), parent).name(name),
+            |fs| fs.lookup(req, parent, name, reply),
+        );
+    }
+
+    fn forget(&mut self, req: &Request<'_>, ino: u64, nlookup: u64) {
+        self.guard(Span::new("forget", req.unique(), ino), |fs| {
+            fs.forget(req, ino, nlookup)
+        });
+    }
+
+    fn getattr(&mut self, req: &Request, ino: u64, reply: ReplyAttr) {
+        self.guard(Span::new("getattr", req.unique(), ino), |fs| {
+            fs.getattr(req, ino, reply)
+        });
+    }
+
+    fn create(
+        &mut self,
+        req: &Request<'_>,
+        parent: u64,
+        name: &OsStr,
+        mode: u32,
+        umask: u32,
+        flags: i32,
+        reply: ReplyCreate,
+    ) {
+        self.guard(
+            Span::new("create", req.unique(), parent).name(name),
+            |fs| fs.create(req, parent, name, mode, umask, flags, reply),
+        );
+    }
+
+    fn mkdir(
+        &mut self,
+        req: &Request,
+        parent: u64,
+        name: &OsStr,
+        mode: u32,
+        umask: u32,
+        reply: ReplyEntry,
+    ) {
+        self.guard(Span::new("mkdir", req.unique(), parent).name(name), |fs| {
+            fs.mkdir(req, parent, name, mode, umask, reply)
+        });
+    }
+
+    fn rmdir(
+        &mut self,
+        req: &Request,
+        parent: u64,
+        name: &OsStr,
+        reply: ReplyEmpty,
+    ) {
+        self.guard(Span::new("rmdir", req.unique(), parent).name(name), |fs| {
+            fs.rmdir(req, parent, name, reply)
+        });
+    }
+
+    fn mknod(
+        &mut self,
+        req: &Request,
+        parent: u64,
+        name: &OsStr,
+        mode: u32,
+        umask: u32,
+        rdev: u32,
+        reply: ReplyEntry,
+    ) {
+        self.guard(Span::new("mknod", req.unique(), parent).name(name), |fs| {
+            fs.mknod(req, parent, name, mode, umask, rdev, reply)
+        });
+    }
+
+    fn symlink(
+        &mut self,
+        req: &Request,
+        parent: u64,
+        name: &OsStr,
+        link: &Path,
+        reply: ReplyEntry,
+    ) {
+        self.guard(
+            Span::new("symlink", req.unique(), parent).name(name),
+            |fs| fs.symlink(req, parent, name, link, reply),
+        );
+    }
+
+    fn link(
+        &mut self,
+        req: &Request,
+        ino: u64,
+        newparent: u64,
+        newname: &OsStr,
+        reply: ReplyEntry,
+    ) {
+        self.guard(Span::new("link", req.unique(), ino).name(newname), |fs| {
+            fs.link(req, ino, newparent, newname, reply)
+        });
+    }
+
+    fn unlink(
+        &mut self,
+        req: &Request,
+        parent: u64,
+        name: &OsStr,
+        reply: ReplyEmpty,
+    ) {
+        self.guard(
+            Span::new("unlink", req.unique(), parent).name(name),
+            |fs| fs.unlink(req, parent, name, reply),
+        );
+    }
+
+    fn rename(
+        &mut self,
+        req: &Request<'_>,
+        parent: u64,
+        name: &OsStr,
+        newparent: u64,
+        newname: &OsStr,
+        flags: u32,
+        reply: ReplyEmpty,
+    ) {
+        self.guard(
+            Span::new("rename", req.unique(), parent).name(name),
+            |fs| fs.rename(req, parent, name, newparent, newname, flags, reply),
+        );
+    }
+
+    fn open(&mut self, req: &Request, ino: u64, flags: i32, reply: ReplyOpen) {
+        self.guard(Span::new("open", req.unique(), ino), |fs| {
+            fs.open(req, ino, flags, reply)
+        });
+    }
+
+    fn release(
+        &mut self,
+        req: &Request<'_>,
+        ino: u64,
+        fh: u64,
+        flags: i32,
+        lock_owner: Option<u64>,
+        flush: bool,
+        reply: ReplyEmpty,
+    ) {
+        self.guard(Span::new("release", req.unique(), ino), |fs| {
+            fs.release(req, ino, fh, flags, lock_owner, flush, reply)
+        });
+    }
+
+    fn read(
+        &mut self,
+        req: &Request,
+        ino: u64,
+        fh: u64,
+        offset: i64,
+        size: u32,
+        flags: i32,
+        lock: Option<u64>,
+        reply: ReplyData,
+    ) {
+        self.guard(Span::new("read", req.unique(), ino), |fs| {
+            fs.read(req, ino, fh, offset, size, flags, lock, reply)
+        });
+    }
+
+    fn write(
+        &mut self,
+        req: &Request<'_>,
+        ino: u64,
+        fh: u64,
+        offset: i64,
+        data: &[u8],
+        write_flags: u32,
+        flags: i32,
+        lock_owner: Option<u64>,
+        reply: ReplyWrite,
+    ) {
+        self.guard(Span::new("write", req.unique(), ino), |fs| {
+            fs.write(
+                req,
+                ino,
+                fh,
+                offset,
+                data,
+                write_flags,
+                flags,
+                lock_owner,
+                reply,
+            )
+        });
+    }
+
+    fn flush(
+        &mut self,
+        req: &Request<'_>,
+        ino: u64,
+        fh: u64,
+        lock_owner: u64,
+        reply: ReplyEmpty,
+    ) {
+        self.guard(Span::new("flush", req.unique(), ino), |fs| {
+            fs.flush(req, ino, fh, lock_owner, reply)
+        });
+    }
+
+    fn fsync(
+        &mut self,
+        req: &Request<'_>,
+        ino: u64,
+        fh: u64,
+        datasync: bool,
+        reply: ReplyEmpty,
+    ) {
+        self.guard(Span::new("fsync", req.unique(), ino), |fs| {
+            fs.fsync(req, ino, fh, datasync, reply)
+        });
+    }
+
+    fn ioctl(
+        &mut self,
+        req: &Request<'_>,
+        ino: u64,
+        fh: u64,
+        flags: u32,
+        cmd: u32,
+        in_data: &[u8],
+        out_size: u32,
+        reply: ReplyIoctl,
+    ) {
+        self.guard(Span::new("ioctl", req.unique(), ino), |fs| {
+            fs.ioctl(req, ino, fh, flags, cmd, in_data, out_size, reply)
+        });
+    }
+
+    fn fsyncdir(
+        &mut self,
+        req: &Request<'_>,
+        ino: u64,
+        fh: u64,
+        datasync: bool,
+        reply: ReplyEmpty,
+    ) {
+        self.guard(Span::new("fsyncdir", req.unique(), ino), |fs| {
+            fs.fsyncdir(req, ino, fh, datasync, reply)
+        });
+    }
+
+    fn setattr(
+        &mut self,
+        req: &Request<'_>,
+        ino: u64,
+        mode: Option<u32>,
+        uid: Option<u32>,
+        gid: Option<u32>,
+        size: Option<u64>,
+        atime: Option<TimeOrNow>,
+        mtime: Option<TimeOrNow>,
+        ctime: Option<SystemTime>,
+        fh: Option<u64>,
+        crtime: Option<SystemTime>,
+        chgtime: Option<SystemTime>,
+        bkuptime: Option<SystemTime>,
+        flags: Option<u32>,
+        reply: ReplyAttr,
+    ) {
+        self.guard(Span::new("setattr", req.unique(), ino), |fs| {
+            fs.setattr(
+                req, ino, mode, uid, gid, size, atime, mtime, ctime, fh,
+                crtime, chgtime, bkuptime, flags, reply,
+            )
+        });
+    }
+
+    fn opendir(
+        &mut self,
+        req: &Request<'_>,
+        ino: u64,
+        flags: i32,
+        reply: ReplyOpen,
+    ) {
+        self.guard(Span::new("opendir", req.unique(), ino), |fs| {
+            fs.opendir(req, ino, flags, reply)
+        });
+    }
+
+    fn releasedir(
+        &mut self,
+        req: &Request<'_>,
+        ino: u64,
+        fh: u64,
+        flags: i32,
+        reply: ReplyEmpty,
+    ) {
+        self.guard(Span::new("releasedir", req.unique(), ino), |fs| {
+            fs.releasedir(req, ino, fh, flags, reply)
+        });
+    }
+
+    fn readdir(
+        &mut self,
+        req: &Request,
+        ino: u64,
+        fh: u64,
+        offset: i64,
+        reply: ReplyDirectory,
+    ) {
+        self.guard(Span::new("readdir", req.unique(), ino), |fs| {
+            fs.readdir(req, ino, fh, offset, reply)
+        });
+    }
+
+    fn statfs(&mut self, req: &Request, ino: u64, reply: ReplyStatfs) {
+        self.guard(Span::new("statfs", req.unique(), ino), |fs| {
+            fs.statfs(req, ino, reply)
+        });
+    }
+
+    fn getxattr(
+        &mut self,
+        req: &Request<'_>,
+        ino: u64,
+        name: &OsStr,
+        size: u32,
+        reply: ReplyXattr,
+    ) {
+        self.guard(Span::new("getxattr", req.unique(), ino).name(name), |fs| {
+            fs.getxattr(req, ino, name, size, reply)
+        });
+    }
+
+    fn listxattr(
+        &mut self,
+        req: &Request<'_>,
+        ino: u64,
+        size: u32,
+        reply: ReplyXattr,
+    ) {
+        self.guard(Span::new("listxattr", req.unique(), ino), |fs| {
+            fs.listxattr(req, ino, size, reply)
+        });
+    }
+
+    fn setxattr(
+        &mut self,
+        req: &Request<'_>,
+        ino: u64,
+        name: &OsStr,
+        value: &[u8],
+        flags: i32,
+        position: u32,
+        reply: ReplyEmpty,
+    ) {
+        self.guard(Span::new("setxattr", req.unique(), ino).name(name), |fs| {
+            fs.setxattr(req, ino, name, value, flags, position, reply)
+        });
+    }
+
+    fn removexattr(
+        &mut self,
+        req: &Request<'_>,
+        ino: u64,
+        name: &OsStr,
+        reply: ReplyEmpty,
+    ) {
+        let span = Span::new("removexattr", req.unique(), ino).name(name);
+        self.guard(span, |fs| fs.removexattr(req, ino, name, reply));
+    }
+}
@@ -0,0 +1,435 @@
+use std::collections::{HashMap, HashSet};
+use std::ffi::{OsStr, OsString};
+use std::fmt;
+use std::io;
+use std::os::unix::ffi::{OsStrExt, OsStringExt};
+use std::path::{Component, Path, PathBuf};
+
+use crate::direntry::{DirEntry, EntryType, ORPHANS_INO};
+
+// The library only links entries to their folder by uuid, the mount shows
+// them under the names of those folders. This goes from a path as shown in
+// the mount ("Work/Papers/Draft.pdf") to the entry and back, for the
+// subcommands and --root. Names are those of the listing, with the conflict
+// suffixes, and the trash is the virtual "trash" folder.
+
+#[derive(Debug)]
+pub enum ResolveError {
+    NotFound(String),
+    // with the (uuid, name) of every match
+    Ambiguous(String, Vec<(OsString, OsString)>),
+    NotAFolder(String),
+}
+
+impl ResolveError {
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            ResolveError::NotFound(_) => 30,
+            ResolveError::Ambiguous(..) => 31,
+            ResolveError::NotAFolder(_) => 33,
+        }
+    }
+}
+
+impl fmt::Display for ResolveError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ResolveError::NotFound(what) => {
+                write!(f, "No document or folder {:?}", what)
+            }
+            ResolveError::Ambiguous(what, matches) => {
+                write!(f, "{:?} is ambiguous, pass one of the uuids:", what)?;
+                for (uuid, name) in matches {
+                    write!(
+                        f,
+                        "\n  {}  {}",
+                        uuid.to_string_lossy(),
+                        name.to_string_lossy()
+                    )?;
+                }
+                Ok(())
+            }
+            ResolveError::NotAFolder(what) => {
+                write!(f, "{:?} is not a folder", what)
+            }
+        }
+    }
+}
+
+/// `name` as the name of a file copied out of the library. A '/' in it is
+/// replaced by '\u{2215}' (division slash), a name no file can have ("",
+/// "." or "..") is an error.
+pub fn file_name(name: &OsStr) -> io::Result<OsString> {
+    let mut escaped = Vec::with_capacity(name.len());
+    for byte in name.as_bytes() {
+        match byte {
+            b'/' => escaped.extend_from_slice("\u{2215}".as_bytes()),
+            _ => escaped.push(*byte),
+        }
+    }
+    let escaped = OsString::from_vec(escaped);
+    let mut components = Path::new(&escaped).components();
+    match (components.next(), components.next()) {
+        (Some(Component::Normal(_)), None) => Ok(escaped),
+        _ => Err(io::Error::new(
+            io::ErrorKind::InvalidFilename,
+            format!("{:?} can't be a file name", name),
+        )),
+    }
+}
+
+pub struct PathResolver<'a> {
+    entries: &'a [DirEntry],
+    by_uuid: HashMap<&'a OsStr, &'a DirEntry>,
+}
+
+impl<'a> PathResolver<'a> {
+    pub fn new(entries: &'a [DirEntry]) -> PathResolver<'a> {
+        let by_uuid = entries
+            .iter()
+            .filter(|e| !e.rendition)
+            .map(|e| (e.prefix.as_os_str(), e));
+        PathResolver {
+            entries,
+            by_uuid: by_uuid.collect(),
+        }
+    }
+
+    /// A uuid, or a path of visible names as shown in the mount. Path
+    /// components may leave out the extension as long as that doesn't make
+    /// them ambiguous.
+    pub fn resolve(&self, what: &str) -> Result<&'a DirEntry, ResolveError> {
+        if let Some(entry) = self.by_uuid.get(OsStr::new(what)) {
+            return Ok(entry);
+        }
+
+        let mut found = None;
+        let mut parent = OsString::new();
+        for part in what.split('/').filter(|p| !p.is_empty()) {
+            let children: Vec<&DirEntry> =
+                self.entries.iter().filter(|e| e.parent == parent).collect();
+            let mut matches: Vec<&DirEntry> = children
+                .iter()
+                .copied()
+                .filter(|e| e.file_name() == part)
+                .collect();
+            if matches.is_empty() {
+                matches =
+                    children.into_iter().filter(|e| e.name == part).collect();
+            }
+            match matches.len() {
+                0 => return Err(ResolveError::NotFound(what.to_string())),
+                1 => (),
+                _ => {
+                    return Err(ResolveError::Ambiguous(
+                        what.to_string(),
+                        matches
+                            .iter()
+                            .map(|e| (e.prefix.clone(), e.file_name()))
+                            .collect(),
+                    ))
+                }
+            }
+            parent = matches[0].prefix.clone();
+            found = Some(matches[0]);
+        }
+        found.ok_or_else(|| ResolveError::NotFound(what.to_string()))
+    }
+
+    /// Like `resolve`, for a folder of the library (not the trash)
+    pub fn resolve_folder(
+        &self,
+        what: &str,
+    ) -> Result<&'a DirEntry, ResolveError> {
+        let dir = self.resolve(what)?;
+        if dir.entry_type != EntryType::NONE || dir.attr.ino <= ORPHANS_INO {
+            return Err(ResolveError::NotAFolder(what.to_string()));
+        }
+        Ok(dir)
+    }
+
+    // The folders from `entry` up, as far as they are listed. Broken
+    // metadata could make a folder its own ancestor.
+    fn ancestors(&self, entry: &'a DirEntry) -> Vec<&'a DirEntry> {
+        let mut res = vec![entry];
+        let mut seen = HashSet::from([entry.prefix.as_os_str()]);
+        let mut current = entry;
+        while let Some(parent) = self.by_uuid.get(current.parent.as_os_str()) {
+            if !seen.insert(parent.prefix.as_os_str()) {
+                break;
+            }
+            res.push(parent);
+            current = parent;
+        }
+        res
+    }
+
+    /// Where `entry` is shown from the root. None for one in a folder that
+    /// is missing (or in a loop of folders): like the mount, which lists it
+    /// in no dir.
+    pub fn visible_path(&self, entry: &'a DirEntry) -> Option<PathBuf> {
+        let ancestors = self.ancestors(entry);
+        if ancestors.last().is_some_and(|top| !top.parent.is_empty()) {
+            return None;
+        }
+        Some(ancestors.iter().rev().map(|e| e.file_name()).collect())
+    }
+
+    /// The visible path of `entry` made of file names (see `file_name`), to
+    /// copy it out under a dir
+    pub fn file_path(&self, entry: &'a DirEntry) -> io::Result<PathBuf> {
+        if self.visible_path(entry).is_none() {
+            let why = "its folder is missing";
+            return Err(io::Error::new(io::ErrorKind::NotFound, why));
+        }
+        self.ancestors(entry)
+            .iter()
+            .rev()
+            .map(|e| file_name(&e.file_name()))
+            .collect()
+    }
+
+    /// The visible path of `entry` for messages, the uuid if it has none
+    pub fn describe(&self, entry: &'a DirEntry) -> String {
+        match self.visible_path(entry) {
+            Some(path) => path.to_string_lossy().into_owned(),
+            None => entry.prefix.to_string_lossy().into_owned(),
+        }
+    }
+
+    /// True if `entry` is marked deleted, or in a folder that is
+    pub fn is_deleted(&self, entry: &'a DirEntry) -> bool {
+        self.ancestors(entry).iter().any(|e| e.deleted)
+    }
+
+    /// True if `entry` is somewhere in the folder of uuid `folder`
+    pub fn is_under(&self, entry: &'a DirEntry, folder: &OsStr) -> bool {
+        self.ancestors(entry).iter().any(|e| e.parent == folder)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rmxfs::list_dir_metadata;
+    use std::fs;
+
+    // (uuid, parent, visibleName, type, lastModified)
+    const LIBRARY: &[(&str, &str, &str, &str, &str)] = &[
+        ("work", "", "Work", "folder", "1707566400000"),
+        ("papers", "work", "Papers", "folder", "1707566400000"),
+        ("draft-pdf", "papers", "Draft", "pdf", "1707566400000"),
+        ("draft-epub", "papers", "Draft", "epub", "1707566400000"),
+        // the same name twice in a folder, and for a folder of the root
+        ("notes", "work", "Notes", "pdf", "1707566400000"),
+        ("notes-old", "work", "Notes", "pdf", "1704456000000"),
+        ("work-old", "", "Work", "folder", "1701388800000"),
+        ("notes-other", "work-old", "Notes", "pdf", "1701388800000"),
+        ("old", "trash", "Old", "pdf", "1701388800000"),
+        ("lost", "gone", "Lost", "pdf", "1701388800000"),
+        ("loop-a", "loop-b", "A", "folder", "1701388800000"),
+        ("loop-b", "loop-a", "B", "folder", "1701388800000"),
+        ("in-loop", "loop-a", "Inside", "pdf", "1701388800000"),
+    ];
+
+    fn fixture(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!(
+            "fuse-rm-paths-{}-{}",
+            std::process::id(),
+            name
+        ));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        for (uuid, parent, visible_name, tp, modified) in LIBRARY {
+            let doc_type = match *tp {
+                "folder" => "CollectionType",
+                ext => {
+                    fs::write(dir.join(format!("{}.{}", uuid, ext)), ext)
+                        .unwrap();
+                    "DocumentType"
+                }
+            };
+            let metadata = serde_json::json!({
+                "parent": parent,
+                "visibleName": visible_name,
+                "type": doc_type,
+                "lastModified": modified,
+                "deleted": false,
+            });
+            fs::write(
+                dir.join(format!("{}.metadata", uuid)),
+                metadata.to_string(),
+            )
+            .unwrap();
+        }
+        dir
+    }
+
+    fn with_library(name: &str, test: impl FnOnce(&PathResolver)) {
+        let dir = fixture(name);
+        let entries = list_dir_metadata(&dir).unwrap();
+        test(&PathResolver::new(&entries));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    fn uuid_of(resolver: &PathResolver, what: &str) -> String {
+        match resolver.resolve(what) {
+            Ok(entry) => entry.prefix.to_string_lossy().into_owned(),
+            Err(e) => panic!("{}: {}", what, e),
+        }
+    }
+
+    fn path_of(resolver: &PathResolver, uuid: &str) -> Option<PathBuf> {
+        resolver.visible_path(resolver.resolve(uuid).unwrap())
+    }
+
+    #[test]
+    fn resolve_nested() {
+        with_library("nested", |resolver| {
+            assert_eq!(uuid_of(resolver, "Work"), "work");
+            assert_eq!(uuid_of(resolver, "/Work/Papers/"), "papers");
+            assert_eq!(uuid_of(resolver, "Work/Papers/Draft.pdf"), "draft-pdf");
+            assert_eq!(
+                uuid_of(resolver, "Work/Papers/Draft.epub"),
+                "draft-epub"
+            );
+            assert_eq!(uuid_of(resolver, "Work/Notes"), "notes");
+            assert_eq!(uuid_of(resolver, "trash/Old.pdf"), "old");
+            // a uuid is taken as it is
+            assert_eq!(uuid_of(resolver, "notes-old"), "notes-old");
+        });
+    }
+
+    #[test]
+    fn resolve_duplicates() {
+        with_library("duplicates", |resolver| {
+            assert_eq!(
+                uuid_of(resolver, "Work/Notes (conflict 2024-01-05).pdf"),
+                "notes-old"
+            );
+            assert_eq!(
+                uuid_of(resolver, "Work (conflict 2023-12-01)/Notes.pdf"),
+                "notes-other"
+            );
+            match resolver.resolve("Work/Papers/Draft") {
+                Err(ResolveError::Ambiguous(_, mut matches)) => {
+                    matches.sort();
+                    assert_eq!(
+                        matches,
+                        [
+                            ("draft-epub".into(), "Draft.epub".into()),
+                            ("draft-pdf".into(), "Draft.pdf".into()),
+                        ]
+                    );
+                }
+                other => panic!("not ambiguous: {:?}", other.map(|_| ())),
+            }
+        });
+    }
+
+    #[test]
+    fn resolve_errors() {
+        with_library("errors", |resolver| {
+            for what in &["", "Sit", "Work/Sit", "Work/Notes.pdf/Sit", "Lost"] {
+                assert_eq!(
+                    resolver.resolve(what).map(|_| ()).unwrap_err().exit_code(),
+                    30,
+                    "{:?}",
+                    what
+                );
+            }
+            let notes = resolver.resolve_folder("Work/Notes.pdf");
+            assert_eq!(notes.unwrap_err().exit_code(), 33);
+            let trash = resolver.resolve_folder("trash");
+            assert_eq!(trash.unwrap_err().exit_code(), 33);
+        });
+    }
+
+    #[test]
+    fn visible_paths() {
+        with_library("visible", |resolver| {
+            let path = |uuid| path_of(resolver, uuid);
+            assert_eq!(path("work"), Some(PathBuf::from("Work")));
+            assert_eq!(
+                path("draft-epub"),
+                Some(PathBuf::from("Work/Papers/Draft.epub"))
+            );
+            assert_eq!(
+                path("notes-other"),
+                Some(PathBuf::from("Work (conflict 2023-12-01)/Notes.pdf"))
+            );
+            assert_eq!(path("old"), Some(PathBuf::from("trash/Old.pdf")));
+        });
+    }
+
+    #[test]
+    fn shown_nowhere() {
+        with_library("nowhere", |resolver| {
+            for uuid in &["lost", "loop-a", "loop-b", "in-loop"] {
+                assert_eq!(path_of(resolver, uuid), None, "{}", uuid);
+            }
+            let lost = resolver.resolve("lost").unwrap();
+            assert_eq!(resolver.describe(lost), "lost");
+        });
+    }
+
+    #[test]
+    fn paths_resolve_back() {
+        with_library("round-trip", |resolver| {
+            for entry in resolver.entries {
+                if let Some(path) = resolver.visible_path(entry) {
+                    let what = path.to_string_lossy();
+                    let found = resolver.resolve(&what).unwrap();
+                    assert_eq!(found.prefix, entry.prefix, "{}", what);
+                }
+            }
+        });
+    }
+
+    #[test]
+    fn file_names() {
+        let name = |n: &str| file_name(OsStr::new(n)).map_err(|e| e.kind());
+        assert_eq!(name("Draft.pdf"), Ok("Draft.pdf".into()));
+        assert_eq!(name("..pdf"), Ok("..pdf".into()));
+        assert_eq!(name(".hidden"), Ok(".hidden".into()));
+        assert_eq!(name("a/b"), Ok("a\u{2215}b".into()));
+        assert_eq!(name("/etc"), Ok("\u{2215}etc".into()));
+        assert_eq!(name("../.."), Ok("..\u{2215}..".into()));
+        for bad in &["", ".", ".."] {
+            assert_eq!(
+                name(bad),
+                Err(io::ErrorKind::InvalidFilename),
+                "{}",
+                bad
+            );
+        }
+    }
+
+    #[test]
+    fn file_paths() {
+        with_library("file-paths", |resolver| {
+            let path = |uuid| {
+                let entry = resolver.resolve(uuid).unwrap();
+                resolver.file_path(entry).map_err(|e| e.kind())
+            };
+            assert_eq!(
+                path("draft-pdf"),
+                Ok(PathBuf::from("Work/Papers/Draft.pdf"))
+            );
+            assert_eq!(path("lost"), Err(io::ErrorKind::NotFound));
+            assert_eq!(path("in-loop"), Err(io::ErrorKind::NotFound));
+        });
+    }
+
+    #[test]
+    fn under() {
+        with_library("under", |resolver| {
+            let entry = resolver.resolve("draft-pdf").unwrap();
+            assert!(resolver.is_under(entry, OsStr::new("work")));
+            assert!(resolver.is_under(entry, OsStr::new("papers")));
+            assert!(!resolver.is_under(entry, OsStr::new("work-old")));
+            let old = resolver.resolve("old").unwrap();
+            assert!(resolver.is_under(old, OsStr::new("trash")));
+        });
+    }
+}
@@ -0,0 +1,177 @@
+use fuser::MountOption;
+
+// Where Linux and macOS (macFUSE) differ. The rest goes through the unix
+// parts of std and libc, which both have.
+
+/// What getxattr fails with for an attribute that isn't there
+#[cfg(target_os = "macos")]
+pub const ENOATTR: libc::c_int = libc::ENOATTR;
+#[cfg(not(target_os = "macos"))]
+pub const ENOATTR: libc::c_int = libc::ENODATA;
+
+// The flags of renameat2(2), renamex_np(2) on macOS
+#[cfg(target_os = "macos")]
+pub const RENAME_NOREPLACE: u32 = libc::RENAME_EXCL;
+#[cfg(target_os = "macos")]
+pub const RENAME_EXCHANGE: u32 = libc::RENAME_SWAP;
+#[cfg(not(target_os = "macos"))]
+pub const RENAME_NOREPLACE: u32 = libc::RENAME_NOREPLACE;
+#[cfg(not(target_os = "macos"))]
+pub const RENAME_EXCHANGE: u32 = libc::RENAME_EXCHANGE;
+
+/// Options for mounting `source` besides those given on the command line.
+/// macFUSE names the volume in the Finder and doesn't write AppleDouble
+/// `._` files, which would be refused as documents of no supported type.
+#[cfg(target_os = "macos")]
+pub fn mount_options(source: &str) -> Vec<MountOption> {
+    let name = std::path::Path::new(source)
+        .file_name()
+        .map_or("xochitl".into(), |name| name.to_string_lossy());
+    vec![
+        MountOption::FSName(source.to_string()),
+        MountOption::CUSTOM(format!("volname={}", name)),
+        MountOption::CUSTOM("local".to_string()),
+        MountOption::CUSTOM("noappledouble".to_string()),
+    ]
+}
+
+#[cfg(not(target_os = "macos"))]
+pub fn mount_options(source: &str) -> Vec<MountOption> {
+    vec![
+        MountOption::FSName(source.to_string()),
+        MountOption::Subtype("fuse-rm".to_string()),
+    ]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::ffi::CString;
+    use std::fs;
+    use std::io;
+    use std::os::unix::ffi::OsStrExt;
+    use std::path::{Path, PathBuf};
+
+    fn scratch(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!(
+            "fuse-rm-platform-{}-{}",
+            std::process::id(),
+            name
+        ));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn c_path(path: &Path) -> CString {
+        CString::new(path.as_os_str().as_bytes()).unwrap()
+    }
+
+    #[cfg(not(target_os = "macos"))]
+    #[test]
+    fn linux_values() {
+        assert_eq!(ENOATTR, libc::ENODATA);
+        assert_eq!(RENAME_NOREPLACE, 1);
+        assert_eq!(RENAME_EXCHANGE, 2);
+    }
+
+    #[cfg(target_os = "macos")]
+    #[test]
+    fn macos_values() {
+        assert_eq!(ENOATTR, libc::ENOATTR);
+        assert_eq!(RENAME_NOREPLACE, libc::RENAME_EXCL);
+        assert_eq!(RENAME_EXCHANGE, libc::RENAME_SWAP);
+    }
+
+    #[test]
+    fn flags_apart() {
+        assert_ne!(RENAME_NOREPLACE, 0);
+        assert_ne!(RENAME_EXCHANGE, 0);
+        assert_eq!(RENAME_NOREPLACE & RENAME_EXCHANGE, 0);
+    }
+
+    // what the host itself fails with for a missing attribute
+    #[cfg(not(target_os = "macos"))]
+    #[test]
+    fn missing_xattr() {
+        let dir = scratch("xattr");
+        let path = c_path(&dir.join("file"));
+        fs::write(dir.join("file"), "").unwrap();
+        let name = CString::new("user.fuse-rm.missing").unwrap();
+        let res = unsafe {
+            libc::getxattr(
+                path.as_ptr(),
+                name.as_ptr(),
+                std::ptr::null_mut(),
+                0,
+            )
+        };
+        let err = io::Error::last_os_error().raw_os_error();
+        // not every filesystem of the temp dir has user xattrs
+        if err != Some(libc::ENOTSUP) {
+            assert_eq!(res, -1);
+            assert_eq!(err, Some(ENOATTR));
+        }
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    // the flags given to the host mean what rename takes them for
+    #[cfg(not(target_os = "macos"))]
+    #[test]
+    fn rename_flags() {
+        let dir = scratch("rename");
+        fs::write(dir.join("a"), "a").unwrap();
+        fs::write(dir.join("b"), "b").unwrap();
+        let (a, b) = (c_path(&dir.join("a")), c_path(&dir.join("b")));
+        let rename = |flags: u32| unsafe {
+            libc::renameat2(
+                libc::AT_FDCWD,
+                a.as_ptr(),
+                libc::AT_FDCWD,
+                b.as_ptr(),
+                flags,
+            )
+        };
+        let res = rename(RENAME_NOREPLACE);
+        let err = io::Error::last_os_error().raw_os_error();
+        if res == -1 && err == Some(libc::EINVAL) {
+            // neither flag is supported by the filesystem
+            fs::remove_dir_all(&dir).unwrap();
+            return;
+        }
+        assert_eq!((res, err), (-1, Some(libc::EEXIST)));
+        assert_eq!(rename(RENAME_EXCHANGE), 0);
+        assert_eq!(fs::read_to_string(dir.join("a")).unwrap(), "b");
+        assert_eq!(fs::read_to_string(dir.join("b")).unwrap(), "a");
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[cfg(not(target_os = "macos"))]
+    #[test]
+    fn linux_mount_options() {
+        assert_eq!(
+            mount_options("/home/root/.local/share/remarkable/xochitl"),
+            vec![
+                MountOption::FSName(
+                    "/home/root/.local/share/remarkable/xochitl".to_string()
+                ),
+                MountOption::Subtype("fuse-rm".to_string()),
+            ]
+        );
+    }
+
+    #[cfg(target_os = "macos")]
+    #[test]
+    fn macos_mount_options() {
+        let options = mount_options("/Volumes/backup/xochitl");
+        assert_eq!(
+            options[0],
+            MountOption::FSName("/Volumes/backup/xochitl".to_string())
+        );
+        assert!(options
+            .contains(&MountOption::CUSTOM("volname=xochitl".to_string())));
+        assert!(
+            options.contains(&MountOption::CUSTOM("noappledouble".to_string()))
+        );
+    }
+}
@@ -0,0 +1,159 @@
+use std::collections::HashSet;
+use std::ffi::OsStr;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+use crate::direntry::{DirEntry, EntryType, ExtensionMap};
+use crate::jsonmetadata::{DocType, JsonMetadata};
+use crate::retry::retry;
+use crate::rmxfs::conv_attr;
+use crate::stats;
+
+// The first listing of a big library reads every metadata file, and stats
+// every data file for its size, which takes seconds on a slow card or over
+// the network. --prescan does that once before the mount is reported ready,
+// so the files come from the page cache and the sizes of notebooks from
+// their cache when the first `ls` arrives.
+
+// a progress line every so many entries, at -v
+const PROGRESS_EVERY: usize = 500;
+
+#[derive(Debug, Default)]
+pub struct Summary {
+    pub documents: usize,
+    pub folders: usize,
+    pub trashed: usize,
+    // data files without metadata
+    pub orphans: usize,
+    // metadata files that can't be read or parsed
+    pub errors: usize,
+    // of the data files, when sizes were probed
+    pub bytes: u64,
+    pub took: Duration,
+}
+
+impl std::fmt::Display for Summary {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(
+            f,
+            "{} documents, {} folders, {} in the trash, {} orphans, {} \
+             unreadable",
+            self.documents,
+            self.folders,
+            self.trashed,
+            self.orphans,
+            self.errors
+        )?;
+        if self.bytes > 0 {
+            write!(f, ", {} bytes", self.bytes)?;
+        }
+        write!(f, " ({} ms)", self.took.as_millis())
+    }
+}
+
+// 1342 as 1,342
+fn thousands(n: usize) -> String {
+    let digits = n.to_string();
+    let mut res = String::new();
+    for (i, digit) in digits.chars().enumerate() {
+        if i > 0 && (digits.len() - i).is_multiple_of(3) {
+            res.push(',');
+        }
+        res.push(digit);
+    }
+    res
+}
+
+impl Summary {
+    /// The line logged at mount, to tell at a glance whether the source is
+    /// the right library
+    pub fn mounted(&self, source: &Path) -> String {
+        format!(
+            "mounted {} documents in {} folders ({} in trash, {} orphans, {} \
+             metadata errors) from {}",
+            thousands(self.documents),
+            thousands(self.folders),
+            thousands(self.trashed),
+            thousands(self.orphans),
+            thousands(self.errors),
+            source.display()
+        )
+    }
+}
+
+/// Logs what `source` held at mount and keeps it for the stats
+pub fn report(source: &Path, summary: &Summary) {
+    let line = summary.mounted(source);
+    info!("{}", line);
+    stats::add_library(line);
+}
+
+/// Counts what the sources hold in a thread of its own, unless a prescan
+/// already did
+pub fn report_in_background(sources: Vec<PathBuf>) {
+    if stats::has_library() {
+        return;
+    }
+    std::thread::spawn(move || {
+        for source in sources.iter() {
+            match prescan(source, false) {
+                Ok(summary) => report(source, &summary),
+                Err(e) => warn!("Can't count {}: {}", source.display(), e),
+            }
+        }
+    });
+}
+
+/// Reads the metadata of every entry in `source`, and finds the data file
+/// of every document and its size with `sizes`
+pub fn prescan(source: &Path, sizes: bool) -> io::Result<Summary> {
+    let start = Instant::now();
+    let mut res = Summary::default();
+    let mut seen = 0;
+    let (mut uuids, mut data_files) = (HashSet::new(), Vec::new());
+    for e in retry(|| fs::read_dir(source))? {
+        let e = e?;
+        let path = e.path();
+        let ext = path.extension().and_then(OsStr::to_str).unwrap_or("");
+        if ext != "metadata" {
+            if ExtensionMap::device().entry_type(ext) != &EntryType::NONE {
+                data_files.push(path);
+            }
+            continue;
+        }
+        uuids.insert(path.file_stem().unwrap_or_default().to_os_string());
+        seen += 1;
+        if seen % PROGRESS_EVERY == 0 {
+            info!("prescan: {} entries of {:?}", seen, source);
+        }
+        let json_data = match retry(|| JsonMetadata::from_file(&path)) {
+            Ok(json_data) => json_data,
+            Err(err) => {
+                warn!("prescan: {:?}: {}", path, err);
+                res.errors += 1;
+                continue;
+            }
+        };
+        match json_data.doc_type() {
+            DocType::CollectionType => res.folders += 1,
+            _ => res.documents += 1,
+        }
+        if json_data.parent == "trash" {
+            res.trashed += 1;
+        }
+        if sizes && json_data.doc_type() != &DocType::CollectionType {
+            let entry =
+                DirEntry::new(&path, &retry(|| conv_attr(&e))?, &json_data);
+            res.bytes += entry.attr.size;
+        }
+    }
+    res.orphans = data_files
+        .iter()
+        .filter(|path| !uuids.contains(path.file_stem().unwrap_or_default()))
+        .count();
+    res.took = start.elapsed();
+    info!("prescan: {} entries of {:?}", seen, source);
+    Ok(res)
+}
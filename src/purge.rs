@@ -0,0 +1,192 @@
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::time::{Duration, SystemTime};
+
+use crate::direntry::{DirEntry, ORPHANS_INO};
+use crate::paths::PathResolver;
+use crate::rmxfs::list_dir_metadata;
+
+// Deleting in xochitl only moves to the trash, or marks entries deleted
+// until the cloud has caught up. This removes them for good, with every
+// file of theirs, and a trashed folder with everything in it.
+
+argwerk::define! {
+    /// Permanently remove the documents in the trash.
+    #[usage = "fuse-rm purge-trash [--older-than AGE] [--dry-run] source"]
+    pub struct PurgeArgs {
+        pub help: bool,
+        pub source: Option<String>,
+        pub older_than: Option<Age>,
+        pub dry_run: bool,
+        pub force: bool,
+    }
+    /// Print this help.
+    ["-h" | "--help"] => {
+        println!("{}", HELP);
+        help = true;
+    }
+    /// Only what was last modified (moved to the trash) this long ago,
+    /// e.g. 30d, 12h or 90m.
+    ["--older-than", age] => {
+        older_than = Some(str::parse(&age)?);
+    }
+    /// Print what would be removed without removing it.
+    ["--dry-run"] => {
+        dry_run = true;
+    }
+    /// Purge even while a mount holds the lock on source.
+    ["--force"] => {
+        force = true;
+    }
+    /// <source> dir
+    [dir] if source.is_none() => {
+        source = Some(dir);
+    }
+}
+
+/// A number of seconds, minutes, hours, days or weeks
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Age(pub Duration);
+
+impl std::str::FromStr for Age {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Age, String> {
+        let unit = match s.chars().last() {
+            Some('s') => 1,
+            Some('m') => 60,
+            Some('h') => 60 * 60,
+            Some('d') => 24 * 60 * 60,
+            Some('w') => 7 * 24 * 60 * 60,
+            _ => return Err(format!("Unknown age: {} (e.g. 30d)", s)),
+        };
+        match s[..s.len() - 1].parse::<u64>() {
+            Ok(n) => Ok(Age(Duration::from_secs(n.saturating_mul(unit)))),
+            Err(_) => Err(format!("Unknown age: {} (e.g. 30d)", s)),
+        }
+    }
+}
+
+pub struct Purged {
+    // as shown in the mount
+    pub path: String,
+    pub bytes: u64,
+    // of removing, Ok for a dry run
+    pub result: io::Result<()>,
+}
+
+// Bytes in `path` and below, nothing if it isn't there
+fn disk_usage(path: &Path) -> u64 {
+    match fs::symlink_metadata(path) {
+        Ok(meta) if meta.is_dir() => fs::read_dir(path)
+            .map(|entries| {
+                entries
+                    .filter_map(|e| e.ok())
+                    .map(|e| disk_usage(&e.path()))
+                    .sum()
+            })
+            .unwrap_or(0),
+        Ok(meta) => meta.len(),
+        Err(_) => 0,
+    }
+}
+
+/// The entries to purge of those in `entries`: the ones in the trash or
+/// marked deleted, last modified before `before` if given, and what is in
+/// the folders among them. Their contents come before folders.
+pub fn candidates(
+    entries: &[DirEntry],
+    before: Option<SystemTime>,
+) -> Vec<&DirEntry> {
+    let resolver = PathResolver::new(entries);
+    let real = || entries.iter().filter(|e| e.attr.ino > ORPHANS_INO);
+    let old_enough = |e: &DirEntry| {
+        before.is_none_or(|before| {
+            e.last_modified().unwrap_or(e.attr.mtime) < before
+        })
+    };
+    let trashed: Vec<&DirEntry> = real()
+        .filter(|e| e.parent == "trash" || e.deleted)
+        .filter(|e| old_enough(e))
+        .collect();
+    let mut res: Vec<&DirEntry> = real()
+        .filter(|e| !trashed.iter().any(|t| t.prefix == e.prefix))
+        .filter(|e| trashed.iter().any(|t| resolver.is_under(e, &t.prefix)))
+        .collect();
+    // deeper first, so that no folder goes before what is in it
+    res.sort_by_key(|e| std::cmp::Reverse(resolver.visible_path(e)));
+    res.extend(trashed);
+    res
+}
+
+/// Removes the files of every candidate in `source`, or only says which
+/// with `dry_run`
+pub fn purge(
+    source: &Path,
+    older_than: Option<Duration>,
+    dry_run: bool,
+) -> io::Result<Vec<Purged>> {
+    let entries = list_dir_metadata(&source.to_path_buf())?;
+    let resolver = PathResolver::new(&entries);
+    let before = older_than.and_then(|age| SystemTime::now().checked_sub(age));
+    Ok(candidates(&entries, before)
+        .into_iter()
+        .map(|entry| {
+            let bytes = entry
+                .associated_paths()
+                .iter()
+                .map(|p| disk_usage(&p.path))
+                .sum();
+            Purged {
+                path: resolver.describe(entry),
+                bytes,
+                result: if dry_run { Ok(()) } else { entry.remove() },
+            }
+        })
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse() {
+        let args = PurgeArgs::parse(["src"]).unwrap();
+        assert_eq!(args.source.as_deref(), Some("src"));
+        assert!(!args.dry_run && !args.force && args.older_than.is_none());
+        let args = PurgeArgs::parse([
+            "--older-than",
+            "30d",
+            "--dry-run",
+            "--force",
+            "src",
+        ])
+        .unwrap();
+        assert!(args.dry_run && args.force);
+        assert_eq!(
+            args.older_than,
+            Some(Age(Duration::from_secs(30 * 24 * 60 * 60)))
+        );
+    }
+
+    #[test]
+    fn ages() {
+        let secs = |age: &str| age.parse::<Age>().map(|age| age.0.as_secs());
+        assert_eq!(secs("90s"), Ok(90));
+        assert_eq!(secs("90m"), Ok(90 * 60));
+        assert_eq!(secs("12h"), Ok(12 * 60 * 60));
+        assert_eq!(secs("2w"), Ok(2 * 7 * 24 * 60 * 60));
+        assert!(secs("30").is_err());
+        assert!(secs("d").is_err());
+        assert!(secs("-1d").is_err());
+    }
+
+    #[test]
+    fn parse_errors() {
+        assert!(PurgeArgs::parse(["--older-than", "soon", "src"]).is_err());
+        assert!(PurgeArgs::parse(["--older-than"]).is_err());
+        assert!(PurgeArgs::parse(["src", "more"]).is_err());
+    }
+}
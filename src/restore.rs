@@ -0,0 +1,307 @@
+use std::collections::{HashMap, HashSet};
+use std::ffi::{OsStr, OsString};
+use std::io;
+use std::path::{Path, PathBuf};
+
+use crate::backup::{self, BackupError, Document};
+use crate::direntry::{DirEntry, EntryType};
+use crate::import::{self, ImportError};
+use crate::paths::{PathResolver, ResolveError};
+use crate::rmxfs::list_dir_metadata;
+use crate::sha256;
+
+// Puts the documents of a backup (see backup.rs) back into a library, with
+// the metadata made anew from the paths of the manifest. They get their
+// uuids back, and go into the folders of their path, those the library
+// lacks are made. What the library has already, by uuid or by content, is
+// skipped, so a restore that was interrupted goes on where it stopped when
+// run again. A document whose path is taken by one of other content is a
+// conflict, left alone unless --overwrite replaces the data of that one.
+
+argwerk::define! {
+    /// Put the documents of a backup back into a xochitl directory.
+    #[usage = "fuse-rm restore [--dry-run] [--overwrite] source backup"]
+    pub struct RestoreArgs {
+        pub help: bool,
+        pub dry_run: bool,
+        pub overwrite: bool,
+        pub positional: Option<(String, String)>,
+    }
+    /// Print what would be restored without changing source.
+    ["--dry-run"] => {
+        dry_run = true;
+    }
+    /// Replace the data of documents at the path of one in the backup with
+    /// other content.
+    ["--overwrite"] => {
+        overwrite = true;
+    }
+    /// Print this help.
+    ["-h" | "--help"] => {
+        println!("{}", HELP);
+        help = true;
+    }
+    /// <source> dir, <backup> dir with the manifest
+    [source, dir] if positional.is_none() => {
+        positional = Some((source, dir))
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub enum Action {
+    Restore,
+    // what of the library it is already: "its uuid" or the path of a copy
+    Skip(String),
+    // another document has its path
+    Conflict,
+    Overwrite,
+}
+
+pub struct Planned {
+    // as shown in the mount
+    pub path: String,
+    pub action: Action,
+    // of restoring, Ok for a dry run and what is skipped
+    pub result: Result<(), ImportError>,
+}
+
+// The folders documents are restored into, by visible path
+struct Folders<'a> {
+    resolver: &'a PathResolver<'a>,
+    root: DirEntry,
+    // made by this restore
+    made: HashMap<PathBuf, DirEntry>,
+}
+
+impl<'a> Folders<'a> {
+    // The folder at `path`, made if there is none
+    fn get(&mut self, path: &Path) -> Result<DirEntry, ImportError> {
+        if path.as_os_str().is_empty() {
+            return Ok(self.root.clone());
+        }
+        if let Some(dir) = self.made.get(path) {
+            return Ok(dir.clone());
+        }
+        let what = path.to_string_lossy();
+        match self.resolver.resolve(&what) {
+            Ok(dir) if dir.entry_type == EntryType::NONE => Ok(dir.clone()),
+            Ok(_) => Err(ImportError::NotAFolder(what.into_owned())),
+            Err(ResolveError::NotFound(_)) => {
+                let parent =
+                    self.get(path.parent().unwrap_or(Path::new("")))?;
+                let name = path.file_name().unwrap_or_default();
+                let dir = DirEntry::make_dir(&parent, name, 0o755, 0o022)?;
+                self.made.insert(path.to_path_buf(), dir.clone());
+                Ok(dir)
+            }
+            Err(e) => Err(e.into()),
+        }
+    }
+}
+
+// Whether the library has `entry` where a document of the trash, or of
+// the library with `in_trash` false, would be. What is deleted it doesn't.
+fn kept(resolver: &PathResolver, entry: &DirEntry, in_trash: bool) -> bool {
+    !resolver.is_deleted(entry)
+        && resolver.is_under(entry, OsStr::new("trash")) == in_trash
+}
+
+// The documents of the library by size, and their hashes once needed
+struct Contents<'a> {
+    resolver: &'a PathResolver<'a>,
+    by_size: HashMap<u64, Vec<&'a DirEntry>>,
+    hashes: HashMap<&'a OsStr, Option<String>>,
+}
+
+impl<'a> Contents<'a> {
+    fn new(
+        resolver: &'a PathResolver<'a>,
+        entries: &'a [DirEntry],
+    ) -> Contents<'a> {
+        let mut by_size: HashMap<u64, Vec<&DirEntry>> = HashMap::new();
+        let documents = entries.iter().filter(|e| {
+            matches!(
+                e.entry_type,
+                EntryType::PDF | EntryType::EPUB | EntryType::OTHER(_)
+            ) && !e.rendition
+                && !resolver.is_deleted(e)
+        });
+        for entry in documents {
+            by_size.entry(entry.attr.size).or_default().push(entry);
+        }
+        Contents {
+            resolver,
+            by_size,
+            hashes: HashMap::new(),
+        }
+    }
+
+    fn hash(&mut self, entry: &'a DirEntry) -> Option<&String> {
+        self.hashes
+            .entry(&entry.prefix)
+            .or_insert_with(|| {
+                sha256::hash_file(&entry.source_file_path()).ok()
+            })
+            .as_ref()
+    }
+
+    // A document of the library with the content of `doc`, in the trash
+    // or out of it as `doc` is
+    fn find(&mut self, doc: &Document, in_trash: bool) -> Option<&'a DirEntry> {
+        let resolver = self.resolver;
+        let candidates = self.by_size.get(&doc.size)?.clone();
+        candidates
+            .into_iter()
+            .filter(|e| kept(resolver, e, in_trash))
+            .find(|e| self.hash(e) == Some(&doc.sha256))
+    }
+}
+
+fn bad_entry(doc: &Document) -> ImportError {
+    let why = format!("{:?} of the manifest has no uuid or name", doc.path);
+    ImportError::Io(io::Error::other(why))
+}
+
+fn damaged(doc: &Document) -> ImportError {
+    let why = format!("the copy of {} in the backup is damaged", doc.path);
+    ImportError::Io(io::Error::other(why))
+}
+
+// The copy of `doc` in the backup, if it is what the manifest says
+fn checked_copy(dir: &Path, doc: &Document) -> Result<PathBuf, ImportError> {
+    let copy = backup::in_backup(dir, &doc.file)?;
+    match sha256::hash_file(&copy) {
+        Ok(hash) if hash == doc.sha256 => Ok(copy),
+        Ok(_) => Err(damaged(doc)),
+        Err(e) => Err(e.into()),
+    }
+}
+
+pub fn restore(
+    source: &Path,
+    dir: &Path,
+    dry_run: bool,
+    overwrite: bool,
+) -> Result<Vec<Planned>, BackupError> {
+    let manifest = match backup::read_manifest(dir) {
+        Err(BackupError::Io(e)) if e.kind() == io::ErrorKind::NotFound => {
+            let path = dir.join(backup::MANIFEST);
+            return Err(BackupError::BadManifest(path, "not found".into()));
+        }
+        manifest => manifest?,
+    };
+    let entries = list_dir_metadata(&source.to_path_buf())?;
+    let resolver = PathResolver::new(&entries);
+    let mut contents = Contents::new(&resolver, &entries);
+    let mut folders = Folders {
+        resolver: &resolver,
+        root: DirEntry::make_root(source),
+        made: HashMap::new(),
+    };
+
+    // what this restore put in the library, by path (with no entry in a
+    // dry run) and uuid: the manifest may have a path or a uuid twice, and
+    // the listing is of before
+    let mut restored: HashMap<&str, Option<DirEntry>> = HashMap::new();
+    let mut uuids = HashSet::new();
+    let mut res = Vec::new();
+    for doc in &manifest.documents {
+        let at = Path::new(&doc.path);
+        // the uuid names files of the source
+        if uuid::Uuid::parse_str(&doc.uuid).is_err() || at.file_name().is_none()
+        {
+            res.push(Planned {
+                path: doc.path.clone(),
+                action: Action::Restore,
+                result: Err(bad_entry(doc)),
+            });
+            continue;
+        }
+        let in_trash = at.starts_with("trash");
+        let taken = match restored.get(doc.path.as_str()) {
+            Some(entry) => Some(entry.clone()),
+            None => match resolver.resolve(&doc.path) {
+                Ok(entry)
+                    if entry.entry_type != EntryType::NONE
+                        && kept(&resolver, entry, in_trash) =>
+                {
+                    Some(Some(entry.clone()))
+                }
+                _ => None,
+            },
+        };
+        let has_uuid = entries
+            .iter()
+            .any(|e| e.prefix == *doc.uuid && kept(&resolver, e, in_trash));
+        let action = if has_uuid || uuids.contains(doc.uuid.as_str()) {
+            Action::Skip("its uuid".to_string())
+        } else if let Some(copy) = contents.find(doc, in_trash) {
+            Action::Skip(resolver.describe(copy))
+        } else if taken.is_some() && overwrite {
+            Action::Overwrite
+        } else if taken.is_some() {
+            Action::Conflict
+        } else {
+            Action::Restore
+        };
+        let result = match (&action, taken) {
+            _ if dry_run => Ok(None),
+            (Action::Restore, _) => checked_copy(dir, doc).and_then(|copy| {
+                let parent = at.parent().unwrap_or(Path::new(""));
+                let parent = folders.get(parent)?;
+                let name = at.file_name().unwrap_or_default();
+                let uuid = OsString::from(&doc.uuid);
+                Ok(Some(import::import_as(&parent, name, uuid, &copy)?))
+            }),
+            (Action::Overwrite, Some(Some(entry))) => checked_copy(dir, doc)
+                .and_then(|copy| {
+                    Ok(Some(import::replace_entry(entry, &copy)?))
+                }),
+            _ => Ok(None),
+        };
+        if let Ok(entry) = &result {
+            match action {
+                Action::Restore => {
+                    uuids.insert(doc.uuid.as_str());
+                    restored.insert(&doc.path, entry.clone());
+                }
+                Action::Overwrite => {
+                    restored.insert(&doc.path, entry.clone());
+                }
+                _ => (),
+            }
+        }
+        res.push(Planned {
+            path: doc.path.clone(),
+            action,
+            result: result.map(|_| ()),
+        });
+    }
+    Ok(res)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse() {
+        let args = RestoreArgs::parse(["src", "backup"]).unwrap();
+        assert!(!args.dry_run && !args.overwrite);
+        assert_eq!(
+            args.positional,
+            Some(("src".to_string(), "backup".to_string()))
+        );
+        let args =
+            RestoreArgs::parse(["--overwrite", "src", "backup", "--dry-run"])
+                .unwrap();
+        assert!(args.dry_run && args.overwrite);
+        assert!(args.positional.is_some());
+    }
+
+    #[test]
+    fn parse_errors() {
+        assert!(RestoreArgs::parse(["src"]).is_err());
+        assert!(RestoreArgs::parse(["--force", "src", "backup"]).is_err());
+    }
+}
@@ -0,0 +1,89 @@
+use std::io;
+use std::sync::atomic::{AtomicU32, AtomicU64, Ordering};
+use std::thread;
+use std::time::Duration;
+
+// Sources over sshfs or on a flaky SD card fail a read or stat now and then
+// and succeed right after. Reads, stats and listings of the source are
+// retried a few times with a doubling pause before the error is passed on.
+// Writes are not, a failed one may have partly happened.
+//
+// The policy is set once at startup and shared by the mount and the
+// subcommands, which reach the source through the same free functions.
+
+static ATTEMPTS: AtomicU32 = AtomicU32::new(3);
+static BACKOFF_MICROS: AtomicU64 = AtomicU64::new(10_000);
+// retries so far, for the summary at unmount
+static RETRIES: AtomicU64 = AtomicU64::new(0);
+
+// the pause doesn't grow beyond this
+const MAX_BACKOFF: Duration = Duration::from_secs(1);
+
+// Debug builds fail this many source operations with EIO before doing any,
+// so the tests can check the retries
+#[cfg(debug_assertions)]
+const INJECT_VAR: &str = "FUSE_RM_INJECT_IO_ERRORS";
+
+pub fn configure(attempts: u32, backoff: Duration) {
+    ATTEMPTS.store(attempts, Ordering::Relaxed);
+    BACKOFF_MICROS.store(backoff.as_micros() as u64, Ordering::Relaxed);
+}
+
+pub fn retries() -> u64 {
+    RETRIES.load(Ordering::Relaxed)
+}
+
+fn is_transient(e: &io::Error) -> bool {
+    matches!(
+        e.raw_os_error(),
+        Some(libc::EINTR) | Some(libc::EAGAIN) | Some(libc::EIO)
+    ) || matches!(
+        e.kind(),
+        io::ErrorKind::Interrupted | io::ErrorKind::WouldBlock
+    )
+}
+
+#[cfg(debug_assertions)]
+fn injected() -> Option<io::Error> {
+    use std::sync::OnceLock;
+    static LEFT: OnceLock<AtomicU64> = OnceLock::new();
+    let left = LEFT.get_or_init(|| {
+        let count = std::env::var(INJECT_VAR).ok();
+        AtomicU64::new(count.and_then(|c| c.parse().ok()).unwrap_or(0))
+    });
+    left.fetch_update(Ordering::Relaxed, Ordering::Relaxed, |n| {
+        n.checked_sub(1)
+    })
+    .ok()
+    .map(|_| io::Error::from_raw_os_error(libc::EIO))
+}
+
+#[cfg(not(debug_assertions))]
+fn injected() -> Option<io::Error> {
+    None
+}
+
+/// Runs `op` until it succeeds, fails for good or runs out of attempts. Only
+/// for operations that can be repeated without changing anything.
+pub fn retry<T>(mut op: impl FnMut() -> io::Result<T>) -> io::Result<T> {
+    let attempts = ATTEMPTS.load(Ordering::Relaxed);
+    let mut backoff =
+        Duration::from_micros(BACKOFF_MICROS.load(Ordering::Relaxed));
+    let mut retried = 0;
+    loop {
+        let res = match injected() {
+            Some(e) => Err(e),
+            None => op(),
+        };
+        match res {
+            Err(e) if retried < attempts && is_transient(&e) => {
+                debug!("retrying after: {}", e);
+                RETRIES.fetch_add(1, Ordering::Relaxed);
+                retried += 1;
+                thread::sleep(backoff);
+                backoff = (backoff * 2).min(MAX_BACKOFF);
+            }
+            res => return res,
+        }
+    }
+}
@@ -1,69 +1,204 @@
 use fuser::{
-    FileAttr, FileType, Filesystem, ReplyAttr, ReplyCreate, ReplyData,
-    ReplyDirectory, ReplyEmpty, ReplyEntry, ReplyOpen, ReplyWrite, Request,
+    consts, FileAttr, FileType, Filesystem, KernelConfig, ReplyAttr,
+    ReplyCreate, ReplyData, ReplyDirectory, ReplyEmpty, ReplyEntry, ReplyIoctl,
+    ReplyOpen, ReplyStatfs, ReplyWrite, ReplyXattr, Request, TimeOrNow,
 };
-use io::{Seek, Write};
 use libc::ENOENT;
-use std::collections::HashMap;
-use std::ffi::OsStr;
+use std::cell::Cell;
+use std::collections::{HashMap, HashSet};
+use std::ffi::{OsStr, OsString};
 use std::fs;
 use std::io;
-use std::iter::FromIterator;
+use std::ops::Deref;
 use std::os::unix::fs::MetadataExt;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
 use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
+use crate::ascii;
+use crate::bufpool::BufPool;
+use crate::busyguard::BusyGuard;
+use crate::control;
+use crate::ctl::{self, Command};
+use crate::deadline;
+use crate::dedup::{self, Dedup};
 use crate::direntry::{
-    entry_type_ext, DirEntry, EntryType, DEFAULT_TTL, ROOT_DIR_ATTR,
+    self, blocks, source_bits, DirEntry, EntryType, ExtensionMap, CONTROL_INO,
+    DEFAULT_TTL, DELETED_INO, ORPHANS_INO, RECENT_INO, REFRESH_INO,
+    SOURCE_SHIFT, TRASH_INO,
 };
+use crate::errno;
+use crate::exclude;
+use crate::handles::Handles;
+use crate::highlights;
 use crate::jsonmetadata::JsonMetadata;
+use crate::logging;
+use crate::lookups::Lookups;
+use crate::normalize;
+use crate::options::{
+    AttrChanges, Highlights, Layout, Options, Rendition, SnapshotMode,
+    SortOrder, SyncGuard, XochitlGuard,
+};
+use crate::paths::PathResolver;
+use crate::platform;
+use crate::prescan;
+use crate::retry::{self, retry};
+use crate::scanpool;
+use crate::sha256;
+use crate::space;
+use crate::staging;
+use crate::stats::{self, Fail};
+use crate::syncguard;
+use crate::tags;
+use crate::timeconv;
+use crate::workers::Workers;
+use crate::writebuf::BufferedFile;
+use crate::xochitl;
+
+const SHA256_XATTR: &str = "user.rm.sha256";
+// on the root, what the mount did so far
+const STATS_XATTR: &str = "user.rm.stats";
+// set on documents whose data file is missing
+const MISSING_DATA_XATTR: &str = "user.rm.missing_data";
+// a json array of the names, can be set
+const TAGS_XATTR: &str = "user.rm.tags";
+// "true" for entries synced to the cloud without local changes since, not
+// listed: every entry with metadata has it
+const SYNCED_XATTR: &str = "user.rm.synced";
+// what cp -p sets along with the mode, a change of the mode as well
+const ACL_XATTR: &str = "system.posix_acl_access";
+
+// Longest file name the kernel accepts in a dir entry
+const NAME_MAX: usize = 255;
+
+// sha256 of data files by path, valid while size and mtime match
+type HashCache = HashMap<PathBuf, (u64, SystemTime, String)>;
+
+// What a data file was when the snapshot was taken: inode, size and mtime
+type Pinned = (u64, u64, Option<SystemTime>);
+
+// The library as --snapshot scanned it at mount, listings and lookups are
+// served from it and never from the source. The tags and hashes are still
+// read from the files when asked for.
+struct Frozen {
+    library: Arc<[DirEntry]>,
+    // the library without what --exclude hides, as listings show it
+    shown: Arc<[DirEntry]>,
+    // what Recent is taken from
+    recent: Arc<[DirEntry]>,
+    // the data files by ino, with --snapshot=deep
+    data: HashMap<u64, Pinned>,
+}
+
+fn pinned(meta: &fs::Metadata) -> Pinned {
+    (meta.ino(), meta.len(), meta.modified().ok())
+}
 
+#[allow(clippy::upper_case_acronyms)]
 pub struct RMXFS {
     source_dir: PathBuf,
-    dir_map: HashMap<u64, (u32, Vec<DirEntry>)>, // refcounter because
-    file_map: HashMap<u64, (u32, fs::File)>,     // releases may be interleaved
-    // map for files being created
+    options: Options,
+    // the listings of the open dirs by handle, with the ino of the dir:
+    // every opendir lists again, so that it sees what was changed before it
+    // even while older handles keep their snapshot. Handles of the same dir
+    // share one where nothing changed in between.
+    dir_map: HashMap<u64, (u64, Snapshot)>,
+    next_dir_fh: u64,
+    // the metadata of the open folders by handle, a folder removed while
+    // open lists nothing more
+    dir_metadata: HashMap<u64, PathBuf>,
+    // refcounter because shared with the reads in the worker threads
+    file_map: HashMap<u64, (u32, Arc<fs::File>)>, // releases may be interleaved
+    // map for files being created, refcounted as they can be opened again
     // when closed, the must be moved from ".pending" to the root
-    pending_map: HashMap<u64, (DirEntry, fs::File)>,
+    pending_map: HashMap<u64, (u32, DirEntry, BufferedFile)>,
+    // documents created through this mount, for --replace-preserves-uuid
+    created: HashSet<OsString>,
+    // the sizes of those finalized, counted against --quota until removed,
+    // and the handles whose writes went over it, discarded when released
+    created_sizes: HashMap<OsString, u64>,
+    over_quota: HashSet<u64>,
+    // open merged highlights, refcounted like the files
+    companion_map: HashMap<u64, (u32, PathBuf)>,
+    // the references of the kernel to the entries
+    lookups: Lookups,
+    // when the open files were last used, to find leaked handles
+    handles: Handles,
+    xochitl_checked: bool,
+    // the library is counted for the log and the stats once it was listed
+    library_counted: bool,
+    // when the source was last listed, for the control file
+    last_scan: Cell<Option<SystemTime>>,
+    // the documents and folders of the last listing, for statfs
+    library_size: Cell<Option<u64>>,
+    hashes: Arc<Mutex<HashCache>>,
+    // the hashes of the documents for --dedup, and the new ones found to be
+    // copies, discarded when released
+    dedup: Option<dedup::Index>,
+    duplicates: HashSet<u64>,
+    // the document open in xochitl, None with --no-busy-guard
+    busy: Option<BusyGuard>,
+    workers: Workers,
+    // buffers for the reads, shared with the workers
+    buffers: Arc<BufPool>,
+    // with --snapshot, taken in init
+    frozen: Option<Frozen>,
 }
 
 impl RMXFS {
-    pub fn new(source: &str) -> RMXFS {
+    pub fn new(source: &str, options: Options) -> RMXFS {
+        stats::set(&stats::QUOTA, options.quota.unwrap_or(0));
         RMXFS {
             source_dir: PathBuf::from(source),
+            workers: Workers::new(options.io_threads),
+            dedup: (options.dedup != Dedup::Off)
+                .then(|| dedup::Index::new(Path::new(source))),
+            duplicates: HashSet::new(),
+            busy: options
+                .busy_guard
+                .then(|| BusyGuard::new(options.busy_state.as_deref())),
+            options,
             dir_map: HashMap::new(),
+            next_dir_fh: 1,
+            dir_metadata: HashMap::new(),
             file_map: HashMap::new(),
             pending_map: HashMap::new(),
+            created: HashSet::new(),
+            created_sizes: HashMap::new(),
+            over_quota: HashSet::new(),
+            companion_map: HashMap::new(),
+            lookups: Lookups::new(),
+            handles: Handles::new(),
+            xochitl_checked: false,
+            library_counted: false,
+            last_scan: Cell::new(None),
+            library_size: Cell::new(None),
+            hashes: Arc::new(Mutex::new(HashMap::new())),
+            buffers: Arc::new(BufPool::new()),
+            frozen: None,
         }
     }
 }
 
-fn secs_to_systime(secs: i64) -> SystemTime {
-    use std::convert::TryInto;
-    let dur = Duration::from_secs(secs.abs().try_into().unwrap());
-    if secs > 0 {
-        UNIX_EPOCH + dur
-    } else {
-        UNIX_EPOCH - dur
-    }
-}
-
-fn conv_attr(attr: &fs::DirEntry) -> io::Result<FileAttr> {
+pub fn conv_attr(attr: &fs::DirEntry) -> io::Result<FileAttr> {
     let meta = attr.metadata()?;
     Ok(FileAttr {
         ino: meta.ino(),
         size: meta.size(),
         blocks: meta.blocks(),
-        atime: secs_to_systime(meta.atime()),
-        mtime: secs_to_systime(meta.mtime()),
-        ctime: secs_to_systime(meta.ctime()),
-        crtime: meta.created()?,
+        atime: timeconv::from_secs(meta.atime()),
+        mtime: timeconv::from_secs(meta.mtime()),
+        ctime: timeconv::from_secs(meta.ctime()),
+        // UNIX_EPOCH where the filesystem doesn't keep birth times
+        crtime: meta.created().unwrap_or(UNIX_EPOCH),
         kind: if meta.is_dir() {
             FileType::Directory
         } else {
             FileType::RegularFile
         },
-        perm: meta.mode() as u16,
+        // without the type bits, which st_mode has on both platforms but
+        // fuser sets from `kind`
+        perm: (meta.mode() & 0o7777) as u16,
         nlink: 1,
         uid: meta.uid(),
         gid: meta.gid(),
@@ -73,46 +208,1574 @@ fn conv_attr(attr: &fs::DirEntry) -> io::Result<FileAttr> {
     })
 }
 
-fn list_dir_metadata(dir: &PathBuf) -> io::Result<Vec<DirEntry>> {
+// YYYY-MM-DD from a unix timestamp (H. Hinnant's civil_from_days)
+fn format_date(time: SystemTime) -> String {
+    let secs = timeconv::to_secs(time);
+    let z = secs.div_euclid(86400) + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z - era * 146097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+    format!("{:04}-{:02}-{:02}", year, month, day)
+}
+
+// Cloud sync conflicts leave several documents with the same visible name
+// in the same folder. The newest copy keeps its name, the others get a
+// "(conflict <date>)" suffix so they can be told apart (and removed).
+fn mark_conflicts(entries: &mut [DirEntry]) {
+    let mut groups: HashMap<(OsString, OsString), Vec<usize>> = HashMap::new();
+    for (i, e) in entries.iter().enumerate() {
+        // the virtual trash is not a real entry
+        if e.attr.ino == TRASH_INO {
+            continue;
+        }
+        groups
+            .entry((e.parent.clone(), e.file_name()))
+            .or_default()
+            .push(i);
+    }
+
+    for (_, mut group) in groups.into_iter().filter(|(_, g)| g.len() > 1) {
+        group.sort_by_key(|i| {
+            std::cmp::Reverse(entries[*i].last_modified().unwrap_or(UNIX_EPOCH))
+        });
+        let dates: Vec<String> = group
+            .iter()
+            .map(|i| {
+                format_date(entries[*i].last_modified().unwrap_or(UNIX_EPOCH))
+            })
+            .collect();
+        for (n, i) in group.iter().enumerate() {
+            let entry = &mut entries[*i];
+            if let Some(modtime) = entry.last_modified() {
+                entry.attr.mtime = modtime;
+            }
+            if n == 0 {
+                continue;
+            }
+            let mut suffix = format!(" (conflict {}", dates[n]);
+            if dates[1..].iter().filter(|d| **d == dates[n]).count() > 1 {
+                suffix.push(' ');
+                suffix.push_str(&uuid_fragment(entry));
+            }
+            suffix.push(')');
+            append_to_name(entry, &suffix);
+        }
+    }
+}
+
+// A folder the user called "trash" at the top level would be hidden behind
+// the virtual trash. It is shown as "trash (folder)", the virtual dirs keep
+// their names so that lookups of them always find the same entry.
+fn unshadow_virtual(entries: &mut [DirEntry]) {
+    let reserved: Vec<OsString> = entries
+        .iter()
+        .filter(|e| e.attr.ino <= ORPHANS_INO)
+        .map(|e| e.file_name())
+        .collect();
+    for entry in entries.iter_mut() {
+        if entry.attr.ino > ORPHANS_INO
+            && entry.parent.is_empty()
+            && reserved.contains(&entry.file_name())
+        {
+            let suffix = if entry.entry_type == EntryType::NONE {
+                " (folder)".to_string()
+            } else {
+                format!(" ({})", uuid_fragment(entry))
+            };
+            append_to_name(entry, &suffix);
+        }
+    }
+}
+
+// --ascii-names shows the names transliterated. Characters left out make a
+// name end with the start of the uuid, and so do names that only became
+// the same as another one in the folder, the one that was already ASCII
+// keeping its name.
+fn transliterate_names(entries: &mut [DirEntry]) {
+    let mut changed = vec![false; entries.len()];
+    for (entry, changed) in entries.iter_mut().zip(changed.iter_mut()) {
+        if entry.attr.ino <= ORPHANS_INO
+            || entry.name.to_str().is_some_and(str::is_ascii)
+        {
+            continue;
+        }
+        // the extension is put back by file_name()
+        let stem = match entry.entry_type {
+            EntryType::NONE => entry.name.clone(),
+            _ => Path::new(&entry.file_name())
+                .file_stem()
+                .unwrap_or_default()
+                .to_os_string(),
+        };
+        let (ascii, lossy) = ascii::transliterate(&stem.to_string_lossy());
+        let fragment = uuid_fragment(entry);
+        entry.name = OsString::from(match ascii.trim() {
+            _ if !lossy => ascii,
+            "" => fragment,
+            ascii => format!("{} ({})", ascii, fragment),
+        });
+        *changed = true;
+    }
+    let names: Vec<(OsString, OsString)> = entries
+        .iter()
+        .map(|e| (e.parent.clone(), e.file_name()))
+        .collect();
+    for (i, entry) in entries.iter_mut().enumerate() {
+        if changed[i] && names.iter().filter(|n| **n == names[i]).count() > 1 {
+            let suffix = format!(" ({})", uuid_fragment(entry));
+            append_to_name(entry, &suffix);
+        }
+    }
+}
+
+fn uuid_fragment(entry: &DirEntry) -> String {
+    let fragment = entry.prefix.to_string_lossy();
+    fragment[..8.min(fragment.len())].to_string()
+}
+
+// The suffix goes before the extension of documents
+fn append_to_name(entry: &mut DirEntry, suffix: &str) {
+    let base = if entry.entry_type == EntryType::NONE {
+        entry.name.clone()
+    } else {
+        Path::new(&entry.file_name())
+            .file_stem()
+            .unwrap_or(&entry.name)
+            .to_os_string()
+    };
+    entry.name = base;
+    entry.name.push(suffix);
+}
+
+// Visible names have no limit on the device, but the kernel takes at most
+// NAME_MAX bytes. Longer names are cut, keeping the extension and adding the
+// start of the uuid so that documents starting alike stay apart. Lookups
+// match the cut name, as that is what the listing has.
+fn fit_name_max(entry: &mut DirEntry) {
+    use std::os::unix::ffi::{OsStrExt, OsStringExt};
+    let name = entry.file_name();
+    let bytes = name.as_bytes();
+    if bytes.len() <= NAME_MAX {
+        return;
+    }
+    let ext_len = match entry.entry_type {
+        EntryType::NONE => 0,
+        _ => Path::new(&name).extension().map_or(0, |ext| ext.len() + 1),
+    };
+    let suffix = format!(" ({})", uuid_fragment(entry));
+    let mut cut = NAME_MAX.saturating_sub(suffix.len() + ext_len);
+    // not in the middle of an utf-8 sequence
+    while cut > 0 && bytes[cut] & 0xc0 == 0x80 {
+        cut -= 1;
+    }
+    let mut short = bytes[..cut].to_vec();
+    short.extend_from_slice(suffix.as_bytes());
+    short.extend_from_slice(&bytes[bytes.len() - ext_len..]);
+    // with the extension, file_name() leaves it as it is
+    entry.name = OsString::from_vec(short);
+}
+
+// The newest documents outside the trash, under their usual names unless
+// documents in different folders share one
+fn recent_entries<'a>(
+    entries: impl Iterator<Item = &'a DirEntry>,
+    count: usize,
+) -> Vec<DirEntry> {
+    let mut docs: Vec<&DirEntry> = entries
+        .filter(|e| {
+            e.entry_type != EntryType::NONE
+                && e.entry_type != EntryType::PENDING
+                && e.parent != "trash"
+                && !e.deleted
+        })
+        .collect();
+    docs.sort_by_key(|e| {
+        std::cmp::Reverse(e.last_modified().unwrap_or(e.attr.mtime))
+    });
+    docs.truncate(count);
+    let mut docs: Vec<DirEntry> = docs.into_iter().cloned().collect();
+
+    let names: Vec<OsString> = docs.iter().map(|e| e.file_name()).collect();
+    for (doc, name) in docs.iter_mut().zip(&names) {
+        if names.iter().filter(|n| *n == name).count() > 1 {
+            let suffix = format!(" ({})", uuid_fragment(doc));
+            append_to_name(doc, &suffix);
+        }
+        doc.parent = OsString::from("recent");
+    }
+    docs
+}
+
+// A folder in the trash takes its contents along, however deep. `folders`
+// maps the folders to their parents.
+fn in_trash<'a>(
+    folders: &'a HashMap<OsString, OsString>,
+    mut parent: &'a OsString,
+) -> bool {
+    for _ in 0..=folders.len() {
+        if parent == "trash" {
+            return true;
+        }
+        match folders.get(parent) {
+            Some(grandparent) => parent = grandparent,
+            None => return false,
+        }
+    }
+    // parents in a cycle, never reaching the trash
+    false
+}
+
+// Whether `ino` is the trash, or a folder in it, walking up its parents in
+// `entries`. What is there can be read, copied out, moved out or removed,
+// but nothing is made or renamed in it.
+fn in_trash_dir(entries: &[DirEntry], ino: u64) -> bool {
+    if ino == TRASH_INO {
+        return true;
+    }
+    if ino <= ORPHANS_INO {
+        return false;
+    }
+    let is_dir = |e: &&DirEntry| e.attr.kind == FileType::Directory;
+    let mut dir =
+        match entries.iter().filter(is_dir).find(|e| e.attr.ino == ino) {
+            Some(dir) => dir,
+            None => return false,
+        };
+    for _ in 0..entries.len() {
+        if dir.parent == "trash" {
+            return true;
+        }
+        let parent = entries
+            .iter()
+            .filter(is_dir)
+            .find(|e| e.attr.ino > ORPHANS_INO && e.prefix == dir.parent);
+        match parent {
+            Some(parent) => dir = parent,
+            None => return false,
+        }
+    }
+    // parents in a cycle, never reaching the trash
+    false
+}
+
+// The documents outside the trash moved to the root for --flat, folders
+// (and the trash) left out. Documents from different folders sharing a name
+// get the start of their uuid appended.
+fn flatten(entries: Vec<DirEntry>) -> Vec<DirEntry> {
+    let folders: HashMap<OsString, OsString> = entries
+        .iter()
+        .filter(|e| e.entry_type == EntryType::NONE && e.attr.ino > ORPHANS_INO)
+        .map(|e| (e.prefix.clone(), e.parent.clone()))
+        .collect();
+    let mut docs: Vec<DirEntry> = entries
+        .into_iter()
+        .filter(|e| {
+            e.entry_type != EntryType::NONE && !in_trash(&folders, &e.parent)
+        })
+        .collect();
+    for doc in docs.iter_mut() {
+        doc.parent = OsString::new();
+    }
+
+    let names: Vec<OsString> = docs.iter().map(|e| e.file_name()).collect();
+    for (doc, name) in docs.iter_mut().zip(&names) {
+        if names.iter().filter(|n| *n == name).count() > 1 {
+            let suffix = format!(" ({})", uuid_fragment(doc));
+            append_to_name(doc, &suffix);
+        }
+    }
+    docs
+}
+
+fn sort_entries(entries: &mut [&DirEntry], options: &Options) {
+    use std::os::unix::ffi::OsStringExt;
+    match options.sort {
+        // a name is made once per entry, not for every comparison
+        SortOrder::Name => {
+            entries.sort_by_cached_key(|e| e.file_name().into_vec());
+        }
+        SortOrder::Mtime => {
+            entries.sort_by_key(|e| {
+                std::cmp::Reverse(e.last_modified().unwrap_or(e.attr.mtime))
+            });
+        }
+        SortOrder::None => (),
+    }
+    if options.folders_first {
+        // stable, so the order within folders and documents is kept
+        entries.sort_by_key(|e| e.entry_type != EntryType::NONE);
+    }
+}
+
+/// The children of `parent` as kept for an open dir, borrowed from the
+/// listing. Sorted once per snapshot, as readdir offsets index into it.
+pub fn dir_snapshot<'a>(
+    entries: &'a [DirEntry],
+    parent: &DirEntry,
+    options: &Options,
+) -> Vec<&'a DirEntry> {
+    let mut entries: Vec<&DirEntry> =
+        entries.iter().filter(|e| e.is_parent(parent)).collect();
+    sort_entries(&mut entries, options);
+    entries
+}
+
+// The documents and folders of a listing, without the virtual dirs and the
+// companions, which are views of the documents
+fn library_size(entries: &[DirEntry]) -> u64 {
+    let counted = entries.iter().filter(|e| {
+        e.attr.ino > ORPHANS_INO
+            && e.entry_type != EntryType::HIGHLIGHTS
+            && !e.rendition
+    });
+    counted.count() as u64
+}
+
+/// What readdir needs of an entry of an open dir, lookups find the rest in
+/// a listing of their own
+#[derive(PartialEq)]
+pub struct Listed {
+    ino: u64,
+    kind: FileType,
+    name: Box<OsStr>,
+}
+
+/// The entries of an open dir, shared by the handles that listed the same
+pub type Snapshot = Arc<[Listed]>;
+
+pub fn listed<'a>(entries: impl IntoIterator<Item = &'a DirEntry>) -> Snapshot {
+    entries
+        .into_iter()
+        .map(|e| Listed {
+            ino: e.attr.ino,
+            kind: e.attr.kind,
+            name: e.file_name().into_boxed_os_str(),
+        })
+        .collect()
+}
+
+// Appended to the names listed of what isn't synced with --mark-unsynced.
+// Only readdir shows it, names looked up or removed don't need it.
+const UNSYNCED_MARK: &str = " \u{2022}";
+
+// `listed`, with the names of what isn't synced marked
+fn listed_marked<'a>(
+    entries: impl IntoIterator<Item = &'a DirEntry>,
+) -> Snapshot {
+    entries
+        .into_iter()
+        .map(|e| {
+            let mut name = e.file_name();
+            if e.synced == Some(false) {
+                name.push(UNSYNCED_MARK);
+            }
+            Listed {
+                ino: e.attr.ino,
+                kind: e.attr.kind,
+                name: name.into_boxed_os_str(),
+            }
+        })
+        .collect()
+}
+
+/// `snapshot`, or one of `open` with the same entries in its place
+pub fn share<'a>(
+    snapshot: Snapshot,
+    mut open: impl Iterator<Item = &'a Snapshot>,
+) -> Snapshot {
+    open.find(|open| **open == snapshot)
+        .cloned()
+        .unwrap_or(snapshot)
+}
+
+/// The bytes `snapshot` takes
+pub fn snapshot_size(snapshot: &[Listed]) -> usize {
+    let names: usize = snapshot.iter().map(|e| e.name.len()).sum();
+    std::mem::size_of_val(snapshot) + names
+}
+
+// The size of a pending file is whatever has been written so far, and its
+// mtime that of the last write
+fn pending_attr(entry: &DirEntry, file: &BufferedFile) -> FileAttr {
+    let size = file.len().unwrap_or(0);
+    let modified = file.modified().max(entry.attr.mtime);
+    FileAttr {
+        size,
+        blocks: blocks(size),
+        mtime: modified,
+        ctime: modified,
+        ..entry.attr
+    }
+}
+
+// The data file of a document open for reading may have changed since it
+// was listed, its handle has the size it has now
+fn open_attr(entry: &DirEntry, file: &fs::File) -> FileAttr {
+    let has_data = matches!(
+        entry.entry_type,
+        EntryType::PDF | EntryType::EPUB | EntryType::OTHER(_)
+    );
+    match file.metadata() {
+        Ok(meta) if has_data => FileAttr {
+            size: meta.len(),
+            blocks: blocks(meta.len()),
+            mtime: meta
+                .modified()
+                .map_or(entry.attr.mtime, |m| m.max(entry.attr.mtime)),
+            ..entry.attr
+        },
+        _ => entry.attr,
+    }
+}
+
+// What finalizing wrote and moved in place, and the dir of the renames
+fn sync_finalized(entry: &DirEntry) -> io::Result<()> {
+    let paths = [
+        entry.source_file_path(),
+        entry.content_path(),
+        entry.metadata_file_name(),
+        entry.root_path.clone(),
+    ];
+    for path in paths.iter() {
+        retry(|| fs::File::open(path)?.sync_all())?;
+        stats::add(&stats::SYNCS, 1);
+    }
+    Ok(())
+}
+
+// A dir of the source or the staging dir, read-only filesystems and those
+// that can't sync dirs have nothing to sync
+fn sync_dir(path: &Path, datasync: bool) -> io::Result<()> {
+    let res = retry(|| {
+        let dir = fs::File::open(path)?;
+        match datasync {
+            true => dir.sync_data(),
+            false => dir.sync_all(),
+        }
+    });
+    match res {
+        Err(e)
+            if e.raw_os_error() == Some(libc::EROFS)
+                || e.raw_os_error() == Some(libc::EINVAL) =>
+        {
+            Ok(())
+        }
+        Err(e) => Err(e),
+        Ok(()) => {
+            stats::add(&stats::SYNCS, 1);
+            Ok(())
+        }
+    }
+}
+
+fn reply_data(
+    fh: u64,
+    read: io::Result<impl Deref<Target = [u8]>>,
+    reply: ReplyData,
+) {
+    match read {
+        Ok(data) => {
+            stats::add(&stats::BYTES_READ, data.len() as u64);
+            reply.data(&data)
+        }
+        Err(e) => {
+            debug!("read: error on {}: {}", fh, e);
+            reply.fail(errno::from_io(&e));
+        }
+    }
+}
+
+fn reply_read(
+    pool: &Arc<BufPool>,
+    fh: u64,
+    file: &fs::File,
+    offset: u64,
+    size: u32,
+    reply: ReplyData,
+) {
+    if !deadline::enabled() {
+        return reply_data(fh, pool.read_at(file, offset, size), reply);
+    }
+    // on a thread that may outlive the request, so the buffer keeps the
+    // pool alive
+    let pool = Arc::clone(pool);
+    let read = file.try_clone().and_then(|file| {
+        deadline::run_for(fh, "read", move || {
+            pool.read_shared(&file, offset, size)
+        })
+    });
+    reply_data(fh, read, reply)
+}
+
+fn reply_xattr(reply: ReplyXattr, size: u32, value: &[u8]) {
+    if size == 0 {
+        reply.size(value.len() as u32);
+    } else if value.len() > size as usize {
+        // not counted, the caller asks again with a bigger buffer
+        reply.error(libc::ERANGE);
+    } else {
+        reply.data(value);
+    }
+}
+
+// Swaps the names and parents of two entries for RENAME_EXCHANGE. The first
+// one is put back if the second can't be moved.
+fn exchange(
+    entry: &DirEntry,
+    parent: Option<&OsStr>,
+    target: &DirEntry,
+    newparent: Option<&OsStr>,
+) -> Result<(), i32> {
+    // the names of the listing may have a suffix, the stored ones are
+    // swapped
+    let stored = |e: &DirEntry| e.stored_name().map_err(|e| errno::from_io(&e));
+    let (name, target_name) = (stored(entry)?, stored(target)?);
+    entry
+        .move_as(newparent, &target_name)
+        .map_err(|e| errno::from_io(&e))?;
+    if let Err(e) = target.move_as(parent, &name) {
+        debug!("rename: exchange failed, rolling back: {}", e);
+        if let Err(e) = entry.move_as(parent, &name) {
+            error!("rename: couldn't roll back {:?}: {}", entry.prefix, e);
+        }
+        return Err(errno::from_io(&e));
+    }
+    Ok(())
+}
+
+pub fn list_dir_metadata(dir: &PathBuf) -> io::Result<Vec<DirEntry>> {
+    list_dir_metadata_with(dir, scanpool::threads())
+}
+
+/// The listing parsed by `threads` threads, for measuring
+pub fn list_dir_metadata_with(
+    dir: &PathBuf,
+    threads: usize,
+) -> io::Result<Vec<DirEntry>> {
+    scan_dir(
+        dir,
+        false,
+        false,
+        Highlights::Off,
+        Layout::Tree,
+        Deleted::InPlace,
+        threads,
+    )
+}
+
+// The companions of a document for its highlights dir, none if it is empty
+fn highlight_entries(
+    doc: &DirEntry,
+    dir: &fs::DirEntry,
+    mode: Highlights,
+) -> io::Result<Vec<DirEntry>> {
+    let pages = highlights::pages(&dir.path())?;
+    if pages.is_empty() {
+        return Ok(Vec::new());
+    }
+    let attr = conv_attr(dir)?;
+    if mode == Highlights::Merged {
+        let size = highlights::merged_size(&pages)?;
+        return Ok(vec![DirEntry::make_highlights(doc, &attr, size, false)]);
+    }
+    let raw = DirEntry::make_highlights(doc, &attr, 0, true);
+    let mut res = Vec::with_capacity(pages.len() + 1);
+    for page in pages {
+        res.push(DirEntry::make_highlights_page(
+            &raw,
+            &page.file_name(),
+            &conv_attr(&page)?,
+        ));
+    }
+    res.push(raw);
+    Ok(res)
+}
+
+// What a listing does with the entries xochitl marked deleted: the tools
+// see them where they were, the mount leaves them out or shows them in
+// .deleted
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Deleted {
+    InPlace,
+    Hidden,
+    Shown,
+}
+
+// Takes the entries marked deleted out of `entries`. Those in a folder that
+// is deleted too stay in it, the others are put at the top of .deleted.
+fn take_deleted(entries: &mut Vec<DirEntry>) -> Vec<DirEntry> {
+    let gone: HashSet<OsString> = entries
+        .iter()
+        .filter(|e| e.deleted)
+        .map(|e| e.prefix.clone())
+        .collect();
+    let (mut res, kept): (Vec<DirEntry>, Vec<DirEntry>) =
+        std::mem::take(entries).into_iter().partition(|e| e.deleted);
+    *entries = kept;
+    for entry in res.iter_mut().filter(|e| !gone.contains(&e.parent)) {
+        entry.parent = OsString::from("deleted");
+    }
+    res
+}
+
+// With `orphans`, data files whose uuid has no metadata are collected into
+// a virtual .orphans dir. Entries of other types (templates) are left out
+// unless `show_hidden`, and those marked deleted as `deleted` says.
+// Documents with highlights get companions as set by `highlights`, placed
+// as the documents are by `layout`.
+fn scan_dir(
+    dir: &PathBuf,
+    orphans: bool,
+    show_hidden: bool,
+    highlights: Highlights,
+    layout: Layout,
+    deleted: Deleted,
+    threads: usize,
+) -> io::Result<Vec<DirEntry>> {
     let mut res = Vec::new();
 
     // Special dirs (currently trash:2):
     res.push(DirEntry::make_trash(dir));
 
-    for entry in fs::read_dir(dir)? {
-        let e = entry?;
-        if !e.file_name().to_str().unwrap_or("").ends_with(".metadata") {
-            continue;
+    let mut claimed = HashSet::new();
+    let mut metadata_files = Vec::new();
+    let mut data_files = Vec::new();
+    let mut highlight_dirs = Vec::new();
+    for entry in retry(|| fs::read_dir(dir))? {
+        let e = entry?;
+        let path = e.path();
+        let ext = path.extension().and_then(OsStr::to_str).unwrap_or("");
+        if ext != "metadata" {
+            if orphans
+                && ExtensionMap::device().entry_type(ext) != &EntryType::NONE
+            {
+                data_files.push(e);
+            } else if highlights != Highlights::Off && ext == "highlights" {
+                highlight_dirs.push(e);
+            }
+            continue;
+        }
+        // its data files aren't orphans, whether it is shown or not
+        claimed.insert(path.file_stem().unwrap_or_default().to_os_string());
+        metadata_files.push(e);
+    }
+    scanpool::extend(&mut res, &metadata_files, threads, |e| {
+        let path = e.path();
+        let json_data = JsonMetadata::from_file(&path)?;
+        let entry = DirEntry::new(&path, &retry(|| conv_attr(e))?, &json_data);
+        if entry.hidden && !show_hidden {
+            debug!(
+                "scan: hiding {:?} of type {}",
+                entry.name,
+                json_data.doc_type().as_str()
+            );
+            return Ok(None);
+        }
+        Ok(Some(entry))
+    })?;
+    drop(metadata_files);
+    // before the conflicts, a rendition may take the name of a document
+    let renditions: Vec<DirEntry> =
+        res.iter().filter_map(DirEntry::make_rendition).collect();
+    res.extend(renditions);
+    if ascii::enabled() {
+        transliterate_names(&mut res);
+    }
+    let mut gone = match deleted {
+        Deleted::InPlace => Vec::new(),
+        _ => take_deleted(&mut res),
+    };
+    mark_conflicts(&mut res);
+    if layout == Layout::Flat {
+        res = flatten(res);
+    }
+    if deleted == Deleted::Shown {
+        mark_conflicts(&mut gone);
+        res.push(DirEntry::make_deleted(dir));
+        res.append(&mut gone);
+    }
+
+    // after the conflicts, companions are named after the shown file name
+    let mut companions = Vec::new();
+    for e in highlight_dirs {
+        let path = e.path();
+        let uuid = path.file_stem().unwrap_or_default();
+        let doc = res
+            .iter()
+            .find(|d| d.prefix == uuid && d.attr.kind == FileType::RegularFile);
+        if let Some(doc) = doc {
+            // a broken highlights dir only loses the companion
+            match highlight_entries(doc, &e, highlights) {
+                Ok(entries) => companions.extend(entries),
+                Err(err) => debug!("highlights {:?}: {}", path, err),
+            }
+        }
+    }
+    res.extend(companions);
+    for entry in res.iter_mut() {
+        fit_name_max(entry);
+    }
+
+    if orphans {
+        res.push(DirEntry::make_orphans(dir));
+        for e in data_files {
+            let path = e.path();
+            if claimed.contains(path.file_stem().unwrap_or_default()) {
+                continue;
+            }
+            let ext = path.extension().and_then(OsStr::to_str).unwrap_or("");
+            res.push(DirEntry::make_orphan(
+                &path,
+                &retry(|| conv_attr(&e))?,
+                *ExtensionMap::device().entry_type(ext),
+            ));
+        }
+    }
+    unshadow_virtual(&mut res);
+    Ok(res)
+}
+
+// Adds the entries of the `number`th source of a union mount. Sources only
+// share the root, the trash and .deleted, where an entry named like one of
+// an earlier source gets the number of its source appended.
+fn merge_source(
+    entries: &mut Vec<DirEntry>,
+    extra: Vec<DirEntry>,
+    number: usize,
+) {
+    let shared = |e: &DirEntry| {
+        e.parent.is_empty() || e.parent == "trash" || e.parent == "deleted"
+    };
+    let taken: HashSet<(OsString, OsString)> = entries
+        .iter()
+        .filter(|e| shared(e))
+        .map(|e| (e.parent.clone(), e.file_name()))
+        .collect();
+    for mut entry in extra {
+        if shared(&entry)
+            && taken.contains(&(entry.parent.clone(), entry.file_name()))
+        {
+            append_to_name(&mut entry, &format!(" (source {})", number));
+            fit_name_max(&mut entry);
+        }
+        entries.push(entry);
+    }
+}
+
+// Entries of the extra sources of a union mount can't be changed
+fn in_mirror(ino: u64) -> bool {
+    source_bits(ino) != 0
+}
+
+// Documents are tagged, not the virtual dirs, folders, orphans (which have
+// no metadata) or highlights
+fn has_tags(entry: &DirEntry) -> bool {
+    entry.attr.ino > ORPHANS_INO
+        && !entry.rendition
+        && entry.entry_type != EntryType::NONE
+        && entry.entry_type != EntryType::HIGHLIGHTS
+        && entry.parent != "orphans"
+}
+
+impl RMXFS {
+    // A source as the options show it, given up on after --io-timeout
+    fn scan(&self, dir: &Path, orphans: bool) -> io::Result<Vec<DirEntry>> {
+        let deleted = if self.options.expose_deleted {
+            Deleted::Shown
+        } else {
+            Deleted::Hidden
+        };
+        let (show_hidden, highlights, layout) = (
+            self.options.show_other_types,
+            self.options.highlights,
+            self.options.layout,
+        );
+        let dir = dir.to_path_buf();
+        deadline::run("listing", move || {
+            scan_dir(
+                &dir,
+                orphans,
+                show_hidden,
+                highlights,
+                layout,
+                deleted,
+                scanpool::threads(),
+            )
+        })
+    }
+
+    // The library as shown, without what --exclude hides. A snapshot hands
+    // out the same listing every time.
+    fn list(&self) -> io::Result<Arc<[DirEntry]>> {
+        let entries = match &self.frozen {
+            Some(frozen) => Arc::clone(&frozen.shown),
+            None => self.without_excluded(self.scan_library()?).into(),
+        };
+        self.library_size.set(Some(library_size(&entries)));
+        let unsynced = entries.iter().filter(|e| {
+            e.attr.kind == FileType::RegularFile && e.synced == Some(false)
+        });
+        stats::set(&stats::UNSYNCED, unsynced.count() as u64);
+        Ok(entries)
+    }
+
+    // `entries` without what --exclude hides
+    fn without_excluded(&self, mut entries: Vec<DirEntry>) -> Vec<DirEntry> {
+        if self.options.excludes.is_empty() {
+            return entries;
+        }
+        let resolver = PathResolver::new(&entries);
+        let excluded: Vec<bool> = entries
+            .iter()
+            .map(|e| {
+                resolver.visible_path(e).is_some_and(|path| {
+                    exclude::is_excluded(&self.options.excludes, &path)
+                })
+            })
+            .collect();
+        let mut excluded = excluded.into_iter();
+        entries.retain(|_| !excluded.next().unwrap_or(false));
+        entries
+    }
+
+    // The library with the virtual dirs that depend on options
+    fn library(&self) -> io::Result<Arc<[DirEntry]>> {
+        match &self.frozen {
+            Some(frozen) => Ok(Arc::clone(&frozen.library)),
+            None => Ok(self.scan_library()?.into()),
+        }
+    }
+
+    // The library as the sources have it now
+    fn scan_library(&self) -> io::Result<Vec<DirEntry>> {
+        let mut entries =
+            self.scan(&self.source_dir, self.options.expose_orphans)?;
+        self.last_scan.set(Some(SystemTime::now()));
+        for (i, mirror) in self.options.mirrors.iter().enumerate() {
+            let bits = (i as u64 + 1) << SOURCE_SHIFT;
+            // one that went away (an unplugged disk) is left out
+            let mut extra = match self.scan(mirror, false) {
+                Ok(extra) => extra,
+                Err(e) => {
+                    debug!("list: skipping {:?}: {}", mirror, e);
+                    continue;
+                }
+            };
+            extra.retain(|e| e.attr.ino > ORPHANS_INO);
+            for entry in extra.iter_mut() {
+                entry.attr.ino |= bits;
+            }
+            merge_source(&mut entries, extra, i + 2);
+        }
+        if let Some(root) = &self.options.root {
+            // the virtual dirs are of the whole library
+            let resolver = PathResolver::new(&entries);
+            let under: Vec<bool> =
+                entries.iter().map(|e| resolver.is_under(e, root)).collect();
+            let entries: Vec<DirEntry> = entries
+                .into_iter()
+                .zip(under)
+                .filter_map(|(e, under)| under.then_some(e))
+                .collect();
+            return Ok(entries);
+        }
+        if self.options.recent > 0 {
+            entries.push(DirEntry::make_recent(&self.source_dir));
+            unshadow_virtual(&mut entries);
+        }
+        // the virtual dirs have no file to take an owner from
+        for entry in entries.iter_mut().filter(|e| e.attr.ino <= ORPHANS_INO) {
+            self.own(entry);
+        }
+        Ok(entries)
+    }
+
+    fn recent(&self) -> io::Result<Vec<DirEntry>> {
+        let entries: Arc<[DirEntry]> = match &self.frozen {
+            Some(frozen) => Arc::clone(&frozen.recent),
+            None => {
+                let dir = self.source_dir.clone();
+                deadline::run("listing", move || list_dir_metadata(&dir))?
+                    .into()
+            }
+        };
+        // what is excluded isn't recent either
+        let listed = match self.options.excludes.is_empty() {
+            true => None,
+            false => Some(self.list()?),
+        };
+        let shown: Option<HashSet<&OsStr>> = listed
+            .as_ref()
+            .map(|listed| listed.iter().map(|e| &*e.prefix).collect());
+        let entries = entries.iter().filter(|e| {
+            shown
+                .as_ref()
+                .is_none_or(|shown| shown.contains(&*e.prefix))
+        });
+        Ok(recent_entries(entries, self.options.recent))
+    }
+
+    // Whether a new entry `name` in the dir `parent` would be hidden by
+    // --exclude. Nothing is made where it couldn't be seen.
+    fn is_excluded(&self, parent: u64, name: &OsStr) -> bool {
+        if self.options.excludes.is_empty() {
+            return false;
+        }
+        let entries = match self.list() {
+            Ok(entries) => entries,
+            Err(_) => return false,
+        };
+        let resolver = PathResolver::new(&entries);
+        let mut path = match entries.iter().find(|e| e.attr.ino == parent) {
+            Some(dir) if parent != 1 => {
+                resolver.visible_path(dir).unwrap_or_default()
+            }
+            _ => PathBuf::new(),
+        };
+        path.push(name);
+        exclude::is_excluded(&self.options.excludes, &path)
+    }
+
+    // Whether the dir has entries, those --exclude hides too, so that
+    // removing it doesn't lose them
+    fn has_children(&self, dir: &DirEntry) -> bool {
+        match self.library() {
+            Ok(entries) => entries.iter().any(|e| e.is_parent(dir)),
+            Err(e) => {
+                debug!("Find file err: {}", e);
+                false
+            }
+        }
+    }
+
+    // Recent only links to documents elsewhere, it can't be changed, and
+    // .deleted is only for copying out. Orphans can only be removed, so
+    // unlink checks for them before this. The trash itself can't be replaced
+    // or removed, only moved into.
+    fn is_read_only(&self, parent: u64, name: &OsStr) -> bool {
+        let virtual_root = parent == 1 && self.options.root.is_none();
+        parent == RECENT_INO
+            || parent == DELETED_INO
+            || parent == ORPHANS_INO
+            || (virtual_root
+                && self.options.layout == Layout::Tree
+                && name == "trash")
+            || (virtual_root && self.options.recent > 0 && name == "Recent")
+            || (virtual_root
+                && self.options.expose_deleted
+                && name == ".deleted")
+            || (virtual_root
+                && self.options.expose_orphans
+                && name == ".orphans")
+            || control::is_reserved(parent, name)
+    }
+
+    fn own(&self, entry: &mut DirEntry) {
+        entry.attr.uid = self.options.uid;
+        entry.attr.gid = self.options.gid;
+    }
+
+    // Highlights and pdf renditions are views of xochitl's files and can't
+    // be changed. True if `ino` is a companion, or has one called `name`.
+    fn is_companion(&self, ino: u64, name: Option<&OsStr>) -> bool {
+        let companion =
+            |e: &DirEntry| e.entry_type == EntryType::HIGHLIGHTS || e.rendition;
+        (self.options.highlights != Highlights::Off
+            || self.options.rendition == Rendition::Both)
+            && (self
+                .find_file(&|e: &DirEntry| companion(e) && e.attr.ino == ino)
+                .is_some()
+                || name.is_some_and(|name| {
+                    self.find_in_dir(ino, &|e: &DirEntry| {
+                        companion(e) && e.file_name() == name
+                    })
+                    .is_some()
+                }))
+    }
+
+    // An entry of the dir `ino` matching `pred`. Tested as the snapshots of
+    // readdir are, so lookups never find what listings don't show: an entry
+    // whose folder is gone is in no dir, rather than at the root.
+    fn find_in_dir(
+        &self,
+        ino: u64,
+        pred: &dyn Fn(&DirEntry) -> bool,
+    ) -> Option<DirEntry> {
+        let entries = match self.list() {
+            Ok(entries) => entries,
+            Err(e) => {
+                debug!("Find file err: {}", e);
+                return None;
+            }
+        };
+        let root;
+        let dir = match ino {
+            1 => {
+                root = self.dir_from_ino(1)?;
+                &root
+            }
+            _ => entries.iter().find(|e| e.attr.ino == ino)?,
+        };
+        entries
+            .iter()
+            .find(|e| e.is_parent(dir) && pred(e))
+            .cloned()
+    }
+
+    fn find_child(&self, parent: u64, name: &OsStr) -> Option<DirEntry> {
+        self.find_in_dir(parent, &|e: &DirEntry| e.file_name() == name)
+    }
+
+    // A new file is shown with the extension of its type, but whoever made it
+    // will still use the name it was created with (e.g. to rename it)
+    fn find_created(&self, parent: u64, name: &OsStr) -> Option<DirEntry> {
+        if self.created.is_empty() {
+            return None;
+        }
+        self.find_in_dir(parent, &|e: &DirEntry| {
+            self.created.contains(&e.prefix) && e.name == name
+        })
+    }
+
+    // Editors save by writing a temporary file and renaming it over the
+    // original. Replacing only the data keeps what xochitl has for the uuid.
+    fn preserves_uuid(&self, entry: &DirEntry, target: &DirEntry) -> bool {
+        self.options.replace_preserves_uuid
+            && self.created.contains(&entry.prefix)
+            && entry.entry_type == target.entry_type
+            && entry.attr.kind == FileType::RegularFile
+            && ![EntryType::PENDING, EntryType::HIGHLIGHTS]
+                .contains(&entry.entry_type)
+    }
+
+    // The folder a rename moves to. The flat view has none, so documents
+    // only get a new name and stay where they are on the device.
+    fn rename_parent<'a>(&self, newparent: &'a DirEntry) -> Option<&'a OsStr> {
+        match self.options.layout {
+            Layout::Tree => Some(&newparent.prefix),
+            Layout::Flat => None,
+        }
+    }
+
+    // A plain rename onto an existing entry replaces it, as in rename(2)
+    fn replace(&self, entry: &DirEntry, target: &DirEntry) -> Result<(), i32> {
+        let is_dir = |e: &DirEntry| e.entry_type == EntryType::NONE;
+        match (is_dir(entry), is_dir(target)) {
+            (false, true) => Err(libc::EISDIR),
+            (true, false) => Err(libc::ENOTDIR),
+            (true, true) if self.has_children(target) => Err(libc::ENOTEMPTY),
+            (false, false) if self.file_map.contains_key(&target.attr.ino) => {
+                Err(libc::EBUSY)
+            }
+            _ => target.remove().map_err(|e| errno::from_io(&e)),
+        }
+    }
+
+    fn find_file(&self, pred: &dyn Fn(&DirEntry) -> bool) -> Option<DirEntry> {
+        match self.list() {
+            Ok(files) => files.iter().find(|e| pred(e)).cloned(),
+            Err(e) => {
+                debug!("Find file err: {}", e);
+                None
+            }
+        }
+    }
+
+    // Every reply carrying an entry (lookup, create, mkdir) counts as a lookup
+    fn remember(&mut self, entry: &DirEntry) {
+        self.lookups
+            .looked_up(entry.attr.ino, &entry.source_file_path());
+    }
+
+    // Drops what is cached of an entry the kernel forgot, unless it is open
+    fn evict(&self, ino: u64, path: &Path) {
+        if self.file_map.contains_key(&ino)
+            || self.pending_map.contains_key(&ino)
+            || self.companion_map.contains_key(&ino)
+        {
+            return;
+        }
+        debug!("forget: evicting {}", ino);
+        self.hashes.lock().unwrap().remove(path);
+        direntry::forget_pages(path);
+    }
+
+    // What forget does, the kernel dropped `nlookup` references to `ino`
+    fn forget_inode(&mut self, ino: u64, nlookup: u64) {
+        if let Some(path) = self.lookups.forget(ino, nlookup) {
+            self.evict(ino, &path);
+        }
+    }
+
+    // Called before modifying the library. Warnings are only given once, but
+    // in read-only mode every modification checks for xochitl.
+    fn check_xochitl(&mut self) -> Result<(), i32> {
+        match self.options.xochitl_guard {
+            XochitlGuard::Off => Ok(()),
+            XochitlGuard::Warn => {
+                if !self.xochitl_checked && xochitl::is_running() {
+                    warn!("xochitl is running, changes may corrupt its state");
+                }
+                self.xochitl_checked = true;
+                Ok(())
+            }
+            XochitlGuard::ReadOnly => {
+                if xochitl::is_running() {
+                    warn!("xochitl is running, refusing change");
+                    Err(libc::EROFS)
+                } else {
+                    Ok(())
+                }
+            }
+        }
+    }
+
+    // Called before modifying the library, like check_xochitl
+    fn check_sync(&self) -> Result<(), i32> {
+        let (source, markers) = (&self.source_dir, &self.options.sync_markers);
+        let busy = match self.options.sync_guard {
+            SyncGuard::Off => false,
+            SyncGuard::Wait => {
+                !syncguard::wait(source, markers, self.options.sync_timeout)
+            }
+            SyncGuard::Fail => syncguard::in_progress(source, markers),
+        };
+        if busy {
+            warn!("sync in progress, refusing change");
+            Err(libc::EBUSY)
+        } else {
+            Ok(())
+        }
+    }
+
+    fn check_change(&mut self) -> Result<(), i32> {
+        // the kernel refuses most of them already, the mount is read-only
+        if self.options.snapshot != SnapshotMode::Off {
+            return Err(libc::EROFS);
+        }
+        self.check_xochitl()?;
+        self.check_sync()
+    }
+
+    // Called before changing an existing entry, after check_change
+    fn check_busy(&mut self, entry: &DirEntry) -> Result<(), i32> {
+        let open = self.busy.as_mut().and_then(BusyGuard::open_document);
+        if open.is_some_and(|uuid| entry.prefix == uuid) {
+            warn!("{:?} is open in xochitl, refusing change", entry.name);
+            return Err(libc::EBUSY);
+        }
+        Ok(())
+    }
+
+    // Replaces the tags of the document `ino`, for setxattr and removexattr
+    fn set_tags(&mut self, ino: u64, tags: &[String], reply: ReplyEmpty) {
+        let mut entry = match self.find_file(&|e: &DirEntry| e.attr.ino == ino)
+        {
+            Some(entry) if has_tags(&entry) => entry,
+            Some(_) => return reply.fail(libc::ENOTSUP),
+            None => return reply.fail(ENOENT),
+        };
+        if in_mirror(ino) {
+            return reply.fail(libc::EROFS);
+        }
+        if let Err(e) =
+            self.check_change().and_then(|_| self.check_busy(&entry))
+        {
+            return reply.fail(e);
+        }
+        match entry.set_tags(tags) {
+            Ok(()) => reply.ok(),
+            Err(e) => {
+                debug!("set_tags: {:?} failed: {}", entry.prefix, e);
+                reply.fail(errno::from_io(&e));
+            }
+        }
+    }
+
+    // Data that was already written can't be refused, so finalizing always
+    // waits for the sync (bounded) unless the guard is off
+    fn finalize(
+        &self,
+        entry: &DirEntry,
+        mut file: BufferedFile,
+    ) -> io::Result<()> {
+        if self.options.sync_write {
+            file.sync(false)?;
+        } else {
+            file.flush()?;
+        }
+        drop(file);
+        if self.options.sync_guard != SyncGuard::Off
+            && !syncguard::wait(
+                &self.source_dir,
+                &self.options.sync_markers,
+                self.options.sync_timeout,
+            )
+        {
+            warn!("sync still in progress, finalizing anyway");
+        }
+        entry.finalize_pending()?;
+        if self.options.sync_write {
+            sync_finalized(entry)?;
+        }
+        stats::add(&stats::DOCUMENTS_CREATED, 1);
+        Ok(())
+    }
+
+    /// Whether the pending document of `fh` has the data of another one
+    /// outside the trash
+    fn is_duplicate(&mut self, fh: u64) -> bool {
+        let entry = match self.pending_map.get(&fh) {
+            Some((_, entry, _)) if entry.entry_type != EntryType::PENDING => {
+                entry
+            }
+            _ => return false,
+        };
+        let (prefix, path) = (entry.prefix.clone(), entry.pending_path());
+        let entries = match self.list() {
+            Ok(entries) => entries,
+            Err(e) => {
+                warn!("dedup: can't list the source: {}", e);
+                return false;
+            }
+        };
+        let docs: Vec<&DirEntry> = entries
+            .iter()
+            .filter(|e| {
+                matches!(
+                    e.entry_type,
+                    EntryType::PDF | EntryType::EPUB | EntryType::OTHER(_)
+                ) && !e.rendition
+                    && e.parent != "trash"
+                    && e.prefix != prefix
+                    && !in_mirror(e.attr.ino)
+            })
+            .collect();
+        let index = match &mut self.dedup {
+            Some(index) => index,
+            None => return false,
+        };
+        match index.find(&docs, &prefix, &path) {
+            Ok(Some(uuid)) => {
+                info!("dedup: {:?} is a copy of {:?}", prefix, uuid);
+                true
+            }
+            Ok(None) => false,
+            Err(e) => {
+                warn!("dedup: can't compare {:?}: {}", prefix, e);
+                false
+            }
+        }
+    }
+
+    /// Drops the handles idle for longer than --handle-timeout
+    fn sweep_handles(&mut self) {
+        let stale = self.handles.sweep(self.options.handle_timeout);
+        if stale.is_empty() {
+            return;
+        }
+        for fh in stale {
+            if let Some((_, entry, file)) = self.pending_map.remove(&fh) {
+                if self.over_quota.remove(&fh) {
+                    drop(file);
+                    entry.discard_pending();
+                    continue;
+                }
+                let size = file.extent();
+                match end_pending(&entry, file) {
+                    Ok(true) => {
+                        self.created_sizes.insert(entry.prefix.clone(), size);
+                    }
+                    Ok(false) => (),
+                    Err(e) => error!(
+                        "couldn't finalize pending file {:?}: {}",
+                        entry.prefix, e
+                    ),
+                }
+            }
+            self.file_map.remove(&fh);
+            self.companion_map.remove(&fh);
+        }
+        self.staging_changed();
+        info!("{}", self.handle_counts());
+    }
+
+    // The bytes of the new files being written
+    fn staged_bytes(&self) -> u64 {
+        self.pending_map
+            .values()
+            .map(|(_, _, file)| file.extent())
+            .sum()
+    }
+
+    // What counts against --quota: the new documents of this session, those
+    // finalized and those being written
+    fn quota_used(&self) -> u64 {
+        self.created_sizes.values().sum::<u64>() + self.staged_bytes()
+    }
+
+    // For the stats, whenever a new file is written, finished or dropped
+    fn staging_changed(&self) {
+        stats::set(&stats::PENDING, self.pending_map.len() as u64);
+        stats::set(&stats::STAGED_BYTES, self.staged_bytes());
+        stats::set(&stats::QUOTA_USED, self.quota_used());
+    }
+
+    pub fn handle_counts(&self) -> String {
+        format!(
+            "open handles: {} files, {} pending, {} highlights, {} dirs",
+            self.file_map.len(),
+            self.pending_map.len(),
+            self.companion_map.len(),
+            self.dir_map.len()
+        )
+    }
+
+    // The --root folder is the root, so are folders that can't be found
+    // Documents (and companions) have nothing below them, pending and open
+    // ones are found without a scan
+    fn is_file(&self, ino: u64) -> bool {
+        ino > ORPHANS_INO
+            && (self.pending_map.contains_key(&ino)
+                || self.file_map.contains_key(&ino)
+                || self.companion_map.contains_key(&ino)
+                || self
+                    .find_file(&|e: &DirEntry| e.attr.ino == ino)
+                    .is_some_and(|e| e.attr.kind == FileType::RegularFile))
+    }
+
+    // The control dir and its file, see control.rs
+    // The unsynced documents are counted by every listing, what the stats
+    // show is listed again so that it is current
+    fn count_unsynced(&self) {
+        if let Err(e) = self.list() {
+            debug!("stats: listing failed: {}", e);
+        }
+    }
+
+    // `name` without the mark --mark-unsynced lists it with
+    fn unmarked<'a>(&self, name: &'a OsStr) -> &'a OsStr {
+        use std::os::unix::ffi::OsStrExt;
+        if !self.options.mark_unsynced {
+            return name;
+        }
+        match name.as_bytes().strip_suffix(UNSYNCED_MARK.as_bytes()) {
+            Some(bare) if !bare.is_empty() => OsStr::from_bytes(bare),
+            _ => name,
+        }
+    }
+
+    // What getattr replies for an entry that isn't a control entry. What the
+    // open handles have is newer than the listing, a pending file changes
+    // with every write.
+    fn current_attr(&self, ino: u64) -> Option<(Duration, FileAttr)> {
+        if let Some((_, entry, file)) = self.pending_map.get(&ino) {
+            return Some((Duration::new(0, 0), pending_attr(entry, file)));
+        }
+        let entry = self.dir_from_ino(ino)?;
+        // a deep snapshot keeps what was scanned
+        let attr = match self.file_map.get(&ino) {
+            Some((_, file)) if self.options.snapshot != SnapshotMode::Deep => {
+                open_attr(&entry, file)
+            }
+            _ => entry.attr,
+        };
+        Some((DEFAULT_TTL, attr))
+    }
+
+    // The library as it is now, for --snapshot
+    fn freeze(&self) -> io::Result<Frozen> {
+        let library: Arc<[DirEntry]> = self.scan_library()?.into();
+        let shown = match self.options.excludes.is_empty() {
+            true => Arc::clone(&library),
+            false => self.without_excluded(library.to_vec()).into(),
+        };
+        let recent = match self.options.recent {
+            0 => Vec::new(),
+            _ => list_dir_metadata(&self.source_dir)?,
+        };
+        let mut data = HashMap::new();
+        if self.options.snapshot == SnapshotMode::Deep {
+            let documents = library.iter().filter(|e| {
+                matches!(
+                    e.entry_type,
+                    EntryType::PDF | EntryType::EPUB | EntryType::OTHER(_)
+                )
+            });
+            for entry in documents {
+                // one without its data file stays so
+                if let Ok(meta) = fs::metadata(entry.source_file_path()) {
+                    data.insert(entry.attr.ino, pinned(&meta));
+                }
+            }
+        }
+        Ok(Frozen {
+            library,
+            shown,
+            recent: recent.into(),
+            data,
+        })
+    }
+
+    // With --snapshot=deep, whether the data file open as `file` isn't the
+    // one the snapshot saw
+    fn is_stale(&self, ino: u64, file: &fs::File) -> bool {
+        let frozen = self.frozen.as_ref();
+        match frozen.and_then(|frozen| frozen.data.get(&ino)) {
+            Some(then) => file.metadata().map_or(true, |m| pinned(&m) != *then),
+            None => false,
+        }
+    }
+
+    // What readdir lists of `entries`
+    fn listed<'a>(
+        &self,
+        entries: impl IntoIterator<Item = &'a DirEntry>,
+    ) -> Snapshot {
+        match self.options.mark_unsynced {
+            true => listed_marked(entries),
+            false => listed(entries),
         }
-        let mut path = PathBuf::from(dir);
-        path.push(e.file_name());
-        let json_data = JsonMetadata::from_file(&path)?;
-        res.push(DirEntry::new(&path, &conv_attr(&e)?, &json_data));
     }
-    Ok(res)
-}
 
-impl RMXFS {
-    fn find_file(&self, pred: &dyn Fn(&DirEntry) -> bool) -> Option<DirEntry> {
-        match list_dir_metadata(&self.source_dir) {
-            Ok(files) => files.into_iter().find(pred),
-            Err(e) => {
-                debug!("Find file err: {}", e);
-                None
+    fn control_entry(&self, ino: u64) -> Option<DirEntry> {
+        let mut entry = match ino {
+            CONTROL_INO => DirEntry::make_control(&self.source_dir),
+            REFRESH_INO => {
+                let size = control::last_scan(self.last_scan.get()).len();
+                DirEntry::make_refresh(&self.source_dir, size as u64)
             }
+            _ => return None,
+        };
+        self.own(&mut entry);
+        Some(entry)
+    }
+
+    // The pages of notebooks and the hashes are read again when next needed
+    fn flush_caches(&self) {
+        direntry::clear_pages();
+        self.hashes.lock().unwrap().clear();
+    }
+
+    // For writes to the control file: lists the source afresh and counts
+    // the library again
+    fn refresh(&self) {
+        self.flush_caches();
+        if let Err(e) = self.list() {
+            debug!("refresh: listing failed: {}", e);
         }
+        stats::clear_library();
+        self.count_library();
+    }
+
+    // For the log and the stats, in a thread of its own
+    fn count_library(&self) {
+        let sources = std::iter::once(&self.source_dir)
+            .chain(self.options.mirrors.iter())
+            .cloned()
+            .collect();
+        prescan::report_in_background(sources);
     }
 
     fn dir_from_ino(&self, ino: u64) -> Option<DirEntry> {
         if ino == 1 {
-            Some(DirEntry::make_root(&self.source_dir))
+            let mut root = DirEntry::make_root(&self.source_dir);
+            // what is created or moved in goes into the --root folder
+            if let Some(uuid) = &self.options.root {
+                root.prefix = uuid.clone();
+            }
+            self.own(&mut root);
+            Some(root)
         } else {
             self.find_file(&|e: &DirEntry| e.attr.ino == ino)
         }
     }
 }
 
+/// Finalizes a pending file whose type is known and discards it otherwise,
+/// true if it was finalized
+fn end_pending(entry: &DirEntry, mut file: BufferedFile) -> io::Result<bool> {
+    file.flush()?;
+    drop(file);
+    if entry.entry_type == EntryType::PENDING {
+        entry.forget_pending();
+        Ok(false)
+    } else {
+        entry.finalize_pending()?;
+        stats::add(&stats::DOCUMENTS_CREATED, 1);
+        Ok(true)
+    }
+}
+
 impl Filesystem for RMXFS {
+    // A snapshot is taken before the first request, a mount of a source
+    // that can't be listed fails
+    fn init(
+        &mut self,
+        _req: &Request<'_>,
+        _config: &mut KernelConfig,
+    ) -> Result<(), libc::c_int> {
+        if self.options.snapshot == SnapshotMode::Off {
+            return Ok(());
+        }
+        match self.freeze() {
+            Ok(frozen) => {
+                info!("Snapshot of {} entries", frozen.library.len());
+                self.frozen = Some(frozen);
+                Ok(())
+            }
+            Err(e) => {
+                error!("Can't take the snapshot: {}", e);
+                Err(errno::from_io(&e))
+            }
+        }
+    }
+
+    // Runs when the session ends, so pending files that were never released
+    // are finalized if their type is known and removed otherwise
+    fn destroy(&mut self) {
+        let (mut finalized, mut discarded, mut failed) = (0, 0, 0);
+        info!("destroy: {}", self.handle_counts());
+        for (fh, (_, entry, file)) in self.pending_map.drain() {
+            if self.duplicates.contains(&fh) || self.over_quota.contains(&fh) {
+                drop(file);
+                entry.discard_pending();
+                discarded += 1;
+                continue;
+            }
+            match end_pending(&entry, file) {
+                Ok(true) => finalized += 1,
+                Ok(false) => discarded += 1,
+                Err(e) => {
+                    error!(
+                        "destroy: couldn't finalize pending file {:?}: {}",
+                        entry.prefix, e
+                    );
+                    failed += 1;
+                }
+            }
+        }
+        self.staging_changed();
+        let mut pending_dir = self.source_dir.clone();
+        pending_dir.push(".pending");
+        fs::remove_dir(pending_dir).unwrap_or(());
+        staging::cleanup();
+        info!(
+            "destroy: pending files: {} finalized, {} discarded, {} failed",
+            finalized, discarded, failed
+        );
+        info!("destroy: {} source operations retried", retry::retries());
+        info!(
+            "destroy: {} read buffers allocated",
+            self.buffers.allocated()
+        );
+    }
+
     fn lookup(
         &mut self,
         _req: &Request,
@@ -120,31 +1783,134 @@ impl Filesystem for RMXFS {
         name: &OsStr,
         reply: ReplyEntry,
     ) {
-        debug!("lookup: {}/{}", parent, name.to_str().unwrap());
-        match self.find_file(&|e: &DirEntry| {
-            name == e.file_name() && parent == e.parent_inode().unwrap_or(1)
-        }) {
+        debug!("lookup: {}/{:?}", parent, name);
+        let name = &*normalize::os_name(name);
+        let name = self.unmarked(name);
+        self.sweep_handles();
+        if let Some(entry) =
+            control::child(parent, name).and_then(|ino| self.control_entry(ino))
+        {
+            reply.entry(&DEFAULT_TTL, &entry.attr, 0);
+            return;
+        }
+        if parent == CONTROL_INO {
+            reply.error(ENOENT);
+            return;
+        }
+        if self.is_file(parent) {
+            reply.fail(libc::ENOTDIR);
+            return;
+        }
+        if parent == RECENT_INO {
+            let entry = self.recent().ok().and_then(|recent| {
+                recent.into_iter().find(|e| e.file_name() == name)
+            });
+            match entry {
+                Some(entry) => {
+                    self.remember(&entry);
+                    reply.entry(&DEFAULT_TTL, &entry.attr, 0);
+                }
+                None => reply.error(ENOENT),
+            }
+            return;
+        }
+        let pending = match self.pending_map.is_empty() {
+            true => None,
+            false => self.dir_from_ino(parent).and_then(|dir| {
+                self.pending_map
+                    .values()
+                    .find(|(_, e, _)| e.name == name && e.is_parent(&dir))
+            }),
+        };
+        if let Some((_, entry, file)) = pending {
+            let attr = pending_attr(entry, file);
+            self.lookups.looked_up(attr.ino, &entry.source_file_path());
+            reply.entry(&Duration::new(0, 0), &attr, 0);
+            return;
+        }
+        match self
+            .find_child(parent, name)
+            .or_else(|| self.find_created(parent, name))
+        {
             Some(entry) => {
-                &entry;
+                self.remember(&entry);
                 reply.entry(&DEFAULT_TTL, &entry.attr, 0)
             }
             None => {
-                debug!("lookup: not found {}", name.to_str().unwrap());
+                debug!("lookup: not found {:?}", name);
                 reply.error(ENOENT)
             }
         }
     }
 
+    // batch_forget falls back to this
+    fn forget(&mut self, _req: &Request<'_>, ino: u64, nlookup: u64) {
+        self.forget_inode(ino, nlookup);
+    }
+
     fn getattr(&mut self, _req: &Request, ino: u64, reply: ReplyAttr) {
-        if let Some((entry, _)) = self.pending_map.get(&ino) {
+        self.sweep_handles();
+        if let Some(entry) = self.control_entry(ino) {
             reply.attr(&DEFAULT_TTL, &entry.attr);
             return;
         }
-        if let Some(entry) = self.dir_from_ino(ino) {
-            reply.attr(&DEFAULT_TTL, &entry.attr);
-        } else {
-            debug!("getattr not found {}", ino);
-            reply.error(ENOENT)
+        match self.current_attr(ino) {
+            Some((ttl, attr)) => reply.attr(&ttl, &attr),
+            None => {
+                debug!("getattr not found {}", ino);
+                reply.fail(ENOENT)
+            }
+        }
+    }
+
+    // Mode, owner and times have no place in the metadata. With
+    // --attr-changes ignore (the default) changing them succeeds and changes
+    // nothing, so that cp -p and rsync -a finish without errors. Truncating
+    // isn't supported, other than that of `echo 1 > .fuse-rm/refresh`.
+    fn setattr(
+        &mut self,
+        _req: &Request<'_>,
+        ino: u64,
+        mode: Option<u32>,
+        uid: Option<u32>,
+        gid: Option<u32>,
+        size: Option<u64>,
+        atime: Option<TimeOrNow>,
+        mtime: Option<TimeOrNow>,
+        _ctime: Option<SystemTime>,
+        _fh: Option<u64>,
+        _crtime: Option<SystemTime>,
+        _chgtime: Option<SystemTime>,
+        _bkuptime: Option<SystemTime>,
+        _flags: Option<u32>,
+        reply: ReplyAttr,
+    ) {
+        self.sweep_handles();
+        match self.control_entry(ino) {
+            Some(entry) if ino == REFRESH_INO => {
+                reply.attr(&DEFAULT_TTL, &entry.attr)
+            }
+            Some(_) => reply.fail(libc::EPERM),
+            None if size.is_some() => reply.error(libc::ENOSYS),
+            None => {
+                let (ttl, attr) = match self.current_attr(ino) {
+                    Some(found) => found,
+                    None => return reply.fail(ENOENT),
+                };
+                debug!(
+                    "setattr {} mode {:?} owner {:?}:{:?} times {:?} {:?}",
+                    ino, mode, uid, gid, atime, mtime
+                );
+                let other_owner = uid.is_some_and(|uid| uid != attr.uid)
+                    || gid.is_some_and(|gid| gid != attr.gid);
+                match self.options.attr_changes {
+                    AttrChanges::Refuse => reply.fail(libc::EPERM),
+                    AttrChanges::Check if other_owner => {
+                        reply.fail(libc::EPERM)
+                    }
+                    _ => reply.attr(&ttl, &attr),
+                }
+            }
         }
     }
 
@@ -158,54 +1924,126 @@ impl Filesystem for RMXFS {
         flags: i32,
         reply: ReplyCreate,
     ) {
-        debug!("create: {}/{}", parent, name.to_str().unwrap());
+        debug!("create: {}/{:?} flags {:#o}", parent, name, flags);
+        let name = &*normalize::os_name(name);
+        self.sweep_handles();
+        if self.is_file(parent) {
+            reply.fail(libc::ENOTDIR);
+            return;
+        }
+        if name.len() > NAME_MAX {
+            reply.fail(libc::ENAMETOOLONG);
+            return;
+        }
+        let entries = self.list().unwrap_or_default();
+        if self.is_read_only(parent, name) || in_trash_dir(&entries, parent) {
+            reply.fail(libc::EPERM);
+            return;
+        }
+        if self.is_excluded(parent, name) {
+            reply.fail(libc::EACCES);
+            return;
+        }
+        if self.is_companion(parent, Some(name)) || in_mirror(parent) {
+            reply.fail(libc::EROFS);
+            return;
+        }
+        if let Err(e) = self.check_change() {
+            reply.fail(e);
+            return;
+        }
+        // With O_EXCL, a name is also taken by a document or new file called
+        // the same but for the extension, as the tablet shows them alike.
+        // Requests come one at a time, nothing is created between the checks
+        // and the insert into the pending files.
+        let excl = flags & libc::O_EXCL != 0;
+        let bare = direntry::bare_name(name);
         if let Some(parent_dir) = self.dir_from_ino(parent) {
-            if self
-                .pending_map
-                .iter()
-                .find(&|(_, (e, _)): &(&u64, &(DirEntry, fs::File))| {
-                    e.name == name && e.parent_inode().unwrap_or(1) == parent
-                })
-                .is_some()
-            {
+            if self.pending_map.values().any(|(_, e, _)| {
+                e.is_parent(&parent_dir)
+                    && (e.name == name
+                        || (excl && direntry::bare_name(&e.name) == bare))
+            }) {
                 debug!("create: file already pending {}/{:?}", parent, name);
-                reply.error(libc::EEXIST);
+                reply.fail(libc::EEXIST);
                 return;
             }
-            if self
-                .find_file(&|e: &DirEntry| {
-                    name == e.file_name()
-                        && parent == e.parent_inode().unwrap_or(1)
-                })
-                .is_some()
-            {
+            let taken = |e: &DirEntry| {
+                e.file_name() == name
+                    || (excl
+                        && e.attr.kind == FileType::RegularFile
+                        && e.entry_type != EntryType::HIGHLIGHTS
+                        && !e.rendition
+                        && direntry::bare_name(&e.file_name()) == bare)
+            };
+            if self.find_in_dir(parent, &taken).is_some() {
                 debug!("create: file exists {}/{:?}", parent, name);
-                reply.error(libc::EEXIST);
+                reply.fail(libc::EEXIST);
                 return;
             }
-            if flags & libc::O_ACCMODE != libc::O_WRONLY {
+            // reading back is fine, but a new file has nothing to read
+            if flags & libc::O_ACCMODE == libc::O_RDONLY {
                 debug!("create: wrong file mode");
-                reply.error(libc::EINVAL);
+                reply.fail(libc::EINVAL);
                 return;
             }
+            // a runaway client would fill the source with staged files
+            if self.pending_map.len() >= self.options.max_pending {
+                debug!("create: {} files pending", self.pending_map.len());
+                reply.fail(libc::EMFILE);
+                return;
+            }
+            if let Some(quota) = self.options.quota {
+                if self.quota_used() >= quota {
+                    debug!("create: the quota of {} bytes is used", quota);
+                    reply.fail(libc::EDQUOT);
+                    return;
+                }
+            }
             match DirEntry::make_file(&parent_dir, name, mode, umask) {
-                Ok(entry) => {
+                Ok(mut entry) => {
+                    self.own(&mut entry);
                     let path = entry.source_file_path();
-                    if let Ok(file) = fs::File::create(&path) {
-                        let ino = entry.attr.ino;
-                        let attr = entry.attr;
-                        self.pending_map.insert(ino, (entry, file));
-                        reply.created(&Duration::new(0, 0), &attr, 0, ino, 0);
-                    } else {
-                        debug!("create: failed to create file at {:?}", &path);
-                        reply.error(libc::EIO);
+                    let created = fs::OpenOptions::new()
+                        .read(true)
+                        .write(true)
+                        .create(true)
+                        .truncate(true)
+                        .open(&path);
+                    match created {
+                        Ok(file) => {
+                            let ino = entry.attr.ino;
+                            let attr = entry.attr;
+                            self.created.insert(entry.prefix.clone());
+                            self.remember(&entry);
+                            self.pending_map.insert(
+                                ino,
+                                (1, entry, BufferedFile::new(file)),
+                            );
+                            self.staging_changed();
+                            self.handles.opened(ino);
+                            reply.created(
+                                &Duration::new(0, 0),
+                                &attr,
+                                0,
+                                ino,
+                                0,
+                            );
+                        }
+                        Err(e) => {
+                            debug!("create: can't create {:?}: {}", &path, e);
+                            reply.fail(errno::from_io(&e));
+                        }
                     }
                 }
                 Err(e) => {
                     debug!("create: failed making file: {}", e);
-                    reply.error(libc::EIO);
+                    reply.fail(errno::from_io(&e));
                 }
             }
+        } else {
+            debug!("create: parent not found {}", parent);
+            reply.fail(ENOENT);
         }
     }
 
@@ -218,32 +2056,107 @@ impl Filesystem for RMXFS {
         umask: u32,
         reply: ReplyEntry,
     ) {
-        debug!("mkdir: {}/{}", parent, name.to_str().unwrap());
+        debug!("mkdir: {}/{:?}", parent, name);
+        let name = &*normalize::os_name(name);
+        if self.is_file(parent) {
+            reply.fail(libc::ENOTDIR);
+            return;
+        }
+        if name.len() > NAME_MAX {
+            reply.fail(libc::ENAMETOOLONG);
+            return;
+        }
+        let entries = self.list().unwrap_or_default();
+        if self.is_read_only(parent, name)
+            || self.options.layout == Layout::Flat
+            || in_trash_dir(&entries, parent)
+        {
+            reply.fail(libc::EPERM);
+            return;
+        }
+        if self.is_excluded(parent, name) {
+            reply.fail(libc::EACCES);
+            return;
+        }
+        if self.is_companion(parent, Some(name)) || in_mirror(parent) {
+            reply.fail(libc::EROFS);
+            return;
+        }
+        if let Err(e) = self.check_change() {
+            reply.fail(e);
+            return;
+        }
         // make KOReader's side cars go away
         if name
             .to_string_lossy()
             .as_bytes()
             .iter()
-            .find(|x| **x == '.' as u8)
+            .find(|x| **x == b'.')
             .is_some()
         {
-            reply.error(libc::ENOSYS);
+            reply.fail(libc::ENOSYS);
             return;
         }
         if let Some(parent_dir) = self.dir_from_ino(parent) {
             match DirEntry::make_dir(&parent_dir, name, mode, umask) {
-                Ok(dir) => reply.entry(&DEFAULT_TTL, &dir.attr, 0),
+                Ok(mut dir) => {
+                    self.own(&mut dir);
+                    self.remember(&dir);
+                    stats::add(&stats::FOLDERS_CREATED, 1);
+                    reply.entry(&DEFAULT_TTL, &dir.attr, 0)
+                }
                 Err(e) => {
                     debug!("mkdir: {}", e);
-                    reply.error(libc::EIO);
+                    reply.fail(errno::from_io(&e));
                 }
             }
         } else {
             debug!("mkdir: parent not found {}", parent);
-            reply.error(ENOENT);
+            reply.fail(ENOENT);
         }
     }
 
+    // The library has documents and folders only. EPERM is what link(2),
+    // symlink(2) and mknod(2) have for a filesystem without the kind of
+    // node asked for.
+    fn mknod(
+        &mut self,
+        _req: &Request<'_>,
+        parent: u64,
+        name: &OsStr,
+        _mode: u32,
+        _umask: u32,
+        _rdev: u32,
+        reply: ReplyEntry,
+    ) {
+        debug!("mknod: {}/{:?}", parent, name);
+        reply.error(libc::EPERM);
+    }
+
+    fn symlink(
+        &mut self,
+        _req: &Request<'_>,
+        parent: u64,
+        name: &OsStr,
+        _link: &Path,
+        reply: ReplyEntry,
+    ) {
+        debug!("symlink: {}/{:?}", parent, name);
+        reply.error(libc::EPERM);
+    }
+
+    fn link(
+        &mut self,
+        _req: &Request<'_>,
+        ino: u64,
+        newparent: u64,
+        newname: &OsStr,
+        reply: ReplyEntry,
+    ) {
+        debug!("link: {} -> {}/{:?}", ino, newparent, newname);
+        reply.error(libc::EPERM);
+    }
+
     fn rmdir(
         &mut self,
         _req: &Request,
@@ -251,37 +2164,55 @@ impl Filesystem for RMXFS {
         name: &OsStr,
         reply: ReplyEmpty,
     ) {
-        debug!("rmdir: {}/{}", parent, name.to_str().unwrap());
+        debug!("rmdir: {}/{:?}", parent, name);
+        let name = &*normalize::os_name(name);
+        let name = self.unmarked(name);
+        if self.is_read_only(parent, name) {
+            reply.fail(libc::EPERM);
+            return;
+        }
+        if self.is_companion(parent, Some(name)) {
+            reply.fail(libc::EROFS);
+            return;
+        }
+        if let Err(e) = self.check_change() {
+            reply.fail(e);
+            return;
+        }
         if let Some(parent_dir) = self.dir_from_ino(parent) {
             if let Some(dir) = self.find_file(&|e: &DirEntry| {
-                e.parent == parent_dir.prefix && name == e.name
+                e.is_parent(&parent_dir) && name == e.name
             }) {
+                if in_mirror(dir.attr.ino) {
+                    reply.fail(libc::EROFS);
+                    return;
+                }
                 // Removing the directory is ok, since open dirs hang around
                 // in the dir_map
                 /* if self.dir_map.contains_key(&dir.attr.ino) {
-                    reply.error(libc::EBUSY);
+                    reply.fail(libc::EBUSY);
                 } else */
-                if self
-                    .find_file(&|e: &DirEntry| e.parent == dir.prefix)
-                    .is_some()
-                {
-                    reply.error(libc::ENOTEMPTY);
+                if self.has_children(&dir) {
+                    reply.fail(libc::ENOTEMPTY);
                 } else {
-                    match fs::remove_file(dir.metadata_file_name()) {
-                        Ok(_) => reply.ok(),
+                    match dir.remove() {
+                        Ok(_) => {
+                            stats::add(&stats::DELETED, 1);
+                            reply.ok()
+                        }
                         Err(e) => {
-                            debug!("rmdir: couldn't remove metadata: {}", e);
-                            reply.error(libc::EIO);
+                            debug!("rmdir: couldn't remove {:?}: {}", name, e);
+                            reply.fail(errno::from_io(&e));
                         }
                     }
                 }
             } else {
-                debug!("mkdir: dir not found {}", name.to_str().unwrap());
-                reply.error(ENOENT);
+                debug!("rmdir: dir not found {:?}", name);
+                reply.fail(ENOENT);
             }
         } else {
-            debug!("mkdir: parent not found: {}", parent);
-            reply.error(ENOENT);
+            debug!("rmdir: parent not found: {}", parent);
+            reply.fail(ENOENT);
         }
     }
 
@@ -293,47 +2224,65 @@ impl Filesystem for RMXFS {
         reply: ReplyEmpty,
     ) {
         debug!("unlink: {}/{:?}", parent, name);
+        let name = &*normalize::os_name(name);
+        let name = self.unmarked(name);
+        if parent != ORPHANS_INO && self.is_read_only(parent, name) {
+            reply.fail(libc::EPERM);
+            return;
+        }
+        if self.is_companion(parent, Some(name)) {
+            reply.fail(libc::EROFS);
+            return;
+        }
+        if let Err(e) = self.check_change() {
+            reply.fail(e);
+            return;
+        }
         if let Some(parent_dir) = self.dir_from_ino(parent) {
             debug!(
                 "unlink: parent dir {}/{:?}",
                 parent_dir.attr.ino, parent_dir.name
             );
             if let Some(entry) = self.find_file(&|e: &DirEntry| {
-                parent_dir.prefix == e.parent && name == e.file_name()
+                e.is_parent(&parent_dir) && name == e.file_name()
             }) {
+                if in_mirror(entry.attr.ino) {
+                    reply.fail(libc::EROFS);
+                    return;
+                }
                 if self.file_map.contains_key(&entry.attr.ino) {
                     debug!("unlink: file is being read: {}/{:?}", parent, name);
-                    reply.error(libc::EBUSY);
+                    reply.fail(libc::EBUSY);
                     return;
                 }
-                if let Err(e) = fs::remove_file(entry.source_file_path()) {
-                    debug!(
-                        "unlink: couldn't remove file {:?}: {}",
-                        entry.source_file_path(),
-                        e
-                    );
-                    reply.error(libc::EIO);
+                if let Err(e) = self.check_busy(&entry) {
+                    reply.fail(e);
                     return;
                 }
-                let mut metadata_path = entry.metadata_file_name();
-                debug!("unlink: removing {:?}", metadata_path);
-                match fs::remove_file(&metadata_path) {
-                    Ok(_) => reply.ok(),
+                debug!("unlink: removing {:?}", entry.prefix);
+                match entry.remove() {
+                    Ok(_) => {
+                        if let Some(index) = &mut self.dedup {
+                            index.remove(&entry.prefix);
+                        }
+                        if self.created_sizes.remove(&entry.prefix).is_some() {
+                            self.staging_changed();
+                        }
+                        stats::add(&stats::DELETED, 1);
+                        reply.ok()
+                    }
                     Err(e) => {
-                        debug!("unlink: couldn't remove metadata: {}", e);
-                        reply.error(libc::EIO);
+                        debug!("unlink: couldn't remove {:?}: {}", name, e);
+                        reply.fail(errno::from_io(&e));
                     }
                 }
-                // TODO: have proper file list for direntry
-                metadata_path.set_extension("content");
-                fs::remove_file(metadata_path).unwrap_or(());
             } else {
                 debug!("unlink: file not found {}/{:?}", parent, name);
-                reply.error(ENOENT);
+                reply.fail(ENOENT);
             }
         } else {
             debug!("unlink: parent not found: {}", parent);
-            reply.error(ENOENT);
+            reply.fail(ENOENT);
         }
     }
 
@@ -344,62 +2293,213 @@ impl Filesystem for RMXFS {
         name: &OsStr,
         newparent: u64,
         newname: &OsStr,
-        _flags: u32,
+        flags: u32,
         reply: ReplyEmpty,
     ) {
         debug!(
-            "rename: {}/{} -> {}/{}",
-            parent,
-            name.to_str().unwrap(),
-            newparent,
-            newname.to_str().unwrap()
+            "rename: {}/{:?} -> {}/{:?} flags {:#x}",
+            parent, name, newparent, newname, flags
         );
-        if let Some(entry) = self.find_file(&|e: &DirEntry| {
-            e.parent_inode().unwrap_or(1) == parent && e.file_name() == name
-        }) {
-            if let Some(parent_entry) = self.dir_from_ino(newparent) {
-                if let Err(_) = entry.rename(&parent_entry, newname) {
-                    reply.error(libc::EIO);
-                    return;
-                }
-                reply.ok();
+        let name = &*normalize::os_name(name);
+        let name = self.unmarked(name);
+        let newname = &*normalize::os_name(newname);
+        let known = platform::RENAME_NOREPLACE | platform::RENAME_EXCHANGE;
+        if flags & !known != 0 || flags == known {
+            reply.fail(libc::EINVAL);
+            return;
+        }
+        if newname.len() > NAME_MAX {
+            reply.fail(libc::ENAMETOOLONG);
+            return;
+        }
+        let entries = self.list().unwrap_or_default();
+        if self.is_read_only(parent, name)
+            || self.is_read_only(newparent, newname)
+            || (in_trash_dir(&entries, parent)
+                && in_trash_dir(&entries, newparent))
+        {
+            reply.fail(libc::EPERM);
+            return;
+        }
+        if self.is_excluded(newparent, newname) {
+            reply.fail(libc::EACCES);
+            return;
+        }
+        if self.is_companion(parent, Some(name))
+            || self.is_companion(newparent, Some(newname))
+        {
+            reply.fail(libc::EROFS);
+            return;
+        }
+        if let Err(e) = self.check_change() {
+            reply.fail(e);
+            return;
+        }
+        let entry = match self
+            .find_child(parent, name)
+            .or_else(|| self.find_created(parent, name))
+        {
+            Some(entry) => entry,
+            None => {
+                debug!("rename: not found {}/{:?}", parent, name);
+                reply.fail(ENOENT);
                 return;
-            } else {
+            }
+        };
+        let parent_entry = match self.dir_from_ino(newparent) {
+            Some(parent_entry) => parent_entry,
+            None => {
                 debug!("rename: newparent not found: {}", newparent);
+                reply.fail(ENOENT);
+                return;
+            }
+        };
+        if in_mirror(entry.attr.ino) || in_mirror(newparent) {
+            reply.fail(libc::EROFS);
+            return;
+        }
+        let target = self.find_child(newparent, newname);
+        let busy = self.check_busy(&entry).and_then(|_| match &target {
+            Some(target) => self.check_busy(target),
+            None => Ok(()),
+        });
+        if let Err(e) = busy {
+            reply.fail(e);
+            return;
+        }
+
+        let res = match target {
+            // renaming onto itself changes nothing
+            Some(target) if target.attr.ino == entry.attr.ino => Ok(()),
+            Some(target) if in_mirror(target.attr.ino) => Err(libc::EROFS),
+            Some(_) if flags & platform::RENAME_NOREPLACE != 0 => {
+                Err(libc::EEXIST)
+            }
+            Some(target) if flags & platform::RENAME_EXCHANGE != 0 => {
+                match self.dir_from_ino(parent) {
+                    Some(old_parent) => exchange(
+                        &entry,
+                        self.rename_parent(&old_parent),
+                        &target,
+                        self.rename_parent(&parent_entry),
+                    ),
+                    None => Err(ENOENT),
+                }
+            }
+            None if flags & platform::RENAME_EXCHANGE != 0 => Err(ENOENT),
+            Some(target) if self.preserves_uuid(&entry, &target) => {
+                self.created.remove(&entry.prefix);
+                // the new data is that of the target now
+                if let Some(size) = self.created_sizes.remove(&entry.prefix) {
+                    self.created_sizes.insert(target.prefix.clone(), size);
+                }
+                entry
+                    .replace_content(&target)
+                    .map_err(|e| errno::from_io(&e))
+            }
+            Some(target) => {
+                let res = self.replace(&entry, &target).and_then(|_| {
+                    entry
+                        .rename(self.rename_parent(&parent_entry), newname)
+                        .map(|_| ())
+                        .map_err(|e| errno::from_io(&e))
+                });
+                if res.is_ok()
+                    && self.created_sizes.remove(&target.prefix).is_some()
+                {
+                    self.staging_changed();
+                }
+                res
+            }
+            None => entry
+                .rename(self.rename_parent(&parent_entry), newname)
+                .map(|_| ())
+                .map_err(|e| errno::from_io(&e)),
+        };
+        match res {
+            Ok(_) => {
+                stats::add(&stats::RENAMED, 1);
+                reply.ok()
+            }
+            Err(e) => {
+                debug!("rename: failed with {}", e);
+                reply.fail(e);
             }
         }
-        debug!("rename: not found {}/{}", parent, name.to_str().unwrap());
-        reply.error(ENOENT);
     }
 
-    fn open(
-        &mut self,
-        _req: &Request,
-        ino: u64,
-        _flags: i32,
-        reply: ReplyOpen,
-    ) {
+    fn open(&mut self, _req: &Request, ino: u64, flags: i32, reply: ReplyOpen) {
         debug!("open: {}", ino);
-        if let Some((counter, file)) = self.file_map.remove(&ino) {
+        self.sweep_handles();
+        // read as it is now, whatever size the kernel has
+        if ino == REFRESH_INO {
+            reply.opened(ino, consts::FOPEN_DIRECT_IO);
+            return;
+        }
+        if flags & libc::O_ACCMODE != libc::O_RDONLY
+            && self.is_companion(ino, None)
+        {
+            reply.fail(libc::EROFS);
+            return;
+        }
+        // A document is replaced by renaming a new one over it, only new
+        // files still being written take writes
+        if flags & libc::O_ACCMODE != libc::O_RDONLY
+            && !self.pending_map.contains_key(&ino)
+        {
+            debug!("open: {} can't be written", ino);
+            reply.fail(libc::EACCES);
+            return;
+        }
+        // The kernel sends release asynchronously, so an open right after
+        // close(2) may still find the file pending. It is then served from
+        // the staged data and finalized when the last handle is released.
+        if let Some((counter, _, _)) = self.pending_map.get_mut(&ino) {
+            *counter += 1;
+            self.handles.opened(ino);
+            reply.opened(ino, 0);
+        } else if let Some((counter, file)) = self.file_map.remove(&ino) {
             self.file_map.insert(ino, (counter + 1, file));
+            self.handles.opened(ino);
+            reply.opened(ino, 0);
+        } else if let Some((counter, _)) = self.companion_map.get_mut(&ino) {
+            *counter += 1;
+            self.handles.opened(ino);
             reply.opened(ino, 0);
         } else {
             match self.find_file(&|e: &DirEntry| ino == e.attr.ino) {
+                // merged highlights are read from the pages as needed
+                Some(entry)
+                    if entry.entry_type == EntryType::HIGHLIGHTS
+                        && self.options.highlights == Highlights::Merged =>
+                {
+                    self.companion_map
+                        .insert(ino, (1, entry.source_file_path()));
+                    self.handles.opened(ino);
+                    reply.opened(ino, 0);
+                }
                 Some(entry) => {
-                    let mut path = PathBuf::from(&self.source_dir);
-                    path.push(entry.prefix);
-                    path.set_extension(entry_type_ext(&entry.entry_type));
-                    if let Ok(file) = fs::File::open(&path) {
-                        self.file_map.insert(ino, (1, file));
-                        reply.opened(ino, 0);
-                    } else {
-                        debug!("open failed: {}", ino);
-                        reply.error(libc::ENODATA);
+                    let path = entry.source_file_path();
+                    let open = move || retry(|| fs::File::open(&path));
+                    match deadline::run_for(ino, "open", open) {
+                        Ok(file) if self.is_stale(ino, &file) => {
+                            debug!("open: {} changed since the snapshot", ino);
+                            reply.fail(libc::ESTALE);
+                        }
+                        Ok(file) => {
+                            self.file_map.insert(ino, (1, Arc::new(file)));
+                            self.handles.opened(ino);
+                            reply.opened(ino, 0);
+                        }
+                        Err(e) => {
+                            debug!("open failed: {}: {}", ino, e);
+                            reply.fail(errno::from_io(&e));
+                        }
                     }
                 }
                 None => {
                     debug!("open: not found {}", ino);
-                    reply.error(ENOENT);
+                    reply.fail(ENOENT);
                 }
             }
         }
@@ -416,27 +2516,75 @@ impl Filesystem for RMXFS {
         reply: ReplyEmpty,
     ) {
         debug!("release: {}", fh);
-        if let Some((entry, _)) = self.pending_map.remove(&fh) {
-            if let Err(e) = entry.finalize_pending() {
+        if fh == REFRESH_INO {
+            reply.ok();
+            return;
+        }
+        if let Some((counter, entry, file)) = self.pending_map.remove(&fh) {
+            if counter > 1 {
+                debug!("release: pending {} ref {}", fh, counter);
+                self.pending_map.insert(fh, (counter - 1, entry, file));
+                reply.ok();
+                return;
+            }
+            self.handles.closed(fh);
+            deadline::forget(fh);
+            self.staging_changed();
+            if self.duplicates.remove(&fh) {
+                drop(file);
+                entry.discard_pending();
+                if let Some(index) = &mut self.dedup {
+                    index.remove(&entry.prefix);
+                }
+                reply.ok();
+                return;
+            }
+            // what was written before the quota ran out isn't kept
+            if self.over_quota.remove(&fh) {
+                drop(file);
+                entry.discard_pending();
+                reply.ok();
+                return;
+            }
+            let size = file.extent();
+            if let Err(e) = self.finalize(&entry, file) {
                 debug!("release: couldn't finalize pending file {}", e);
-                reply.error(libc::EIO);
+                reply.fail(errno::from_io(&e));
                 return;
             } else {
+                if let Some(index) = &mut self.dedup {
+                    index.add(&entry);
+                }
+                self.created_sizes.insert(entry.prefix.clone(), size);
+                self.staging_changed();
                 reply.ok();
                 return;
             }
         }
+        if let Some((counter, dir)) = self.companion_map.remove(&fh) {
+            if counter > 1 {
+                self.companion_map.insert(fh, (counter - 1, dir));
+            } else {
+                self.handles.closed(fh);
+                deadline::forget(fh);
+            }
+            reply.ok();
+            return;
+        }
         match self.file_map.remove(&fh) {
             Some((counter, file)) => {
                 debug!("release: {} ref {}", fh, counter);
                 if counter > 1 {
                     self.file_map.insert(fh, (counter - 1, file));
+                } else {
+                    self.handles.closed(fh);
+                    deadline::forget(fh);
                 }
                 reply.ok();
             }
             None => {
                 debug!("releasedir failed on: {}", fh);
-                reply.error(ENOENT);
+                reply.fail(ENOENT);
             }
         }
     }
@@ -448,26 +2596,68 @@ impl Filesystem for RMXFS {
         fh: u64,
         offset: i64,
         size: u32,
-        _flags: i32,
+        flags: i32,
         _lock: Option<u64>,
         reply: ReplyData,
     ) {
-        if let Some((_, file)) = self.file_map.get(&fh) {
-            use std::cmp::min;
-            use std::os::unix::fs::FileExt;
-            let file_size = file.metadata().unwrap().len();
-            let read_size =
-                min(size, file_size.saturating_sub(offset as u64) as u32);
-            let mut buffer = vec![0; read_size as usize];
-            if let Err(e) = file.read_exact_at(&mut buffer, offset as u64) {
-                debug!("read: error {}", e);
-                reply.error(libc::EIO);
-            } else {
-                reply.data(&buffer);
+        self.handles.used(fh);
+        self.sweep_handles();
+        // the flags are those the file was opened with
+        if flags & libc::O_ACCMODE == libc::O_WRONLY {
+            debug!("read: opened write-only {}", fh);
+            reply.fail(libc::EBADF);
+            return;
+        }
+        if fh == REFRESH_INO {
+            let data = control::last_scan(self.last_scan.get()).into_bytes();
+            let start = (offset as usize).min(data.len());
+            let end = (start + size as usize).min(data.len());
+            reply.data(&data[start..end]);
+            return;
+        }
+        if let Some((_, dir)) = self.companion_map.get(&fh) {
+            match highlights::read_merged(dir, offset as u64, size as usize) {
+                Ok(data) => {
+                    stats::add(&stats::BYTES_READ, data.len() as u64);
+                    reply.data(&data)
+                }
+                Err(e) => {
+                    debug!("read: highlights {:?}: {}", dir, e);
+                    reply.fail(errno::from_io(&e));
+                }
+            }
+            return;
+        }
+        // files being written can be read back through the same handle,
+        // in order with the writes
+        if let Some((_, _, file)) = self.pending_map.get_mut(&fh) {
+            match file.flushed() {
+                Ok(file) => reply_read(
+                    &self.buffers,
+                    fh,
+                    file,
+                    offset as u64,
+                    size,
+                    reply,
+                ),
+                Err(e) => {
+                    debug!("read: couldn't write out {}: {}", fh, e);
+                    reply.fail(errno::from_io(&e));
+                }
+            }
+        } else if let Some((_, file)) = self.file_map.get(&fh) {
+            if self.is_stale(fh, file) {
+                debug!("read: {} changed since the snapshot", fh);
+                reply.fail(libc::ESTALE);
+                return;
             }
+            let (file, pool) = (Arc::clone(file), Arc::clone(&self.buffers));
+            self.workers.submit(fh, move || {
+                reply_read(&pool, fh, &file, offset as u64, size, reply)
+            });
         } else {
             debug!("read: not opened {}", fh);
-            reply.error(ENOENT)
+            reply.fail(ENOENT)
         }
     }
 
@@ -479,38 +2669,180 @@ impl Filesystem for RMXFS {
         offset: i64,
         data: &[u8],
         _write_flags: u32,
-        _flags: i32,
+        flags: i32,
         _lock_owner: Option<u64>,
         reply: ReplyWrite,
     ) {
-        debug!("write: {} {}", fh, offset);
-        if let Some((entry, file)) = self.pending_map.get_mut(&fh) {
+        trace!("write: {} {}", fh, offset);
+        self.handles.used(fh);
+        self.sweep_handles();
+        if flags & libc::O_ACCMODE == libc::O_RDONLY {
+            debug!("write: opened read-only {}", fh);
+            reply.fail(libc::EBADF);
+            return;
+        }
+        if fh == REFRESH_INO {
+            debug!("write: refresh");
+            self.refresh();
+            reply.written(data.len() as u32);
+            return;
+        }
+        if deadline::timed_out(fh) {
+            debug!("write: an operation of {} timed out", fh);
+            reply.fail(libc::ETIMEDOUT);
+            return;
+        }
+        let grows = self.pending_map.get(&fh).map_or(0, |(_, _, file)| {
+            (offset as u64 + data.len() as u64).saturating_sub(file.extent())
+        });
+        if let Some(max) = self.options.max_staging_bytes {
+            if grows > 0 && self.staged_bytes() + grows > max {
+                debug!("write: staging is over {} bytes", max);
+                reply.fail(libc::ENOSPC);
+                return;
+            }
+        }
+        if let Some(quota) = self.options.quota {
+            if grows > 0 && self.quota_used() + grows > quota {
+                debug!("write: over the quota of {} bytes", quota);
+                self.over_quota.insert(fh);
+                reply.fail(libc::EDQUOT);
+                return;
+            }
+        }
+        if let Some((_, entry, file)) = self.pending_map.get_mut(&fh) {
             // Check file compatibility and abort early
             if offset == 0 {
                 if let Err(ext) = entry.update_type(data) {
                     debug!("write: unsupported type: {}", ext);
                     entry.forget_pending();
-                    reply.error(libc::ENOSYS);
+                    reply.fail(libc::ENOSYS);
                     return;
                 }
             }
-            if let Err(e) = file.seek(io::SeekFrom::Start(offset as u64)) {
-                debug!("write: seek failed: {}", e);
-                reply.error(libc::EIO);
-                return;
+            let mut written = file.write_at(offset as u64, data);
+            if self.options.sync_write {
+                written = written.and_then(|_| file.sync(true));
             }
-
-            if let Err(e) = file.write_all(data) {
+            if let Err(e) = written {
                 debug!("write: write failed: {}", e);
-                reply.error(libc::EIO);
+                reply.fail(errno::from_io(&e));
                 return;
             }
 
+            stats::add(&stats::BYTES_WRITTEN, data.len() as u64);
+            self.staging_changed();
             reply.written(data.len() as u32);
         } else {
             debug!("write: no pending file open: {}", fh);
-            reply.error(ENOENT);
+            reply.fail(ENOENT);
+        }
+    }
+
+    // close(2), once per file descriptor: its errors are those of writing
+    // out what was collected, EEXIST for a copy with --dedup fail, and
+    // EDQUOT for a file that went over --quota, which isn't kept
+    fn flush(
+        &mut self,
+        _req: &Request<'_>,
+        _ino: u64,
+        fh: u64,
+        _lock_owner: u64,
+        reply: ReplyEmpty,
+    ) {
+        let last = match self.pending_map.get_mut(&fh) {
+            Some((counter, _, file)) => match file.flush() {
+                Ok(()) => *counter == 1,
+                Err(e) => {
+                    debug!("flush: failed on {}: {}", fh, e);
+                    reply.fail(errno::from_io(&e));
+                    return;
+                }
+            },
+            None => false,
+        };
+        if last && self.over_quota.contains(&fh) {
+            reply.fail(libc::EDQUOT);
+            return;
+        }
+        if last && self.dedup.is_some() && self.is_duplicate(fh) {
+            self.duplicates.insert(fh);
+            if self.options.dedup == Dedup::Fail {
+                reply.fail(libc::EEXIST);
+                return;
+            }
+        }
+        reply.ok();
+    }
+
+    // Only pending files were written, the others have nothing to sync
+    fn fsync(
+        &mut self,
+        _req: &Request<'_>,
+        _ino: u64,
+        fh: u64,
+        datasync: bool,
+        reply: ReplyEmpty,
+    ) {
+        self.handles.used(fh);
+        match self.pending_map.get_mut(&fh) {
+            Some((_, _, file)) => match file.sync(datasync) {
+                Ok(()) => reply.ok(),
+                Err(e) => {
+                    debug!("fsync: failed on {}: {}", fh, e);
+                    reply.fail(errno::from_io(&e));
+                }
+            },
+            None => reply.ok(),
+        }
+    }
+
+    // Folders are only in the metadata, every document of one is in the flat
+    // source dir, and the data of the pending ones in the staging dir.
+    // Recent, .deleted, the orphans and read-only sources have nothing to
+    // sync.
+    fn fsyncdir(
+        &mut self,
+        _req: &Request<'_>,
+        ino: u64,
+        _fh: u64,
+        datasync: bool,
+        reply: ReplyEmpty,
+    ) {
+        let dir = match self.dir_from_ino(ino) {
+            Some(dir) if dir.attr.kind == FileType::Directory => dir,
+            Some(_) => {
+                reply.fail(libc::ENOTDIR);
+                return;
+            }
+            None => {
+                debug!("fsyncdir: not found: {}", ino);
+                reply.fail(ENOENT);
+                return;
+            }
+        };
+        if ino == RECENT_INO
+            || ino == DELETED_INO
+            || ino == ORPHANS_INO
+            || in_mirror(ino)
+        {
+            reply.ok();
+            return;
+        }
+        let mut dirs = vec![self.source_dir.clone()];
+        if self.pending_map.values().any(|(_, e, _)| e.is_parent(&dir)) {
+            dirs.push(staging::data_dir(&self.source_dir));
+            dirs.push(self.source_dir.join(".pending"));
+            dirs.dedup();
+        }
+        for path in dirs.iter() {
+            if let Err(e) = sync_dir(path, datasync) {
+                debug!("fsyncdir: failed on {:?}: {}", path, e);
+                reply.fail(errno::from_io(&e));
+                return;
+            }
         }
+        reply.ok();
     }
 
     fn opendir(
@@ -521,38 +2853,70 @@ impl Filesystem for RMXFS {
         reply: ReplyOpen,
     ) {
         debug!("opendir: {}", ino);
+        self.sweep_handles();
+        let fh = self.next_dir_fh;
+        if ino == CONTROL_INO {
+            let refresh = self.control_entry(REFRESH_INO);
+            self.next_dir_fh += 1;
+            self.dir_map.insert(fh, (ino, listed(&refresh)));
+            reply.opened(fh, 0);
+            return;
+        }
         let parent = match self.dir_from_ino(ino) {
             Some(entry) => entry,
             None => {
                 debug!("opendir: not found: {}", ino);
-                reply.error(ENOENT);
+                reply.fail(ENOENT);
                 return;
             }
         };
+        // dir_from_ino finds documents too, they have no listing
+        if parent.attr.kind != FileType::Directory {
+            debug!("opendir: not a dir: {}", ino);
+            reply.fail(libc::ENOTDIR);
+            return;
+        }
 
-        if let Some((counter, entries)) = self.dir_map.remove(&ino) {
-            self.dir_map.insert(ino, (counter + 1, entries));
-            reply.opened(ino, 0);
+        let control = match ino == 1 && self.options.expose_control {
+            true => self.control_entry(CONTROL_INO),
+            false => None,
+        };
+        let listing = if ino == RECENT_INO {
+            // already newest first
+            self.recent().map(|entries| self.listed(&entries))
         } else {
-            match list_dir_metadata(&self.source_dir) {
-                Ok(entries) => {
-                    self.dir_map.insert(
-                        ino,
-                        (
-                            1,
-                            Vec::from_iter(
-                                entries
-                                    .into_iter()
-                                    .filter(|e| e.is_parent(&parent)),
-                            ),
-                        ),
-                    );
-                    reply.opened(ino, 0);
+            self.list().map(|entries| {
+                let mut entries =
+                    dir_snapshot(&entries, &parent, &self.options);
+                entries.extend(&control);
+                self.listed(entries)
+            })
+        };
+        match listing {
+            Ok(snapshot) => {
+                let open = self.dir_map.values();
+                let snapshot = share(
+                    snapshot,
+                    open.filter(|(dir, _)| *dir == ino).map(|(_, s)| s),
+                );
+                self.next_dir_fh += 1;
+                self.dir_map.insert(fh, (ino, snapshot));
+                // a snapshot keeps listing what it has
+                if !parent.associated_paths().is_empty()
+                    && self.frozen.is_none()
+                {
+                    self.dir_metadata.insert(fh, parent.metadata_file_name());
                 }
-                Err(_e) => {
-                    reply.error(ENOENT);
+                reply.opened(fh, 0);
+                if !self.library_counted {
+                    self.library_counted = true;
+                    self.count_library();
                 }
             }
+            Err(e) => {
+                debug!("opendir: listing failed: {}", e);
+                reply.fail(errno::from_io(&e));
+            }
         }
     }
 
@@ -565,16 +2929,14 @@ impl Filesystem for RMXFS {
         reply: ReplyEmpty,
     ) {
         match self.dir_map.remove(&fh) {
-            Some((counter, entries)) => {
-                debug!("releasedir: {} ref {}", fh, counter);
-                if counter > 1 {
-                    self.dir_map.insert(fh, (counter - 1, entries));
-                }
+            Some(_) => {
+                debug!("releasedir: {}", fh);
+                self.dir_metadata.remove(&fh);
                 reply.ok();
             }
             None => {
                 debug!("releasedir failed on: {}", fh);
-                reply.error(ENOENT);
+                reply.fail(ENOENT);
             }
         }
     }
@@ -588,27 +2950,473 @@ impl Filesystem for RMXFS {
         mut reply: ReplyDirectory,
     ) {
         debug!("readdir: {}", fh);
+        // One removed while open (from here or on the tablet) lists nothing
+        // from then on, and is no longer found by getattr or lookup. A stat
+        // per call tells.
+        let removed = self.dir_metadata.get(&fh).is_some_and(|path| {
+            fs::symlink_metadata(path)
+                .is_err_and(|e| e.kind() == io::ErrorKind::NotFound)
+        });
+        if removed {
+            debug!("readdir: {} was removed", fh);
+            reply.ok();
+            return;
+        }
         if let Some((_, entries)) = self.dir_map.get(&fh) {
-            for (i, entry) in
-                entries.into_iter().enumerate().skip(offset as usize)
-            {
-                if reply.add(
-                    entry.attr.ino,
-                    (i + 1) as i64,
-                    if entry.entry_type == EntryType::PDF {
-                        FileType::RegularFile
-                    } else {
-                        FileType::Directory
-                    },
-                    entry.file_name(),
-                ) {
+            for (i, entry) in entries.iter().enumerate().skip(offset as usize) {
+                if reply.add(entry.ino, (i + 1) as i64, entry.kind, &entry.name)
+                {
                     break;
                 }
             }
             reply.ok();
         } else {
             debug!("readdir: no handle {}", fh);
-            reply.error(ENOENT);
+            reply.fail(ENOENT);
+        }
+    }
+
+    fn statfs(&mut self, _req: &Request<'_>, _ino: u64, reply: ReplyStatfs) {
+        debug!("statfs");
+        // counted once, the listings of other operations keep it current
+        if self.library_size.get().is_none() {
+            if let Err(e) = self.list() {
+                debug!("statfs: listing failed: {}", e);
+            }
+        }
+        let files = self.library_size.get().unwrap_or(0);
+        let space =
+            space::of(&self.source_dir).map(|space| match self.options.quota {
+                Some(quota) => {
+                    space.within(quota.saturating_sub(self.quota_used()))
+                }
+                None => space,
+            });
+        match space {
+            Ok(space) => reply.statfs(
+                space.blocks,
+                space.bfree,
+                space.bavail,
+                files + space::FREE_INODES,
+                space::FREE_INODES,
+                space.bsize,
+                NAME_MAX as u32,
+                space.frsize,
+            ),
+            Err(e) => {
+                debug!("statfs: can't stat the source: {}", e);
+                reply.fail(errno::from_io(&e));
+            }
+        }
+    }
+
+    fn getxattr(
+        &mut self,
+        _req: &Request<'_>,
+        ino: u64,
+        name: &OsStr,
+        size: u32,
+        reply: ReplyXattr,
+    ) {
+        debug!("getxattr: {} {:?}", ino, name);
+        if name == STATS_XATTR && ino == 1 {
+            self.count_unsynced();
+            reply_xattr(reply, size, stats::summary().as_bytes());
+            return;
+        }
+        if name == MISSING_DATA_XATTR {
+            let missing = self
+                .find_file(&|e: &DirEntry| e.attr.ino == ino)
+                .is_some_and(|e| e.entry_type == EntryType::MISSING);
+            if missing {
+                reply_xattr(reply, size, b"true");
+            } else {
+                reply.error(platform::ENOATTR);
+            }
+            return;
         }
+        if name == SYNCED_XATTR {
+            let entry = self.find_file(&|e: &DirEntry| e.attr.ino == ino);
+            match entry.and_then(|e| e.synced) {
+                Some(true) => reply_xattr(reply, size, b"true"),
+                Some(false) => reply_xattr(reply, size, b"false"),
+                None => reply.error(platform::ENOATTR),
+            }
+            return;
+        }
+        if name == TAGS_XATTR {
+            let entry = self.find_file(&|e: &DirEntry| e.attr.ino == ino);
+            match entry.filter(has_tags).map(|e| e.tags()) {
+                Some(Ok(tags)) => match serde_json::to_vec(&tags) {
+                    Ok(value) => reply_xattr(reply, size, &value),
+                    Err(_) => reply.fail(libc::EIO),
+                },
+                Some(Err(e)) => {
+                    debug!("getxattr: can't read tags of {}: {}", ino, e);
+                    reply.fail(errno::from_io(&e));
+                }
+                None => reply.error(platform::ENOATTR),
+            }
+            return;
+        }
+        if name != SHA256_XATTR {
+            reply.error(platform::ENOATTR);
+            return;
+        }
+        let entry = match self.find_file(&|e: &DirEntry| e.attr.ino == ino) {
+            Some(entry) => entry,
+            // the virtual dirs and the control files have no data
+            None if ino <= REFRESH_INO => {
+                reply.error(platform::ENOATTR);
+                return;
+            }
+            None => {
+                reply.fail(ENOENT);
+                return;
+            }
+        };
+        if entry.entry_type == EntryType::NONE
+            || entry.entry_type == EntryType::MISSING
+        {
+            reply.error(platform::ENOATTR);
+            return;
+        }
+
+        let path = entry.source_file_path();
+        let (len, mtime) = match fs::metadata(&path) {
+            Ok(meta) => (meta.len(), meta.modified().unwrap_or(UNIX_EPOCH)),
+            Err(e) => {
+                debug!("getxattr: can't stat {:?}: {}", path, e);
+                reply.fail(errno::from_io(&e));
+                return;
+            }
+        };
+        if let Some((_, _, hash)) = self
+            .hashes
+            .lock()
+            .unwrap()
+            .get(&path)
+            .filter(|(l, m, _)| *l == len && *m == mtime)
+        {
+            reply_xattr(reply, size, hash.as_bytes());
+            return;
+        }
+
+        // Hashing a big document takes a while, don't hold up the session
+        let hashes = Arc::clone(&self.hashes);
+        self.workers
+            .submit(ino, move || match sha256::hash_file(&path) {
+                Ok(hash) => {
+                    reply_xattr(reply, size, hash.as_bytes());
+                    hashes.lock().unwrap().insert(path, (len, mtime, hash));
+                }
+                Err(e) => {
+                    debug!("getxattr: hashing {:?} failed: {}", path, e);
+                    reply.fail(errno::from_io(&e));
+                }
+            });
+    }
+
+    fn listxattr(
+        &mut self,
+        _req: &Request<'_>,
+        ino: u64,
+        size: u32,
+        reply: ReplyXattr,
+    ) {
+        debug!("listxattr: {}", ino);
+        if ino == 1 {
+            let names = format!("{}\0", STATS_XATTR);
+            reply_xattr(reply, size, names.as_bytes());
+            return;
+        }
+        let entry = self.find_file(&|e: &DirEntry| e.attr.ino == ino);
+        // listed once there are any, like an attribute that was set
+        let tagged = entry
+            .as_ref()
+            .filter(|e| has_tags(e))
+            .is_some_and(|e| e.tags().is_ok_and(|tags| !tags.is_empty()));
+        let tags = if tagged {
+            format!("{}\0", TAGS_XATTR)
+        } else {
+            String::new()
+        };
+        match entry.map(|e| e.entry_type) {
+            Some(EntryType::MISSING) => reply_xattr(
+                reply,
+                size,
+                format!("{}\0{}", MISSING_DATA_XATTR, tags).as_bytes(),
+            ),
+            Some(EntryType::NONE) | None => reply_xattr(reply, size, b""),
+            Some(_) => reply_xattr(
+                reply,
+                size,
+                format!("{}\0{}", SHA256_XATTR, tags).as_bytes(),
+            ),
+        }
+    }
+
+    fn setxattr(
+        &mut self,
+        _req: &Request<'_>,
+        ino: u64,
+        name: &OsStr,
+        value: &[u8],
+        _flags: i32,
+        _position: u32,
+        reply: ReplyEmpty,
+    ) {
+        debug!("setxattr: {} {:?}", ino, name);
+        if control::is_control(ino) {
+            reply.fail(libc::EPERM);
+            return;
+        }
+        if name == ACL_XATTR && self.options.attr_changes != AttrChanges::Refuse
+        {
+            reply.ok();
+            return;
+        }
+        if name != TAGS_XATTR {
+            reply.fail(libc::ENOTSUP);
+            return;
+        }
+        let tags = match tags::parse(value) {
+            Some(tags) => tags,
+            None => {
+                reply.fail(libc::EINVAL);
+                return;
+            }
+        };
+        self.set_tags(ino, &tags, reply);
+    }
+
+    fn removexattr(
+        &mut self,
+        _req: &Request<'_>,
+        ino: u64,
+        name: &OsStr,
+        reply: ReplyEmpty,
+    ) {
+        debug!("removexattr: {} {:?}", ino, name);
+        if control::is_control(ino) {
+            reply.fail(libc::EPERM);
+            return;
+        }
+        if name != TAGS_XATTR {
+            reply.error(platform::ENOATTR);
+            return;
+        }
+        self.set_tags(ino, &[], reply);
+    }
+
+    // The commands of `fuse-rm ctl`, on the root only
+    fn ioctl(
+        &mut self,
+        _req: &Request<'_>,
+        ino: u64,
+        _fh: u64,
+        _flags: u32,
+        cmd: u32,
+        in_data: &[u8],
+        _out_size: u32,
+        reply: ReplyIoctl,
+    ) {
+        debug!("ioctl: {} {:#x}", ino, cmd);
+        let command = match Command::from_cmd(cmd) {
+            Some(command) if ino == 1 => command,
+            _ => {
+                reply.error(libc::ENOTTY);
+                return;
+            }
+        };
+        let arg = match ctl::decode(in_data) {
+            Some(arg) => arg,
+            None => {
+                reply.fail(libc::EINVAL);
+                return;
+            }
+        };
+        let answer = match command {
+            Command::FlushCaches => {
+                self.flush_caches();
+                Vec::new()
+            }
+            Command::GetStats => {
+                self.count_unsynced();
+                stats::json().to_string().into_bytes()
+            }
+            Command::SetLogLevel => {
+                match std::str::from_utf8(arg).ok().and_then(|s| s.parse().ok())
+                {
+                    Some(level) => logging::set_level(level),
+                    None => {
+                        reply.fail(libc::EINVAL);
+                        return;
+                    }
+                }
+                Vec::new()
+            }
+            Command::TriggerRescan => {
+                self.refresh();
+                Vec::new()
+            }
+        };
+        match ctl::encode(&answer) {
+            Some(message) => reply.ioctl(0, &message),
+            None => reply.fail(libc::E2BIG),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // The fixture of the mount tests, only listed. Request has no public
+    // constructor, so the tests call what lookup and forget do.
+    fn mounted() -> RMXFS {
+        let source = concat!(env!("CARGO_MANIFEST_DIR"), "/test-data/source");
+        RMXFS::new(source, Options::default())
+    }
+
+    fn looked_up(fs: &mut RMXFS, name: &str) -> DirEntry {
+        let entry = fs.find_child(1, OsStr::new(name)).unwrap();
+        fs.remember(&entry);
+        entry
+    }
+
+    fn hash(fs: &RMXFS, entry: &DirEntry) {
+        let path = entry.source_file_path();
+        let hash = (entry.attr.size, entry.attr.mtime, "cached".to_string());
+        fs.hashes.lock().unwrap().insert(path, hash);
+    }
+
+    fn is_hashed(fs: &RMXFS, entry: &DirEntry) -> bool {
+        let path = entry.source_file_path();
+        fs.hashes.lock().unwrap().contains_key(&path)
+    }
+
+    #[test]
+    fn evicted_with_the_last_lookup() {
+        let mut fs = mounted();
+        let entry = looked_up(&mut fs, "ipsum.pdf");
+        looked_up(&mut fs, "ipsum.pdf");
+        hash(&fs, &entry);
+        fs.forget_inode(entry.attr.ino, 1);
+        assert!(is_hashed(&fs, &entry));
+        fs.forget_inode(entry.attr.ino, 1);
+        assert!(!is_hashed(&fs, &entry));
+    }
+
+    #[test]
+    fn batch_forget() {
+        let mut fs = mounted();
+        let entry = looked_up(&mut fs, "lorem.epub");
+        for _ in 0..4 {
+            looked_up(&mut fs, "lorem.epub");
+        }
+        hash(&fs, &entry);
+        // the count of the kernel at once, as batch_forget passes it
+        fs.forget_inode(entry.attr.ino, 5);
+        assert!(!is_hashed(&fs, &entry));
+    }
+
+    #[test]
+    fn kept_while_open() {
+        let mut fs = mounted();
+        let entry = looked_up(&mut fs, "ipsum.pdf");
+        hash(&fs, &entry);
+        let file = fs::File::open(entry.source_file_path()).unwrap();
+        fs.file_map.insert(entry.attr.ino, (1, Arc::new(file)));
+        fs.forget_inode(entry.attr.ino, 1);
+        assert!(is_hashed(&fs, &entry));
+    }
+
+    #[test]
+    fn others_are_kept() {
+        let mut fs = mounted();
+        let ipsum = looked_up(&mut fs, "ipsum.pdf");
+        let lorem = looked_up(&mut fs, "lorem.epub");
+        hash(&fs, &ipsum);
+        hash(&fs, &lorem);
+        fs.forget_inode(ipsum.attr.ino, 1);
+        // one the kernel never looked up, or already forgot
+        fs.forget_inode(ipsum.attr.ino, 1);
+        fs.forget_inode(u64::MAX, 1);
+        assert!(!is_hashed(&fs, &ipsum));
+        assert!(is_hashed(&fs, &lorem));
+    }
+
+    fn attr_of(path: &Path) -> FileAttr {
+        let parent = path.parent().unwrap();
+        let entry = fs::read_dir(parent)
+            .unwrap()
+            .map(|e| e.unwrap())
+            .find(|e| e.path() == path)
+            .unwrap();
+        conv_attr(&entry).unwrap()
+    }
+
+    #[test]
+    fn attr_without_type_bits() {
+        use std::os::unix::fs::PermissionsExt;
+        let dir = std::env::temp_dir()
+            .join(format!("fuse-rm-rmxfs-{}-attr", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(dir.join("folder")).unwrap();
+        fs::write(dir.join("file"), "data").unwrap();
+        let mode = |mode| fs::Permissions::from_mode(mode);
+        fs::set_permissions(dir.join("folder"), mode(0o750)).unwrap();
+        fs::set_permissions(dir.join("file"), mode(0o4640)).unwrap();
+
+        let folder = attr_of(&dir.join("folder"));
+        assert_eq!(folder.kind, FileType::Directory);
+        assert_eq!(folder.perm, 0o750);
+        let file = attr_of(&dir.join("file"));
+        assert_eq!(file.kind, FileType::RegularFile);
+        // the setuid bit is kept, S_IFREG isn't
+        assert_eq!(file.perm, 0o4640);
+        assert_eq!(file.size, 4);
+        let meta = fs::metadata(dir.join("file")).unwrap();
+        assert_eq!(file.ino, meta.ino());
+        assert_ne!(u32::from(file.perm), meta.mode());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn trash_dirs() {
+        let dir = std::env::temp_dir()
+            .join(format!("fuse-rm-rmxfs-{}-trash", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        // (uuid, parent), folders in the trash, outside it and in a loop
+        let folders = [
+            ("old", "trash"),
+            ("older", "old"),
+            ("work", ""),
+            ("loop-a", "loop-b"),
+            ("loop-b", "loop-a"),
+        ];
+        for (uuid, parent) in folders {
+            let metadata = serde_json::json!({
+                "parent": parent,
+                "visibleName": uuid,
+                "type": "CollectionType",
+            });
+            let path = dir.join(uuid).with_extension("metadata");
+            fs::write(path, metadata.to_string()).unwrap();
+        }
+        let entries = list_dir_metadata(&dir).unwrap();
+        let ino = |uuid: &str| {
+            let path = dir.join(uuid).with_extension("metadata");
+            fs::metadata(path).unwrap().ino()
+        };
+        assert!(in_trash_dir(&entries, TRASH_INO));
+        assert!(in_trash_dir(&entries, ino("old")));
+        assert!(in_trash_dir(&entries, ino("older")));
+        assert!(!in_trash_dir(&entries, 1));
+        assert!(!in_trash_dir(&entries, ino("work")));
+        assert!(!in_trash_dir(&entries, ino("loop-a")));
+        fs::remove_dir_all(&dir).unwrap();
     }
 }
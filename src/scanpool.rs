@@ -0,0 +1,108 @@
+use std::convert::TryFrom;
+use std::io;
+use std::num::NonZeroUsize;
+use std::panic;
+use std::sync::OnceLock;
+use std::thread;
+
+// A listing parses every metadata file of the source, thousands of small
+// JSON files for a big library. They are parsed by a few threads, each
+// taking a run of the files in the order read_dir gave them, and the runs
+// are put back in that order, so a listing comes out the same whichever
+// thread finishes first. The tablet has two cores, elsewhere there is one
+// thread per core unless --scan-threads says otherwise.
+//
+// Every thread has one file open at a time, and they are fewer than what
+// the descriptor limit leaves for the open files of the mount.
+
+static THREADS: OnceLock<usize> = OnceLock::new();
+
+// with fewer files, starting threads costs more than they save
+const MIN_PARALLEL: usize = 64;
+const MAX_THREADS: usize = 16;
+// of the descriptor limit, the rest is for the mount
+const FDS_PER_THREAD: usize = 8;
+
+fn default_threads() -> usize {
+    if cfg!(target_arch = "arm") {
+        2
+    } else {
+        thread::available_parallelism().map_or(1, NonZeroUsize::get)
+    }
+}
+
+pub fn configure(threads: Option<usize>) {
+    THREADS.get_or_init(|| threads.unwrap_or_else(default_threads));
+}
+
+/// The threads a listing is parsed by
+pub fn threads() -> usize {
+    *THREADS.get_or_init(default_threads)
+}
+
+fn fd_limit() -> usize {
+    let mut limit = libc::rlimit {
+        rlim_cur: 0,
+        rlim_max: 0,
+    };
+    match unsafe { libc::getrlimit(libc::RLIMIT_NOFILE, &mut limit) } {
+        0 => usize::try_from(limit.rlim_cur).unwrap_or(usize::MAX),
+        _ => 1024,
+    }
+}
+
+fn run<T, R, F>(items: &[T], f: &F, res: &mut Vec<R>) -> io::Result<()>
+where
+    F: Fn(&T) -> io::Result<Option<R>>,
+{
+    res.reserve(items.len());
+    for item in items {
+        res.extend(f(item)?);
+    }
+    Ok(())
+}
+
+/// Adds to `res` what `f` gives for each of `items` that it gives something
+/// for, in the order of the items, with `threads` threads. The error is
+/// that of the first item that failed.
+pub fn extend<T, R, F>(
+    res: &mut Vec<R>,
+    items: &[T],
+    threads: usize,
+    f: F,
+) -> io::Result<()>
+where
+    T: Sync,
+    R: Send,
+    F: Fn(&T) -> io::Result<Option<R>> + Sync,
+{
+    let threads = threads
+        .min(MAX_THREADS)
+        .min(fd_limit() / FDS_PER_THREAD)
+        .max(1);
+    if threads == 1 || items.len() < MIN_PARALLEL {
+        return run(items, &f, res);
+    }
+    let f = &f;
+    let runs: Vec<thread::Result<io::Result<Vec<R>>>> = thread::scope(|s| {
+        let handles: Vec<_> = items
+            .chunks(items.len().div_ceil(threads))
+            .map(|items| {
+                s.spawn(move || {
+                    let mut res = Vec::new();
+                    run(items, f, &mut res).map(|_| res)
+                })
+            })
+            .collect();
+        handles.into_iter().map(|handle| handle.join()).collect()
+    });
+    res.reserve(items.len());
+    for run in runs {
+        match run {
+            Ok(run) => res.extend(run?),
+            // as if it had been parsed by the calling thread
+            Err(e) => panic::resume_unwind(e),
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,86 @@
+// Minimal systemd notification protocol (see sd_notify(3)): newline separated
+// VAR=value assignments in a datagram sent to $NOTIFY_SOCKET
+use std::env;
+use std::io;
+use std::os::unix::net::UnixDatagram;
+use std::time::Duration;
+
+pub fn encode(vars: &[(&str, &str)]) -> String {
+    vars.iter()
+        .map(|(var, value)| format!("{}={}\n", var, value))
+        .collect()
+}
+
+// Does nothing (and returns false) when not started by systemd
+pub fn notify(vars: &[(&str, &str)]) -> io::Result<bool> {
+    let socket_path = match env::var_os("NOTIFY_SOCKET") {
+        Some(path) => path,
+        None => return Ok(false),
+    };
+    let socket = UnixDatagram::unbound()?;
+    let msg = encode(vars);
+    #[cfg(target_os = "linux")]
+    {
+        let path = socket_path.to_string_lossy();
+        if let Some(name) = path.strip_prefix('@') {
+            use std::os::linux::net::SocketAddrExt;
+            let addr =
+                std::os::unix::net::SocketAddr::from_abstract_name(name)?;
+            socket.send_to_addr(msg.as_bytes(), &addr)?;
+            return Ok(true);
+        }
+    }
+    socket.send_to(msg.as_bytes(), &socket_path)?;
+    Ok(true)
+}
+
+// Pings the watchdog at half the interval systemd asked for
+pub fn spawn_watchdog() {
+    let usec = match env::var("WATCHDOG_USEC").map(|v| v.parse::<u64>()) {
+        Ok(Ok(usec)) if usec > 0 => usec,
+        _ => return,
+    };
+    let interval = Duration::from_micros(usec / 2);
+    std::thread::spawn(move || loop {
+        if let Err(e) = notify(&[("WATCHDOG", "1")]) {
+            error!("watchdog notification failed: {}", e);
+        }
+        std::thread::sleep(interval);
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn encode_assignments() {
+        assert_eq!(encode(&[]), "");
+        assert_eq!(encode(&[("READY", "1")]), "READY=1\n");
+        assert_eq!(
+            encode(&[("READY", "1"), ("STATUS", "Mounted at /mnt/rm")]),
+            "READY=1\nSTATUS=Mounted at /mnt/rm\n"
+        );
+    }
+
+    // the only test touching NOTIFY_SOCKET, so they don't race
+    #[test]
+    fn notify_socket() {
+        env::remove_var("NOTIFY_SOCKET");
+        assert!(!notify(&[("READY", "1")]).unwrap());
+
+        let path = env::temp_dir()
+            .join(format!("fuse-rm-notify-{}", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let systemd = UnixDatagram::bind(&path).unwrap();
+        env::set_var("NOTIFY_SOCKET", &path);
+        let sent = notify(&[("READY", "1"), ("STATUS", "Mounted")]);
+        env::remove_var("NOTIFY_SOCKET");
+        let _ = std::fs::remove_file(&path);
+        assert!(sent.unwrap());
+
+        let mut buf = [0; 64];
+        let len = systemd.recv(&mut buf).unwrap();
+        assert_eq!(&buf[..len], b"READY=1\nSTATUS=Mounted\n");
+    }
+}
@@ -0,0 +1,44 @@
+// Sizes given on the command line: bytes, or a number with a suffix as
+// coreutils takes them. K, M, G and T (and KiB, MiB, ...) are powers of
+// 1024, KB, MB, GB and TB powers of 1000. Case doesn't matter, and the
+// number may have a fraction, e.g. 1.5G.
+
+const SUFFIXES: &[(&str, u64)] = &[
+    ("", 1),
+    ("b", 1),
+    ("k", 1 << 10),
+    ("kib", 1 << 10),
+    ("kb", 1000),
+    ("m", 1 << 20),
+    ("mib", 1 << 20),
+    ("mb", 1000 * 1000),
+    ("g", 1 << 30),
+    ("gib", 1 << 30),
+    ("gb", 1000 * 1000 * 1000),
+    ("t", 1 << 40),
+    ("tib", 1 << 40),
+    ("tb", 1000 * 1000 * 1000 * 1000),
+];
+
+/// The bytes `s` stands for
+pub fn parse(s: &str) -> Result<u64, String> {
+    let split = s
+        .find(|c: char| !c.is_ascii_digit() && c != '.')
+        .unwrap_or(s.len());
+    let (number, suffix) = (&s[..split], s[split..].trim());
+    let unit = SUFFIXES
+        .iter()
+        .find(|(name, _)| suffix.eq_ignore_ascii_case(name))
+        .map(|(_, unit)| *unit)
+        .ok_or_else(|| format!("Unknown size suffix in {:?}", s))?;
+    let bytes = match number.parse::<u64>() {
+        Ok(n) => n.checked_mul(unit),
+        Err(_) => number
+            .parse::<f64>()
+            .ok()
+            .map(|n| n * unit as f64)
+            .filter(|n| n.is_finite() && *n < u64::MAX as f64)
+            .map(|n| n as u64),
+    };
+    bytes.ok_or_else(|| format!("Bad size: {:?}", s))
+}
@@ -0,0 +1,49 @@
+use std::ffi::CString;
+use std::io;
+use std::os::unix::ffi::OsStrExt;
+use std::path::Path;
+
+// What statfs reports. The blocks are those of the filesystem the source is
+// on, so df shows the room left for new documents. The inodes aren't: the
+// used ones are the documents and folders the mount shows (the trash counts
+// where it is shown), as of the last listing, and the free ones a fixed
+// headroom, so `df -i` reads as the size of the library. With --quota, the
+// free blocks are at most what is left of it.
+
+pub const FREE_INODES: u64 = 1_000_000;
+
+pub struct Space {
+    pub blocks: u64,
+    pub bfree: u64,
+    pub bavail: u64,
+    pub bsize: u32,
+    pub frsize: u32,
+}
+
+impl Space {
+    /// With no more than `bytes` free
+    pub fn within(self, bytes: u64) -> Space {
+        let blocks = bytes / u64::from(self.frsize.max(1));
+        Space {
+            bfree: self.bfree.min(blocks),
+            bavail: self.bavail.min(blocks),
+            ..self
+        }
+    }
+}
+
+/// The blocks of the filesystem `path` is on
+pub fn of(path: &Path) -> io::Result<Space> {
+    let path = CString::new(path.as_os_str().as_bytes())?;
+    let mut st: libc::statvfs = unsafe { std::mem::zeroed() };
+    if unsafe { libc::statvfs(path.as_ptr(), &mut st) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(Space {
+        blocks: st.f_blocks as u64,
+        bfree: st.f_bfree as u64,
+        bavail: st.f_bavail as u64,
+        bsize: st.f_bsize as u32,
+        frsize: st.f_frsize as u32,
+    })
+}
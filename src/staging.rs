@@ -0,0 +1,141 @@
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::OnceLock;
+use std::time::{Duration, SystemTime};
+
+use crate::direntry::DirEntry;
+use crate::jsonmetadata::JsonMetadata;
+
+// The data of new documents is written to `.pending` in the source until the
+// file is closed. With --staging-dir it goes elsewhere instead, out of reach
+// of backups and syncs of the source. Moving it in place is then a copy if
+// the staging dir is on another filesystem.
+//
+// The metadata of a pending document stays in the source's `.pending`, its
+// inode is the document's and has to survive the move.
+
+struct Staging {
+    dir: PathBuf,
+    // removed at unmount if empty, a dir given by the user is kept
+    created: bool,
+}
+
+static STAGING: OnceLock<Staging> = OnceLock::new();
+
+// Leftovers changed more recently may still be written by another instance
+// mounted with --no-lock
+const GRACE: Duration = Duration::from_secs(3600);
+
+/// What was found in `.pending` at mount
+#[derive(Debug, Default)]
+pub struct Reclaimed {
+    pub completed: u64,
+    pub removed: u64,
+    pub bytes: u64,
+}
+
+/// Stages data in `dir`, created if it doesn't exist
+pub fn configure(dir: &Path) -> io::Result<()> {
+    let created = !dir.exists();
+    fs::create_dir_all(dir)?;
+    let dir = fs::canonicalize(dir)?;
+    STAGING.get_or_init(|| Staging { dir, created });
+    Ok(())
+}
+
+/// Where the data of pending documents of `source` goes
+pub fn data_dir(source: &Path) -> PathBuf {
+    match STAGING.get() {
+        Some(staging) => staging.dir.clone(),
+        None => source.join(".pending"),
+    }
+}
+
+/// Moves staged data to `to`, through a copy in the source's `.pending` when
+/// a rename isn't possible, so `to` appears complete or not at all
+pub fn move_into(from: &Path, to: &Path) -> io::Result<()> {
+    match fs::rename(from, to) {
+        Err(e) if e.raw_os_error() == Some(libc::EXDEV) => {}
+        res => return res,
+    }
+    let mut copy = to.parent().unwrap_or(Path::new("")).join(".pending");
+    fs::create_dir_all(&copy)?;
+    copy.push(to.file_name().unwrap_or_default());
+    let res = fs::copy(from, &copy)
+        .and_then(|_| fs::File::open(&copy)?.sync_all())
+        .and_then(|_| fs::rename(&copy, to));
+    if let Err(e) = res {
+        fs::remove_file(&copy).unwrap_or(());
+        return Err(e);
+    }
+    fs::remove_file(from)
+}
+
+/// Removes the staging dir at unmount if it was created for the mount and
+/// nothing is left in it
+pub fn cleanup() {
+    if let Some(staging) = STAGING.get().filter(|s| s.created) {
+        fs::remove_dir(&staging.dir).unwrap_or(());
+    }
+}
+
+fn is_stale(path: &Path, now: SystemTime) -> bool {
+    fs::metadata(path)
+        .and_then(|m| m.modified())
+        .is_ok_and(|mtime| {
+            now.duration_since(mtime).is_ok_and(|age| age >= GRACE)
+        })
+}
+
+fn remove(path: &Path, res: &mut Reclaimed) -> io::Result<()> {
+    let size = match fs::metadata(path) {
+        Ok(meta) => meta.len(),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(e),
+    };
+    fs::remove_file(path)?;
+    res.bytes += size;
+    Ok(())
+}
+
+/// Completes or removes what crashed and aborted uploads left in `.pending`.
+/// In a staging dir, which may be shared, only the data of documents pending
+/// in `source` is touched.
+pub fn reclaim(source: &Path) -> io::Result<Reclaimed> {
+    let pending = source.join(".pending");
+    let mut res = Reclaimed::default();
+    let listing = match fs::read_dir(&pending) {
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(res),
+        listing => listing?,
+    };
+    let now = SystemTime::now();
+    for file in listing {
+        let path = file?.path();
+        let uuid = path.file_stem().unwrap_or_default().to_os_string();
+        let metadata = pending.join(&uuid).with_extension("metadata");
+        if path != metadata {
+            // data with metadata is dealt with along with the metadata,
+            // anything else is an untyped upload or an unfinished copy
+            if !metadata.exists() && is_stale(&path, now) {
+                remove(&path, &mut res)?;
+                res.removed += 1;
+            }
+            continue;
+        }
+        let data = data_dir(source).join(&uuid);
+        if !is_stale(&metadata, now) || data.exists() && !is_stale(&data, now) {
+            continue;
+        }
+        let completed = JsonMetadata::from_file(&metadata).is_ok()
+            && DirEntry::recover_pending(source, &uuid)?;
+        if completed {
+            res.completed += 1;
+        } else {
+            remove(&data, &mut res)?;
+            remove(&metadata, &mut res)?;
+            res.removed += 1;
+        }
+    }
+    Ok(res)
+}
@@ -0,0 +1,211 @@
+use fuser::{
+    ReplyAttr, ReplyCreate, ReplyData, ReplyDirectory, ReplyEmpty, ReplyEntry,
+    ReplyIoctl, ReplyOpen, ReplyStatfs, ReplyWrite, ReplyXattr,
+};
+use serde_json::Value;
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::Duration;
+
+use crate::retry;
+
+// What the mount did, for --stats-on-exit and the user.rm.stats xattr of
+// the root. Kept outside the filesystem so that the counts are still there
+// after the session is gone and destroy() has finalized the uploads.
+
+pub static DOCUMENTS_CREATED: AtomicU64 = AtomicU64::new(0);
+pub static FOLDERS_CREATED: AtomicU64 = AtomicU64::new(0);
+pub static RENAMED: AtomicU64 = AtomicU64::new(0);
+pub static DELETED: AtomicU64 = AtomicU64::new(0);
+pub static BYTES_READ: AtomicU64 = AtomicU64::new(0);
+pub static BYTES_WRITTEN: AtomicU64 = AtomicU64::new(0);
+pub static SYNCS: AtomicU64 = AtomicU64::new(0);
+// not counts but what is staged now: the new files being written, and the
+// bytes they hold
+pub static PENDING: AtomicU64 = AtomicU64::new(0);
+pub static STAGED_BYTES: AtomicU64 = AtomicU64::new(0);
+// documents of the last listing with changes not synced to the cloud
+pub static UNSYNCED: AtomicU64 = AtomicU64::new(0);
+// the bytes counted against --quota, and the quota, 0 for none
+pub static QUOTA_USED: AtomicU64 = AtomicU64::new(0);
+pub static QUOTA: AtomicU64 = AtomicU64::new(0);
+
+// errors replied by errno, and the slowest operation
+static ERRORS: Mutex<BTreeMap<i32, u64>> = Mutex::new(BTreeMap::new());
+static SLOWEST: Mutex<Option<(&'static str, Duration)>> = Mutex::new(None);
+// what the sources held at mount
+static LIBRARY: Mutex<Vec<String>> = Mutex::new(Vec::new());
+
+pub fn add(counter: &AtomicU64, n: u64) {
+    counter.fetch_add(n, Ordering::Relaxed);
+}
+
+pub fn set(gauge: &AtomicU64, n: u64) {
+    gauge.store(n, Ordering::Relaxed);
+}
+
+pub fn add_library(summary: String) {
+    LIBRARY.lock().unwrap().push(summary);
+}
+
+pub fn has_library() -> bool {
+    !LIBRARY.lock().unwrap().is_empty()
+}
+
+/// Forgets what the sources held, for counting them again
+pub fn clear_library() {
+    LIBRARY.lock().unwrap().clear();
+}
+
+/// Notes how long an operation took
+pub fn timed(op: &'static str, took: Duration) {
+    let mut slowest = SLOWEST.lock().unwrap();
+    if slowest.is_none_or(|(_, longest)| took > longest) {
+        *slowest = Some((op, took));
+    }
+}
+
+/// Replying an error through this counts it. Answers that aren't failures,
+/// like ENOENT to a lookup, go through `error` as they are.
+pub trait Fail {
+    fn fail(self, errno: libc::c_int);
+}
+
+macro_rules! impl_fail {
+    ($($reply:ty),*) => {
+        $(impl Fail for $reply {
+            fn fail(self, errno: libc::c_int) {
+                *ERRORS.lock().unwrap().entry(errno).or_insert(0) += 1;
+                self.error(errno);
+            }
+        })*
+    };
+}
+
+impl_fail!(
+    ReplyAttr,
+    ReplyCreate,
+    ReplyData,
+    ReplyDirectory,
+    ReplyEmpty,
+    ReplyEntry,
+    ReplyIoctl,
+    ReplyOpen,
+    ReplyStatfs,
+    ReplyWrite,
+    ReplyXattr
+);
+
+fn errno_name(errno: i32) -> String {
+    let name = match errno {
+        libc::EPERM => "EPERM",
+        libc::ENOENT => "ENOENT",
+        libc::EIO => "EIO",
+        libc::EBADF => "EBADF",
+        libc::EACCES => "EACCES",
+        libc::EBUSY => "EBUSY",
+        libc::EEXIST => "EEXIST",
+        libc::EXDEV => "EXDEV",
+        libc::ENOTDIR => "ENOTDIR",
+        libc::EISDIR => "EISDIR",
+        libc::EINVAL => "EINVAL",
+        libc::ENOSPC => "ENOSPC",
+        libc::EMFILE => "EMFILE",
+        libc::EDQUOT => "EDQUOT",
+        libc::EROFS => "EROFS",
+        libc::ERANGE => "ERANGE",
+        libc::ENAMETOOLONG => "ENAMETOOLONG",
+        libc::ENOSYS => "ENOSYS",
+        libc::ENOTEMPTY => "ENOTEMPTY",
+        libc::ENODATA => "ENODATA",
+        #[cfg(target_os = "macos")]
+        libc::ENOATTR => "ENOATTR",
+        _ => return format!("errno {}", errno),
+    };
+    name.to_string()
+}
+
+/// A line per count
+pub fn summary() -> String {
+    let load = |counter: &AtomicU64| counter.load(Ordering::Relaxed);
+    let errors = ERRORS.lock().unwrap();
+    let mut error_line = errors.values().sum::<u64>().to_string();
+    if !errors.is_empty() {
+        let by_errno: Vec<String> = errors
+            .iter()
+            .map(|(errno, n)| format!("{} {}", errno_name(*errno), n))
+            .collect();
+        error_line.push_str(&format!(" ({})", by_errno.join(", ")));
+    }
+    let slowest = match *SLOWEST.lock().unwrap() {
+        Some((op, took)) => format!("{} ({} ms)", op, took.as_millis()),
+        None => "-".to_string(),
+    };
+    let quota = match load(&QUOTA) {
+        0 => "-".to_string(),
+        quota => format!("{} of {} bytes", load(&QUOTA_USED), quota),
+    };
+    let library = match LIBRARY.lock().unwrap().join("; ") {
+        library if library.is_empty() => "-".to_string(),
+        library => library,
+    };
+    let rows = [
+        ("library", library),
+        ("documents created", load(&DOCUMENTS_CREATED).to_string()),
+        ("folders created", load(&FOLDERS_CREATED).to_string()),
+        ("renamed", load(&RENAMED).to_string()),
+        ("deleted", load(&DELETED).to_string()),
+        ("bytes read", load(&BYTES_READ).to_string()),
+        ("bytes written", load(&BYTES_WRITTEN).to_string()),
+        ("fsyncs", load(&SYNCS).to_string()),
+        ("pending uploads", load(&PENDING).to_string()),
+        ("staged bytes", load(&STAGED_BYTES).to_string()),
+        ("quota used", quota),
+        ("unsynced", load(&UNSYNCED).to_string()),
+        ("errors", error_line),
+        ("retries", retry::retries().to_string()),
+        ("slowest operation", slowest),
+    ];
+    rows.iter()
+        .map(|(what, value)| {
+            format!("{:<18} {}\n", what.to_string() + ":", value)
+        })
+        .collect()
+}
+
+/// The counts as a JSON object, for scripts
+pub fn json() -> Value {
+    let load = |counter: &AtomicU64| counter.load(Ordering::Relaxed);
+    let errors: serde_json::Map<String, Value> = ERRORS
+        .lock()
+        .unwrap()
+        .iter()
+        .map(|(errno, n)| (errno_name(*errno), json!(n)))
+        .collect();
+    let slowest = match *SLOWEST.lock().unwrap() {
+        Some((op, took)) => json!({"op": op, "ms": took.as_millis() as u64}),
+        None => Value::Null,
+    };
+    let quota = match load(&QUOTA) {
+        0 => Value::Null,
+        quota => json!({"used": load(&QUOTA_USED), "limit": quota}),
+    };
+    json!({
+        "library": *LIBRARY.lock().unwrap(),
+        "documents_created": load(&DOCUMENTS_CREATED),
+        "folders_created": load(&FOLDERS_CREATED),
+        "renamed": load(&RENAMED),
+        "deleted": load(&DELETED),
+        "bytes_read": load(&BYTES_READ),
+        "bytes_written": load(&BYTES_WRITTEN),
+        "fsyncs": load(&SYNCS),
+        "pending": load(&PENDING),
+        "staged_bytes": load(&STAGED_BYTES),
+        "quota": quota,
+        "unsynced": load(&UNSYNCED),
+        "errors": errors,
+        "retries": retry::retries(),
+        "slowest_operation": slowest,
+    })
+}
@@ -0,0 +1,54 @@
+use std::fs;
+use std::path::Path;
+use std::thread;
+use std::time::{Duration, Instant};
+
+// Files the cloud sync holds while rewriting the library
+pub const DEFAULT_MARKERS: &[&str] = &[".lock", ".tree.lock"];
+
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+// Only '*' is special, it matches any run of characters
+fn matches(pattern: &str, name: &str) -> bool {
+    match pattern.find('*') {
+        None => pattern == name,
+        Some(star) => {
+            let (head, tail) = (&pattern[..star], &pattern[star + 1..]);
+            name.starts_with(head)
+                && (head.len()..=name.len()).any(|i| {
+                    name.is_char_boundary(i) && matches(tail, &name[i..])
+                })
+        }
+    }
+}
+
+/// Plain names are checked with a stat each, only patterns need a listing
+pub fn in_progress(source: &Path, markers: &[String]) -> bool {
+    let (patterns, names): (Vec<&String>, Vec<&String>) =
+        markers.iter().partition(|m| m.contains('*'));
+    if names.iter().any(|name| source.join(name).exists()) {
+        return true;
+    }
+    !patterns.is_empty()
+        && fs::read_dir(source)
+            .map(|entries| {
+                entries.filter_map(|e| e.ok()).any(|e| {
+                    let name = e.file_name();
+                    let name = name.to_string_lossy();
+                    patterns.iter().any(|p| matches(p, &name))
+                })
+            })
+            .unwrap_or(false)
+}
+
+/// Returns false if the sync is still going after the timeout
+pub fn wait(source: &Path, markers: &[String], timeout: Duration) -> bool {
+    let start = Instant::now();
+    while in_progress(source, markers) {
+        if start.elapsed() >= timeout {
+            return false;
+        }
+        thread::sleep(POLL_INTERVAL);
+    }
+    true
+}
@@ -0,0 +1,74 @@
+use serde_json::Value;
+use std::time::SystemTime;
+
+use crate::timeconv;
+
+// Tags of a document. Firmware 3.x keeps them in the content file, as
+// objects with the name and when the tag was added; tools written for
+// older firmware put a list of names in the metadata. Both are read, and
+// written back in the form they were found in.
+
+// Longest value accepted for the xattr, and longest tag
+const MAX_VALUE_LEN: usize = 4096;
+const MAX_TAG_LEN: usize = 255;
+
+/// The names in a `tags` array of either form
+pub fn names(tags: Option<&Value>) -> Vec<String> {
+    let tags = tags.and_then(|tags| tags.as_array());
+    tags.into_iter()
+        .flatten()
+        .filter_map(|tag| match tag {
+            Value::String(name) => Some(name.clone()),
+            tag => tag.get("name")?.as_str().map(str::to_string),
+        })
+        .collect()
+}
+
+/// `names` as a `tags` array in the form of `previous`, or as objects if
+/// it has none. Objects of tags that were already there are kept as they
+/// are, with their timestamp.
+pub fn to_value(
+    names: &[String],
+    previous: Option<&Value>,
+    objects: bool,
+) -> Value {
+    let previous = previous.and_then(|tags| tags.as_array());
+    let objects = previous
+        .and_then(|tags| tags.first())
+        .map_or(objects, |first| first.is_object());
+    if !objects {
+        return json!(names);
+    }
+    let now = timeconv::to_millis(SystemTime::now());
+    let tags = names.iter().map(|name| {
+        previous
+            .into_iter()
+            .flatten()
+            .find(|tag| tag.get("name").and_then(|n| n.as_str()) == Some(name))
+            .cloned()
+            .unwrap_or_else(|| json!({"name": name, "timestamp": now}))
+    });
+    Value::Array(tags.collect())
+}
+
+/// The names in a value set through the xattr, a JSON array of strings.
+/// None for anything else, or values too long to be meant. Repeated names
+/// are only kept once.
+pub fn parse(value: &[u8]) -> Option<Vec<String>> {
+    if value.len() > MAX_VALUE_LEN {
+        return None;
+    }
+    let value: Value =
+        serde_json::from_str(std::str::from_utf8(value).ok()?).ok()?;
+    let mut res: Vec<String> = Vec::new();
+    for tag in value.as_array()? {
+        let name = tag.as_str()?;
+        if name.is_empty() || name.len() > MAX_TAG_LEN {
+            return None;
+        }
+        if !res.iter().any(|n| n == name) {
+            res.push(name.to_string());
+        }
+    }
+    Some(res)
+}
@@ -0,0 +1,146 @@
+use std::convert::TryFrom;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+// Conversions between SystemTime and the unix timestamps found in stat
+// (seconds) and xochitl metadata (milliseconds, usually as a string). Values
+// out of range are clamped instead of panicking, a broken metadata file
+// shouldn't take the mount down.
+
+// SystemTime is a timespec with signed 64 bit seconds on unix, so every
+// offset fits. std takes no more than i64::MAX seconds off at once though,
+// the last second before the epoch is taken off on its own.
+fn from_offset(secs: i64, nanos: u32) -> SystemTime {
+    let time = if secs < 0 {
+        UNIX_EPOCH
+            .checked_sub(Duration::from_secs((secs + 1).unsigned_abs()))
+            .and_then(|t| t.checked_sub(Duration::from_secs(1)))
+    } else {
+        UNIX_EPOCH.checked_add(Duration::from_secs(secs.unsigned_abs()))
+    };
+    // nanos always count forward, like in a timespec
+    time.and_then(|t| {
+        t.checked_add(Duration::from_nanos(nanos.into()))
+            .or(Some(t))
+    })
+    .unwrap_or(UNIX_EPOCH)
+}
+
+/// Seconds since the epoch, negative ones before it
+pub fn from_secs(secs: i64) -> SystemTime {
+    from_offset(secs, 0)
+}
+
+/// Milliseconds since the epoch, negative ones before it
+pub fn from_millis(millis: i64) -> SystemTime {
+    let nanos = millis.rem_euclid(1000) as u32 * 1_000_000;
+    from_offset(millis.div_euclid(1000), nanos)
+}
+
+/// A decimal string of milliseconds, like `lastModified`. Numbers too large
+/// for an i64 are clamped, anything else is None.
+pub fn from_millis_str(millis: &str) -> Option<SystemTime> {
+    let millis = millis.trim();
+    let digits = millis.strip_prefix('-').unwrap_or(millis);
+    if digits.is_empty() || !digits.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+    let clamped = if digits == millis { i64::MAX } else { i64::MIN };
+    Some(from_millis(millis.parse().unwrap_or(clamped)))
+}
+
+// Whole units since the epoch, rounded down so that times before it round
+// trip through the from_* functions
+fn to_units(time: SystemTime, per_sec: u32) -> i64 {
+    let units = |d: Duration| {
+        let sub = u128::from(d.subsec_nanos()) * u128::from(per_sec);
+        (u128::from(d.as_secs()) * u128::from(per_sec), sub)
+    };
+    match time.duration_since(UNIX_EPOCH) {
+        Ok(after) => {
+            let (whole, sub) = units(after);
+            i64::try_from(whole + sub / 1_000_000_000).unwrap_or(i64::MAX)
+        }
+        Err(before) => {
+            let (whole, sub) = units(before.duration());
+            let ceil = whole + sub.div_ceil(1_000_000_000);
+            i64::try_from(ceil).map(|u| -u).unwrap_or(i64::MIN)
+        }
+    }
+}
+
+pub fn to_secs(time: SystemTime) -> i64 {
+    to_units(time, 1)
+}
+
+pub fn to_millis(time: SystemTime) -> i64 {
+    to_units(time, 1000)
+}
+
+/// The `lastModified` form of `time`
+pub fn to_millis_str(time: SystemTime) -> String {
+    to_millis(time).to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // the ends of the range, around the epoch and a far future
+    const MILLIS: &[i64] = &[
+        i64::MIN,
+        i64::MIN + 1,
+        -1_500,
+        -1,
+        0,
+        1,
+        1_600_000_000_123,
+        253_402_300_799_999,
+        i64::MAX - 1,
+        i64::MAX,
+    ];
+
+    #[test]
+    fn secs_round_trip() {
+        for secs in MILLIS {
+            assert_eq!(to_secs(from_secs(*secs)), *secs, "{}", secs);
+        }
+    }
+
+    #[test]
+    fn millis_round_trip() {
+        for millis in MILLIS {
+            let time = from_millis(*millis);
+            assert_eq!(to_millis(time), *millis, "{}", millis);
+            assert_eq!(from_millis_str(&to_millis_str(time)), Some(time));
+        }
+    }
+
+    #[test]
+    fn millis_before_the_epoch_round_down() {
+        assert_eq!(
+            from_millis(-1_500),
+            from_secs(-2) + Duration::from_millis(500)
+        );
+        assert_eq!(to_secs(from_millis(-1_500)), -2);
+        assert_eq!(to_secs(from_millis(-1_000)), -1);
+        assert_eq!(to_secs(from_millis(1_999)), 1);
+    }
+
+    #[test]
+    fn millis_str() {
+        assert_eq!(from_millis_str("1500"), Some(from_millis(1_500)));
+        assert_eq!(from_millis_str(" -1500\n"), Some(from_millis(-1_500)));
+        assert_eq!(from_millis_str("-0"), Some(UNIX_EPOCH));
+        for bad in &["", "-", "+1", "1.5", "1e3", "0x10", "--1", "lorem"] {
+            assert_eq!(from_millis_str(bad), None, "{:?}", bad);
+        }
+    }
+
+    #[test]
+    fn millis_str_out_of_range_is_clamped() {
+        let far = "99999999999999999999999";
+        assert_eq!(from_millis_str(far), Some(from_millis(i64::MAX)));
+        let before = format!("-{}", far);
+        assert_eq!(from_millis_str(&before), Some(from_millis(i64::MIN)));
+    }
+}
@@ -0,0 +1,101 @@
+use std::fs;
+use std::panic;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::thread;
+
+// However the process ends after mounting, the target shouldn't be left
+// behind as a dead mount ("Transport endpoint is not connected"). Dropping
+// the session unmounts, this covers what that misses: a panic on the main
+// thread (the hook runs before unwinding, and also when panics abort) and
+// an unmount that failed.
+
+// Mount points in /proc/mounts have space, tab, newline and backslash
+// escaped as octal
+fn unescape(field: &str) -> String {
+    let mut res = Vec::with_capacity(field.len());
+    let bytes = field.as_bytes();
+    let mut i = 0;
+    while i < bytes.len() {
+        let octal = bytes.get(i + 1..i + 4).and_then(|digits| {
+            std::str::from_utf8(digits)
+                .ok()
+                .and_then(|d| u8::from_str_radix(d, 8).ok())
+        });
+        match (bytes[i], octal) {
+            (b'\\', Some(byte)) => {
+                res.push(byte);
+                i += 4;
+            }
+            (byte, _) => {
+                res.push(byte);
+                i += 1;
+            }
+        }
+    }
+    String::from_utf8_lossy(&res).to_string()
+}
+
+pub fn is_mounted(target: &Path) -> bool {
+    match fs::read_to_string("/proc/mounts") {
+        Ok(mounts) => mounts.lines().any(|line| {
+            line.split(' ')
+                .nth(1)
+                .is_some_and(|point| Path::new(&unescape(point)) == target)
+        }),
+        // can't tell, trying to unmount does no harm
+        Err(_) => true,
+    }
+}
+
+fn unmount(target: &Path) {
+    if !is_mounted(target) {
+        return;
+    }
+    let unmounted = [("fusermount", Some("-u")), ("umount", None)].iter().any(
+        |(cmd, flag)| {
+            let mut cmd = Command::new(cmd);
+            cmd.args(flag).arg(target);
+            cmd.status().is_ok_and(|status| status.success())
+        },
+    );
+    if unmounted {
+        info!("Unmounted {:?} after an abnormal exit", target);
+    } else {
+        error!(
+            "Couldn't unmount {:?}, see /proc/mounts and unmount it with \
+             `fusermount -u {}`",
+            target,
+            target.display()
+        );
+    }
+}
+
+/// Unmounts `target` when dropped or on a panic in the main thread, if it
+/// is still mounted by then
+pub struct UnmountGuard {
+    target: PathBuf,
+}
+
+impl UnmountGuard {
+    pub fn new(target: &Path) -> UnmountGuard {
+        // a dead mount can't be resolved, so the path is resolved now
+        let target = fs::canonicalize(target).unwrap_or(target.to_path_buf());
+        let hook_target = target.clone();
+        let previous = panic::take_hook();
+        panic::set_hook(Box::new(move |info| {
+            previous(info);
+            // panics in operations are caught and don't end the process
+            if thread::current().name() == Some("main") {
+                unmount(&hook_target);
+            }
+        }));
+        UnmountGuard { target }
+    }
+}
+
+impl Drop for UnmountGuard {
+    fn drop(&mut self) {
+        unmount(&self.target);
+    }
+}
@@ -0,0 +1,330 @@
+use std::ffi::CString;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
+
+use crate::lock::LOCK_FILE;
+
+#[derive(Debug)]
+pub enum MountError {
+    TargetMissing(PathBuf),
+    TargetNotDir(PathBuf),
+    AlreadyMounted(PathBuf),
+    TargetNotEmpty(PathBuf),
+    TargetInSource(PathBuf),
+    SourceMissing(PathBuf),
+    SourceNotDir(PathBuf),
+    // with a suggestion for the real xochitl dir
+    SourceNotXochitl(PathBuf, Option<PathBuf>),
+    SourceNotWritable(PathBuf),
+    SourceLocked(PathBuf, Option<u32>),
+    LockFailed(PathBuf, io::Error),
+}
+
+impl MountError {
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            MountError::TargetMissing(_) => 10,
+            MountError::TargetNotDir(_) => 11,
+            MountError::AlreadyMounted(_) => 12,
+            MountError::TargetNotEmpty(_) => 13,
+            MountError::TargetInSource(_) => 14,
+            MountError::SourceMissing(_) => 20,
+            MountError::SourceNotDir(_) => 21,
+            MountError::SourceNotXochitl(..) => 22,
+            MountError::SourceNotWritable(_) => 23,
+            MountError::SourceLocked(..) => 24,
+            MountError::LockFailed(..) => 25,
+        }
+    }
+}
+
+impl fmt::Display for MountError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MountError::TargetMissing(p) => {
+                write!(f, "Target {:?} does not exist", p)
+            }
+            MountError::TargetNotDir(p) => {
+                write!(f, "Target {:?} is not a directory", p)
+            }
+            MountError::AlreadyMounted(p) => write!(
+                f,
+                "{:?} is already a FUSE mountpoint (stale mounts can be \
+                 removed with `fusermount -u`)",
+                p
+            ),
+            MountError::TargetNotEmpty(p) => write!(
+                f,
+                "Target {:?} is not empty (use --nonempty to mount anyway)",
+                p
+            ),
+            MountError::TargetInSource(p) => {
+                write!(f, "Target {:?} is inside the source directory", p)
+            }
+            MountError::SourceMissing(p) => {
+                write!(f, "Source {:?} does not exist", p)
+            }
+            MountError::SourceNotDir(p) => {
+                write!(f, "Source {:?} is not a directory", p)
+            }
+            MountError::SourceNotXochitl(p, Some(suggestion)) => write!(
+                f,
+                "Source {:?} contains no documents, did you mean {:?}? \
+                 (use --force to mount anyway)",
+                p, suggestion
+            ),
+            MountError::SourceNotXochitl(p, None) => write!(
+                f,
+                "Source {:?} doesn't look like a xochitl directory \
+                 (use --force to mount anyway)",
+                p
+            ),
+            MountError::SourceNotWritable(p) => write!(
+                f,
+                "Source {:?} is empty and not writable \
+                 (use --force to mount anyway)",
+                p
+            ),
+            MountError::SourceLocked(p, pid) => write!(
+                f,
+                "Source is in use by another fuse-rm (pid {}, lock {:?}), \
+                 use --no-lock for read-only access",
+                pid.map(|p| p.to_string())
+                    .unwrap_or_else(|| "?".to_string()),
+                p
+            ),
+            MountError::LockFailed(p, e) => {
+                write!(f, "Couldn't create lock file {:?}: {}", p, e)
+            }
+        }
+    }
+}
+
+// Canonical path without touching the path itself, which may be a
+// disconnected mountpoint that can't be stat-ed
+fn absolute(path: &Path) -> PathBuf {
+    let path = if path.is_absolute() {
+        path.to_path_buf()
+    } else {
+        std::env::current_dir().unwrap_or_default().join(path)
+    };
+    match (path.parent(), path.file_name()) {
+        (Some(parent), Some(name)) => fs::canonicalize(parent)
+            .map(|p| p.join(name))
+            .unwrap_or(path),
+        _ => path,
+    }
+}
+
+// /proc/mounts escapes whitespace and backslashes as octal
+fn unescape_mount_path(field: &str) -> PathBuf {
+    let mut res = String::new();
+    let mut chars = field.chars();
+    while let Some(c) = chars.next() {
+        if c == '\\' {
+            let code: String = chars.by_ref().take(3).collect();
+            match u8::from_str_radix(&code, 8) {
+                Ok(byte) => res.push(byte as char),
+                Err(_) => res.push_str(&code),
+            }
+        } else {
+            res.push(c);
+        }
+    }
+    PathBuf::from(res)
+}
+
+// Mounted as root the fstype is just "fuse", via fusermount "fuse.fuse-rm"
+fn is_fuse_mount(mounts: &str, target: &Path) -> bool {
+    mounts.lines().any(|line| {
+        let fields: Vec<&str> = line.split(' ').collect();
+        fields.len() > 2
+            && (fields[2] == "fuse" || fields[2].starts_with("fuse."))
+            && unescape_mount_path(fields[1]) == target
+    })
+}
+
+pub fn check_target(
+    source: &Path,
+    target: &Path,
+    nonempty: bool,
+) -> Result<(), MountError> {
+    let abs_target = absolute(target);
+    let mounts = fs::read_to_string("/proc/mounts").unwrap_or_default();
+    if is_fuse_mount(&mounts, &abs_target) {
+        return Err(MountError::AlreadyMounted(abs_target));
+    }
+
+    let meta = match fs::metadata(target) {
+        Ok(meta) => meta,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {
+            return Err(MountError::TargetMissing(abs_target));
+        }
+        Err(_) => return Err(MountError::TargetNotDir(abs_target)),
+    };
+    if !meta.is_dir() {
+        return Err(MountError::TargetNotDir(abs_target));
+    }
+
+    if let Ok(abs_source) = fs::canonicalize(source) {
+        if abs_target.starts_with(abs_source) {
+            return Err(MountError::TargetInSource(abs_target));
+        }
+    }
+
+    if !nonempty
+        && fs::read_dir(target)
+            .map(|mut d| d.next().is_some())
+            .unwrap_or(false)
+    {
+        return Err(MountError::TargetNotEmpty(abs_target));
+    }
+    Ok(())
+}
+
+fn has_metadata(dir: &Path) -> bool {
+    fs::read_dir(dir)
+        .map(|entries| {
+            entries.filter_map(|e| e.ok()).any(|e| {
+                Path::new(&e.file_name()).extension()
+                    == Some("metadata".as_ref())
+            })
+        })
+        .unwrap_or(false)
+}
+
+fn is_writable(dir: &Path) -> bool {
+    match CString::new(dir.as_os_str().as_bytes()) {
+        Ok(path) => unsafe { libc::access(path.as_ptr(), libc::W_OK) == 0 },
+        Err(_) => false,
+    }
+}
+
+pub fn check_source(source: &Path, force: bool) -> Result<(), MountError> {
+    let meta = match fs::metadata(source) {
+        Ok(meta) => meta,
+        Err(_) => return Err(MountError::SourceMissing(absolute(source))),
+    };
+    if !meta.is_dir() {
+        return Err(MountError::SourceNotDir(absolute(source)));
+    }
+    if force || has_metadata(source) {
+        return Ok(());
+    }
+
+    // a common mistake is passing .../remarkable instead of .../xochitl
+    let xochitl = source.join("xochitl");
+    if has_metadata(&xochitl) {
+        return Err(MountError::SourceNotXochitl(
+            absolute(source),
+            Some(absolute(&xochitl)),
+        ));
+    }
+
+    // an empty library is fine as long as documents can be added to it
+    let empty = fs::read_dir(source)
+        .map(|entries| {
+            entries.filter_map(|e| e.ok()).all(|e| {
+                e.file_name() == ".pending" || e.file_name() == LOCK_FILE
+            })
+        })
+        .unwrap_or(false);
+    if !empty {
+        Err(MountError::SourceNotXochitl(absolute(source), None))
+    } else if !is_writable(source) {
+        Err(MountError::SourceNotWritable(absolute(source)))
+    } else {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn scratch(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!(
+            "fuse-rm-validate-{}-{}",
+            std::process::id(),
+            name
+        ));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(dir.join("source")).unwrap();
+        dir
+    }
+
+    fn check(dir: &Path, target: &str, nonempty: bool) -> Option<i32> {
+        check_target(&dir.join("source"), &dir.join(target), nonempty)
+            .err()
+            .map(|e| e.exit_code())
+    }
+
+    #[test]
+    fn target_missing() {
+        let dir = scratch("missing");
+        assert_eq!(check(&dir, "mnt", false), Some(10));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn target_not_dir() {
+        let dir = scratch("notdir");
+        fs::write(dir.join("mnt"), "").unwrap();
+        assert_eq!(check(&dir, "mnt", false), Some(11));
+        assert_eq!(check(&dir, "mnt", true), Some(11));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn target_nonempty() {
+        let dir = scratch("nonempty");
+        fs::create_dir(dir.join("mnt")).unwrap();
+        assert_eq!(check(&dir, "mnt", false), None);
+        fs::write(dir.join("mnt/file"), "").unwrap();
+        assert_eq!(check(&dir, "mnt", false), Some(13));
+        assert_eq!(check(&dir, "mnt", true), None);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn target_in_source() {
+        let dir = scratch("insource");
+        fs::create_dir(dir.join("source/mnt")).unwrap();
+        assert_eq!(check(&dir, "source/mnt", false), Some(14));
+        assert_eq!(check(&dir, "source/mnt/..", false), Some(14));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn unescape() {
+        assert_eq!(unescape_mount_path("/mnt/rm"), Path::new("/mnt/rm"));
+        assert_eq!(
+            unescape_mount_path("/mnt/my\\040notes\\011tab"),
+            Path::new("/mnt/my notes\ttab")
+        );
+        assert_eq!(
+            unescape_mount_path("/mnt/back\\134slash"),
+            Path::new("/mnt/back\\slash")
+        );
+        // not an octal escape, kept as it is
+        assert_eq!(unescape_mount_path("/mnt/\\xyz"), Path::new("/mnt/xyz"));
+    }
+
+    #[test]
+    fn fuse_mounts() {
+        let mounts = "\
+            proc /proc proc rw,nosuid 0 0\n\
+            /dev/sda1 /home ext4 rw 0 0\n\
+            fuse-rm /mnt/rm fuse rw,user_id=0 0 0\n\
+            fuse-rm /mnt/my\\040notes fuse.fuse-rm rw 0 0\n";
+        assert!(is_fuse_mount(mounts, Path::new("/mnt/rm")));
+        assert!(is_fuse_mount(mounts, Path::new("/mnt/my notes")));
+        assert!(!is_fuse_mount(mounts, Path::new("/mnt/my\\040notes")));
+        assert!(!is_fuse_mount(mounts, Path::new("/home")));
+        assert!(!is_fuse_mount(mounts, Path::new("/mnt")));
+        assert!(!is_fuse_mount("", Path::new("/mnt/rm")));
+    }
+}
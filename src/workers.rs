@@ -0,0 +1,95 @@
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::mpsc;
+use std::thread;
+use std::time::Instant;
+
+use crate::logging::{self, Span};
+use crate::stats;
+
+// The session handles one request at a time, so a long read from a slow
+// source holds up every other process using the mount. Reads of open files
+// and hashing are handed to a few threads along with their reply, the
+// session goes on with the next request meanwhile.
+//
+// Jobs of a handle always go to the same thread, so they run in the order
+// they came in. Writes and everything that changes the maps or that later
+// requests depend on (scans, finalizing uploads) stay in the session.
+
+type Job = Box<dyn FnOnce() + Send>;
+
+pub struct Workers {
+    queues: Vec<mpsc::Sender<Job>>,
+}
+
+// Debug builds sleep this many milliseconds in each job, so the tests can
+// check that a slow read doesn't block other requests
+#[cfg(debug_assertions)]
+const INJECT_VAR: &str = "FUSE_RM_INJECT_JOB_DELAY_MS";
+
+#[cfg(debug_assertions)]
+fn injected_delay() {
+    use std::sync::OnceLock;
+    static DELAY: OnceLock<u64> = OnceLock::new();
+    let delay = DELAY.get_or_init(|| {
+        let millis = std::env::var(INJECT_VAR).ok();
+        millis.and_then(|m| m.parse().ok()).unwrap_or(0)
+    });
+    if *delay > 0 {
+        thread::sleep(std::time::Duration::from_millis(*delay));
+    }
+}
+
+#[cfg(not(debug_assertions))]
+fn injected_delay() {}
+
+// A panicking job drops its reply, which answers EIO, the thread goes on
+fn run(job: Job) {
+    injected_delay();
+    if let Err(payload) = panic::catch_unwind(AssertUnwindSafe(job)) {
+        let msg = payload
+            .downcast_ref::<&str>()
+            .copied()
+            .or_else(|| payload.downcast_ref::<String>().map(String::as_str))
+            .unwrap_or("(no message)");
+        error!("worker job panicked: {}", msg);
+    }
+}
+
+impl Workers {
+    /// `count` threads, none to run the jobs in the session
+    pub fn new(count: usize) -> Workers {
+        let mut queues = Vec::new();
+        for i in 0..count {
+            let (tx, rx) = mpsc::channel::<Job>();
+            let spawned = thread::Builder::new()
+                .name(format!("fuse-rm-io-{}", i))
+                .spawn(move || rx.into_iter().for_each(run));
+            match spawned {
+                Ok(_) => queues.push(tx),
+                Err(e) => error!("can't start I/O thread: {}", e),
+            }
+        }
+        Workers { queues }
+    }
+
+    /// Runs `job` after the jobs submitted before for the handle `fh`. The
+    /// threads end once the pool is dropped and their queue is done.
+    pub fn submit(&self, fh: u64, job: impl FnOnce() + Send + 'static) {
+        // logged as part of the request it came from
+        let span = logging::current();
+        let job = move || {
+            let op = span.as_ref().map_or("job", Span::op);
+            let (started, _entered) = (Instant::now(), span.map(Span::enter));
+            job();
+            stats::timed(op, started.elapsed());
+        };
+        if self.queues.is_empty() {
+            return run(Box::new(job));
+        }
+        let queue = &self.queues[(fh % self.queues.len() as u64) as usize];
+        if let Err(mpsc::SendError(job)) = queue.send(Box::new(job)) {
+            // the thread is gone, do it here rather than not at all
+            run(job);
+        }
+    }
+}
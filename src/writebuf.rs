@@ -0,0 +1,164 @@
+use std::fs;
+use std::io;
+use std::os::unix::fs::FileExt;
+use std::time::SystemTime;
+
+use crate::retry::retry;
+use crate::stats;
+
+// Uploads arrive as a write request per page or so, in order. Instead of a
+// seek and a write for each, the data of the pending file is collected
+// while the offsets follow on each other and written at once. A write
+// elsewhere writes out what was collected first, so the file always ends up
+// as if every request had been written as it came. Failures to write what
+// was collected surface on the request or operation that writes it out.
+
+// Collected before writing out, a MiB is a handful of flash erase blocks
+const CAPACITY: usize = 1 << 20;
+
+// Requests at least this large aren't copied in, they are written from the
+// request's buffer with what was collected before them
+const DIRECT: usize = 64 << 10;
+
+// `first` and then `second` at `offset`, with one pwritev(2) where there is
+// one
+#[cfg(any(target_os = "linux", target_os = "android"))]
+fn write_all_at2(
+    file: &fs::File,
+    first: &[u8],
+    second: &[u8],
+    mut offset: u64,
+) -> io::Result<()> {
+    use std::os::unix::io::AsRawFd;
+    let (mut first, mut second) = (first, second);
+    while !first.is_empty() || !second.is_empty() {
+        let iov = [first, second].map(|buf| libc::iovec {
+            iov_base: buf.as_ptr() as *mut libc::c_void,
+            iov_len: buf.len(),
+        });
+        let written = unsafe {
+            libc::pwritev(file.as_raw_fd(), iov.as_ptr(), 2, offset as i64)
+        };
+        let written = match written {
+            n if n < 0 => {
+                let e = io::Error::last_os_error();
+                if e.kind() == io::ErrorKind::Interrupted {
+                    continue;
+                }
+                return Err(e);
+            }
+            0 => return Err(io::ErrorKind::WriteZero.into()),
+            n => n as usize,
+        };
+        offset += written as u64;
+        let from_first = written.min(first.len());
+        first = &first[from_first..];
+        second = &second[written - from_first..];
+    }
+    Ok(())
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "android")))]
+fn write_all_at2(
+    file: &fs::File,
+    first: &[u8],
+    second: &[u8],
+    offset: u64,
+) -> io::Result<()> {
+    file.write_all_at(first, offset)?;
+    file.write_all_at(second, offset + first.len() as u64)
+}
+
+pub struct BufferedFile {
+    file: fs::File,
+    buf: Vec<u8>,
+    // where `buf` goes in the file
+    start: u64,
+    // the end of what was written, collected or not
+    extent: u64,
+    // of the last write, collected or not
+    modified: SystemTime,
+}
+
+impl BufferedFile {
+    pub fn new(file: fs::File) -> BufferedFile {
+        BufferedFile {
+            file,
+            buf: Vec::new(),
+            start: 0,
+            extent: 0,
+            modified: SystemTime::now(),
+        }
+    }
+
+    /// Writes `data` at `offset`, or collects it to be written with what
+    /// comes next
+    pub fn write_at(&mut self, offset: u64, data: &[u8]) -> io::Result<()> {
+        self.modified = SystemTime::now();
+        let end = offset + data.len() as u64;
+        if !self.buf.is_empty() && offset != self.start + self.buf.len() as u64
+        {
+            self.flush()?;
+        }
+        if self.buf.is_empty() {
+            self.start = offset;
+        }
+        if data.len() >= DIRECT || self.buf.len() + data.len() >= CAPACITY {
+            let (buf, start) = (&self.buf, self.start);
+            retry(|| write_all_at2(&self.file, buf, data, start))?;
+            self.buf.clear();
+            self.extent = self.extent.max(end);
+            return Ok(());
+        }
+        self.buf.extend_from_slice(data);
+        self.extent = self.extent.max(end);
+        Ok(())
+    }
+
+    /// Writes out what was collected
+    pub fn flush(&mut self) -> io::Result<()> {
+        if self.buf.is_empty() {
+            return Ok(());
+        }
+        retry(|| self.file.write_all_at(&self.buf, self.start))?;
+        self.buf.clear();
+        Ok(())
+    }
+
+    /// The file with everything written to it, for reading back
+    pub fn flushed(&mut self) -> io::Result<&fs::File> {
+        self.flush()?;
+        Ok(&self.file)
+    }
+
+    /// The size of the file once written out
+    pub fn len(&self) -> io::Result<u64> {
+        let len = self.file.metadata()?.len();
+        if self.buf.is_empty() {
+            return Ok(len);
+        }
+        Ok(len.max(self.start + self.buf.len() as u64))
+    }
+
+    /// The size written through the handle, without a stat
+    pub fn extent(&self) -> u64 {
+        self.extent
+    }
+
+    /// When the file was last written to through the handle
+    pub fn modified(&self) -> SystemTime {
+        self.modified
+    }
+
+    /// Writes out what was collected and syncs the file, its data only with
+    /// `datasync`
+    pub fn sync(&mut self, datasync: bool) -> io::Result<()> {
+        self.flush()?;
+        stats::add(&stats::SYNCS, 1);
+        if datasync {
+            retry(|| self.file.sync_data())
+        } else {
+            retry(|| self.file.sync_all())
+        }
+    }
+}
@@ -0,0 +1,45 @@
+// Detection of a running xochitl (the reMarkable UI), which keeps the library
+// state in memory and doesn't expect the data dir to change under it
+use std::fs;
+use std::process::Command;
+
+pub fn is_running() -> bool {
+    let procs = match fs::read_dir("/proc") {
+        Ok(procs) => procs,
+        Err(_) => return false,
+    };
+    procs.filter_map(|e| e.ok()).any(|e| {
+        fs::read_to_string(e.path().join("comm"))
+            .map(|comm| comm.trim_end() == "xochitl")
+            .unwrap_or(false)
+    })
+}
+
+fn systemctl(action: &str) -> bool {
+    match Command::new("systemctl").args([action, "xochitl"]).status() {
+        Ok(status) => status.success(),
+        Err(e) => {
+            error!("systemctl {} xochitl: {}", action, e);
+            false
+        }
+    }
+}
+
+pub fn stop() -> bool {
+    systemctl("stop")
+}
+
+pub fn start() -> bool {
+    systemctl("start")
+}
+
+/// Starts xochitl again when dropped, however the mount that stopped it
+/// ends: an error, a panic in the main thread or the unmount
+pub struct RestartGuard;
+
+impl Drop for RestartGuard {
+    fn drop(&mut self) {
+        println!("Restarting xochitl");
+        start();
+    }
+}
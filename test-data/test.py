@@ -1,36 +1,58 @@
 import unittest
 
+import ctypes
+import errno
+import fcntl
 import glob
+import hashlib
 import json
 import os
+import re
 import shutil
+import socket
+import sys
+import tempfile
 import time
+import zipfile
 from datetime import datetime
-from threading import Thread
-from subprocess import Popen, PIPE, check_output
+from threading import Barrier, Thread
+from subprocess import Popen, PIPE, STDOUT, check_output, run
 from pathlib import Path
 
 ROOT = Path(__file__).parent
 SRC_DIR = ROOT / 'source'
 TARGET_DIR = ROOT / 'target'
+FUSERM = ROOT.parent / 'target' / 'debug' / 'fuse-rm'
+
+class MountTest(unittest.TestCase):
+    source_dir = SRC_DIR
+    target_dir = TARGET_DIR
+    args = []
 
-class Test(unittest.TestCase):
     @classmethod
     def setUpClass(cls):
-        cls._fuserm = Popen(['cargo', 'run', '--', SRC_DIR, TARGET_DIR],
+        cls.target_dir.mkdir(exist_ok=True)
+        cls._fuserm = Popen(['cargo', 'run', '--', *cls.args,
+                             cls.source_arg(), cls.target_dir],
                             stdout=PIPE)
         cls._fuserm_output = []
+        cls._fuserm_banner = []
         while True:
             line = cls._fuserm.stdout.readline()
             if line.startswith(b'Waiting for Ctrl-C'):
                 break
-            elif cls._fuserm.poll is not None:
+            elif cls._fuserm.poll() is not None:
                 raise RuntimeError('fuse-rm failed to start')
             else:
+                cls._fuserm_banner.append(line)
                 print(line)
         cls._fuserm_thread = Thread(target=cls.capture_fuserm_output)
         cls._fuserm_thread.start()
-        os.chdir(TARGET_DIR)
+        os.chdir(cls.target_dir)
+
+    @classmethod
+    def source_arg(cls):
+        return cls.source_dir
 
     @classmethod
     def capture_fuserm_output(cls):
@@ -42,8 +64,35 @@ class Test(unittest.TestCase):
 
     @classmethod
     def tearDownClass(cls):
+        os.chdir(ROOT)
         cls._fuserm.terminate()
+        cls._fuserm.wait()
+
+
+class FixtureCopyTest(MountTest):
+    """Mounts a scratch copy of a fixture directory, for destructive tests"""
+    fixture = None
+
+    @classmethod
+    def setUpClass(cls):
+        cls._scratch = Path(tempfile.mkdtemp())
+        cls.source_dir = cls._scratch / 'source'
+        cls.target_dir = cls._scratch / 'target'
+        shutil.copytree(ROOT / cls.fixture, cls.source_dir)
+        cls.prepare()
+        super().setUpClass()
 
+    @classmethod
+    def prepare(cls):
+        """Adjusts the copy before it is mounted"""
+
+    @classmethod
+    def tearDownClass(cls):
+        super().tearDownClass()
+        shutil.rmtree(cls._scratch)
+
+
+class Test(MountTest):
     def test_file_structure_root(self):
         root = set(check_output('stat -c "%s %n" *', shell=True).decode().split('\n'))
         self.assertSetEqual(root, { '',
@@ -147,3 +196,4551 @@ class Test(unittest.TestCase):
         dir = set(check_output(['stat -c "%s %n" trash/*'], shell=True).decode().split('\n'))
         self.assertSetEqual(dir, { '',
                                    '28859 trash/lorem-trashed.pdf' })
+
+    def test_blocks(self):
+        for path in ['ipsum.pdf', 'lorem.epub', 'dolor/lorem.pdf']:
+            st = os.stat(path)
+            self.assertEqual(st.st_blocks, -(-st.st_size // 512))
+        self.assertEqual(os.stat('dolor').st_blocks, 0)
+        expected = sum(-(-os.stat('dolor/' + n).st_size // 512) * 512
+                       for n in os.listdir('dolor'))
+        du = check_output(['du', '-s', '-B1', 'dolor']).split()[0]
+        self.assertEqual(int(du), expected)
+
+    def test_opendir_document(self):
+        for path in ['ipsum.pdf', 'lorem.epub', 'dolor/lorem.pdf']:
+            with self.assertRaises(OSError) as cm:
+                os.listdir(path)
+            self.assertEqual(cm.exception.errno, errno.ENOTDIR)
+        self.assertIn('ipsum.pdf', os.listdir('.'))
+        self.assertIn('lorem-trashed.pdf', os.listdir('trash'))
+
+    def test_readdir_kinds(self):
+        kinds = {e.name: e.is_dir() for e in os.scandir('.')}
+        self.assertEqual(kinds, {'dolor': True, 'ipsum.pdf': False,
+                                 'lorem.epub': False, 'trash': True})
+
+
+class ConflictTest(FixtureCopyTest):
+    fixture = 'conflict'
+
+    def test_conflict_names(self):
+        root = set(check_output('stat -c "%s %n" *', shell=True).decode().split('\n'))
+        self.assertSetEqual(root, { '',
+                                    '0 trash',
+                                    '10889 Report.pdf',
+                                    '28859 Report (conflict 2024-01-05).pdf' })
+
+    def test_conflict_mtime(self):
+        newer = Path('Report.pdf').stat().st_mtime
+        older = Path('Report (conflict 2024-01-05).pdf').stat().st_mtime
+        self.assertGreater(newer, older)
+
+    def test_conflict_order(self):
+        self.assertEqual(os.listdir('.'), ['Report (conflict 2024-01-05).pdf',
+                                           'Report.pdf', 'trash'])
+
+    def test_conflict_unlink(self):
+        Path('Report (conflict 2024-01-05).pdf').unlink()
+        self.assertEqual(sorted(os.listdir('.')), ['Report.pdf', 'trash'])
+        self.assertEqual(Path('Report.pdf').stat().st_size, 10889)
+        remaining = set(p.name for p in self.source_dir.iterdir())
+        self.assertFalse(any(n.startswith('6d1c3a2e') for n in remaining))
+        self.assertTrue('b3e9f0d4-71a2-4d8c-8e5f-0c4a9d2b7e02.pdf' in remaining)
+
+
+class TimestampTest(FixtureCopyTest):
+    """lastModified values out of the usual range"""
+    fixture = 'conflict'
+
+    @classmethod
+    def prepare(cls):
+        for uuid, modified in [('6d1c3a2e-5b0f-4c1e-9a7d-2f8e4b6c1a01',
+                                '-86400000'),
+                               ('b3e9f0d4-71a2-4d8c-8e5f-0c4a9d2b7e02',
+                                '99999999999999999999')]:
+            path = cls.source_dir / (uuid + '.metadata')
+            metadata = json.loads(path.read_text())
+            metadata['lastModified'] = modified
+            path.write_text(json.dumps(metadata))
+
+    def test_before_epoch(self):
+        path = Path('Report (conflict 1969-12-31).pdf')
+        self.assertEqual(path.stat().st_mtime, -86400)
+        self.assertEqual(path.stat().st_size, 28859)
+
+    def test_far_future(self):
+        # clamped to the largest i64 of milliseconds
+        self.assertEqual(Path('Report.pdf').stat().st_mtime_ns // 10**9,
+                         (2**63 - 1) // 1000)
+
+
+class PendingReadTest(FixtureCopyTest):
+    fixture = 'conflict'
+
+    def test_read_while_writing(self):
+        data = (ROOT / 'ipsum.pdf').read_bytes()
+        fd = os.open('new.pdf', os.O_RDWR | os.O_CREAT, 0o644)
+        try:
+            os.write(fd, data[:4096])
+            self.assertEqual(os.pread(fd, 100, 0), data[:100])
+            os.write(fd, data[4096:8192])
+            self.assertEqual(os.pread(fd, 4096, 4096), data[4096:8192])
+            self.assertEqual(os.stat('new.pdf').st_size, 8192)
+
+            ro = os.open('new.pdf', os.O_RDONLY)
+            try:
+                self.assertEqual(os.read(ro, 8192), data[:8192])
+                os.write(fd, data[8192:])
+            finally:
+                os.close(ro)
+        finally:
+            os.close(fd)
+        self.assertEqual(Path('new.pdf').read_bytes(), data)
+        self.assertFalse((self.source_dir / '.pending').exists()
+                         and os.listdir(self.source_dir / '.pending'))
+
+    def test_reader_outlives_writer(self):
+        data = (ROOT / 'ipsum.pdf').read_bytes()
+        fd = os.open('late.pdf', os.O_WRONLY | os.O_CREAT, 0o644)
+        os.write(fd, data)
+        ro = os.open('late.pdf', os.O_RDONLY)
+        os.close(fd)
+        try:
+            self.assertEqual(os.read(ro, len(data)), data)
+        finally:
+            os.close(ro)
+        self.assertEqual(Path('late.pdf').read_bytes(), data)
+
+
+class OpenAfterCreateTest(FixtureCopyTest):
+    fixture = 'conflict'
+
+    def test_open_right_after_close(self):
+        data = (ROOT / 'ipsum.pdf').read_bytes()
+        for i in range(300):
+            name = 'stress%d.pdf' % i
+            with open(name, 'wb') as f:
+                f.write(data)
+            res = run(['cat', name], stdout=PIPE, stderr=STDOUT)
+            self.assertEqual(res.returncode, 0, res.stdout[:200])
+            self.assertEqual(len(res.stdout), len(data))
+        listing = os.listdir('.')
+        self.assertEqual(len([n for n in listing if n.startswith('stress')]),
+                         300)
+        for i in range(300):
+            os.unlink('stress%d.pdf' % i)
+
+    def test_inode_of_metadata(self):
+        # new entries get the inode of the metadata file written for them
+        os.mkdir('new folder')
+        with open('new.pdf', 'wb') as f:
+            f.write((ROOT / 'ipsum.pdf').read_bytes())
+        for name in ['new folder', 'new.pdf']:
+            path = settled_metadata(self.source_dir, name)
+            self.assertEqual(os.stat(name).st_ino, path.stat().st_ino)
+
+
+class XattrTest(FixtureCopyTest):
+    fixture = 'source'
+
+    def test_sha256(self):
+        shutil.copyfile(ROOT / 'ipsum.epub', 'hashed.epub')
+        expected = hashlib.sha256((ROOT / 'ipsum.epub').read_bytes())
+        self.assertEqual(os.getxattr('hashed.epub', 'user.rm.sha256').decode(),
+                         expected.hexdigest())
+        self.assertIn('user.rm.sha256', os.listxattr('hashed.epub'))
+
+        uuid = next(p.stem for p in self.source_dir.glob('*.metadata')
+                    if json.loads(p.read_text())['visibleName'] == 'hashed.epub')
+        with open(self.source_dir / (uuid + '.epub'), 'ab') as f:
+            f.write(b'changed')
+        expected.update(b'changed')
+        self.assertEqual(os.getxattr('hashed.epub', 'user.rm.sha256').decode(),
+                         expected.hexdigest())
+
+    def test_folder(self):
+        with self.assertRaises(OSError) as cm:
+            os.getxattr('dolor', 'user.rm.sha256')
+        self.assertEqual(cm.exception.errno, errno.ENODATA)
+        self.assertEqual(os.listxattr('dolor'), [])
+
+    def test_other_names(self):
+        with self.assertRaises(OSError) as cm:
+            os.getxattr('ipsum.pdf', 'user.other')
+        self.assertEqual(cm.exception.errno, errno.ENODATA)
+
+
+class ForgetTest(FixtureCopyTest):
+    """What is cached of an entry goes once the kernel forgets it"""
+    fixture = 'source'
+
+    def test_forget(self):
+        data = self.source_dir / 'c180a2a6-fab9-4ba5-9e7f-3a71f9a1bd09.pdf'
+        before = hashlib.sha256(data.read_bytes()).hexdigest()
+        self.assertEqual(os.getxattr('ipsum.pdf', 'user.rm.sha256').decode(),
+                         before)
+        # other content of the same size and mtime, the cached hash stays
+        stat = data.stat()
+        changed = bytes(b ^ 0xff for b in data.read_bytes())
+        data.write_bytes(changed)
+        os.utime(data, ns=(stat.st_atime_ns, stat.st_mtime_ns))
+        self.assertEqual(os.getxattr('ipsum.pdf', 'user.rm.sha256').decode(),
+                         before)
+        try:
+            Path('/proc/sys/vm/drop_caches').write_text('2')
+        except OSError:
+            self.skipTest('the dentry cache cannot be dropped')
+        # looked up and hashed again
+        self.assertEqual(os.getxattr('ipsum.pdf', 'user.rm.sha256').decode(),
+                         hashlib.sha256(changed).hexdigest())
+
+
+class RecentTest(FixtureCopyTest):
+    fixture = 'source'
+    args = ['--recent', '2']
+
+    def test_listing(self):
+        self.assertIn('Recent', os.listdir('.'))
+        self.assertEqual(os.listdir('Recent'), ['ipsum.pdf', 'lorem.epub'])
+
+    def test_same_inode(self):
+        self.assertEqual(os.stat('Recent/ipsum.pdf').st_ino,
+                         os.stat('ipsum.pdf').st_ino)
+        self.assertEqual(Path('Recent/ipsum.pdf').read_bytes(),
+                         (ROOT / 'ipsum.pdf').read_bytes())
+
+    def test_read_only(self):
+        def assertPerm(f, *args):
+            with self.assertRaises(OSError) as cm:
+                f(*args)
+            self.assertEqual(cm.exception.errno, errno.EPERM)
+        assertPerm(os.unlink, 'Recent/ipsum.pdf')
+        assertPerm(os.rename, 'Recent/ipsum.pdf', 'moved.pdf')
+        assertPerm(os.rename, 'dolor/lorem.pdf', 'Recent/lorem.pdf')
+        assertPerm(os.mkdir, 'Recent/d')
+        assertPerm(os.rmdir, 'Recent')
+        self.assertEqual(os.listdir('Recent'), ['ipsum.pdf', 'lorem.epub'])
+
+    def test_shared_names(self):
+        shutil.copyfile(ROOT / 'ipsum.pdf', 'dolor/ipsum.pdf')
+        try:
+            names = os.listdir('Recent')
+            self.assertEqual(len(names), 2)
+            for name in names:
+                self.assertRegex(name, r'^ipsum \([0-9a-f]{8}\)\.pdf$')
+                self.assertEqual(Path('Recent', name).read_bytes(),
+                                 (ROOT / 'ipsum.pdf').read_bytes())
+        finally:
+            os.unlink('dolor/ipsum.pdf')
+
+
+def settled_metadata(source, name, timeout=2):
+    """The metadata file of the new document `name` once it is out of
+    .pending, the kernel sends the release after close(2) returns"""
+    deadline = time.monotonic() + timeout
+    while True:
+        for path in source.glob('*.metadata'):
+            if json.loads(path.read_text())['visibleName'] == name:
+                return path
+        if time.monotonic() > deadline:
+            return None
+        time.sleep(0.05)
+
+
+class FlatTest(FixtureCopyTest):
+    fixture = 'source'
+    args = ['--flat']
+    # dolor/lorem.pdf in the hierarchical view
+    uuid = 'f27527b8-62d4-4e44-9511-43bdf275d364'
+
+    def metadata(self):
+        path = self.source_dir / (self.uuid + '.metadata')
+        return json.loads(path.read_text())
+
+    def test_listing(self):
+        # the documents of Test.test_file_structure_*, without the trash
+        root = set(check_output('stat -c "%s %n" *', shell=True)
+                   .decode().split('\n'))
+        self.assertSetEqual(root, { '',
+                                    '126501 ipsum.pdf',
+                                    '4091 lorem.epub',
+                                    '30875 ipsum.epub',
+                                    '28859 lorem.pdf' })
+
+    def test_read(self):
+        self.assertEqual(Path('lorem.pdf').read_bytes(),
+                         (self.source_dir / (self.uuid + '.pdf')).read_bytes())
+
+    def test_mkdir(self):
+        with self.assertRaises(OSError) as cm:
+            os.mkdir('folder')
+        self.assertEqual(cm.exception.errno, errno.EPERM)
+
+    def test_rename_keeps_folder(self):
+        os.rename('lorem.pdf', 'amet.pdf')
+        try:
+            self.assertIn('amet.pdf', os.listdir('.'))
+            metadata = self.metadata()
+            self.assertEqual(metadata['visibleName'], 'amet.pdf')
+            self.assertEqual(metadata['parent'],
+                             '90e4bbaa-cec6-478f-bd08-aa1fa4b58fe8')
+        finally:
+            os.rename('amet.pdf', 'lorem.pdf')
+
+    def test_create_at_root(self):
+        shutil.copyfile(ROOT / 'ipsum.pdf', 'new.pdf')
+        try:
+            path = settled_metadata(self.source_dir, 'new.pdf')
+            self.assertEqual(json.loads(path.read_text())['parent'], '')
+        finally:
+            os.unlink('new.pdf')
+
+    def test_shared_names(self):
+        path = self.source_dir / (self.uuid + '.metadata')
+        original = path.read_text()
+        path.write_text(json.dumps({**self.metadata(),
+                                    'visibleName': 'ipsum'}))
+        try:
+            names = sorted(n for n in os.listdir('.') if n.endswith('.pdf'))
+            self.assertEqual(names, ['ipsum (c180a2a6).pdf',
+                                     'ipsum (f27527b8).pdf'])
+            self.assertEqual(Path('ipsum (f27527b8).pdf').stat().st_size,
+                             28859)
+        finally:
+            path.write_text(original)
+
+
+class LongNameTest(FixtureCopyTest):
+    fixture = 'longname'
+
+    def assertTooLong(self, f, *args):
+        with self.assertRaises(OSError) as cm:
+            f(*args)
+        self.assertEqual(cm.exception.errno, errno.ENAMETOOLONG)
+
+    def test_listing(self):
+        names = sorted(os.listdir('.'))
+        self.assertEqual(len(names), 3)
+        for name, uuid in zip(names, ['5b3d9e1a', '6c4eaf2b']):
+            self.assertLessEqual(len(name.encode()), 255)
+            self.assertTrue(name.startswith('Long name ä'))
+            self.assertTrue(name.endswith(' (%s).pdf' % uuid))
+
+    def test_read(self):
+        name = next(n for n in os.listdir('.') if '6c4eaf2b' in n)
+        self.assertEqual(Path(name).read_bytes(),
+                         (ROOT / 'source' /
+                          'f27527b8-62d4-4e44-9511-43bdf275d364.pdf')
+                         .read_bytes())
+
+    def test_too_long(self):
+        name = 'x' * 252 + '.pdf'
+        self.assertTooLong(open, name, 'wb')
+        self.assertTooLong(os.mkdir, 'x' * 256)
+        existing = next(n for n in os.listdir('.') if n.endswith('.pdf'))
+        self.assertTooLong(os.rename, existing, name)
+        self.assertIn(existing, os.listdir('.'))
+
+    def test_unlink(self):
+        name = next(n for n in os.listdir('.') if '5b3d9e1a' in n)
+        os.unlink(name)
+        self.assertNotIn(name, os.listdir('.'))
+        self.assertFalse((self.source_dir /
+            '5b3d9e1a-7c2f-4e8b-a6d0-1f4c8e2b9a37.metadata').exists())
+
+
+class TrashFolderTest(FixtureCopyTest):
+    """A user folder called trash next to the virtual one"""
+    fixture = 'trashfolder'
+    folder = '3f8a1c2e-5b7d-4e9f-8a6c-0d2e4f6a8b1c'
+    loose = '6cbd4f5b-8eaa-4b2c-9d9f-3a5b7c9e1f4a'
+
+    def parent(self, uuid):
+        path = self.source_dir / (uuid + '.metadata')
+        return json.loads(path.read_text())['parent']
+
+    def test_listing(self):
+        self.assertEqual(sorted(os.listdir('.')),
+                         ['loose.pdf', 'trash', 'trash (folder)'])
+        self.assertEqual(os.listdir('trash'), ['deleted.pdf'])
+        self.assertEqual(os.listdir('trash (folder)'), ['kept.pdf'])
+
+    def test_lookup(self):
+        self.assertEqual(os.stat('trash').st_ino, 2)
+        self.assertEqual(os.stat('trash (folder)').st_ino,
+                         (self.source_dir / (self.folder + '.metadata'))
+                         .stat().st_ino)
+        self.assertEqual(Path('trash (folder)/kept.pdf').stat().st_size,
+                         28859)
+        self.assertFalse(Path('trash/kept.pdf').exists())
+
+    def test_rename_into_both(self):
+        os.rename('loose.pdf', 'trash (folder)/loose.pdf')
+        self.assertEqual(self.parent(self.loose), self.folder)
+        os.rename('trash (folder)/loose.pdf', 'trash/loose.pdf')
+        self.assertEqual(self.parent(self.loose), 'trash')
+        os.rename('trash/loose.pdf', 'loose.pdf')
+        self.assertEqual(self.parent(self.loose), '')
+
+    def test_virtual_trash_stays(self):
+        for f, args in [(os.rename, ('trash (folder)', 'trash')),
+                        (os.rename, ('trash', 'bin')),
+                        (os.rmdir, ('trash',))]:
+            with self.assertRaises(OSError) as cm:
+                f(*args)
+            self.assertEqual(cm.exception.errno, errno.EPERM)
+        self.assertIn('trash (folder)', os.listdir('.'))
+
+
+class StaleParentTest(FixtureCopyTest):
+    fixture = 'source'
+
+    def test_create_in_removed_folder(self):
+        os.mkdir('gone')
+        fd = os.open('gone', os.O_RDONLY | os.O_DIRECTORY)
+        try:
+            # removed behind the mount's back, the kernel still has the inode
+            for path in self.source_dir.glob('*.metadata'):
+                if json.loads(path.read_text())['visibleName'] == 'gone':
+                    path.unlink()
+            start = time.monotonic()
+            with self.assertRaises(OSError) as cm:
+                os.open('new.pdf', os.O_CREAT | os.O_WRONLY, dir_fd=fd)
+            self.assertEqual(cm.exception.errno, errno.ENOENT)
+            self.assertLess(time.monotonic() - start, 1)
+        finally:
+            os.close(fd)
+
+
+def json_types(value):
+    """The value with every leaf replaced by its type, bool and int apart"""
+    if isinstance(value, dict):
+        return {k: json_types(v) for k, v in value.items()}
+    if isinstance(value, list):
+        return [json_types(v) for v in value]
+    return type(value)
+
+
+class MetadataVariantTest(FixtureCopyTest):
+    """Metadata from several firmware generations and tools"""
+    fixture = 'metadata'
+    paths = {
+        'a1c4e2f0-1b7d-4c3e-9a5f-0d2e4b6c8a10': 'v2 document.pdf',
+        'b2d5f3a1-2c8e-4d4f-8b6a-1e3f5c7d9b21': 'v3 document.pdf',
+        'c3e6a4b2-3d9f-4e5a-9c7b-2f4a6d8e0c32':
+            'v2 folder/integer timestamps.pdf',
+        'd4f7b5c3-4e0a-4f6b-8d8c-3a5b7e9f1d43': 'v2 folder',
+        'e5a8c6d4-5f1b-4a7c-9e9d-4b6c8f0a2e54': 'v3 folder',
+        'f6b9d7e5-6a2c-4b8d-8f0e-5c7d9a1b3f65': 'bare folder',
+    }
+
+    def metadata(self, uuid, source=None):
+        path = (source or self.source_dir) / (uuid + '.metadata')
+        return json.loads(path.read_text())
+
+    def test_all_load(self):
+        for path in self.paths.values():
+            self.assertTrue(os.path.exists(path), path)
+
+    def test_rename_round_trip(self):
+        for uuid, path in self.paths.items():
+            before = self.metadata(uuid, ROOT / self.fixture)
+            renamed = str(Path(path).with_stem('renamed'))
+            os.rename(path, renamed)
+            os.rename(renamed, path)
+            after = self.metadata(uuid)
+            # the name is written as given, extension and all
+            before['visibleName'] = Path(path).name
+            self.assertEqual(after, before, path)
+            self.assertEqual(json_types(after), json_types(before), path)
+
+
+class DeviceFormatTest(FixtureCopyTest):
+    """Metadata is written formatted like the device writes it"""
+    fixture = 'source'
+    # dolor, as written by the device
+    folder = '90e4bbaa-cec6-478f-bd08-aa1fa4b58fe8.metadata'
+
+    def device_format(self, path):
+        return json.dumps(json.loads(path.read_text()), indent=4,
+                          sort_keys=True) + '\n'
+
+    def test_golden(self):
+        os.rename('dolor', 'renamed')
+        os.rename('renamed', 'dolor')
+        self.assertEqual((self.source_dir / self.folder).read_text(),
+                         (ROOT / 'source' / self.folder).read_text())
+
+    def test_new_files(self):
+        os.mkdir('new folder')
+        shutil.copyfile(ROOT / 'ipsum.pdf', 'new.pdf')
+        for name in ['new folder', 'new.pdf']:
+            path = settled_metadata(self.source_dir, name)
+            self.assertEqual(path.read_text(), self.device_format(path))
+        content = settled_metadata(self.source_dir, 'new.pdf')
+        content = content.with_suffix('.content')
+        self.assertEqual(content.read_text(), self.device_format(content))
+
+
+class CompactMetadataTest(DeviceFormatTest):
+    args = ['--compact-metadata']
+
+    def test_golden(self):
+        os.rename('dolor', 'renamed')
+        text = (self.source_dir / self.folder).read_text()
+        self.assertEqual(text.count('\n'), 0)
+        self.assertEqual(json.loads(text)['visibleName'], 'renamed')
+        os.rename('renamed', 'dolor')
+
+    def device_format(self, path):
+        return json.dumps(json.loads(path.read_text()), sort_keys=True,
+                          separators=(',', ':'))
+
+
+class PanicGuardTest(FixtureCopyTest):
+    fixture = 'source'
+
+    @classmethod
+    def setUpClass(cls):
+        # debug builds panic in the named operation
+        os.environ['FUSE_RM_INJECT_PANIC'] = 'getxattr'
+        try:
+            super().setUpClass()
+        finally:
+            del os.environ['FUSE_RM_INJECT_PANIC']
+
+    def test_mount_survives(self):
+        for _ in range(2):
+            with self.assertRaises(OSError) as cm:
+                os.getxattr('ipsum.pdf', 'user.rm.sha256')
+            self.assertEqual(cm.exception.errno, errno.EIO)
+            self.assertEqual(sorted(os.listdir('.')),
+                             ['dolor', 'ipsum.pdf', 'lorem.epub', 'trash'])
+        self.assertEqual(Path('ipsum.pdf').read_bytes(),
+                         (ROOT / 'ipsum.pdf').read_bytes())
+
+
+class IoRetryTest(FixtureCopyTest):
+    fixture = 'source'
+    # debug builds fail this many source operations with EIO first
+    injected = 3
+
+    @classmethod
+    def setUpClass(cls):
+        os.environ['FUSE_RM_INJECT_IO_ERRORS'] = str(cls.injected)
+        try:
+            super().setUpClass()
+        finally:
+            del os.environ['FUSE_RM_INJECT_IO_ERRORS']
+
+    def test_listing(self):
+        self.assertEqual(sorted(os.listdir('.')),
+                         ['dolor', 'ipsum.pdf', 'lorem.epub', 'trash'])
+        self.assertEqual(Path('ipsum.pdf').read_bytes(),
+                         (ROOT / 'ipsum.pdf').read_bytes())
+
+
+class NoIoRetryTest(IoRetryTest):
+    args = ['--io-retries', '0']
+    injected = 1
+
+    def test_listing(self):
+        with self.assertRaises(OSError) as cm:
+            os.listdir('.')
+        self.assertEqual(cm.exception.errno, errno.EIO)
+        super().test_listing()
+
+
+class IoTimeoutTest(FixtureCopyTest):
+    """A source slower than --io-timeout fails the request, not the mount"""
+    fixture = 'source'
+    args = ['--io-timeout', '0.5']
+
+    @classmethod
+    def setUpClass(cls):
+        # debug builds take this long for every timed source operation
+        os.environ['FUSE_RM_INJECT_SOURCE_DELAY_MS'] = '1500'
+        try:
+            super().setUpClass()
+        finally:
+            del os.environ['FUSE_RM_INJECT_SOURCE_DELAY_MS']
+
+    def test_listing_given_up(self):
+        started = time.monotonic()
+        with self.assertRaises(OSError) as cm:
+            os.listdir('.')
+        self.assertEqual(cm.exception.errno, errno.ETIMEDOUT)
+        self.assertLess(time.monotonic() - started, 1.4)
+        # the mount still answers what doesn't need the source
+        self.assertTrue(os.path.isdir('.'))
+
+
+class IoTimeoutInTimeTest(FixtureCopyTest):
+    fixture = 'source'
+    args = ['--io-timeout', '5']
+
+    def test_listing(self):
+        self.assertEqual(sorted(os.listdir('.')),
+                         ['dolor', 'ipsum.pdf', 'lorem.epub', 'trash'])
+        self.assertEqual(Path('ipsum.pdf').read_bytes(),
+                         (ROOT / 'ipsum.pdf').read_bytes())
+
+
+class WorkersTest(FixtureCopyTest):
+    """A slow read doesn't hold up other requests"""
+    fixture = 'source'
+    # debug builds sleep this long in every read and hash
+    delay = 1000
+
+    @classmethod
+    def setUpClass(cls):
+        os.environ['FUSE_RM_INJECT_JOB_DELAY_MS'] = str(cls.delay)
+        try:
+            super().setUpClass()
+        finally:
+            del os.environ['FUSE_RM_INJECT_JOB_DELAY_MS']
+
+    def list_while_reading(self):
+        data = []
+        reader = Thread(target=lambda: data.append(
+            Path('ipsum.pdf').read_bytes()))
+        reader.start()
+        time.sleep(0.2)
+        start = time.monotonic()
+        self.assertIn('ipsum.pdf', os.listdir('.'))
+        os.stat('lorem.epub')
+        took = time.monotonic() - start
+        reader.join()
+        self.assertEqual(data, [(ROOT / 'ipsum.pdf').read_bytes()])
+        return took
+
+    def test_listing_during_read(self):
+        self.assertLess(self.list_while_reading(), self.delay / 2000)
+
+
+class InlineIoTest(WorkersTest):
+    """Without threads the listing waits for the read"""
+    args = ['--io-threads', '0']
+
+    def test_listing_during_read(self):
+        self.assertGreater(self.list_while_reading(), self.delay / 2000)
+
+
+class LogFileTest(FixtureCopyTest):
+    """Log lines carry the request they were logged for"""
+    fixture = 'source'
+    log_format = 'json'
+
+    @classmethod
+    def prepare(cls):
+        cls.log = cls._scratch / 'fuse-rm.log'
+        cls.args = ['--log-file', str(cls.log),
+                    '--log-format', cls.log_format]
+        # reads are only logged at trace level
+        os.environ['RUST_LOG'] = 'trace'
+
+    @classmethod
+    def setUpClass(cls):
+        try:
+            super().setUpClass()
+        finally:
+            os.environ.pop('RUST_LOG', None)
+
+    def test_spans(self):
+        ino = os.stat('ipsum.pdf').st_ino
+        Path('ipsum.pdf').read_bytes()
+        lines = [json.loads(line) for line in self.log.read_text().splitlines()]
+        lookups = [l for l in lines if l.get('op') == 'lookup'
+                   and l.get('name') == 'ipsum.pdf']
+        self.assertTrue(lookups)
+        reads = [l for l in lines if l.get('op') == 'read'
+                 and l.get('ino') == ino]
+        self.assertTrue(reads)
+        # a request's lines share its id, the last has the duration
+        req = reads[-1]['req']
+        same = [l for l in lines if l.get('req') == req]
+        self.assertEqual(same[-1]['msg'], 'done')
+        self.assertIn('took_us', same[-1])
+
+
+class TextLogFileTest(LogFileTest):
+    log_format = 'text'
+
+    def test_spans(self):
+        os.stat('ipsum.pdf')
+        self.assertIn('op=lookup ino=1 name="ipsum.pdf"', self.log.read_text())
+
+
+class VerbosityTest(FixtureCopyTest):
+    """-vv logs at debug level, without the lines of every read"""
+    fixture = 'source'
+
+    @classmethod
+    def prepare(cls):
+        cls.log = cls._scratch / 'fuse-rm.log'
+        cls.args = ['-vv', '--log-file', str(cls.log)]
+        os.environ.pop('RUST_LOG', None)
+
+    def test_debug_without_reads(self):
+        self.assertIn(b'Log level: debug\n', self._fuserm_banner)
+        Path('ipsum.pdf').read_bytes()
+        log = self.log.read_text()
+        self.assertIn('op=lookup ino=1 name="ipsum.pdf"', log)
+        self.assertIn('op=open', log)
+        self.assertNotIn('op=read ', log)
+
+
+class RustLogOverVerbosityTest(LogFileTest):
+    """An explicit RUST_LOG wins over -v"""
+    log_format = 'text'
+
+    @classmethod
+    def prepare(cls):
+        super().prepare()
+        cls.args = ['-v', *cls.args]
+
+    def test_spans(self):
+        self.assertIn(b'Log level: RUST_LOG=trace\n', self._fuserm_banner)
+        Path('ipsum.pdf').read_bytes()
+        self.assertIn('op=read ', self.log.read_text())
+
+
+class HandleTimeoutTest(FixtureCopyTest):
+    fixture = 'conflict'
+    args = ['--handle-timeout', '1']
+
+    def source_names(self):
+        return [json.loads(p.read_text())['visibleName']
+                for p in self.source_dir.glob('*.metadata')]
+
+    def test_idle_upload_finalized(self):
+        fd = os.open('idle.pdf', os.O_CREAT | os.O_WRONLY)
+        try:
+            os.write(fd, (ROOT / 'ipsum.pdf').read_bytes())
+            time.sleep(2.5)
+            os.listdir('.')
+            self.assertIn('idle.pdf', self.source_names())
+            with self.assertRaises(OSError):
+                os.write(fd, b'more')
+        finally:
+            os.close(fd)
+        self.assertEqual(Path('idle.pdf').read_bytes(),
+                         (ROOT / 'ipsum.pdf').read_bytes())
+
+    def test_idle_read_closed(self):
+        fd = os.open('Report.pdf', os.O_RDONLY)
+        try:
+            os.pread(fd, 16, 0)
+            time.sleep(2.5)
+            os.listdir('.')
+            # past the page cache, the read reaches the mount
+            os.posix_fadvise(fd, 0, 0, os.POSIX_FADV_DONTNEED)
+            with self.assertRaises(OSError):
+                os.pread(fd, 16, 0)
+        finally:
+            os.close(fd)
+        with open('Report.pdf', 'rb') as f:
+            self.assertEqual(len(f.read(16)), 16)
+
+    def test_active_upload_kept(self):
+        data = (ROOT / 'ipsum.pdf').read_bytes()
+        chunk = len(data) // 5 + 1
+        with open('active.pdf', 'wb') as f:
+            for i in range(0, len(data), chunk):
+                f.write(data[i:i + chunk])
+                f.flush()
+                os.listdir('.')
+                time.sleep(0.6)
+        self.assertEqual(Path('active.pdf').read_bytes(), data)
+
+
+class StagingDirTest(FixtureCopyTest):
+    """New documents staged on another filesystem than the source"""
+    fixture = 'conflict'
+
+    @classmethod
+    def setUpClass(cls):
+        # tmpfs, the scratch copy of the source is on disk
+        cls.staging = Path(tempfile.mkdtemp(dir='/dev/shm')) / 'staging'
+        cls.args = ['--staging-dir', str(cls.staging)]
+        super().setUpClass()
+
+    @classmethod
+    def tearDownClass(cls):
+        super().tearDownClass()
+        try:
+            # created for the mount, so removed with it
+            if cls.staging.exists():
+                raise AssertionError('staging dir left behind')
+        finally:
+            shutil.rmtree(cls.staging.parent)
+
+    def test_copied_in_place(self):
+        data = (ROOT / 'ipsum.pdf').read_bytes()
+        with open('staged.pdf', 'wb') as f:
+            f.write(data)
+            f.flush()
+            # written out, the mount collects writes until then
+            os.fsync(f.fileno())
+            staged = list(self.staging.iterdir())
+            self.assertEqual([p.stat().st_size for p in staged], [len(data)])
+            self.assertEqual(list(self.source_dir.glob('.pending/*.pdf')), [])
+            self.assertEqual(list(self.source_dir.glob('.pending/*-*-*')),
+                             list(self.source_dir.glob('.pending/*.metadata')))
+        self.assertEqual(Path('staged.pdf').read_bytes(), data)
+        self.assertEqual(list(self.staging.iterdir()), [])
+        uuid = staged[0].name
+        self.assertEqual((self.source_dir / (uuid + '.pdf')).read_bytes(),
+                         data)
+        self.assertEqual(list(self.source_dir.glob('.pending/*.pdf')), [])
+
+    def test_unsupported_discarded(self):
+        with self.assertRaises(OSError):
+            with open('notes.txt', 'wb') as f:
+                f.write((ROOT / 'lorem.txt').read_bytes())
+        self.assertEqual(list(self.staging.iterdir()), [])
+
+
+class PendingLeftoversTest(FixtureCopyTest):
+    """What crashed uploads left in .pending is completed or removed"""
+    fixture = 'conflict'
+    uuids = {name: '%s0000000-0000-4000-8000-000000000000' % c
+             for name, c in [('salvaged', 'a'), ('half', 'b'),
+                             ('text', 'c'), ('no data', 'd'),
+                             ('no metadata', 'e'), ('fresh', 'f'),
+                             ('broken', '1')]}
+
+    @classmethod
+    def leftover(cls, name, data=None, metadata=True, old=True):
+        pending = cls.source_dir / '.pending'
+        pending.mkdir(exist_ok=True)
+        uuid = cls.uuids[name]
+        paths = []
+        if metadata is True:
+            metadata = json.dumps({'parent': '', 'type': 'DocumentType',
+                                   'visibleName': name + '.pdf',
+                                   'lastModified': '1700000000000'})
+        if metadata:
+            paths.append(pending / (uuid + '.metadata'))
+            paths[-1].write_text(metadata)
+        if data is not None:
+            paths.append(pending / uuid)
+            paths[-1].write_bytes(data)
+        for path in paths if old else []:
+            os.utime(path, (time.time() - 7200,) * 2)
+
+    @classmethod
+    def prepare(cls):
+        pdf = (ROOT / 'ipsum.pdf').read_bytes()
+        cls.leftover('salvaged', pdf)
+        cls.leftover('half')
+        (cls.source_dir / (cls.uuids['half'] + '.pdf')).write_bytes(pdf)
+        cls.leftover('text', (ROOT / 'lorem.txt').read_bytes())
+        cls.leftover('no data')
+        cls.leftover('no metadata', b'junk', metadata=False)
+        cls.leftover('fresh', pdf, old=False)
+        cls.leftover('broken', pdf, metadata='{"parent": ')
+
+    def test_completed(self):
+        pdf = (ROOT / 'ipsum.pdf').read_bytes()
+        for name in ['salvaged', 'half']:
+            self.assertEqual(Path(name + '.pdf').read_bytes(), pdf)
+        content = json.loads((self.source_dir /
+                              (self.uuids['salvaged'] + '.content'))
+                             .read_text())
+        self.assertEqual(content['fileType'], 'pdf')
+
+    def test_removed(self):
+        left = sorted(p.name for p in (self.source_dir / '.pending').iterdir())
+        fresh = self.uuids['fresh']
+        self.assertEqual(left, [fresh, fresh + '.metadata'])
+        for name in ['text', 'no data', 'no metadata', 'broken']:
+            self.assertEqual(list(self.source_dir.glob(self.uuids[name] +
+                                                       '*')), [])
+        self.assertNotIn('fresh.pdf', os.listdir('.'))
+
+
+class MissingDataTest(FixtureCopyTest):
+    """Documents whose data file is gone"""
+    fixture = 'missingdata'
+    missing = {'book': '11111111-1111-4111-8111-111111111111',
+               'novel': '22222222-2222-4222-8222-222222222222',
+               'no content': '33333333-3333-4333-8333-333333333333'}
+
+    def test_empty_files(self):
+        for name in self.missing:
+            self.assertTrue(os.path.isfile(name), name)
+            self.assertEqual(os.stat(name).st_size, 0)
+            self.assertEqual(os.getxattr(name, 'user.rm.missing_data'),
+                             b'true')
+            self.assertEqual(os.listxattr(name), ['user.rm.missing_data'])
+
+    def test_others_unchanged(self):
+        self.assertTrue(os.path.isdir('folder'))
+        # notebook pages aren't a data file, the notebook is no folder either
+        self.assertTrue(os.path.isfile('sketches.rm'))
+        self.assertEqual(os.listxattr('intact.pdf'), ['user.rm.sha256'])
+        with self.assertRaises(OSError) as cm:
+            os.getxattr('intact.pdf', 'user.rm.missing_data')
+        self.assertEqual(cm.exception.errno, errno.ENODATA)
+
+    def test_open(self):
+        # the error of opening the data file
+        with self.assertRaises(OSError) as cm:
+            open('book', 'rb')
+        self.assertEqual(cm.exception.errno, errno.ENOENT)
+
+    def test_unlink(self):
+        uuid = self.missing['no content']
+        os.unlink('no content')
+        self.assertNotIn('no content', os.listdir('.'))
+        self.assertEqual(list(self.source_dir.glob(uuid + '*')), [])
+
+
+class DocTypeTest(FixtureCopyTest):
+    """The metadata type decides folder or document, data file or not"""
+    fixture = 'doctype'
+
+    def test_folders(self):
+        self.assertEqual(os.listdir('folder'), [])
+        # the pdf next to its metadata doesn't make it a document
+        self.assertTrue(os.path.isdir('stray'))
+        self.assertEqual(os.listdir('stray'), [])
+        self.assertNotIn('stray.pdf', os.listdir('.'))
+
+    def test_documents(self):
+        self.assertTrue(os.path.isfile('document.pdf'))
+        self.assertEqual(os.stat('document.pdf').st_size, 15)
+        self.assertTrue(os.path.isfile('no data'))
+        self.assertEqual(os.stat('no data').st_size, 0)
+
+    def test_other_types_hidden(self):
+        # a template doesn't keep the rest from being listed
+        self.assertEqual(sorted(os.listdir('.')),
+                         ['document.pdf', 'folder', 'no data', 'stray',
+                          'trash'])
+
+
+class OtherTypeTest(FixtureCopyTest):
+    """Entries of types other than documents and folders, on request"""
+    fixture = 'doctype'
+    args = ['--show-other-types']
+    template = '0a1b2c3d-0000-4000-8000-000000000005'
+
+    def test_listed(self):
+        self.assertTrue(os.path.isfile('grid'))
+
+    def test_rename_keeps_type(self):
+        path = self.source_dir / (self.template + '.metadata')
+        os.rename('grid', 'grid 2')
+        meta = json.loads(path.read_text())
+        self.assertEqual(meta['type'], 'TemplateType')
+        self.assertEqual(meta['visibleName'], 'grid 2')
+
+
+class TagsTest(FixtureCopyTest):
+    """Tags in the content file (3.x) or the metadata, through user.rm.tags"""
+    fixture = 'tags'
+    v3 = '0b1c2d3e-0000-4000-8000-000000000001'
+    legacy = '0b1c2d3e-0000-4000-8000-000000000002'
+    untagged = '0b1c2d3e-0000-4000-8000-000000000003'
+
+    def setUp(self):
+        # written over in place, the mount keeps the inodes
+        for path in (ROOT / self.fixture).iterdir():
+            shutil.copyfile(path, self.source_dir / path.name)
+
+    def tags(self, name):
+        return json.loads(os.getxattr(name, 'user.rm.tags'))
+
+    def source_json(self, uuid, ext):
+        return json.loads((self.source_dir / (uuid + ext)).read_text())
+
+    def test_read(self):
+        self.assertEqual(self.tags('v3.pdf'), ['work', 'urgent'])
+        self.assertEqual(self.tags('legacy.pdf'), ['old'])
+        self.assertEqual(self.tags('untagged.pdf'), [])
+        self.assertIn('user.rm.tags', os.listxattr('v3.pdf'))
+        self.assertNotIn('user.rm.tags', os.listxattr('untagged.pdf'))
+        with self.assertRaises(OSError) as cm:
+            os.getxattr('folder', 'user.rm.tags')
+        self.assertEqual(cm.exception.errno, errno.ENODATA)
+
+    def test_round_trip_content(self):
+        os.setxattr('v3.pdf', 'user.rm.tags', b'["urgent", "home"]')
+        self.assertEqual(self.tags('v3.pdf'), ['urgent', 'home'])
+        content = self.source_json(self.v3, '.content')
+        # the tag kept keeps when it was added
+        self.assertEqual(content['tags'][0],
+                         {'name': 'urgent', 'timestamp': 1700000000002})
+        self.assertEqual(content['tags'][1]['name'], 'home')
+        self.assertEqual(content['pageTags'], [])
+        meta = self.source_json(self.v3, '.metadata')
+        self.assertTrue(meta['modified'])
+        self.assertGreater(int(meta['lastModified']), 1700000000000)
+        self.assertNotIn('tags', meta)
+
+    def test_round_trip_metadata(self):
+        os.setxattr('legacy.pdf', 'user.rm.tags', b'["old", "new", "old"]')
+        self.assertEqual(self.tags('legacy.pdf'), ['old', 'new'])
+        meta = self.source_json(self.legacy, '.metadata')
+        self.assertEqual(meta['tags'], ['old', 'new'])
+        self.assertEqual(meta['version'], 2)
+        self.assertNotIn('tags', self.source_json(self.legacy, '.content'))
+
+    def test_new_tags_in_content(self):
+        os.setxattr('untagged.pdf', 'user.rm.tags', b'["first"]')
+        content = self.source_json(self.untagged, '.content')
+        self.assertEqual([t['name'] for t in content['tags']], ['first'])
+        self.assertIsInstance(content['tags'][0]['timestamp'], int)
+
+    def test_remove(self):
+        os.removexattr('v3.pdf', 'user.rm.tags')
+        self.assertEqual(self.tags('v3.pdf'), [])
+        self.assertEqual(self.source_json(self.v3, '.content')['tags'], [])
+
+    def test_invalid(self):
+        for value in [b'"work"', b'[1]', b'[""]', b'["\xff"]', b'\xff',
+                      json.dumps(['x' * 300]).encode(),
+                      json.dumps(['t%d' % i for i in range(1000)]).encode()]:
+            with self.assertRaises(OSError) as cm:
+                os.setxattr('untagged.pdf', 'user.rm.tags', value)
+            self.assertEqual(cm.exception.errno, errno.EINVAL, value)
+        self.assertEqual(self.tags('untagged.pdf'), [])
+
+    def test_other_names_refused(self):
+        with self.assertRaises(OSError) as cm:
+            os.setxattr('v3.pdf', 'user.rm.sha256', b'x')
+        self.assertEqual(cm.exception.errno, errno.ENOTSUP)
+
+
+class RenditionTest(FixtureCopyTest):
+    """An epub the device rendered to a pdf, shown as the original"""
+    fixture = 'rendition'
+    uuid = '0c1d2e3f-0000-4000-8000-000000000001'
+    shown, ext = 'Book.epub', '.epub'
+
+    def test_data_file(self):
+        self.assertEqual(sorted(os.listdir('.')), [self.shown, 'trash'])
+        data = (self.source_dir / (self.uuid + self.ext)).read_bytes()
+        self.assertEqual(os.stat(self.shown).st_size, len(data))
+        self.assertEqual(Path(self.shown).read_bytes(), data)
+
+    def test_unlink_removes_both(self):
+        os.unlink(self.shown)
+        self.assertEqual(list(self.source_dir.glob(self.uuid + '*')), [])
+
+
+class PdfRenditionTest(RenditionTest):
+    args = ['--prefer-rendition', 'pdf']
+    shown, ext = 'Book.pdf', '.pdf'
+
+
+class BothRenditionsTest(FixtureCopyTest):
+    """The epub and its pdf rendition side by side, the pdf read-only"""
+    fixture = 'rendition'
+    args = ['--prefer-rendition', 'both']
+    uuid = '0c1d2e3f-0000-4000-8000-000000000001'
+
+    def source_bytes(self, ext):
+        return (self.source_dir / (self.uuid + ext)).read_bytes()
+
+    def test_both_shown(self):
+        self.assertEqual(sorted(os.listdir('.')),
+                         ['Book.epub', 'Book.pdf', 'trash'])
+        self.assertNotEqual(os.stat('Book.epub').st_ino,
+                            os.stat('Book.pdf').st_ino)
+        for ext in ['.epub', '.pdf']:
+            data = self.source_bytes(ext)
+            self.assertEqual(os.stat('Book' + ext).st_size, len(data))
+            self.assertEqual(Path('Book' + ext).read_bytes(), data)
+        self.assertEqual(os.stat('Book.pdf').st_mode & 0o777, 0o444)
+
+    def test_rendition_read_only(self):
+        for change in [lambda: os.unlink('Book.pdf'),
+                       lambda: os.rename('Book.pdf', 'Other.pdf'),
+                       lambda: os.open('Book.pdf', os.O_WRONLY)]:
+            with self.assertRaises(OSError) as cm:
+                change()
+            self.assertEqual(cm.exception.errno, errno.EROFS)
+        self.assertEqual(len(self.source_bytes('.pdf')),
+                         (ROOT / 'ipsum.pdf').stat().st_size)
+
+    def test_rename_original(self):
+        os.rename('Book.epub', 'Novel.epub')
+        self.assertEqual(sorted(os.listdir('.')),
+                         ['Novel.epub', 'Novel.pdf', 'trash'])
+        os.rename('Novel.epub', 'Book.epub')
+
+
+class BirthTimeTest(FixtureCopyTest):
+    """createdTime as the birth time, lastModified where there is none"""
+    fixture = 'birthtime'
+
+    def birth_time(self, name):
+        # 0 where the kernel doesn't ask fuse for it
+        out = check_output(['stat', '--format=%W', name]).decode().strip()
+        return int(out) if out.isdigit() else 0
+
+    def assertBirthTime(self, name, expected):
+        if self.birth_time('.') == 0 and self.birth_time(name) == 0:
+            self.skipTest('birth times are not visible through this mount')
+        self.assertEqual(self.birth_time(name), expected)
+
+    def test_created_time(self):
+        self.assertBirthTime('v3.pdf', 1600000000)
+
+    def test_last_modified_without_created_time(self):
+        self.assertBirthTime('legacy.pdf', 1650000000)
+
+    def test_both_shown(self):
+        self.assertEqual(sorted(os.listdir('.')),
+                         ['legacy.pdf', 'trash', 'v3.pdf'])
+
+    def test_new_document_has_created_time(self):
+        before = int(time.time() * 1000)
+        with open('new.pdf', 'wb') as f:
+            f.write(b'%PDF-1.4\n')
+        path = settled_metadata(self.source_dir, 'new.pdf')
+        self.assertIsNotNone(path)
+        meta = json.loads(path.read_text())
+        self.assertIsInstance(meta['createdTime'], str)
+        self.assertGreaterEqual(int(meta['createdTime']), before)
+
+
+class NotebookSizeTest(FixtureCopyTest):
+    """Notebooks are as large as their .rm pages together"""
+    fixture = 'notebook'
+    pages = Path('0e1f2a3b-0000-4000-8000-000000000001')
+
+    def test_sum_of_pages(self):
+        # the layer metadata next to the pages isn't counted
+        self.assertEqual(os.stat('Sketches.rm').st_size, 100 + 250 + 1000)
+
+    def test_without_page_dir(self):
+        self.assertEqual(os.stat('Blank.rm').st_size, 0)
+
+    def test_page_added(self):
+        page = self.source_dir / self.pages / 'new.rm'
+        page.write_bytes(b'x' * 500)
+        try:
+            # past the attr ttl
+            time.sleep(1.1)
+            self.assertEqual(os.stat('Sketches.rm').st_size, 1850)
+        finally:
+            page.unlink()
+            time.sleep(1.1)
+        self.assertEqual(os.stat('Sketches.rm').st_size, 1350)
+
+
+class ControlFileTest(FixtureCopyTest):
+    """Writing to .fuse-rm/refresh drops the caches, reading it gives when
+    the source was last listed"""
+    fixture = 'notebook'
+    page = Path('0e1f2a3b-0000-4000-8000-000000000001',
+                'aaaaaaaa-0000-4000-8000-000000000001.rm')
+
+    def assertPerm(self, f, *args):
+        with self.assertRaises(OSError) as cm:
+            f(*args)
+        self.assertEqual(cm.exception.errno, errno.EPERM)
+
+    def test_refresh(self):
+        self.assertEqual(os.stat('Sketches.rm').st_size, 1350)
+        # the page dir keeps its mtime, the cached sizes are kept
+        with open(self.source_dir / self.page, 'ab') as f:
+            f.write(b'x' * 500)
+        time.sleep(1.1)
+        self.assertEqual(os.stat('Sketches.rm').st_size, 1350)
+        before = float(Path('.fuse-rm/refresh').read_text())
+        time.sleep(0.01)
+        with open('.fuse-rm/refresh', 'w') as f:
+            f.write('1\n')
+        after = float(Path('.fuse-rm/refresh').read_text())
+        self.assertGreater(after, before)
+        self.assertLess(abs(after - time.time()), 60)
+        time.sleep(1.1)
+        self.assertEqual(os.stat('Sketches.rm').st_size, 1850)
+
+    def test_hidden(self):
+        self.assertNotIn('.fuse-rm', os.listdir('.'))
+        self.assertTrue(os.path.isdir('.fuse-rm'))
+        self.assertEqual(os.listdir('.fuse-rm'), ['refresh'])
+
+    def test_rejected(self):
+        self.assertPerm(os.mkdir, '.fuse-rm/folder')
+        self.assertPerm(open, '.fuse-rm/new.pdf', 'wb')
+        self.assertPerm(os.unlink, '.fuse-rm/refresh')
+        self.assertPerm(os.rename, '.fuse-rm', 'control')
+        self.assertPerm(os.rmdir, '.fuse-rm')
+        with self.assertRaises(FileExistsError):
+            os.mkdir('.fuse-rm')
+
+    def test_no_hash(self):
+        for path in ['.fuse-rm', '.fuse-rm/refresh']:
+            with self.assertRaises(OSError) as cm:
+                os.getxattr(path, 'user.rm.sha256')
+            self.assertEqual(cm.exception.errno, errno.ENODATA, path)
+
+
+class ExposeControlTest(MountTest):
+    args = ['--expose-control']
+
+    def test_listed(self):
+        self.assertIn('.fuse-rm', os.listdir('.'))
+
+
+class DeepMtimeTest(FixtureCopyTest):
+    """--deep-mtime shows notebooks as modified when their newest page was"""
+    fixture = 'notebook'
+    args = ['--deep-mtime']
+    page = Path('0e1f2a3b-0000-4000-8000-000000000001',
+                'aaaaaaaa-0000-4000-8000-000000000002.rm')
+
+    def touch_page(self):
+        later = int(time.time()) + 3600
+        os.utime(self.source_dir / self.page, (later, later))
+        # past the attr ttl
+        time.sleep(1.1)
+        return later
+
+    def test_touched_page(self):
+        later = self.touch_page()
+        self.assertEqual(int(os.stat('Sketches.rm').st_mtime), later)
+
+    def test_without_pages(self):
+        meta = self.source_dir / '0e1f2a3b-0000-4000-8000-000000000002.metadata'
+        self.assertEqual(os.stat('Blank.rm').st_mtime,
+                         int(meta.stat().st_mtime))
+
+
+class ShallowMtimeTest(DeepMtimeTest):
+    """Without --deep-mtime the pages don't count"""
+    args = []
+
+    def test_touched_page(self):
+        self.touch_page()
+        meta = self.source_dir / '0e1f2a3b-0000-4000-8000-000000000001.metadata'
+        self.assertEqual(os.stat('Sketches.rm').st_mtime,
+                         int(meta.stat().st_mtime))
+
+
+class NotADirTest(FixtureCopyTest):
+    """A document has nothing below it, paths through one are ENOTDIR"""
+    fixture = 'source'
+
+    def assertNotDir(self, f, *args):
+        with self.assertRaises(OSError) as cm:
+            f(*args)
+        self.assertEqual(cm.exception.errno, errno.ENOTDIR)
+
+    def test_stat(self):
+        self.assertNotDir(os.stat, 'ipsum.pdf/anything')
+        self.assertNotDir(os.stat, 'dolor/lorem.pdf/anything')
+
+    def test_create(self):
+        self.assertNotDir(open, 'ipsum.pdf/new.pdf', 'wb')
+        self.assertEqual(sorted(os.listdir('.')),
+                         ['dolor', 'ipsum.pdf', 'lorem.epub', 'trash'])
+
+    def test_mkdir(self):
+        self.assertNotDir(os.mkdir, 'ipsum.pdf/folder')
+        self.assertEqual(sorted(os.listdir('.')),
+                         ['dolor', 'ipsum.pdf', 'lorem.epub', 'trash'])
+
+
+class BufferedWriteTest(FixtureCopyTest):
+    """Writes to new documents are collected, they end up as if written one
+    by one"""
+    fixture = 'source'
+
+    @classmethod
+    def prepare(cls):
+        cls.data = (ROOT / 'ipsum.pdf').read_bytes()
+
+    def data_file(self, name):
+        path = settled_metadata(self.source_dir, name)
+        self.assertIsNotNone(path)
+        return path.with_suffix('.pdf')
+
+    def test_out_of_order(self):
+        chunks = [(0, 4096), (8192, 4096), (4096, 4096), (12288, None)]
+        fd = os.open('unordered.pdf', os.O_CREAT | os.O_RDWR)
+        try:
+            for offset, size in chunks:
+                end = None if size is None else offset + size
+                os.pwrite(fd, self.data[offset:end], offset)
+            # read back through the same handle
+            self.assertEqual(os.pread(fd, 16384, 0), self.data[:16384])
+        finally:
+            os.close(fd)
+        self.assertEqual(self.data_file('unordered.pdf').read_bytes(),
+                         self.data)
+
+    def test_size_while_open(self):
+        with open('open.pdf', 'wb', buffering=0) as f:
+            for offset in range(0, len(self.data), 4096):
+                f.write(self.data[offset:offset + 4096])
+            self.assertEqual(os.fstat(f.fileno()).st_size, len(self.data))
+        self.assertEqual(self.data_file('open.pdf').read_bytes(), self.data)
+
+    def test_large(self):
+        # more than is collected before writing out
+        data = self.data * 20
+        with open('large.pdf', 'wb', buffering=0) as f:
+            for offset in range(0, len(data), 4096):
+                f.write(data[offset:offset + 4096])
+        self.assertEqual(self.data_file('large.pdf').read_bytes(), data)
+
+    def test_mixed_sizes(self):
+        # large requests go out from their buffer with what was collected
+        data = self.data * 20
+        sizes = [4096, 100000, 1, 4096, 200000, 128 * 1024, 7, 4096]
+        with open('mixed.pdf', 'wb', buffering=0) as f:
+            offset, i = 0, 0
+            while offset < len(data):
+                size = sizes[i % len(sizes)]
+                f.write(data[offset:offset + size])
+                offset, i = offset + size, i + 1
+        self.assertEqual(self.data_file('mixed.pdf').read_bytes(), data)
+
+    def test_fsync(self):
+        with open('synced.pdf', 'wb', buffering=0) as f:
+            f.write(self.data[:4096])
+            os.fsync(f.fileno())
+            staged = [p for p in (self.source_dir / '.pending').iterdir()
+                      if p.suffix != '.metadata']
+            self.assertEqual([p.stat().st_size for p in staged], [4096])
+        self.assertEqual(self.data_file('synced.pdf').read_bytes(),
+                         self.data[:4096])
+
+
+class SyncWriteTest(FixtureCopyTest):
+    """--sync-write syncs every write and the finished document"""
+    fixture = 'conflict'
+    args = ['--sync-write']
+
+    def fsyncs(self, expected, timeout=2):
+        # the files are synced on the release, after close(2) returned
+        deadline = time.monotonic() + timeout
+        while True:
+            stats = os.getxattr('.', 'user.rm.stats').decode()
+            n = int(re.search(r'^fsyncs: +(\d+)$', stats, re.M).group(1))
+            if n >= expected or time.monotonic() > deadline:
+                return n
+            time.sleep(0.05)
+
+    def test_counts(self):
+        data = (ROOT / 'ipsum.pdf').read_bytes()[:3 * 4096]
+        before = self.fsyncs(0)
+        with open('synced.pdf', 'wb', buffering=0) as f:
+            for offset in range(0, len(data), 4096):
+                f.write(data[offset:offset + 4096])
+            # a write each
+            self.assertEqual(self.fsyncs(before + 3, 0), before + 3)
+        # the data again, the content, metadata and the dir of the renames
+        self.assertEqual(self.fsyncs(before + 8), before + 8)
+        path = settled_metadata(self.source_dir, 'synced.pdf')
+        self.assertEqual(path.with_suffix('.pdf').read_bytes(), data)
+
+
+class PrescanTest(FixtureCopyTest):
+    """--prescan reads the library before the mount is ready"""
+    fixture = 'source'
+    args = ['--prescan']
+    expected = (b'5 documents, 1 folders, 1 in the trash, 0 orphans, '
+                b'0 unreadable, 219185 bytes (')
+
+    def test_summary(self):
+        prefix = b'Prescanned %s: ' % bytes(self.source_dir)
+        lines = [l for l in self._fuserm_banner if l.startswith(prefix)]
+        self.assertEqual(len(lines), 1)
+        self.assertTrue(lines[0][len(prefix):].startswith(self.expected),
+                        lines[0])
+
+    def test_listing(self):
+        self.assertEqual(sorted(os.listdir('.')),
+                         ['dolor', 'ipsum.pdf', 'lorem.epub', 'trash'])
+
+
+class PrescanNoSizesTest(PrescanTest):
+    args = ['--prescan-no-sizes']
+    expected = (b'5 documents, 1 folders, 1 in the trash, 0 orphans, '
+                b'0 unreadable (')
+
+    @classmethod
+    def prepare(cls):
+        # counted as unreadable
+        (cls.source_dir / 'broken.metadata').write_text('{')
+        cls.expected = cls.expected.replace(b'0 unreadable', b'1 unreadable')
+
+    def test_listing(self):
+        # which listings don't get past
+        (self.source_dir / 'broken.metadata').unlink()
+        super().test_listing()
+
+
+class LibrarySummaryTest(FixtureCopyTest):
+    """What the source holds is counted once it was first listed, for the
+    log and the stats"""
+    fixture = 'source'
+
+    @classmethod
+    def prepare(cls):
+        orphan = cls.source_dir / '0f1a2b3c-0000-4000-8000-000000000001.pdf'
+        shutil.copyfile(ROOT / 'ipsum.pdf', orphan)
+
+    def library(self, timeout=2):
+        # counted in the background after the first listing
+        os.listdir('.')
+        deadline = time.monotonic() + timeout
+        while True:
+            stats = os.getxattr('.', 'user.rm.stats').decode()
+            line = re.search(r'^library: +(.*)$', stats, re.M).group(1)
+            if line != '-' or time.monotonic() > deadline:
+                return line
+            time.sleep(0.05)
+
+    def test_stats(self):
+        self.assertEqual(self.library(),
+                         'mounted 5 documents in 1 folders (1 in trash, '
+                         '1 orphans, 0 metadata errors) from %s'
+                         % self.source_dir)
+
+
+class PrescanLibraryTest(LibrarySummaryTest):
+    args = ['--prescan']
+
+    def test_stats(self):
+        # before any listing
+        stats = os.getxattr('.', 'user.rm.stats').decode()
+        self.assertNotIn('library:           -', stats)
+        super().test_stats()
+
+
+class DanglingParentTest(FixtureCopyTest):
+    """A document whose folder is gone is in no dir, lookups at the root
+    don't find it either"""
+    fixture = 'source'
+    stray = '5a6b7c8d-0000-4000-8000-000000000001'
+    twin = '5a6b7c8d-0000-4000-8000-000000000002'
+
+    @classmethod
+    def prepare(cls):
+        source = cls.source_dir / 'c180a2a6-fab9-4ba5-9e7f-3a71f9a1bd09'
+        for uuid, name in [(cls.stray, 'stray.pdf'), (cls.twin, 'ipsum.pdf')]:
+            metadata = json.loads(source.with_suffix('.metadata').read_text())
+            metadata['parent'] = '5a6b7c8d-0000-4000-8000-0000000000ff'
+            metadata['visibleName'] = name
+            (cls.source_dir / (uuid + '.metadata')).write_text(
+                json.dumps(metadata))
+            shutil.copy(source.with_suffix('.content'),
+                        cls.source_dir / (uuid + '.content'))
+            (cls.source_dir / (uuid + '.pdf')).write_bytes(b'%PDF-1.4 stray')
+
+    def test_not_listed(self):
+        self.assertEqual(sorted(os.listdir('.')),
+                         ['dolor', 'ipsum.pdf', 'lorem.epub', 'trash'])
+
+    def test_not_found(self):
+        with self.assertRaises(FileNotFoundError):
+            os.stat('stray.pdf')
+
+    def test_same_name(self):
+        self.assertEqual(os.stat('ipsum.pdf').st_size, 126501)
+        self.assertEqual(Path('ipsum.pdf').read_bytes()[:8], b'%PDF-1.5')
+
+
+class FsyncDirTest(FixtureCopyTest):
+    """Dirs can be synced, for programs making sure new files are there"""
+    fixture = 'source'
+
+    def fsync_dir(self, path):
+        fd = os.open(path, os.O_RDONLY | os.O_DIRECTORY)
+        try:
+            os.fsync(fd)
+            os.fdatasync(fd)
+        finally:
+            os.close(fd)
+
+    def test_root(self):
+        self.fsync_dir('.')
+
+    def test_folder(self):
+        self.fsync_dir('dolor')
+        self.fsync_dir('trash')
+
+    def test_pending(self):
+        with open('dolor/synced.pdf', 'wb') as f:
+            f.write((ROOT / 'ipsum.pdf').read_bytes())
+            self.fsync_dir('dolor')
+        self.assertIsNotNone(settled_metadata(self.source_dir, 'synced.pdf'))
+        self.fsync_dir('dolor')
+        self.assertIn('synced.pdf', os.listdir('dolor'))
+
+
+class CtlTest(FixtureCopyTest):
+    """`fuse-rm ctl` commands a live mount through ioctls on its root"""
+    fixture = 'source'
+
+    @classmethod
+    def prepare(cls):
+        cls.log = cls._scratch / 'fuse-rm.log'
+        cls.args = ['--log-file', str(cls.log)]
+
+    def ctl(self, *args):
+        return run([FUSERM, 'ctl', self.target_dir, *args], stdout=PIPE,
+                   check=True).stdout.decode()
+
+    def ioctl(self, path, nr):
+        cmd = (3 << 30) | (4096 << 16) | (ord('R') << 8) | nr
+        message = bytearray(4096)
+        message[0:4] = (1).to_bytes(4, sys.byteorder)
+        fd = os.open(path, os.O_RDONLY)
+        try:
+            fcntl.ioctl(fd, cmd, message)
+        finally:
+            os.close(fd)
+        return message
+
+    def test_stats(self):
+        Path('ipsum.pdf').read_bytes()
+        stats = json.loads(self.ctl('stats'))
+        self.assertEqual(stats['bytes_read'], 126501)
+        self.assertEqual(stats['fsyncs'], 0)
+
+    def test_log_level(self):
+        self.ctl('log-level', 'debug')
+        os.stat('lorem.epub')
+        self.ctl('log-level', 'error')
+        self.assertIn('lookup', self.log.read_text())
+
+    def test_flush_and_rescan(self):
+        self.ctl('flush-caches')
+        self.ctl('rescan')
+        for _ in range(40):
+            library = json.loads(self.ctl('stats'))['library']
+            if library:
+                break
+            time.sleep(0.05)
+        self.assertEqual(len(library), 1)
+        self.assertIn('mounted 5 documents', library[0])
+
+    def test_unknown(self):
+        with self.assertRaises(OSError) as cm:
+            self.ioctl('.', 99)
+        self.assertEqual(cm.exception.errno, errno.ENOTTY)
+        with self.assertRaises(OSError) as cm:
+            self.ioctl('ipsum.pdf', 2)
+        self.assertEqual(cm.exception.errno, errno.ENOTTY)
+        self.assertIn(b'"fsyncs"', self.ioctl('.', 2))
+
+    def test_bad_level(self):
+        res = run([FUSERM, 'ctl', self.target_dir, 'log-level', 'loud'],
+                  stdout=PIPE)
+        self.assertEqual(res.returncode, 1)
+
+
+class NormalizeTest(FixtureCopyTest):
+    """With --normalize nfc, a name stored with a combining accent is shown
+    composed and found in either form"""
+    fixture = 'source'
+    args = ['--normalize', 'nfc']
+    composed = 'Caf\u00e9'
+    decomposed = 'Cafe\u0301'
+
+    @classmethod
+    def prepare(cls):
+        path = cls.source_dir / '90e4bbaa-cec6-478f-bd08-aa1fa4b58fe8.metadata'
+        metadata = json.loads(path.read_text())
+        metadata['visibleName'] = cls.decomposed
+        path.write_text(json.dumps(metadata))
+
+    def test_listed_composed(self):
+        self.assertIn(self.composed, os.listdir('.'))
+        self.assertNotIn(self.decomposed, os.listdir('.'))
+
+    def test_both_forms(self):
+        self.assertEqual(os.stat(self.composed).st_ino,
+                         os.stat(self.decomposed).st_ino)
+        self.assertEqual(sorted(os.listdir(self.decomposed)),
+                         sorted(os.listdir(self.composed)))
+
+    def test_collision(self):
+        with self.assertRaises(FileExistsError):
+            os.mkdir(self.composed)
+
+    def test_stored_composed(self):
+        Path('Cre\u0300me.pdf').write_bytes(b'%PDF-1.4 creme')
+        self.assertIsNotNone(
+            settled_metadata(self.source_dir, 'Cr\u00e8me.pdf'))
+        self.assertEqual(Path('Cr\u00e8me.pdf').read_bytes(),
+                         b'%PDF-1.4 creme')
+
+
+class NormalizeNfdTest(FixtureCopyTest):
+    """With --normalize nfd, names are shown decomposed"""
+    fixture = 'source'
+    args = ['--normalize', 'nfd']
+
+    @classmethod
+    def prepare(cls):
+        path = cls.source_dir / '90e4bbaa-cec6-478f-bd08-aa1fa4b58fe8.metadata'
+        metadata = json.loads(path.read_text())
+        metadata['visibleName'] = 'Caf\u00e9'
+        path.write_text(json.dumps(metadata))
+
+    def test_listed_decomposed(self):
+        self.assertIn('Cafe\u0301', os.listdir('.'))
+        self.assertTrue(os.path.isdir('Caf\u00e9'))
+
+
+class AsciiNamesTest(FixtureCopyTest):
+    """--ascii-names shows names transliterated, the metadata keeps them"""
+    fixture = 'source'
+    args = ['--ascii-names']
+    documents = {
+        'b1000000-0000-4000-8000-000000000001': '\u041f\u0440\u0438\u0432\u0435\u0442.pdf',
+        'b2000000-0000-4000-8000-000000000002': '\u65e5\u672c.pdf',
+        'b3000000-0000-4000-8000-000000000003': '\u00edpsum.pdf',
+        'b4000000-0000-4000-8000-000000000004': '\u03b1\u03b2\u03b3.pdf',
+    }
+
+    @classmethod
+    def prepare(cls):
+        source = cls.source_dir / 'c180a2a6-fab9-4ba5-9e7f-3a71f9a1bd09'
+        for uuid, name in cls.documents.items():
+            metadata = json.loads(source.with_suffix('.metadata').read_text())
+            metadata['visibleName'] = name
+            (cls.source_dir / (uuid + '.metadata')).write_text(
+                json.dumps(metadata))
+            shutil.copy(source.with_suffix('.content'),
+                        cls.source_dir / (uuid + '.content'))
+            shutil.copy(source.with_suffix('.pdf'),
+                        cls.source_dir / (uuid + '.pdf'))
+        path = cls.source_dir / '90e4bbaa-cec6-478f-bd08-aa1fa4b58fe8.metadata'
+        metadata = json.loads(path.read_text())
+        metadata['visibleName'] = 'D\u00e9j\u00e0 vu'
+        path.write_text(json.dumps(metadata))
+
+    def test_listed(self):
+        self.assertEqual(sorted(os.listdir('.')),
+                         ['Deja vu', 'Privet.pdf', 'avg.pdf', 'b2000000.pdf',
+                          'ipsum (b3000000).pdf', 'ipsum.pdf', 'lorem.epub',
+                          'trash'])
+
+    def test_lookup(self):
+        self.assertEqual(os.stat('Privet.pdf').st_size, 126501)
+        self.assertEqual(sorted(os.listdir('Deja vu')),
+                         ['ipsum.epub', 'lorem.pdf'])
+        with self.assertRaises(FileNotFoundError):
+            os.stat(self.documents['b1000000-0000-4000-8000-000000000001'])
+
+    def test_metadata_untouched(self):
+        for uuid, name in self.documents.items():
+            metadata = self.source_dir / (uuid + '.metadata')
+            self.assertEqual(json.loads(metadata.read_text())['visibleName'],
+                             name)
+
+    def test_rename_verbatim(self):
+        os.rename('avg.pdf', '\u0416\u0443\u043a.pdf')
+        metadata = self.source_dir / 'b4000000-0000-4000-8000-000000000004.metadata'
+        self.assertEqual(json.loads(metadata.read_text())['visibleName'],
+                         '\u0416\u0443\u043a.pdf')
+        self.assertTrue(os.path.isfile('Zhuk.pdf'))
+        os.rename('Zhuk.pdf', 'avg.pdf')
+        self.assertEqual(json.loads(metadata.read_text())['visibleName'],
+                         'avg.pdf')
+
+
+class ExtensionMapTest(FixtureCopyTest):
+    """--map shows documents of a type with another extension, and takes it
+    in new names"""
+    fixture = 'source'
+    args = ['--map', 'pdf=pdfx', '--map', 'epub=epub3']
+
+    def test_listed(self):
+        self.assertLessEqual({'dolor', 'ipsum.pdfx', 'lorem.epub3', 'trash'},
+                             set(os.listdir('.')))
+        self.assertNotIn('ipsum.pdf', os.listdir('.'))
+        self.assertEqual(sorted(os.listdir('dolor')),
+                         ['ipsum.epub3', 'lorem.pdfx'])
+
+    def test_lookup(self):
+        self.assertEqual(os.stat('ipsum.pdfx').st_size, 126501)
+        with self.assertRaises(FileNotFoundError):
+            os.stat('ipsum.pdf')
+
+    def test_create(self):
+        Path('mapped.pdfx').write_bytes(b'%PDF-1.4 mapped')
+        metadata = settled_metadata(self.source_dir, 'mapped.pdf')
+        self.assertIsNotNone(metadata)
+        self.assertTrue(metadata.with_suffix('.pdf').exists())
+        self.assertIn('mapped.pdfx', os.listdir('.'))
+        self.assertEqual(Path('mapped.pdfx').read_bytes(), b'%PDF-1.4 mapped')
+
+    def test_rename(self):
+        os.rename('dolor/lorem.pdfx', 'dolor/sit.pdfx')
+        names = [json.loads(path.read_text())['visibleName']
+                 for path in self.source_dir.glob('*.metadata')]
+        self.assertIn('sit.pdf', names)
+        os.rename('dolor/sit.pdfx', 'dolor/lorem.pdfx')
+
+    def test_duplicate_target(self):
+        res = run([FUSERM, '--map', 'rm=pdf', self.source_dir, self.target_dir],
+                  stdout=PIPE)
+        self.assertEqual(res.returncode, 1)
+        self.assertIn(b'pdf', res.stdout)
+
+
+def documents_with(source, data):
+    """The metadata files of the documents in `source` whose pdf is `data`"""
+    return [path for path in source.glob('*.metadata')
+            if path.with_suffix('.pdf').exists()
+            and path.with_suffix('.pdf').read_bytes() == data]
+
+
+class DedupTest(FixtureCopyTest):
+    """--dedup fail refuses a document with the data of another one"""
+    fixture = 'source'
+    args = ['--dedup', 'fail']
+
+    def test_same_bytes(self):
+        data = b'%PDF-1.4 dedup'
+        Path('first.pdf').write_bytes(data)
+        self.assertIsNotNone(settled_metadata(self.source_dir, 'first.pdf'))
+        with self.assertRaises(FileExistsError):
+            Path('second.pdf').write_bytes(data)
+        self.assertIsNone(
+            settled_metadata(self.source_dir, 'second.pdf', timeout=0.5))
+        self.assertEqual(len(documents_with(self.source_dir, data)), 1)
+        self.assertNotIn('second.pdf', os.listdir('.'))
+        self.assertEqual(os.listdir(self.source_dir / '.pending'), [])
+
+    def test_existing(self):
+        data = Path('ipsum.pdf').read_bytes()
+        with self.assertRaises(FileExistsError):
+            Path('dolor/copy.pdf').write_bytes(data)
+        self.assertNotIn('copy.pdf', os.listdir('dolor'))
+        index = json.loads(
+            (self.source_dir / '.fuse-rm' / 'dedup.json').read_text())
+        self.assertIn('c180a2a6-fab9-4ba5-9e7f-3a71f9a1bd09', index)
+
+    def test_other_bytes(self):
+        Path('one.pdf').write_bytes(b'%PDF-1.4 one')
+        Path('two.pdf').write_bytes(b'%PDF-1.4 two')
+        self.assertIsNotNone(settled_metadata(self.source_dir, 'two.pdf'))
+
+    def test_after_removal(self):
+        data = b'%PDF-1.4 removed'
+        Path('removed.pdf').write_bytes(data)
+        self.assertIsNotNone(settled_metadata(self.source_dir, 'removed.pdf'))
+        os.remove('removed.pdf')
+        Path('again.pdf').write_bytes(data)
+        self.assertIsNotNone(settled_metadata(self.source_dir, 'again.pdf'))
+
+
+class DedupDiscardTest(FixtureCopyTest):
+    """--dedup discard drops the copy without an error"""
+    fixture = 'source'
+    args = ['--dedup', 'discard']
+
+    def test_same_bytes(self):
+        data = b'%PDF-1.4 discarded'
+        Path('first.pdf').write_bytes(data)
+        self.assertIsNotNone(settled_metadata(self.source_dir, 'first.pdf'))
+        Path('second.pdf').write_bytes(data)
+        self.assertIsNone(
+            settled_metadata(self.source_dir, 'second.pdf', timeout=0.5))
+        self.assertEqual(len(documents_with(self.source_dir, data)), 1)
+        self.assertEqual(os.listdir(self.source_dir / '.pending'), [])
+
+
+class OpenHandleAttrTest(FixtureCopyTest):
+    """fstat of a descriptor being written has the size and mtime of the
+    last write"""
+    fixture = 'source'
+
+    def test_fstat_after_write(self):
+        fd = os.open('growing.pdf', os.O_WRONLY | os.O_CREAT, 0o644)
+        try:
+            os.write(fd, b'%PDF-1.4 first')
+            first = os.fstat(fd)
+            self.assertEqual(first.st_size, 14)
+            time.sleep(0.05)
+            os.write(fd, b' second')
+            second = os.fstat(fd)
+            self.assertEqual(second.st_size, 21)
+            self.assertGreater(second.st_mtime_ns, first.st_mtime_ns)
+            self.assertEqual(os.stat('growing.pdf').st_size, 21)
+        finally:
+            os.close(fd)
+        self.assertIsNotNone(settled_metadata(self.source_dir, 'growing.pdf'))
+        self.assertEqual(os.stat('growing.pdf').st_size, 21)
+
+    def test_fstat_reading(self):
+        with open('ipsum.pdf', 'rb') as f:
+            self.assertEqual(os.fstat(f.fileno()).st_size, 126501)
+            self.assertEqual(os.fstat(f.fileno()).st_size,
+                             os.stat('ipsum.pdf').st_size)
+
+
+class AccessModeTest(FixtureCopyTest):
+    """Descriptors only read or write as they were opened for"""
+    fixture = 'source'
+
+    def test_write_read_only(self):
+        fd = os.open('ipsum.pdf', os.O_RDONLY)
+        try:
+            with self.assertRaises(OSError) as e:
+                os.write(fd, b'%PDF-1.4')
+            self.assertEqual(e.exception.errno, errno.EBADF)
+            self.assertEqual(len(os.read(fd, 4)), 4)
+        finally:
+            os.close(fd)
+        self.assertEqual(os.stat('ipsum.pdf').st_size, 126501)
+
+    def test_read_write_only(self):
+        fd = os.open('write-only.pdf', os.O_WRONLY | os.O_CREAT, 0o644)
+        try:
+            os.write(fd, b'%PDF-1.4 write only')
+            with self.assertRaises(OSError) as e:
+                os.read(fd, 4)
+            self.assertEqual(e.exception.errno, errno.EBADF)
+        finally:
+            os.close(fd)
+        self.assertIsNotNone(
+            settled_metadata(self.source_dir, 'write-only.pdf'))
+
+    def test_read_write(self):
+        fd = os.open('read-write.pdf', os.O_RDWR | os.O_CREAT, 0o644)
+        try:
+            os.write(fd, b'%PDF-1.4 both')
+            self.assertEqual(os.pread(fd, 8, 0), b'%PDF-1.4')
+        finally:
+            os.close(fd)
+
+
+class DeletedTest(FixtureCopyTest):
+    """Entries marked deleted are left out, apart from the trash"""
+    fixture = 'source'
+    args = []
+    gone = ['0de1e7ed-0000-4000-8000-000000000001',
+            '0de1e7ed-0000-4000-8000-000000000002']
+
+    @classmethod
+    def prepare(cls):
+        source = cls.source_dir / 'c180a2a6-fab9-4ba5-9e7f-3a71f9a1bd09'
+        metadata = json.loads(source.with_suffix('.metadata').read_text())
+        for uuid, parent in zip(cls.gone,
+                                ['', '90e4bbaa-cec6-478f-bd08-aa1fa4b58fe8']):
+            target = cls.source_dir / uuid
+            shutil.copy(source.with_suffix('.pdf'), target.with_suffix('.pdf'))
+            target.with_suffix('.metadata').write_text(json.dumps(dict(
+                metadata, deleted=True, parent=parent,
+                visibleName='gone ' + uuid[-1])))
+
+    def test_left_out(self):
+        self.assertNotIn('gone 1.pdf', os.listdir('.'))
+        self.assertNotIn('gone 2.pdf', os.listdir('dolor'))
+        self.assertNotIn('.deleted', os.listdir('.'))
+        self.assertEqual(os.listdir('trash'), ['lorem-trashed.pdf'])
+
+
+class DeletedShownTest(DeletedTest):
+    """--expose-deleted shows them in .deleted, read-only"""
+    args = ['--expose-deleted']
+
+    def test_left_out(self):
+        self.assertNotIn('gone 1.pdf', os.listdir('.'))
+        self.assertNotIn('gone 2.pdf', os.listdir('dolor'))
+        self.assertIn('.deleted', os.listdir('.'))
+        self.assertEqual(os.listdir('trash'), ['lorem-trashed.pdf'])
+
+    def test_listed(self):
+        self.assertEqual(sorted(os.listdir('.deleted')),
+                         ['gone 1.pdf', 'gone 2.pdf'])
+        self.assertEqual(os.stat('.deleted/gone 1.pdf').st_size, 126501)
+
+    def test_read_only(self):
+        with self.assertRaises(OSError) as e:
+            open('.deleted/new.pdf', 'wb')
+        self.assertEqual(e.exception.errno, errno.EPERM)
+        with self.assertRaises(OSError) as e:
+            os.unlink('.deleted/gone 1.pdf')
+        self.assertEqual(e.exception.errno, errno.EPERM)
+
+    def test_copy_out(self):
+        shutil.copyfile('.deleted/gone 2.pdf', 'recovered.pdf')
+        metadata = settled_metadata(self.source_dir, 'recovered.pdf')
+        self.assertIsNotNone(metadata)
+        self.assertFalse(json.loads(metadata.read_text()).get('deleted'))
+        self.assertEqual(os.stat('recovered.pdf').st_size, 126501)
+        self.assertEqual(len(os.listdir('.deleted')), 2)
+
+    def test_no_hash(self):
+        with self.assertRaises(OSError) as cm:
+            os.getxattr('.deleted', 'user.rm.sha256')
+        self.assertEqual(cm.exception.errno, errno.ENODATA)
+
+
+class BusyGuardTest(FixtureCopyTest):
+    """The document xochitl has open can't be removed or renamed"""
+    fixture = 'source'
+    flags = []
+
+    @classmethod
+    def prepare(cls):
+        cls.state = cls._scratch / 'xochitl.conf'
+        cls.state.write_text(
+            '[General]\nLastOpen=c180a2a6-fab9-4ba5-9e7f-3a71f9a1bd09\n')
+        cls.args = cls.flags + ['--busy-state', str(cls.state)]
+
+    def test_refused(self):
+        for change in [lambda: os.unlink('ipsum.pdf'),
+                       lambda: os.rename('ipsum.pdf', 'dolor/ipsum.pdf'),
+                       lambda: os.rename('lorem.epub', 'ipsum.pdf')]:
+            with self.assertRaises(OSError) as e:
+                change()
+            self.assertEqual(e.exception.errno, errno.EBUSY)
+        self.assertEqual(os.stat('ipsum.pdf').st_size, 126501)
+
+    def test_others_changed(self):
+        os.rename('lorem.epub', 'lorem2.epub')
+        os.rename('lorem2.epub', 'lorem.epub')
+
+
+class NoBusyGuardTest(BusyGuardTest):
+    """--no-busy-guard lets the open document be changed"""
+    flags = ['--no-busy-guard']
+
+    def test_refused(self):
+        os.rename('ipsum.pdf', 'ipsum2.pdf')
+        os.rename('ipsum2.pdf', 'ipsum.pdf')
+
+
+class DirSnapshotTest(FixtureCopyTest):
+    """Every opendir lists again, whatever handles are still open"""
+    fixture = 'source'
+
+    def test_relisted_while_open(self):
+        fd = os.open('dolor', os.O_RDONLY | os.O_DIRECTORY)
+        try:
+            with open('dolor/new.pdf', 'wb') as f:
+                f.write(b'%PDF-1.4 new')
+            self.assertIsNotNone(settled_metadata(self.source_dir, 'new.pdf'))
+            self.assertIn('new.pdf', os.listdir('dolor'))
+            os.unlink('dolor/new.pdf')
+            self.assertNotIn('new.pdf', os.listdir('dolor'))
+            # the handle opened first keeps what it listed
+            with os.scandir(fd) as entries:
+                self.assertEqual(sorted(e.name for e in entries),
+                                 ['ipsum.epub', 'lorem.pdf'])
+        finally:
+            os.close(fd)
+
+
+class RemovedDirTest(FixtureCopyTest):
+    """A folder removed while open lists nothing more"""
+    fixture = 'source'
+    folder = '90e4bbaa-cec6-478f-bd08-aa1fa4b58fe8'
+
+    def test_removed_out_of_band(self):
+        fd = os.open('dolor', os.O_RDONLY | os.O_DIRECTORY)
+        try:
+            (self.source_dir / (self.folder + '.metadata')).unlink()
+            with os.scandir(fd) as entries:
+                self.assertEqual([e.name for e in entries], [])
+        finally:
+            os.close(fd)
+        # once the kernel forgot the attributes
+        time.sleep(1.1)
+        with self.assertRaises(FileNotFoundError):
+            os.stat('dolor')
+        with self.assertRaises(FileNotFoundError):
+            os.stat('dolor/lorem.pdf')
+
+
+class PosixTest(FixtureCopyTest):
+    """Sequences after pjdfstest, with the errno each step has to fail with.
+    Deviations of the library are pinned down as well: documents are
+    replaced by renaming over them, not written to (EACCES), new files
+    can't be created read-only (EINVAL), and there are no links or special
+    files (EPERM)."""
+    fixture = 'source'
+
+    def create(self, name, data=b'%PDF-1.4 posix'):
+        fd = os.open(name, os.O_WRONLY | os.O_CREAT, 0o644)
+        try:
+            os.write(fd, data)
+        finally:
+            os.close(fd)
+        self.assertIsNotNone(
+            settled_metadata(self.source_dir, Path(name).name))
+
+    def assertErrno(self, err, f, *args):
+        with self.assertRaises(OSError) as cm:
+            f(*args)
+        self.assertEqual(cm.exception.errno, err,
+                         '{}{}'.format(f.__name__, args))
+
+    def test_create_unlink_reuse(self):
+        for i in range(3):
+            self.create('reused.pdf', b'%PDF-1.4 take ' + bytes([48 + i]))
+            self.assertIn('reused.pdf', os.listdir('.'))
+            self.assertEqual(Path('reused.pdf').read_bytes()[-1], 48 + i)
+            os.unlink('reused.pdf')
+            self.assertNotIn('reused.pdf', os.listdir('.'))
+            self.assertErrno(errno.ENOENT, os.stat, 'reused.pdf')
+
+    def test_rename_over_existing(self):
+        self.create('over.pdf')
+        self.create('under.pdf', b'%PDF-1.4 replaced')
+        os.rename('over.pdf', 'under.pdf')
+        listing = os.listdir('.')
+        self.assertNotIn('over.pdf', listing)
+        self.assertEqual(listing.count('under.pdf'), 1)
+        self.assertEqual(Path('under.pdf').read_bytes(), b'%PDF-1.4 posix')
+        os.unlink('under.pdf')
+
+    def test_rename_dir_over_empty_dir(self):
+        os.mkdir('posix-a')
+        os.mkdir('posix-b')
+        os.rename('posix-a', 'posix-b')
+        self.assertNotIn('posix-a', os.listdir('.'))
+        self.assertEqual(os.listdir('.').count('posix-b'), 1)
+        self.assertErrno(errno.ENOTEMPTY, os.rename, 'posix-b', 'dolor')
+        os.rmdir('posix-b')
+
+    def test_eexist(self):
+        self.assertErrno(errno.EEXIST, os.mkdir, 'dolor')
+        self.assertErrno(errno.EEXIST, os.mkdir, 'lorem.epub')
+        self.assertErrno(errno.EEXIST, os.open, 'lorem.epub',
+                         os.O_WRONLY | os.O_CREAT | os.O_EXCL)
+
+    def test_enoent(self):
+        self.assertErrno(errno.ENOENT, os.stat, 'missing')
+        self.assertErrno(errno.ENOENT, os.open, 'missing', os.O_RDONLY)
+        self.assertErrno(errno.ENOENT, os.unlink, 'missing')
+        self.assertErrno(errno.ENOENT, os.rmdir, 'missing')
+        self.assertErrno(errno.ENOENT, os.rename, 'missing', 'other')
+        self.assertErrno(errno.ENOENT, os.mkdir, 'missing/dir')
+        self.assertErrno(errno.ENOENT, os.listdir, 'missing')
+
+    def test_enotdir(self):
+        self.assertErrno(errno.ENOTDIR, os.stat, 'lorem.epub/x')
+        self.assertErrno(errno.ENOTDIR, os.mkdir, 'lorem.epub/x')
+        self.assertErrno(errno.ENOTDIR, os.open, 'lorem.epub/x.pdf',
+                         os.O_WRONLY | os.O_CREAT)
+        self.assertErrno(errno.ENOTDIR, os.listdir, 'lorem.epub')
+        self.assertErrno(errno.ENOTDIR, os.rmdir, 'lorem.epub')
+        self.assertErrno(errno.ENOTDIR, os.rename, 'dolor', 'lorem.epub')
+
+    def test_eisdir(self):
+        self.assertErrno(errno.EISDIR, os.unlink, 'dolor')
+        self.assertErrno(errno.EISDIR, os.open, 'dolor', os.O_WRONLY)
+        self.assertErrno(errno.EISDIR, os.rename, 'lorem.epub', 'dolor')
+
+    def test_einval(self):
+        self.assertErrno(errno.EINVAL, os.rename, 'dolor', 'dolor/sub')
+        self.assertErrno(errno.EINVAL, os.rmdir, 'dolor/.')
+        self.assertErrno(errno.EINVAL, os.open, 'read-only.pdf',
+                         os.O_RDONLY | os.O_CREAT, 0o644)
+
+    def test_open_flags(self):
+        for flags in (os.O_WRONLY, os.O_RDWR, os.O_WRONLY | os.O_TRUNC):
+            self.assertErrno(errno.EACCES, os.open, 'lorem.epub', flags)
+        self.assertEqual(os.stat('lorem.epub').st_size, 4091)
+        fd = os.open('lorem.epub', os.O_RDONLY)
+        try:
+            self.assertErrno(errno.EBADF, os.write, fd, b'x')
+        finally:
+            os.close(fd)
+
+    def test_eperm(self):
+        self.assertErrno(errno.EPERM, os.link, 'lorem.epub', 'linked.epub')
+        self.assertErrno(errno.EPERM, os.symlink, 'lorem.epub',
+                         'symlinked.epub')
+        self.assertErrno(errno.EPERM, os.mkfifo, 'fifo')
+        self.assertEqual(sorted(os.listdir('.')),
+                         ['dolor', 'ipsum.pdf', 'lorem.epub', 'trash'])
+
+    def test_readdir_after_mutations(self):
+        os.mkdir('posix-dir')
+        self.create('posix-dir/inner.pdf')
+        self.assertEqual(os.listdir('posix-dir'), ['inner.pdf'])
+        os.rename('posix-dir/inner.pdf', 'posix-dir/renamed.pdf')
+        self.assertEqual(os.listdir('posix-dir'), ['renamed.pdf'])
+        os.rename('posix-dir/renamed.pdf', 'moved.pdf')
+        self.assertEqual(os.listdir('posix-dir'), [])
+        self.assertIn('moved.pdf', os.listdir('.'))
+        os.unlink('moved.pdf')
+        os.rmdir('posix-dir')
+        self.assertEqual(sorted(os.listdir('.')),
+                         ['dolor', 'ipsum.pdf', 'lorem.epub', 'trash'])
+
+
+class StatfsTest(FixtureCopyTest):
+    """df -i gives the documents and folders shown as the used inodes"""
+    fixture = 'source'
+
+    def shown(self):
+        count = 0
+        for top, dirs, files in os.walk('.'):
+            count += len(files) + len([d for d in dirs if top != '.'
+                                       or d != 'trash'])
+        return count
+
+    def used(self):
+        st = os.statvfs('.')
+        return st.f_files - st.f_ffree
+
+    def test_inodes(self):
+        # ipsum.pdf, lorem.epub, dolor and its two, the one in the trash
+        self.assertEqual(self.shown(), 6)
+        self.assertEqual(self.used(), 6)
+        self.assertGreater(os.statvfs('.').f_ffree, 0)
+
+    def test_blocks(self):
+        st, source = os.statvfs('.'), os.statvfs(self.source_dir)
+        self.assertEqual(st.f_blocks, source.f_blocks)
+        self.assertEqual(st.f_frsize, source.f_frsize)
+        self.assertEqual(st.f_namemax, 255)
+
+    def test_growth(self):
+        os.mkdir('statfs-dir')
+        os.listdir('.')
+        self.assertEqual(self.used(), self.shown())
+        os.rmdir('statfs-dir')
+        os.listdir('.')
+        self.assertEqual(self.used(), self.shown())
+
+
+class StatfsFlatTest(StatfsTest):
+    args = ['--flat']
+
+    def test_inodes(self):
+        # no folders and no trash
+        self.assertEqual(self.shown(), 4)
+        self.assertEqual(self.used(), 4)
+
+    def test_growth(self):
+        # no folders can be made either
+        self.assertEqual(os.statvfs('.').f_ffree, 1000000)
+
+
+class ExclusiveCreateTest(FixtureCopyTest):
+    """O_EXCL also refuses a name the tablet shows alike"""
+    fixture = 'source'
+    EXCL = os.O_WRONLY | os.O_CREAT | os.O_EXCL
+
+    def assertExists(self, name):
+        with self.assertRaises(OSError) as cm:
+            os.open(name, self.EXCL, 0o644)
+        self.assertEqual(cm.exception.errno, errno.EEXIST, name)
+
+    def test_other_extension(self):
+        self.assertExists('ipsum.epub')
+        self.assertExists('dolor/lorem.epub')
+        # without O_EXCL only the same file name is taken
+        fd = os.open('lorem.pdf', os.O_WRONLY | os.O_CREAT, 0o644)
+        try:
+            os.write(fd, b'%PDF-1.4 not exclusive')
+        finally:
+            os.close(fd)
+        self.assertIsNotNone(settled_metadata(self.source_dir, 'lorem.pdf'))
+        os.unlink('lorem.pdf')
+
+    def test_pending(self):
+        fd = os.open('excl.pdf', self.EXCL, 0o644)
+        try:
+            os.write(fd, b'%PDF-1.4 pending')
+            self.assertExists('excl.pdf')
+            self.assertExists('excl')
+            self.assertExists('excl.epub')
+        finally:
+            os.close(fd)
+        self.assertIsNotNone(settled_metadata(self.source_dir, 'excl.pdf'))
+        self.assertExists('excl.epub')
+        os.unlink('excl.pdf')
+
+    def test_race(self):
+        barrier = Barrier(8)
+        results = []
+
+        def create():
+            barrier.wait()
+            try:
+                fd = os.open('raced.pdf', self.EXCL, 0o644)
+            except OSError as e:
+                results.append(e.errno)
+                return
+            os.write(fd, b'%PDF-1.4 won')
+            os.close(fd)
+            results.append(0)
+
+        threads = [Thread(target=create) for _ in range(8)]
+        for t in threads:
+            t.start()
+        for t in threads:
+            t.join()
+        self.assertEqual(sorted(results), [0] + [errno.EEXIST] * 7)
+        self.assertIsNotNone(settled_metadata(self.source_dir, 'raced.pdf'))
+        self.assertEqual(os.listdir('.').count('raced.pdf'), 1)
+        os.unlink('raced.pdf')
+
+
+class TrashReadTest(FixtureCopyTest):
+    """What is in the trash can be read and copied out, not changed there"""
+    fixture = 'source'
+
+    def trashed_data(self):
+        uuid = settled_metadata(self.source_dir, 'lorem-trashed').stem
+        return (self.source_dir / (uuid + '.pdf')).read_bytes()
+
+    def test_read(self):
+        data = self.trashed_data()
+        self.assertEqual(os.stat('trash/lorem-trashed.pdf').st_size, len(data))
+        self.assertEqual(Path('trash/lorem-trashed.pdf').read_bytes(), data)
+
+    def test_copy_out(self):
+        shutil.copyfile('trash/lorem-trashed.pdf', 'restored.pdf')
+        self.assertIsNotNone(settled_metadata(self.source_dir, 'restored.pdf'))
+        self.assertEqual(Path('restored.pdf').read_bytes(),
+                         self.trashed_data())
+        self.assertEqual(os.listdir('trash'), ['lorem-trashed.pdf'])
+        os.unlink('restored.pdf')
+
+    def test_unchanged_in_trash(self):
+        cases = [
+            (errno.EACCES, os.open, 'trash/lorem-trashed.pdf', os.O_WRONLY),
+            (errno.EPERM, os.open, 'trash/new.pdf',
+             os.O_WRONLY | os.O_CREAT, 0o644),
+            (errno.EPERM, os.mkdir, 'trash/folder'),
+            (errno.EPERM, os.rename, 'trash/lorem-trashed.pdf',
+             'trash/renamed.pdf'),
+        ]
+        for err, f, *args in cases:
+            with self.subTest(args=args):
+                with self.assertRaises(OSError) as cm:
+                    f(*args)
+                self.assertEqual(cm.exception.errno, err)
+        self.assertEqual(os.listdir('trash'), ['lorem-trashed.pdf'])
+
+
+class StagingLimitsTest(FixtureCopyTest):
+    """--max-pending and --max-staging-bytes bound what is staged at once"""
+    fixture = 'source'
+    args = ['--max-pending', '2', '--max-staging-bytes', '200000']
+
+    def stats(self):
+        return os.getxattr('.', 'user.rm.stats').decode()
+
+    def open_new(self, name):
+        return os.open(name, os.O_WRONLY | os.O_CREAT, 0o644)
+
+    def finish(self, fd, name):
+        os.close(fd)
+        self.assertIsNotNone(settled_metadata(self.source_dir, name))
+
+    def test_max_pending(self):
+        first = self.open_new('first.pdf')
+        second = self.open_new('second.pdf')
+        os.write(first, b'%PDF-1.4 first')
+        os.write(second, b'%PDF-1.4 second')
+        self.assertIn('pending uploads:   2', self.stats())
+        with self.assertRaises(OSError) as cm:
+            self.open_new('third.pdf')
+        self.assertEqual(cm.exception.errno, errno.EMFILE)
+        self.finish(first, 'first.pdf')
+        # the slot is free again once the first is finalized
+        third = self.open_new('third.pdf')
+        os.write(third, b'%PDF-1.4 third')
+        self.finish(second, 'second.pdf')
+        self.finish(third, 'third.pdf')
+        self.assertIn('pending uploads:   0', self.stats())
+        for name in ('first.pdf', 'second.pdf', 'third.pdf'):
+            os.unlink(name)
+
+    def test_max_staging_bytes(self):
+        data = b'%PDF-1.4 ' + bytes(150000)
+        fd = self.open_new('big.pdf')
+        try:
+            os.write(fd, data)
+            self.assertIn('staged bytes:      {}'.format(len(data)),
+                          self.stats())
+            # the requests up to the limit are taken, a short write
+            written = os.write(fd, bytes(100000))
+            self.assertLessEqual(len(data) + written, 200000)
+            with self.assertRaises(OSError) as cm:
+                os.write(fd, bytes(100000 - written))
+            self.assertEqual(cm.exception.errno, errno.ENOSPC)
+        finally:
+            self.finish(fd, 'big.pdf')
+        self.assertIn('staged bytes:      0', self.stats())
+        # what was finalized no longer counts
+        fd = self.open_new('again.pdf')
+        os.write(fd, data)
+        self.finish(fd, 'again.pdf')
+        self.assertEqual(os.stat('big.pdf').st_size, len(data) + written)
+        os.unlink('big.pdf')
+        os.unlink('again.pdf')
+
+
+class UnchangedMetadataTest(FixtureCopyTest):
+    """What changes nothing doesn't write the metadata again"""
+    fixture = 'source'
+    # dolor/ipsum.epub, its metadata as the device writes it
+    IPSUM = '462c4853-378f-4b76-a265-ea4ca5581049'
+
+    def metadata(self):
+        return self.source_dir / (self.IPSUM + '.metadata')
+
+    def assertUntouched(self, f, *args):
+        path = self.metadata()
+        before = (path.stat().st_mtime_ns, path.read_bytes())
+        time.sleep(0.02)
+        f(*args)
+        self.assertEqual((path.stat().st_mtime_ns, path.read_bytes()),
+                         before, '{}{}'.format(f.__name__, args))
+
+    def test_rename_to_itself(self):
+        self.assertUntouched(os.rename, 'dolor/ipsum.epub', 'dolor/ipsum.epub')
+        # stored without the extension, the same name without it
+        self.assertUntouched(os.rename, 'dolor/ipsum.epub', 'dolor/ipsum')
+        self.assertIn('ipsum.epub', os.listdir('dolor'))
+
+    def test_same_tags(self):
+        name = 'dolor/ipsum.epub'
+        os.setxattr(name, 'user.rm.tags', b'["same"]')
+        self.assertUntouched(os.setxattr, name, 'user.rm.tags', b'["same"]')
+        os.removexattr(name, 'user.rm.tags')
+        self.assertUntouched(os.removexattr, name, 'user.rm.tags')
+
+
+class AttrChangesTest(FixtureCopyTest):
+    """chmod, chown and utimes succeed and store nothing"""
+    fixture = 'source'
+
+    def errors(self):
+        stats = os.getxattr('.', 'user.rm.stats').decode()
+        return re.search(r'errors: +(.*)', stats).group(1)
+
+    def test_rsync_sequence(self):
+        # what rsync -a does for a folder and a file in it
+        os.mkdir('backup')
+        os.chmod('backup', 0o755)
+        fd = os.open('backup/copy.pdf', os.O_WRONLY | os.O_CREAT | os.O_EXCL,
+                     0o600)
+        try:
+            os.write(fd, (ROOT / 'ipsum.pdf').read_bytes())
+            os.fchmod(fd, 0o644)
+            os.fchown(fd, os.getuid(), os.getgid())
+        finally:
+            os.close(fd)
+        then = 1600000000
+        os.utime('backup/copy.pdf', (then, then))
+        os.chmod('backup/copy.pdf', 0o644)
+        os.chown('backup/copy.pdf', os.getuid(), os.getgid())
+        os.utime('backup', (then, then))
+        self.assertIsNotNone(settled_metadata(self.source_dir, 'copy.pdf'))
+        self.assertEqual(self.errors(), '0')
+
+    def test_copies_keeping_attrs(self):
+        shutil.copy2(ROOT / 'ipsum.pdf', 'copy2.pdf')
+        run(['cp', '-p', str(ROOT / 'ipsum.pdf'), 'cp-p.pdf'], check=True)
+        for name in ['copy2.pdf', 'cp-p.pdf']:
+            self.assertIsNotNone(settled_metadata(self.source_dir, name))
+        self.assertEqual(self.errors(), '0')
+
+    def test_nothing_stored(self):
+        before = os.stat('ipsum.pdf')
+        os.chmod('ipsum.pdf', 0o600)
+        os.utime('ipsum.pdf', (0, 0))
+        after = os.stat('ipsum.pdf')
+        self.assertEqual(after.st_mode, before.st_mode)
+        self.assertEqual(after.st_mtime, before.st_mtime)
+
+    def test_truncate_unsupported(self):
+        with self.assertRaises(OSError) as cm:
+            os.truncate('ipsum.pdf', 0)
+        self.assertEqual(cm.exception.errno, errno.ENOSYS)
+
+
+class AttrChangesCheckTest(FixtureCopyTest):
+    fixture = 'source'
+    args = ['--attr-changes', 'check']
+
+    def test_other_owner(self):
+        st = os.stat('ipsum.pdf')
+        os.chown('ipsum.pdf', st.st_uid, st.st_gid)
+        os.chmod('ipsum.pdf', 0o600)
+        with self.assertRaises(OSError) as cm:
+            os.chown('ipsum.pdf', st.st_uid + 1, -1)
+        self.assertEqual(cm.exception.errno, errno.EPERM)
+
+
+class AttrChangesRefuseTest(FixtureCopyTest):
+    fixture = 'source'
+    args = ['--attr-changes', 'refuse']
+
+    def test_refused(self):
+        for change in [lambda: os.chmod('ipsum.pdf', 0o600),
+                       lambda: os.utime('ipsum.pdf', (0, 0))]:
+            with self.assertRaises(OSError) as cm:
+                change()
+            self.assertEqual(cm.exception.errno, errno.EPERM)
+
+
+class ExcludeFolderTest(FixtureCopyTest):
+    """--exclude 'dolor/**' hides the folder and what is in it"""
+    fixture = 'source'
+    args = ['--exclude', 'dolor/**']
+
+    def assertErrno(self, code, f, *args):
+        with self.assertRaises(OSError) as cm:
+            f(*args)
+        self.assertEqual(cm.exception.errno, code)
+
+    def test_hidden(self):
+        self.assertEqual(sorted(os.listdir('.')),
+                         ['ipsum.pdf', 'lorem.epub', 'trash'])
+        self.assertErrno(errno.ENOENT, os.stat, 'dolor')
+        self.assertErrno(errno.ENOENT, os.stat, 'dolor/lorem.pdf')
+        self.assertErrno(errno.ENOENT, os.listdir, 'dolor')
+        stat = os.statvfs('.')
+        self.assertEqual(stat.f_files - stat.f_ffree, 3)
+
+    def test_nothing_made_there(self):
+        self.assertErrno(errno.EACCES, os.mkdir, 'dolor')
+        self.assertErrno(errno.EACCES, os.rename, 'ipsum.pdf', 'dolor')
+        self.assertErrno(errno.EACCES, os.open, 'dolor',
+                         os.O_WRONLY | os.O_CREAT, 0o644)
+        self.assertErrno(errno.ENOENT, os.rmdir, 'dolor')
+        folder = self.source_dir / '90e4bbaa-cec6-478f-bd08-aa1fa4b58fe8'
+        self.assertTrue(folder.with_suffix('.metadata').exists())
+
+
+class ExcludeTypeTest(FixtureCopyTest):
+    """--exclude '*.epub' hides epubs in every folder and the Recent view"""
+    fixture = 'source'
+    args = ['--exclude', '*.epub', '--recent', '10']
+
+    def assertErrno(self, code, f, *args):
+        with self.assertRaises(OSError) as cm:
+            f(*args)
+        self.assertEqual(cm.exception.errno, code)
+
+    def test_hidden(self):
+        self.assertNotIn('lorem.epub', os.listdir('.'))
+        self.assertIn('ipsum.pdf', os.listdir('.'))
+        self.assertNotIn('ipsum.epub', os.listdir('dolor'))
+        self.assertFalse([name for name in os.listdir('Recent')
+                          if name.endswith('.epub')])
+        self.assertErrno(errno.ENOENT, os.stat, 'lorem.epub')
+        self.assertErrno(errno.ENOENT, os.unlink, 'dolor/ipsum.epub')
+
+    def test_create_refused(self):
+        for name in ['new.epub', 'dolor/new.epub']:
+            self.assertErrno(errno.EACCES, os.open, name,
+                             os.O_WRONLY | os.O_CREAT, 0o644)
+        shutil.copyfile(ROOT / 'ipsum.pdf', 'new.pdf')
+        self.assertIsNotNone(settled_metadata(self.source_dir, 'new.pdf'))
+        self.assertErrno(errno.EACCES, os.rename, 'new.pdf', 'new.epub')
+
+    def test_folder_with_hidden_entries_kept(self):
+        os.mkdir('kept')
+        os.rename('dolor/lorem.pdf', 'kept/lorem.pdf')
+        self.assertEqual(os.listdir('dolor'), [])
+        self.assertErrno(errno.ENOTEMPTY, os.rmdir, 'dolor')
+        epub = self.source_dir / '462c4853-378f-4b76-a265-ea4ca5581049.epub'
+        self.assertTrue(epub.exists())
+
+
+class ExcludePatternTest(unittest.TestCase):
+    def test_bad_pattern(self):
+        res = run([FUSERM, '--exclude', '[abc', '/nonexistent', '/nowhere'],
+                  stdout=PIPE, stderr=STDOUT)
+        self.assertEqual(res.returncode, 1)
+        self.assertIn(b"Unmatched '['", res.stdout)
+
+
+class QuotaTest(FixtureCopyTest):
+    """--quota bounds the bytes of new documents of the session"""
+    fixture = 'source'
+    args = ['--quota', '200KiB']
+
+    def stats(self):
+        return os.getxattr('.', 'user.rm.stats').decode()
+
+    def wait_for(self, text, timeout=2):
+        deadline = time.time() + timeout
+        while text not in self.stats() and time.time() < deadline:
+            time.sleep(0.05)
+        self.assertIn(text, self.stats())
+
+    def test_mid_write(self):
+        data = (ROOT / 'ipsum.pdf').read_bytes()
+        shutil.copyfile(ROOT / 'ipsum.pdf', 'first.pdf')
+        self.assertIsNotNone(settled_metadata(self.source_dir, 'first.pdf'))
+        self.wait_for('quota used:        %d of 204800 bytes' % len(data))
+        stat = os.statvfs('.')
+        self.assertLessEqual(stat.f_bavail * stat.f_frsize,
+                             204800 - len(data))
+
+        fd = os.open('second.pdf', os.O_WRONLY | os.O_CREAT, 0o644)
+        written = 0
+        with self.assertRaises(OSError) as cm:
+            try:
+                while written < len(data):
+                    written += os.write(fd, data[written:written + 16384])
+            finally:
+                with self.assertRaises(OSError) as closed:
+                    os.close(fd)
+                self.assertEqual(closed.exception.errno, errno.EDQUOT)
+        self.assertEqual(cm.exception.errno, errno.EDQUOT)
+        self.assertLess(written, len(data))
+
+        # what was staged is dropped, nothing is created
+        self.wait_for('staged bytes:      0')
+        self.assertIn('quota used:        %d of' % len(data), self.stats())
+        self.assertNotIn('second.pdf', os.listdir('.'))
+        pending = self.source_dir / '.pending'
+        self.assertEqual(list(pending.iterdir()) if pending.exists() else [],
+                         [])
+        names = [json.loads(p.read_text())['visibleName']
+                 for p in self.source_dir.glob('*.metadata')]
+        self.assertNotIn('second.pdf', names)
+
+
+class QuotaRemoveTest(FixtureCopyTest):
+    """Removing a document of the session gives its bytes back"""
+    fixture = 'source'
+    # two copies of lorem.pdf
+    args = ['--quota', '57718']
+
+    def create(self, name):
+        return os.open(name, os.O_WRONLY | os.O_CREAT, 0o644)
+
+    def test_create_refused_until_removed(self):
+        lorem = ROOT / 'source' / 'a93bea92-3b54-462f-b9f0-858c876c936f.pdf'
+        for name in ['a.pdf', 'b.pdf']:
+            shutil.copyfile(lorem, name)
+        # staged or finalized, the copies count the same
+        with self.assertRaises(OSError) as cm:
+            self.create('c.pdf')
+        self.assertEqual(cm.exception.errno, errno.EDQUOT)
+        self.assertEqual(os.statvfs('.').f_bavail, 0)
+        self.assertIsNotNone(settled_metadata(self.source_dir, 'b.pdf'))
+        # only a document of the session counts
+        os.unlink('ipsum.pdf')
+        with self.assertRaises(OSError) as cm:
+            self.create('c.pdf')
+        self.assertEqual(cm.exception.errno, errno.EDQUOT)
+        os.unlink('a.pdf')
+        fd = self.create('c.pdf')
+        os.write(fd, lorem.read_bytes())
+        os.close(fd)
+        self.assertIsNotNone(settled_metadata(self.source_dir, 'c.pdf'))
+
+
+class SyncStatusTest(FixtureCopyTest):
+    """user.rm.synced and --mark-unsynced show what isn't synced yet"""
+    fixture = 'source'
+    args = ['--mark-unsynced']
+
+    @classmethod
+    def prepare(cls):
+        for uuid in ['c180a2a6-fab9-4ba5-9e7f-3a71f9a1bd09',
+                     'a44b5dee-cf0f-4e06-a0d1-458317c60d5f']:
+            cls.set_synced(uuid, uuid.startswith('c180'))
+
+    @classmethod
+    def set_synced(cls, uuid, synced):
+        path = cls.source_dir / (uuid + '.metadata')
+        meta = json.loads(path.read_text())
+        meta.update(synced=synced, metadatamodified=not synced,
+                    modified=not synced)
+        path.write_text(json.dumps(meta))
+
+    def synced(self, name):
+        return os.getxattr(name, 'user.rm.synced')
+
+    def unsynced_count(self):
+        stats = os.getxattr('.', 'user.rm.stats').decode()
+        return int(re.search(r'unsynced: +(\d+)', stats).group(1))
+
+    def test_xattr(self):
+        self.assertEqual(self.synced('ipsum.pdf'), b'true')
+        self.assertEqual(self.synced('lorem.epub'), b'false')
+        self.assertEqual(self.synced('dolor'), b'false')
+        with self.assertRaises(OSError) as cm:
+            self.synced('trash')
+        self.assertEqual(cm.exception.errno, errno.ENODATA)
+
+    def test_marked_names(self):
+        self.assertEqual(sorted(os.listdir('.')),
+                         ['dolor \u2022', 'ipsum.pdf', 'lorem.epub \u2022',
+                          'trash'])
+        # the mark isn't part of the name
+        self.assertEqual(os.stat('lorem.epub \u2022').st_ino,
+                         os.stat('lorem.epub').st_ino)
+        self.assertEqual(sorted(os.listdir('dolor \u2022')),
+                         ['ipsum.epub \u2022', 'lorem.pdf \u2022'])
+        self.assertEqual(Path('dolor/lorem.pdf \u2022').read_bytes()[:4],
+                         b'%PDF')
+
+    def test_follows_the_source(self):
+        self.assertEqual(self.unsynced_count(), 4)
+        self.set_synced('a44b5dee-cf0f-4e06-a0d1-458317c60d5f', True)
+        try:
+            self.assertEqual(self.synced('lorem.epub'), b'true')
+            self.assertIn('lorem.epub', os.listdir('.'))
+            self.assertEqual(self.unsynced_count(), 3)
+        finally:
+            self.set_synced('a44b5dee-cf0f-4e06-a0d1-458317c60d5f', False)
+        self.assertEqual(self.synced('lorem.epub'), b'false')
+
+    def test_tagging_unsyncs(self):
+        self.set_synced('c180a2a6-fab9-4ba5-9e7f-3a71f9a1bd09', True)
+        try:
+            os.setxattr('ipsum.pdf', 'user.rm.tags', b'["read"]')
+            self.assertEqual(self.synced('ipsum.pdf'), b'false')
+            self.assertIn('ipsum.pdf \u2022', os.listdir('.'))
+        finally:
+            self.set_synced('c180a2a6-fab9-4ba5-9e7f-3a71f9a1bd09', True)
+
+
+class SnapshotTest(FixtureCopyTest):
+    """--snapshot serves the library as it was at mount, read-only"""
+    fixture = 'source'
+    args = ['--snapshot']
+
+    def assertErrno(self, code, op, *args):
+        with self.assertRaises(OSError) as cm:
+            op(*args)
+        self.assertEqual(cm.exception.errno, code)
+
+    def test_changes_refused(self):
+        self.assertTrue(os.statvfs('.').f_flag & os.ST_RDONLY)
+        self.assertErrno(errno.EROFS, os.mkdir, 'new')
+        self.assertErrno(errno.EROFS, open, 'new.pdf', 'wb')
+        self.assertErrno(errno.EROFS, os.unlink, 'lorem.epub')
+        self.assertErrno(errno.EROFS, os.rename, 'lorem.epub', 'new.epub')
+        self.assertErrno(errno.EROFS, os.rmdir, 'dolor')
+        self.assertErrno(errno.EROFS, os.chmod, 'ipsum.pdf', 0o600)
+        self.assertErrno(errno.EROFS, os.setxattr, 'ipsum.pdf',
+                         'user.rm.tags', b'["a"]')
+        self.assertEqual(sorted(os.listdir('.')),
+                         ['dolor', 'ipsum.pdf', 'lorem.epub', 'trash'])
+
+    def test_reads_current_data(self):
+        data = self.source_dir / 'a44b5dee-cf0f-4e06-a0d1-458317c60d5f.epub'
+        with open(data, 'r+b') as f:
+            f.write(b'ZZZZ')
+        with open('lorem.epub', 'rb') as f:
+            self.assertEqual(f.read(4), b'ZZZZ')
+
+    def test_source_changes_not_shown(self):
+        ipsum = self.source_dir / 'c180a2a6-fab9-4ba5-9e7f-3a71f9a1bd09'
+        meta = json.loads(ipsum.with_suffix('.metadata').read_text())
+        meta['visibleName'] = 'changed'
+        ipsum.with_suffix('.metadata').write_text(json.dumps(meta))
+        new = self.source_dir / '0b5a7bd2-6e0e-4a51-9f5e-0c2b6a3d4e10'
+        meta['visibleName'] = 'new'
+        new.with_suffix('.metadata').write_text(json.dumps(meta))
+        shutil.copyfile(ipsum.with_suffix('.pdf'), new.with_suffix('.pdf'))
+        lorem = self.source_dir / 'f27527b8-62d4-4e44-9511-43bdf275d364'
+        lorem.with_suffix('.metadata').unlink()
+        self.assertEqual(sorted(os.listdir('.')),
+                         ['dolor', 'ipsum.pdf', 'lorem.epub', 'trash'])
+        self.assertEqual(sorted(os.listdir('dolor')),
+                         ['ipsum.epub', 'lorem.pdf'])
+        self.assertEqual(os.stat('dolor/lorem.pdf').st_size, 28859)
+        self.assertFalse(Path('changed.pdf').exists())
+        self.assertFalse(Path('new.pdf').exists())
+
+
+class SnapshotExcludeTest(FixtureCopyTest):
+    """--exclude hides the same from the listing a snapshot keeps"""
+    fixture = 'source'
+    args = ['--snapshot', '--exclude', 'dolor/**']
+
+    def test_hidden(self):
+        self.assertEqual(sorted(os.listdir('.')),
+                         ['ipsum.pdf', 'lorem.epub', 'trash'])
+        with self.assertRaises(FileNotFoundError):
+            os.stat('dolor/lorem.pdf')
+        stat = os.statvfs('.')
+        self.assertEqual(stat.f_files - stat.f_ffree, 3)
+
+
+class SnapshotDeepTest(FixtureCopyTest):
+    """--snapshot=deep pins the data files as well"""
+    fixture = 'source'
+    args = ['--snapshot=deep']
+
+    def append(self, uuid, ext):
+        with open(self.source_dir / (uuid + ext), 'ab') as f:
+            f.write(b'appended')
+
+    def test_changed_before_open(self):
+        self.append('c180a2a6-fab9-4ba5-9e7f-3a71f9a1bd09', '.pdf')
+        self.assertEqual(os.stat('ipsum.pdf').st_size, 126501)
+        with self.assertRaises(OSError) as cm:
+            open('ipsum.pdf', 'rb')
+        self.assertEqual(cm.exception.errno, errno.ESTALE)
+
+    def test_changed_while_open(self):
+        with open('lorem.epub', 'rb') as f:
+            self.append('a44b5dee-cf0f-4e06-a0d1-458317c60d5f', '.epub')
+            self.assertEqual(os.fstat(f.fileno()).st_size, 4091)
+            with self.assertRaises(OSError) as cm:
+                f.read()
+            self.assertEqual(cm.exception.errno, errno.ESTALE)
+
+    def test_unchanged(self):
+        with open('dolor/lorem.pdf', 'rb') as f:
+            self.assertEqual(len(f.read()), 28859)
+
+
+class ScanThreadsTest(FixtureCopyTest):
+    """A library parsed by several threads lists the same every time"""
+    fixture = 'source'
+    args = ['--scan-threads', '3', '--sort', 'none']
+    count = 200
+
+    @classmethod
+    def prepare(cls):
+        source = cls.source_dir / 'c180a2a6-fab9-4ba5-9e7f-3a71f9a1bd09'
+        metadata = json.loads(source.with_suffix('.metadata').read_text())
+        for i in range(cls.count):
+            uuid = 'c0ffee00-0000-4000-8000-%012d' % i
+            metadata['visibleName'] = 'scanned %03d.pdf' % i
+            (cls.source_dir / (uuid + '.metadata')).write_text(
+                json.dumps(metadata))
+            shutil.copy(source.with_suffix('.pdf'),
+                        cls.source_dir / (uuid + '.pdf'))
+
+    def test_listed(self):
+        names = [name for name in os.listdir('.')
+                 if name.startswith('scanned')]
+        self.assertEqual(sorted(names),
+                         ['scanned %03d.pdf' % i for i in range(self.count)])
+
+    def test_same_order(self):
+        # without sorting, the order of the source dir
+        first = os.listdir('.')
+        self.assertEqual(os.listdir('.'), first)
+        source_order = [
+            name for name in os.listdir(self.source_dir)
+            if name.endswith('.metadata')]
+        shown = [json.loads((self.source_dir / name).read_text())
+                 ['visibleName'] for name in source_order]
+        self.assertEqual([name for name in first if name in shown],
+                         [name for name in shown if name in first])
+
+
+class UnionTest(FixtureCopyTest):
+    """A second, read-only source with the same uuids and names"""
+    fixture = 'source'
+
+    @classmethod
+    def prepare(cls):
+        cls.mirror = cls._scratch / 'mirror'
+        shutil.copytree(ROOT / cls.fixture, cls.mirror)
+        (cls.mirror / 'c180a2a6-fab9-4ba5-9e7f-3a71f9a1bd09.pdf').write_bytes(
+            (ROOT / 'ipsum.pdf').read_bytes()[:1000])
+        cls.mirrored = sorted(os.listdir(cls.mirror))
+
+    @classmethod
+    def source_arg(cls):
+        return '%s:%s' % (cls.source_dir, cls.mirror)
+
+    def assertReadOnly(self, f, *args):
+        with self.assertRaises(OSError) as cm:
+            f(*args)
+        self.assertEqual(cm.exception.errno, errno.EROFS)
+
+    def test_listing(self):
+        names = os.listdir('.')
+        for name in ['ipsum.pdf', 'ipsum (source 2).pdf', 'dolor',
+                     'dolor (source 2)', 'lorem.epub',
+                     'lorem (source 2).epub']:
+            self.assertIn(name, names)
+        self.assertEqual(sorted(os.listdir('dolor (source 2)')),
+                         sorted(os.listdir('dolor')))
+        self.assertIn('lorem-trashed (source 2).pdf', os.listdir('trash'))
+
+    def test_unique_inodes(self):
+        inodes = set()
+        for dirpath, dirnames, filenames in os.walk('.'):
+            for name in dirnames + filenames:
+                ino = os.stat(os.path.join(dirpath, name)).st_ino
+                self.assertNotIn(ino, inodes, os.path.join(dirpath, name))
+                inodes.add(ino)
+
+    def test_reads_route(self):
+        self.assertEqual(Path('ipsum (source 2).pdf').read_bytes(),
+                         (ROOT / 'ipsum.pdf').read_bytes()[:1000])
+        self.assertEqual(Path('ipsum.pdf').read_bytes(),
+                         (ROOT / 'ipsum.pdf').read_bytes())
+
+    def test_mirror_read_only(self):
+        self.assertReadOnly(os.unlink, 'ipsum (source 2).pdf')
+        self.assertReadOnly(os.rename, 'ipsum (source 2).pdf', 'x.pdf')
+        self.assertReadOnly(os.rename, 'ipsum.pdf', 'dolor (source 2)/x.pdf')
+        self.assertReadOnly(os.rmdir, 'dolor (source 2)')
+        self.assertReadOnly(os.mkdir, 'dolor (source 2)/new')
+        self.assertReadOnly(open, 'dolor (source 2)/new.pdf', 'wb')
+        self.assertEqual(sorted(os.listdir(self.mirror)), self.mirrored)
+
+    def test_writes_to_first(self):
+        shutil.copyfile(ROOT / 'ipsum.pdf', 'new.pdf')
+        self.assertTrue(settled_metadata(self.source_dir, 'new.pdf'))
+        self.assertEqual(sorted(os.listdir(self.mirror)), self.mirrored)
+        os.unlink('new.pdf')
+
+
+class AnyTypeTest(FixtureCopyTest):
+    """Files xochitl doesn't show are stored with --allow-any-type"""
+    fixture = 'conflict'
+    args = ['--allow-any-type', '--deny-type', 'exe']
+
+    def stored(self, name):
+        return settled_metadata(self.source_dir, name).with_suffix('')
+
+    def test_zip_round_trip(self):
+        with zipfile.ZipFile(self._scratch / 'refs.zip', 'w') as z:
+            z.writestr('refs.bib', '@book{lorem}')
+        data = (self._scratch / 'refs.zip').read_bytes()
+        shutil.copyfile(self._scratch / 'refs.zip', 'refs.zip')
+        self.assertIn('refs.zip', os.listdir('.'))
+        self.assertEqual(Path('refs.zip').read_bytes(), data)
+        uuid = self.stored('refs.zip')
+        self.assertEqual(uuid.with_suffix('.zip').read_bytes(), data)
+        content = json.loads(uuid.with_suffix('.content').read_text())
+        self.assertEqual(content['fileType'], 'zip')
+        os.unlink('refs.zip')
+        self.assertNotIn('refs.zip', os.listdir('.'))
+        self.assertEqual(list(self.source_dir.glob(uuid.name + '*')), [])
+
+    def test_type_from_name(self):
+        shutil.copyfile(ROOT / 'lorem.txt', 'notes.txt')
+        self.assertEqual(Path('notes.txt').read_bytes(),
+                         (ROOT / 'lorem.txt').read_bytes())
+        self.assertTrue(self.stored('notes.txt').with_suffix('.txt').exists())
+        os.unlink('notes.txt')
+
+    def test_refused(self):
+        # denied, named like a supported type, named like xochitl's files
+        for name in ['tool.exe', 'fake.pdf', 'sneaky.metadata', 'noext']:
+            with self.assertRaises(OSError, msg=name):
+                shutil.copyfile(ROOT / 'lorem.txt', name)
+            self.assertNotIn(name, os.listdir('.'))
+
+
+class OrphansTest(FixtureCopyTest):
+    fixture = 'orphans'
+    args = ['--expose-orphans']
+    orphans = ['0c5f2a61-8d3e-4b7a-9f10-3e2d4c5b6a71.pdf',
+               '1d6e3b72-9e4f-4c8b-a021-4f3e5d6c7b82.epub',
+               '2e7f4c83-af50-4d9c-b132-504f6e7d8c93.rm']
+
+    def test_listing(self):
+        self.assertEqual(os.listdir('.'), ['.orphans', 'lorem.epub', 'trash'])
+        self.assertEqual(os.listdir('.orphans'), self.orphans)
+
+    def test_read(self):
+        for name in self.orphans:
+            self.assertEqual(Path('.orphans', name).read_bytes(),
+                             (ROOT / 'orphans' / name).read_bytes())
+
+    def test_read_only(self):
+        with self.assertRaises(OSError) as cm:
+            os.mkdir('.orphans/d')
+        self.assertEqual(cm.exception.errno, errno.EPERM)
+        with self.assertRaises(OSError) as cm:
+            os.rename('.orphans/' + self.orphans[0], 'rescued.pdf')
+        self.assertEqual(cm.exception.errno, errno.EPERM)
+
+    def test_unlink(self):
+        os.unlink('.orphans/' + self.orphans[2])
+        self.assertFalse((self.source_dir / self.orphans[2]).exists())
+        self.assertEqual(os.listdir('.orphans'), self.orphans[:2])
+        self.assertEqual(os.listdir('.'), ['.orphans', 'lorem.epub', 'trash'])
+
+
+class HighlightsTest(FixtureCopyTest):
+    fixture = 'highlights'
+    args = ['--highlights', 'merged']
+    uuid = 'a44b5dee-cf0f-4e06-a0d1-458317c60d5f'
+
+    def pages(self):
+        store = ROOT / self.fixture / (self.uuid + '.highlights')
+        return [store / n for n in sorted(os.listdir(store))]
+
+    def assertReadOnly(self, f, *args):
+        with self.assertRaises(OSError) as cm:
+            f(*args)
+        self.assertEqual(cm.exception.errno, errno.EROFS)
+
+    def test_listing(self):
+        self.assertEqual(os.listdir('.'), ['lorem.epub',
+                                           'lorem.epub.highlights.json',
+                                           'trash'])
+
+    def test_merged(self):
+        data = Path('lorem.epub.highlights.json').read_bytes()
+        self.assertEqual(os.stat('lorem.epub.highlights.json').st_size,
+                         len(data))
+        self.assertEqual(json.loads(data),
+                         [json.loads(p.read_text()) for p in self.pages()])
+
+    def test_partial_reads(self):
+        data = Path('lorem.epub.highlights.json').read_bytes()
+        fd = os.open('lorem.epub.highlights.json', os.O_RDONLY)
+        try:
+            for offset in [0, 1, 2, 200, 221, 223, len(data) - 1, len(data)]:
+                self.assertEqual(os.pread(fd, 7, offset),
+                                 data[offset:offset + 7])
+        finally:
+            os.close(fd)
+
+    def test_read_only(self):
+        self.assertReadOnly(open, 'lorem.epub.highlights.json', 'w')
+        self.assertReadOnly(os.unlink, 'lorem.epub.highlights.json')
+        self.assertReadOnly(os.rename, 'lorem.epub.highlights.json', 'h.json')
+        self.assertIn('lorem.epub.highlights.json', os.listdir('.'))
+
+    def test_absent(self):
+        store = self.source_dir / (self.uuid + '.highlights')
+        moved = self._scratch / 'moved'
+        store.rename(moved)
+        try:
+            self.assertEqual(os.listdir('.'), ['lorem.epub', 'trash'])
+        finally:
+            moved.rename(store)
+        self.assertIn('lorem.epub.highlights.json', os.listdir('.'))
+
+
+class RawHighlightsTest(HighlightsTest):
+    args = ['--highlights', 'raw']
+
+    def test_listing(self):
+        self.assertEqual(os.listdir('.'), ['.lorem.epub.highlights',
+                                           'lorem.epub', 'trash'])
+        self.assertEqual(os.listdir('.lorem.epub.highlights'),
+                         [p.name for p in self.pages()])
+
+    def test_merged(self):
+        for page in self.pages():
+            path = Path('.lorem.epub.highlights', page.name)
+            self.assertEqual(path.read_bytes(), page.read_bytes())
+            self.assertEqual(path.stat().st_size, page.stat().st_size)
+
+    def test_partial_reads(self):
+        page = self.pages()[0]
+        with open(Path('.lorem.epub.highlights', page.name), 'rb') as f:
+            f.seek(10)
+            self.assertEqual(f.read(20), page.read_bytes()[10:30])
+
+    def test_read_only(self):
+        page = '.lorem.epub.highlights/' + self.pages()[0].name
+        self.assertReadOnly(open, page, 'w')
+        self.assertReadOnly(os.unlink, page)
+        self.assertReadOnly(os.mkdir, '.lorem.epub.highlights/d')
+        self.assertReadOnly(open, '.lorem.epub.highlights/new.pdf', 'wb')
+        self.assertReadOnly(os.rmdir, '.lorem.epub.highlights')
+
+    def test_absent(self):
+        store = self.source_dir / (self.uuid + '.highlights')
+        moved = self._scratch / 'moved'
+        store.rename(moved)
+        try:
+            self.assertEqual(os.listdir('.'), ['lorem.epub', 'trash'])
+        finally:
+            moved.rename(store)
+
+
+class SortTest(FixtureCopyTest):
+    fixture = 'conflict'
+    args = ['--sort', 'mtime', '--folders-first']
+
+    def test_order(self):
+        self.assertEqual(os.listdir('.'), ['trash', 'Report.pdf',
+                                           'Report (conflict 2024-01-05).pdf'])
+
+
+class RenameFlagsTest(FixtureCopyTest):
+    fixture = 'source'
+    AT_FDCWD = -100
+    NOREPLACE, EXCHANGE, WHITEOUT = 1, 2, 4
+
+    def renameat2(self, old, new, flags):
+        libc = ctypes.CDLL(None, use_errno=True)
+        if libc.renameat2(self.AT_FDCWD, os.fsencode(old), self.AT_FDCWD,
+                          os.fsencode(new), flags) != 0:
+            err = ctypes.get_errno()
+            raise OSError(err, os.strerror(err))
+
+    def assertRenameError(self, code, *args):
+        with self.assertRaises(OSError) as cm:
+            self.renameat2(*args)
+        self.assertEqual(cm.exception.errno, code)
+
+    def test_noreplace(self):
+        self.assertRenameError(errno.EEXIST, 'ipsum.pdf', 'dolor/lorem.pdf',
+                               self.NOREPLACE)
+        self.assertIn('ipsum.pdf', os.listdir('.'))
+        self.renameat2('ipsum.pdf', 'sit.pdf', self.NOREPLACE)
+        self.renameat2('sit.pdf', 'ipsum.pdf', self.NOREPLACE)
+        self.assertIn('ipsum.pdf', os.listdir('.'))
+
+    def test_exchange(self):
+        ipsum = Path('ipsum.pdf').read_bytes()
+        lorem = Path('dolor/lorem.pdf').read_bytes()
+        self.renameat2('ipsum.pdf', 'dolor/lorem.pdf', self.EXCHANGE)
+        self.assertEqual(Path('ipsum.pdf').read_bytes(), lorem)
+        self.assertEqual(Path('dolor/lorem.pdf').read_bytes(), ipsum)
+        self.renameat2('ipsum.pdf', 'dolor/lorem.pdf', self.EXCHANGE)
+        self.assertEqual(Path('ipsum.pdf').read_bytes(), ipsum)
+        self.assertRenameError(errno.ENOENT, 'ipsum.pdf', 'missing.pdf',
+                               self.EXCHANGE)
+
+    @unittest.skipUnless(os.geteuid() == 0, 'whiteouts need CAP_MKNOD')
+    def test_unknown_flags(self):
+        self.assertRenameError(errno.EINVAL, 'ipsum.pdf', 'sit.pdf',
+                               self.WHITEOUT)
+        self.assertIn('ipsum.pdf', os.listdir('.'))
+
+    def test_replace(self):
+        shutil.copyfile(ROOT / 'ipsum.epub', 'new.epub')
+        os.rename('new.epub', 'dolor/ipsum.epub')
+        self.assertEqual(os.listdir('dolor'), ['ipsum.epub', 'lorem.pdf'])
+        self.assertEqual(Path('dolor/ipsum.epub').read_bytes(),
+                         (ROOT / 'ipsum.epub').read_bytes())
+        with self.assertRaises(OSError) as cm:
+            os.rename('ipsum.pdf', 'dolor')
+        self.assertEqual(cm.exception.errno, errno.EISDIR)
+
+
+class ExchangeConflictTest(FixtureCopyTest):
+    """The stored names are swapped, not those of the listing"""
+    fixture = 'conflict'
+    AT_FDCWD = RenameFlagsTest.AT_FDCWD
+    EXCHANGE = RenameFlagsTest.EXCHANGE
+    renameat2 = RenameFlagsTest.renameat2
+
+    def visible_name(self, uuid):
+        metadata = self.source_dir / (uuid + '.metadata')
+        return json.loads(metadata.read_text())['visibleName']
+
+    def test_exchange(self):
+        os.mkdir('sub')
+        shutil.copyfile(ROOT / 'ipsum.pdf', 'sub/ipsum.pdf')
+        self.renameat2('Report (conflict 2024-01-05).pdf', 'sub/ipsum.pdf',
+                       self.EXCHANGE)
+        self.assertEqual(os.listdir('sub'), ['ipsum.pdf'])
+        self.assertEqual(Path('sub/ipsum.pdf').stat().st_size, 28859)
+        self.assertEqual(self.visible_name(
+            '6d1c3a2e-5b0f-4c1e-9a7d-2f8e4b6c1a01'), 'ipsum.pdf')
+        # the new document has the name Report was stored with, and is the
+        # newest of them
+        self.assertEqual(sorted(os.listdir('.')),
+                         ['Report (conflict 2024-02-10).pdf', 'Report.pdf',
+                          'sub', 'trash'])
+        self.assertEqual(Path('Report.pdf').read_bytes(),
+                         (ROOT / 'ipsum.pdf').read_bytes())
+        names = [json.loads(p.read_text())['visibleName']
+                 for p in self.source_dir.glob('*.metadata')]
+        self.assertEqual(sorted(names), ['Report', 'Report', 'ipsum.pdf', 'sub'])
+
+
+class PreserveUuidTest(FixtureCopyTest):
+    fixture = 'source'
+    args = ['--replace-preserves-uuid']
+    uuid = 'c180a2a6-fab9-4ba5-9e7f-3a71f9a1bd09'
+
+    def uuids(self):
+        return set(p.name.split('.')[0] for p in self.source_dir.iterdir())
+
+    def save_as(self, name, data):
+        # how LibreOffice saves: a temporary file renamed over the original
+        with open('lu1234abcd.tmp', 'wb') as f:
+            f.write(data)
+        os.rename('lu1234abcd.tmp', name)
+
+    def test_libreoffice_save(self):
+        before = self.uuids()
+        metadata_path = self.source_dir / (self.uuid + '.metadata')
+        version = json.loads(metadata_path.read_text())['version']
+        edited = (ROOT / 'ipsum.pdf').read_bytes() + b'\n% edited\n'
+        self.save_as('ipsum.pdf', edited)
+        self.assertEqual(Path('ipsum.pdf').read_bytes(), edited)
+        self.assertEqual(self.uuids(), before)
+        self.assertEqual((self.source_dir / (self.uuid + '.pdf')).read_bytes(),
+                         edited)
+        metadata = json.loads(metadata_path.read_text())
+        self.assertEqual(metadata['version'], version + 1)
+        self.assertEqual(metadata['visibleName'], 'ipsum.pdf')
+        self.assertTrue(metadata['modified'])
+        self.assertEqual(sorted(os.listdir('.')),
+                         ['dolor', 'ipsum.pdf', 'lorem.epub', 'trash'])
+
+    def test_other_type(self):
+        before = self.uuids()
+        self.save_as('dolor/lorem.pdf', (ROOT / 'ipsum.epub').read_bytes())
+        self.assertEqual(sorted(os.listdir('dolor')),
+                         ['ipsum.epub', 'lorem.epub'])
+        self.assertNotEqual(self.uuids(), before)
+
+
+class OwnerTest(FixtureCopyTest):
+    fixture = 'orphans'
+    args = ['--recent', '1', '--expose-orphans']
+
+    def assertOwned(self, path):
+        st = os.stat(path)
+        self.assertEqual((st.st_uid, st.st_gid), (os.geteuid(), os.getegid()))
+
+    def test_virtual_dirs(self):
+        for path in ['.', 'trash', 'Recent', '.orphans']:
+            self.assertOwned(path)
+
+    def test_new_entries(self):
+        os.mkdir('folder')
+        self.assertOwned('folder')
+        with open('folder/new.pdf', 'wb') as f:
+            self.assertEqual(os.fstat(f.fileno()).st_uid, os.geteuid())
+            f.write((ROOT / 'ipsum.pdf').read_bytes())
+        self.assertOwned('folder/new.pdf')
+
+
+class AssociatedPathsTest(FixtureCopyTest):
+    """Removing an entry removes every file xochitl keeps for it"""
+    fixture = 'source'
+    uuid = 'c180a2a6-fab9-4ba5-9e7f-3a71f9a1bd09'
+
+    @classmethod
+    def prepare(cls):
+        # the page dir and caches of a document that was annotated
+        for suffix in ['', '.highlights', '.cache', '.textconversion']:
+            path = cls.source_dir / (cls.uuid + suffix)
+            path.mkdir()
+            (path / 'page.json').write_text('{}')
+        (cls.source_dir / (cls.uuid + '.local')).write_text('{}')
+
+    def paths(self, uuid):
+        return [p.name for p in self.source_dir.iterdir()
+                if p.name == uuid or p.name.startswith(uuid + '.')]
+
+    def test_unlink(self):
+        self.assertEqual(len(self.paths(self.uuid)), 10)
+        os.unlink('ipsum.pdf')
+        self.assertEqual(self.paths(self.uuid), [])
+
+    def test_rmdir(self):
+        os.mkdir('empty')
+        uuid = settled_metadata(self.source_dir, 'empty').stem
+        (self.source_dir / (uuid + '.content')).write_text('{}')
+        os.rmdir('empty')
+        self.assertEqual(self.paths(uuid), [])
+
+
+class DestroyTest(FixtureCopyTest):
+    fixture = 'conflict'
+
+    def abort_connection(self, fds):
+        abort = Path('/sys/fs/fuse/connections/%d/abort'
+                     % os.minor(os.stat(self.target_dir).st_dev))
+        if not abort.exists():
+            self.skipTest('fusectl not mounted')
+        os.chdir(ROOT)
+        abort.write_text('1')
+        for fd in fds:
+            try:
+                os.close(fd)
+            except OSError:
+                pass
+        self._fuserm.terminate()
+        self._fuserm.wait()
+        # fuser won't unmount an aborted connection
+        Popen(['umount', self.target_dir]).wait()
+
+    def test_pending_settled_on_unmount(self):
+        pdf = os.open('new.pdf', os.O_WRONLY | os.O_CREAT)
+        os.write(pdf, (ROOT / 'ipsum.pdf').read_bytes())
+        txt = os.open('new.txt', os.O_WRONLY | os.O_CREAT)
+        self.abort_connection([pdf, txt])
+
+        self.assertFalse((self.source_dir / '.pending').exists())
+        new = [json.loads(p.read_text())
+               for p in self.source_dir.glob('*.metadata')
+               if not p.name.startswith(('6d1c3a2e', 'b3e9f0d4'))]
+        self.assertEqual([m['visibleName'] for m in new], ['new.pdf'])
+        pdfs = [p for p in self.source_dir.glob('*.pdf')
+                if p.stat().st_size == (ROOT / 'ipsum.pdf').stat().st_size]
+        self.assertEqual(len(pdfs), 1)
+
+
+class StatsTest(FixtureCopyTest):
+    fixture = 'conflict'
+    args = ['--stats-on-exit']
+
+    def test_summary(self):
+        os.mkdir('folder')
+        shutil.copyfile(ROOT / 'ipsum.pdf', 'folder/new.pdf')
+        os.rename('folder/new.pdf', 'folder/renamed.pdf')
+        Path('folder/renamed.pdf').read_bytes()
+        with self.assertRaises(OSError):
+            os.mkdir('folder/with.dot')
+        os.unlink('folder/renamed.pdf')
+        live = os.getxattr('.', 'user.rm.stats').decode()
+        self.assertIn('folders created:   1', live)
+        self.assertIn('renamed:           1', live)
+        self.assertIn('deleted:           1', live)
+        self.assertIn('errors:            1 (ENOSYS 1)', live)
+        size = (ROOT / 'ipsum.pdf').stat().st_size
+        self.assertIn('bytes written:     %d' % size, live)
+        self.assertIn('fsyncs:            0', live)
+
+        os.chdir(ROOT)
+        self._fuserm.terminate()
+        self._fuserm.wait()
+        self._fuserm_thread.join()
+        output = b''.join(self._fuserm_output).decode()
+        self.assertIn('documents created: 1', output)
+        self.assertIn('bytes read:        %d' % size, output)
+        self.assertIn('slowest operation:', output)
+
+
+def started(fuserm):
+    """Reads the startup lines of fuse-rm up to the one it waits after"""
+    for line in fuserm.stdout:
+        if line.startswith(b'Waiting'):
+            return True
+    return False
+
+
+class CliTest(unittest.TestCase):
+    """Runs the binary directly, for checks that happen before mounting"""
+    @classmethod
+    def setUpClass(cls):
+        check_output(['cargo', 'build', '-q'])
+
+    def setUp(self):
+        self.scratch = Path(tempfile.mkdtemp())
+
+    def tearDown(self):
+        shutil.rmtree(self.scratch)
+
+    def fuserm(self, target, *args, source=SRC_DIR):
+        return run([FUSERM, *args, source, target], stdout=PIPE,
+                   stderr=STDOUT, timeout=10)
+
+
+class TargetValidationTest(CliTest):
+    def test_missing(self):
+        res = self.fuserm(self.scratch / 'nope')
+        self.assertEqual(res.returncode, 10)
+        self.assertIn(b'does not exist', res.stdout)
+
+    def test_not_dir(self):
+        (self.scratch / 'file').touch()
+        res = self.fuserm(self.scratch / 'file')
+        self.assertEqual(res.returncode, 11)
+
+    def test_already_mounted(self):
+        target = self.scratch / 'mnt'
+        target.mkdir()
+        first = Popen([FUSERM, SRC_DIR, target], stdout=PIPE)
+        try:
+            self.assertTrue(started(first))
+            res = self.fuserm(target, '--nonempty')
+            self.assertEqual(res.returncode, 12)
+            self.assertIn(b'already a FUSE mountpoint', res.stdout)
+        finally:
+            first.terminate()
+            first.wait()
+
+    def test_not_empty(self):
+        (self.scratch / 'file').touch()
+        res = self.fuserm(self.scratch)
+        self.assertEqual(res.returncode, 13)
+        self.assertIn(b'--nonempty', res.stdout)
+
+    def test_inside_source(self):
+        res = self.fuserm(SRC_DIR / '462c4853-378f-4b76-a265-ea4ca5581049.thumbnails')
+        self.assertEqual(res.returncode, 14)
+
+
+class SourceValidationTest(CliTest):
+    def setUp(self):
+        super().setUp()
+        self.target = self.scratch / 'mnt'
+        self.target.mkdir()
+
+    def test_missing(self):
+        res = self.fuserm(self.target, source=self.scratch / 'nope')
+        self.assertEqual(res.returncode, 20)
+
+    def test_not_dir(self):
+        (self.scratch / 'file').touch()
+        res = self.fuserm(self.target, source=self.scratch / 'file')
+        self.assertEqual(res.returncode, 21)
+
+    def test_parent_of_xochitl(self):
+        shutil.copytree(SRC_DIR, self.scratch / 'rm' / 'xochitl')
+        res = self.fuserm(self.target, source=self.scratch / 'rm')
+        self.assertEqual(res.returncode, 22)
+        self.assertIn(b'did you mean', res.stdout)
+        self.assertIn(str(self.scratch / 'rm' / 'xochitl').encode(),
+                      res.stdout)
+
+    def test_not_xochitl(self):
+        res = self.fuserm(self.target, source=ROOT)
+        self.assertEqual(res.returncode, 22)
+        self.assertNotIn(b'did you mean', res.stdout)
+
+    @unittest.skipIf(os.geteuid() == 0, 'root can write anywhere')
+    def test_empty_readonly(self):
+        source = self.scratch / 'empty'
+        source.mkdir(mode=0o555)
+        res = self.fuserm(self.target, source=source)
+        self.assertEqual(res.returncode, 23)
+
+    def test_force(self):
+        fuserm = Popen([FUSERM, '--force', ROOT, self.target], stdout=PIPE)
+        try:
+            self.assertTrue(started(fuserm))
+        finally:
+            fuserm.terminate()
+            fuserm.wait()
+
+    def test_empty_writable(self):
+        source = self.scratch / 'empty'
+        source.mkdir()
+        fuserm = Popen([FUSERM, source, self.target], stdout=PIPE)
+        try:
+            self.assertTrue(started(fuserm))
+        finally:
+            fuserm.terminate()
+            fuserm.wait()
+
+
+class LockTest(CliTest):
+    def setUp(self):
+        super().setUp()
+        self.source = self.scratch / 'source'
+        shutil.copytree(ROOT / 'conflict', self.source)
+        self.targets = [self.scratch / 'mnt1', self.scratch / 'mnt2']
+        for t in self.targets:
+            t.mkdir()
+        self.first = Popen([FUSERM, self.source, self.targets[0]], stdout=PIPE)
+        self.assertTrue(started(self.first))
+
+    def tearDown(self):
+        self.first.terminate()
+        self.first.wait()
+        super().tearDown()
+
+    def test_second_instance_refused(self):
+        res = self.fuserm(self.targets[1], source=self.source)
+        self.assertEqual(res.returncode, 24)
+        self.assertIn(('pid %d' % self.first.pid).encode(), res.stdout)
+
+    def test_no_lock(self):
+        second = Popen([FUSERM, '--no-lock', self.source, self.targets[1]],
+                       stdout=PIPE)
+        try:
+            self.assertTrue(started(second))
+        finally:
+            second.terminate()
+            second.wait()
+
+    def test_released_on_exit(self):
+        self.first.terminate()
+        self.first.wait()
+        second = Popen([FUSERM, self.source, self.targets[1]], stdout=PIPE)
+        try:
+            self.assertTrue(started(second))
+        finally:
+            second.terminate()
+            second.wait()
+
+
+def fake_xochitl(scratch):
+    exe = scratch / 'xochitl'
+    shutil.copy(shutil.which('sleep'), exe)
+    return Popen([exe, '60'])
+
+
+class XochitlReadOnlyTest(FixtureCopyTest):
+    fixture = 'conflict'
+    args = ['--xochitl-guard', 'readonly']
+
+    @classmethod
+    def setUpClass(cls):
+        cls._xochitl_dir = Path(tempfile.mkdtemp())
+        cls._xochitl = fake_xochitl(cls._xochitl_dir)
+        super().setUpClass()
+
+    @classmethod
+    def tearDownClass(cls):
+        super().tearDownClass()
+        cls._xochitl.kill()
+        cls._xochitl.wait()
+        shutil.rmtree(cls._xochitl_dir)
+
+    def test_changes_refused(self):
+        self.assertRaisesRegex(OSError, 'Read-only', Path('d').mkdir)
+        self.assertRaisesRegex(OSError, 'Read-only', Path('Report.pdf').unlink)
+        self.assertRaisesRegex(OSError, 'Read-only',
+                               lambda: shutil.copyfile(ROOT / 'ipsum.pdf', 'x.pdf'))
+
+    def test_reads_work(self):
+        self.assertEqual(len(Path('Report.pdf').read_bytes()), 10889)
+
+
+class XochitlWarnTest(CliTest):
+    def test_warning_at_mount(self):
+        xochitl = fake_xochitl(self.scratch)
+        (self.scratch / 'mnt').mkdir()
+        fuserm = Popen([FUSERM, '--no-lock', SRC_DIR, self.scratch / 'mnt'],
+                       stdout=PIPE)
+        try:
+            output = b''
+            while not output.endswith(b'Waiting for Ctrl-C...\n'):
+                output += fuserm.stdout.readline()
+            self.assertIn(b'WARNING: xochitl is running', output)
+        finally:
+            fuserm.terminate()
+            fuserm.wait()
+            xochitl.kill()
+            xochitl.wait()
+
+
+class XochitlStopTest(CliTest):
+    """--stop-xochitl starts it again however the mount ends"""
+    def setUp(self):
+        super().setUp()
+        self.xochitl = fake_xochitl(self.scratch)
+        # a systemctl that only notes what it is asked
+        self.calls = self.scratch / 'systemctl.log'
+        bin_dir = self.scratch / 'bin'
+        bin_dir.mkdir()
+        systemctl = bin_dir / 'systemctl'
+        systemctl.write_text('#!/bin/sh\necho "$@" >> {}\n'.format(self.calls))
+        systemctl.chmod(0o755)
+        self.env = dict(os.environ,
+                        PATH='{}:{}'.format(bin_dir, os.environ['PATH']))
+        self.target = self.scratch / 'mnt'
+        self.target.mkdir()
+
+    def tearDown(self):
+        self.xochitl.kill()
+        self.xochitl.wait()
+        super().tearDown()
+
+    def test_unmounted(self):
+        fuserm = Popen([FUSERM, '--no-lock', '--stop-xochitl', SRC_DIR,
+                        self.target], stdout=PIPE, env=self.env)
+        try:
+            output = b''
+            while not output.endswith(b'Waiting for Ctrl-C...\n'):
+                output += fuserm.stdout.readline()
+            self.assertEqual(self.calls.read_text(), 'stop xochitl\n')
+        finally:
+            fuserm.terminate()
+            fuserm.wait()
+            fuserm.stdout.close()
+        self.assertEqual(self.calls.read_text(),
+                         'stop xochitl\nstart xochitl\n')
+
+    def test_panic(self):
+        env = dict(self.env, FUSE_RM_INJECT_PANIC='main')
+        res = run([FUSERM, '--no-lock', '--stop-xochitl', SRC_DIR,
+                   self.target], stdout=PIPE, stderr=STDOUT, env=env,
+                  timeout=10)
+        self.assertNotEqual(res.returncode, 0)
+        self.assertIn(b'injected', res.stdout)
+        self.assertIn(b'Restarting xochitl', res.stdout)
+        self.assertEqual(self.calls.read_text(),
+                         'stop xochitl\nstart xochitl\n')
+
+
+
+class SyncGuardFailTest(FixtureCopyTest):
+    fixture = 'conflict'
+    args = ['--sync-guard', 'fail', '--sync-marker', '.lock',
+            '--sync-marker', '*.syncing']
+
+    def assertBusy(self, marker):
+        (self.source_dir / marker).touch()
+        try:
+            with self.assertRaises(OSError) as cm:
+                Path('d').mkdir()
+            self.assertEqual(cm.exception.errno, errno.EBUSY)
+        finally:
+            (self.source_dir / marker).unlink()
+        Path('d').mkdir()
+        Path('d').rmdir()
+
+    def test_marker_name(self):
+        self.assertBusy('.lock')
+
+    def test_marker_pattern(self):
+        self.assertBusy('docs.syncing')
+
+
+class SyncGuardWaitTest(FixtureCopyTest):
+    fixture = 'conflict'
+    args = ['--sync-guard', 'wait', '--sync-timeout', '1']
+
+    def test_waits_for_sync(self):
+        marker = self.source_dir / '.lock'
+        marker.touch()
+        remover = Thread(target=lambda: (time.sleep(0.3), marker.unlink()))
+        remover.start()
+        start = time.monotonic()
+        Path('d').mkdir()
+        self.assertGreater(time.monotonic() - start, 0.2)
+        remover.join()
+        Path('d').rmdir()
+
+    def test_times_out(self):
+        marker = self.source_dir / '.lock'
+        marker.touch()
+        try:
+            with self.assertRaises(OSError) as cm:
+                Path('d').mkdir()
+            self.assertEqual(cm.exception.errno, errno.EBUSY)
+        finally:
+            marker.unlink()
+
+
+class MountHelperTest(CliTest):
+    """Runs the way mount(8) runs helpers: source target -o options"""
+    def setUp(self):
+        super().setUp()
+        self.source = self.scratch / 'source'
+        shutil.copytree(ROOT / 'source', self.source)
+        self.target = self.scratch / 'mnt'
+        self.target.mkdir()
+
+    def tearDown(self):
+        if self.mount_line():
+            run(['umount', self.target])
+        super().tearDown()
+
+    def mount_line(self):
+        with open('/proc/mounts') as mounts:
+            return next((l for l in mounts if ' %s ' % self.target in l), None)
+
+    def helper(self, opts, fuserm=FUSERM):
+        return run([fuserm, self.source, self.target, '-o', opts],
+                   stdout=PIPE, stderr=STDOUT, timeout=10)
+
+    def test_options(self):
+        res = self.helper('ro,uid=1234,gid=4321,noauto,x-systemd.automount,'
+                          'allow_other,bogus,size=3')
+        # returns once mounted, the mount goes on in the background
+        self.assertEqual(res.returncode, 0)
+        self.assertEqual(res.stdout.count(b'Ignoring unknown mount option'),
+                         2)
+        self.assertIn(b'option: bogus', res.stdout)
+        self.assertIn(b'option: size=3', res.stdout)
+        mount_opts = self.mount_line().split()[3].split(',')
+        self.assertIn('ro', mount_opts)
+        self.assertIn('allow_other', mount_opts)
+        st = os.stat(self.target)
+        self.assertEqual((st.st_uid, st.st_gid), (1234, 4321))
+        self.assertIn('ipsum.pdf', os.listdir(self.target))
+        with self.assertRaises(OSError) as cm:
+            os.mkdir(self.target / 'folder')
+        self.assertEqual(cm.exception.errno, errno.EROFS)
+
+    def test_stops_at_umount(self):
+        self.assertEqual(self.helper('rw').returncode, 0)
+        pid = int((self.source / '.fuse-rm.lock').read_text())
+        run(['umount', self.target], check=True)
+        for _ in range(50):
+            if not Path('/proc/%d' % pid).exists():
+                break
+            time.sleep(0.1)
+        self.assertFalse(Path('/proc/%d' % pid).exists())
+
+    def test_bad_uid(self):
+        res = self.helper('uid=root')
+        self.assertNotEqual(res.returncode, 0)
+        self.assertIsNone(self.mount_line())
+
+    def test_mount_link(self):
+        link = self.scratch / 'mount.fuse-rm'
+        link.symlink_to(FUSERM)
+        res = run([link, self.source, self.target], stdout=PIPE, timeout=10)
+        self.assertEqual(res.returncode, 0)
+        self.assertIsNotNone(self.mount_line())
+
+    def test_locked_source(self):
+        self.assertEqual(self.helper('rw').returncode, 0)
+        second = self.scratch / 'mnt2'
+        second.mkdir()
+        res = run([FUSERM, self.source, second, '-o', 'rw'],
+                  stdout=PIPE, timeout=10)
+        self.assertEqual(res.returncode, 24)
+
+
+class SdNotifyTest(CliTest):
+    def test_notifications(self):
+        sock_path = self.scratch / 'notify'
+        sock = socket.socket(socket.AF_UNIX, socket.SOCK_DGRAM)
+        sock.bind(str(sock_path))
+        sock.settimeout(5)
+        (self.scratch / 'mnt').mkdir()
+        env = dict(os.environ, NOTIFY_SOCKET=str(sock_path),
+                   WATCHDOG_USEC='200000')
+        fuserm = Popen([FUSERM, '--no-lock', SRC_DIR, self.scratch / 'mnt'],
+                       stdout=PIPE, env=env)
+        try:
+            ready = sock.recv(4096).decode()
+            self.assertTrue(ready.startswith('READY=1\nSTATUS=Mounted '))
+            self.assertEqual(sock.recv(4096), b'WATCHDOG=1\n')
+            self.assertEqual(sock.recv(4096), b'WATCHDOG=1\n')
+            fuserm.terminate()
+            while True:
+                msg = sock.recv(4096)
+                if msg != b'WATCHDOG=1\n':
+                    break
+            self.assertEqual(msg, b'STOPPING=1\n')
+        finally:
+            fuserm.terminate()
+            fuserm.wait()
+            sock.close()
+
+
+class PanicUnmountTest(CliTest):
+    def test_panic_after_mount(self):
+        target = self.scratch / 'mnt'
+        target.mkdir()
+        env = dict(os.environ, FUSE_RM_INJECT_PANIC='main', RUST_LOG='info')
+        res = run([FUSERM, '--no-lock', SRC_DIR, target], stdout=PIPE,
+                  stderr=STDOUT, env=env, timeout=10)
+        self.assertNotEqual(res.returncode, 0)
+        self.assertIn(b'injected', res.stdout)
+        # by the panic hook, before the session is dropped
+        self.assertIn(b'after an abnormal exit', res.stdout)
+        with open('/proc/mounts') as mounts:
+            self.assertNotIn(str(target), mounts.read())
+        self.assertEqual(os.listdir(target), [])
+
+
+class ListTest(CliTest):
+    """Visible paths of a library, folders resolved through their uuids"""
+    def list(self, *args, source=SRC_DIR):
+        return run([FUSERM, 'list', source, *args], stdout=PIPE,
+                   stderr=STDOUT, timeout=10)
+
+    def lines(self, *args, source=SRC_DIR):
+        res = self.list(*args, source=source)
+        self.assertEqual(res.returncode, 0, res.stdout)
+        return res.stdout.decode().splitlines()
+
+    def test_library(self):
+        self.assertEqual(self.lines(), [
+            'dolor/', 'dolor/ipsum.epub', 'dolor/lorem.pdf', 'ipsum.pdf',
+            'lorem.epub', 'trash/', 'trash/lorem-trashed.pdf'])
+
+    def test_folder(self):
+        self.assertEqual(self.lines('dolor'), ['ipsum.epub', 'lorem.pdf'])
+        self.assertEqual(self.lines('90e4bbaa-cec6-478f-bd08-aa1fa4b58fe8'),
+                         ['ipsum.epub', 'lorem.pdf'])
+
+    def test_duplicate_folders(self):
+        # a second, older "dolor" is listed under its conflict name
+        source = self.scratch / 'source'
+        shutil.copytree(SRC_DIR, source)
+        folder = '0f1e2d3c-0000-4000-8000-000000000001'
+        (source / (folder + '.metadata')).write_text(json.dumps({
+            'lastModified': '1600000000000', 'parent': '',
+            'type': 'CollectionType', 'visibleName': 'dolor'}))
+        doc = '0f1e2d3c-0000-4000-8000-000000000002'
+        (source / (doc + '.metadata')).write_text(json.dumps({
+            'lastModified': '1600000000000', 'parent': folder,
+            'type': 'DocumentType', 'visibleName': 'lorem'}))
+        shutil.copyfile(ROOT / 'ipsum.pdf', source / (doc + '.pdf'))
+        lines = self.lines(source=source)
+        self.assertIn('dolor (conflict 2020-09-13)/lorem.pdf', lines)
+        self.assertIn('dolor/lorem.pdf', lines)
+        self.assertEqual(self.lines('dolor (conflict 2020-09-13)',
+                                    source=source), ['lorem.pdf'])
+
+    def test_ambiguous(self):
+        source = self.scratch / 'source'
+        shutil.copytree(ROOT / 'conflict', source)
+        self.assertEqual(self.lines(source=source), [
+            'Report (conflict 2024-01-05).pdf', 'Report.pdf', 'trash/'])
+        uuid = 'b3e9f0d4-71a2-4d8c-8e5f-0c4a9d2b7e02'
+        (source / (uuid + '.pdf')).rename(source / (uuid + '.epub'))
+        res = self.list('Report', source=source)
+        self.assertEqual(res.returncode, 31)
+        self.assertIn(uuid.encode(), res.stdout)
+
+    def test_not_a_folder(self):
+        self.assertEqual(self.list('ipsum.pdf').returncode, 33)
+        self.assertEqual(self.list('nope').returncode, 30)
+
+
+class RootTest(FixtureCopyTest):
+    """--root shows a folder as the whole mount"""
+    fixture = 'source'
+    args = ['--root', 'dolor']
+    folder = '90e4bbaa-cec6-478f-bd08-aa1fa4b58fe8'
+
+    def test_listing(self):
+        self.assertEqual(sorted(os.listdir('.')), ['ipsum.epub', 'lorem.pdf'])
+
+    def test_created_in_folder(self):
+        os.mkdir('sub')
+        with open('new.pdf', 'wb') as f:
+            f.write((ROOT / 'ipsum.pdf').read_bytes())
+        for name in ['sub', 'new.pdf']:
+            path = settled_metadata(self.source_dir, name)
+            self.assertEqual(json.loads(path.read_text())['parent'],
+                             self.folder)
+        os.rename('new.pdf', 'sub/new.pdf')
+        os.rename('sub/new.pdf', 'new.pdf')
+        self.assertIn('new.pdf', os.listdir('.'))
+        os.unlink('new.pdf')
+        os.rmdir('sub')
+
+
+class RootCliTest(CliTest):
+    def test_not_a_folder(self):
+        res = run([FUSERM, '--root', 'ipsum.pdf', SRC_DIR, self.scratch],
+                  stdout=PIPE, stderr=STDOUT, timeout=10)
+        self.assertEqual(res.returncode, 33, res.stdout)
+        self.assertIn(b'not a folder', res.stdout)
+
+    def test_flat(self):
+        res = run([FUSERM, '--root', 'dolor', '--flat', SRC_DIR,
+                   self.scratch], stdout=PIPE, stderr=STDOUT, timeout=10)
+        self.assertNotEqual(res.returncode, 0)
+        self.assertIn(b"can't be combined", res.stdout)
+
+
+class CompletionsTest(CliTest):
+    def completions(self, shell):
+        res = run([FUSERM, 'completions', shell], stdout=PIPE,
+                  stderr=STDOUT, timeout=10)
+        self.assertEqual(res.returncode, 0, res.stdout)
+        return res.stdout.decode()
+
+    def complete(self, *words):
+        script = self.scratch / 'fuse-rm.bash'
+        script.write_text(self.completions('bash'))
+        line = ' '.join(['fuse-rm', *words])
+        res = run(['bash', '-c',
+                   'source "$0"; COMP_WORDS=($1); COMP_CWORD=%d; _fuse_rm; '
+                   'echo "${COMPREPLY[@]}"' % len(words), script, line],
+                  stdout=PIPE, stderr=STDOUT, timeout=10)
+        return res.stdout.decode().split()
+
+    def test_bash(self):
+        self.assertIn('--io-threads', self.complete('--io-t'))
+        self.assertEqual(self.complete('ex'), ['export'])
+        self.assertEqual(self.complete('export', '--r'), ['--raw'])
+        self.assertEqual(self.complete('import', '--re'), ['--replace'])
+
+    def test_fish(self):
+        script = self.completions('fish')
+        self.assertIn("-l root -r -d 'Mount this folder", script)
+        self.assertIn("__fish_seen_subcommand_from export' -l raw", script)
+
+    def test_unknown_shell(self):
+        res = run([FUSERM, 'completions', 'tcsh'], stdout=PIPE,
+                  stderr=STDOUT, timeout=10)
+        self.assertNotEqual(res.returncode, 0)
+        self.assertIn(b'Unknown shell: tcsh', res.stdout)
+
+
+class DoctorTest(CliTest):
+    def doctor(self, *args):
+        return run([FUSERM, 'doctor', *args], stdout=PIPE, stderr=STDOUT,
+                   timeout=20)
+
+    def test_all_pass(self):
+        target = self.scratch / 'mnt'
+        target.mkdir()
+        res = self.doctor(SRC_DIR, target)
+        self.assertEqual(res.returncode, 0, res.stdout)
+        for check in [b'/dev/fuse', b'source', b'target', b'mount']:
+            self.assertIn(b'PASS  ' + check + b':', res.stdout)
+        self.assertNotIn(b'FAIL', res.stdout)
+        # the dry run leaves nothing mounted behind
+        self.assertFalse(list(Path(tempfile.gettempdir())
+                              .glob('fuse-rm-doctor-*')))
+
+    def test_wrong_source(self):
+        res = self.doctor(self.scratch / 'missing')
+        self.assertEqual(res.returncode, 1)
+        self.assertIn(b'FAIL  source: Source', res.stdout)
+        self.assertIn(b'hint: pass the xochitl dir', res.stdout)
+        self.assertIn(b'SKIP  target: no target given', res.stdout)
+
+    def test_target_mounted(self):
+        target = self.scratch / 'mnt'
+        target.mkdir()
+        fuserm = Popen([FUSERM, '--no-lock', SRC_DIR, target], stdout=PIPE)
+        try:
+            self.assertTrue(started(fuserm))
+            res = self.doctor(SRC_DIR, target)
+        finally:
+            fuserm.terminate()
+            fuserm.wait()
+        self.assertEqual(res.returncode, 1)
+        self.assertIn(b'FAIL  target:', res.stdout)
+        self.assertIn(b'already a FUSE mountpoint', res.stdout)
+
+
+class PurgeTrashTest(CliTest):
+    """Trashed entries of different ages, a folder and a deleted document"""
+    old = '0f1e2d3c-0000-4000-8000-000000000011'
+    recent = '0f1e2d3c-0000-4000-8000-000000000012'
+    folder = '0f1e2d3c-0000-4000-8000-000000000013'
+    inside = '0f1e2d3c-0000-4000-8000-000000000014'
+    deleted = '0f1e2d3c-0000-4000-8000-000000000015'
+    trashed = 'a93bea92-3b54-462f-b9f0-858c876c936f'
+
+    def setUp(self):
+        super().setUp()
+        self.source = self.scratch / 'source'
+        shutil.copytree(SRC_DIR, self.source)
+        now = int(time.time() * 1000)
+        day = 24 * 60 * 60 * 1000
+        for uuid, parent, days, kind in [
+                (self.old, 'trash', 60, 'DocumentType'),
+                (self.recent, 'trash', 1, 'DocumentType'),
+                (self.folder, 'trash', 40, 'CollectionType'),
+                (self.inside, self.folder, 1, 'DocumentType'),
+                (self.deleted, '', 90, 'DocumentType')]:
+            self.write(uuid, parent, now - days * day, kind)
+
+    def write(self, uuid, parent, modified, kind):
+        (self.source / (uuid + '.metadata')).write_text(json.dumps({
+            'deleted': uuid == self.deleted, 'lastModified': str(modified),
+            'parent': parent, 'type': kind, 'visibleName': uuid[-2:]}))
+        if kind == 'DocumentType':
+            shutil.copy(ROOT / 'ipsum.pdf', self.source / (uuid + '.pdf'))
+            (self.source / (uuid + '.content')).write_text('{}')
+            thumbnails = self.source / (uuid + '.thumbnails')
+            thumbnails.mkdir()
+            (thumbnails / '0.png').write_bytes(b'png')
+
+    def purge(self, *args):
+        return run([FUSERM, 'purge-trash', *args, self.source], stdout=PIPE,
+                   stderr=STDOUT, timeout=10)
+
+    def left(self, uuid):
+        return sorted(p.name for p in self.source.glob(uuid + '*'))
+
+    def test_all(self):
+        res = self.purge()
+        self.assertEqual(res.returncode, 0, res.stdout)
+        for uuid in [self.old, self.recent, self.folder, self.inside,
+                     self.deleted, self.trashed]:
+            self.assertEqual(self.left(uuid), [])
+        self.assertIn(b'Removed trash/13/14.pdf', res.stdout)
+        self.assertIn(b'Reclaimed 6 entries', res.stdout)
+        # the rest of the library is kept
+        self.assertTrue((self.source /
+                         'a44b5dee-cf0f-4e06-a0d1-458317c60d5f.pdf').exists())
+
+    def test_older_than(self):
+        res = self.purge('--older-than', '30d')
+        self.assertEqual(res.returncode, 0, res.stdout)
+        self.assertEqual(self.left(self.old), [])
+        self.assertEqual(self.left(self.deleted), [])
+        # in a folder trashed long enough ago, however new itself
+        self.assertEqual(self.left(self.inside), [])
+        self.assertEqual(len(self.left(self.recent)), 4)
+
+    def test_dry_run(self):
+        size = (ROOT / 'ipsum.pdf').stat().st_size
+        res = self.purge('--dry-run', '--older-than', '8w')
+        self.assertEqual(res.returncode, 0, res.stdout)
+        self.assertEqual(len(self.left(self.old)), 4)
+        lines = res.stdout.decode().splitlines()
+        self.assertIn('Would remove trash/11.pdf (%d bytes)'
+                      % (size + len('{}') + len('png') +
+                         (self.source / (self.old + '.metadata'))
+                         .stat().st_size), lines)
+        self.assertEqual(len([l for l in lines if 'Would remove' in l]), 3)
+
+    def test_bad_age(self):
+        res = self.purge('--older-than', '30 days')
+        self.assertNotEqual(res.returncode, 0)
+        self.assertIn(b'Unknown age', res.stdout)
+
+    def test_refused_while_mounted(self):
+        target = self.scratch / 'mnt'
+        target.mkdir()
+        fuserm = Popen([FUSERM, self.source, target], stdout=PIPE)
+        try:
+            self.assertTrue(started(fuserm))
+            res = self.purge()
+            self.assertEqual(res.returncode, 24)
+            self.assertIn(b'--force', res.stdout)
+            self.assertEqual(len(self.left(self.old)), 4)
+            res = self.purge('--force')
+            self.assertEqual(res.returncode, 0, res.stdout)
+            self.assertEqual(self.left(self.old), [])
+        finally:
+            fuserm.terminate()
+            fuserm.wait()
+
+
+class ExportTest(CliTest):
+    def export(self, *args, source=SRC_DIR):
+        return run([FUSERM, 'export', *args[:-2], source, *args[-2:]],
+                   stdout=PIPE, stderr=STDOUT, timeout=10)
+
+    def test_document_by_uuid(self):
+        res = self.export('c180a2a6-fab9-4ba5-9e7f-3a71f9a1bd09', self.scratch)
+        self.assertEqual(res.returncode, 0)
+        self.assertEqual(os.listdir(self.scratch), ['ipsum.pdf'])
+        self.assertEqual((self.scratch / 'ipsum.pdf').read_bytes(),
+                         (ROOT / 'ipsum.pdf').read_bytes())
+
+    def test_folder(self):
+        res = self.export('dolor', self.scratch / 'out')
+        self.assertEqual(res.returncode, 0)
+        self.assertEqual(sorted(os.listdir(self.scratch / 'out' / 'dolor')),
+                         ['ipsum.epub', 'lorem.pdf'])
+
+    def test_nested_path(self):
+        res = self.export('dolor/lorem', self.scratch)
+        self.assertEqual(res.returncode, 0)
+        self.assertEqual(os.listdir(self.scratch), ['lorem.pdf'])
+
+    def test_raw(self):
+        res = self.export('--raw', 'ipsum.pdf', self.scratch)
+        self.assertEqual(res.returncode, 0)
+        uuid = 'c180a2a6-fab9-4ba5-9e7f-3a71f9a1bd09'
+        self.assertEqual(sorted(os.listdir(self.scratch)),
+                         sorted(['ipsum.pdf'] + [
+                             f for f in os.listdir(SRC_DIR)
+                             if f.startswith(uuid)]))
+
+    def test_not_found(self):
+        res = self.export('nope', self.scratch)
+        self.assertEqual(res.returncode, 30)
+
+    def notebooks_in_folder(self):
+        source = self.scratch / 'source'
+        shutil.copytree(SRC_DIR, source)
+        for path in (ROOT / 'notebook').iterdir():
+            if path.is_dir():
+                shutil.copytree(path, source / path.name)
+            elif path.suffix == '.metadata':
+                meta = json.loads(path.read_text())
+                meta['parent'] = '90e4bbaa-cec6-478f-bd08-aa1fa4b58fe8'
+                (source / path.name).write_text(json.dumps(meta))
+            else:
+                shutil.copyfile(path, source / path.name)
+        return source
+
+    def test_notebooks_left_out(self):
+        source, dest = self.notebooks_in_folder(), self.scratch / 'out'
+        res = self.export('dolor', dest, source=source)
+        self.assertEqual(res.returncode, 0, res.stdout)
+        self.assertIn(b'Exported 2 document(s)', res.stdout)
+        self.assertIn(b'Left out 2 notebook(s)', res.stdout)
+        self.assertEqual(sorted(os.listdir(dest / 'dolor')),
+                         ['ipsum.epub', 'lorem.pdf'])
+
+    def test_notebooks_raw(self):
+        source, dest = self.notebooks_in_folder(), self.scratch / 'out'
+        res = self.export('--raw', 'dolor', dest, source=source)
+        self.assertEqual(res.returncode, 0, res.stdout)
+        self.assertIn(b'Left out 2 notebook(s)', res.stdout)
+        self.assertIn(b'their raw files copied', res.stdout)
+        notebook = '0e1f2a3b-0000-4000-8000-000000000001'
+        copied = dest / 'dolor' / notebook
+        self.assertTrue(copied.is_dir())
+        self.assertEqual(sorted(os.listdir(copied)),
+                         sorted(os.listdir(ROOT / 'notebook' / notebook)))
+        self.assertTrue((dest / 'dolor' / (notebook + '.metadata')).exists())
+
+    def test_missing_data_left_out(self):
+        source = self.scratch / 'source'
+        shutil.copytree(SRC_DIR, source)
+        (source / 'f27527b8-62d4-4e44-9511-43bdf275d364.pdf').unlink()
+        res = self.export('dolor', self.scratch / 'out', source=source)
+        self.assertEqual(res.returncode, 0, res.stdout)
+        self.assertIn(b'Left out 1 notebook(s)', res.stdout)
+        self.assertEqual(os.listdir(self.scratch / 'out' / 'dolor'),
+                         ['ipsum.epub'])
+
+    def test_names_stay_in_dest(self):
+        source, dest = self.scratch / 'source', self.scratch / 'out'
+        shutil.copytree(SRC_DIR, source)
+        ipsum = source / '462c4853-378f-4b76-a265-ea4ca5581049.metadata'
+        meta = json.loads(ipsum.read_text())
+        meta['visibleName'] = '../../escape.epub'
+        ipsum.write_text(json.dumps(meta))
+        res = self.export('dolor', dest, source=source)
+        self.assertEqual(res.returncode, 0, res.stdout)
+        self.assertEqual(sorted(os.listdir(dest / 'dolor')),
+                         ['..\u2215..\u2215escape.epub', 'lorem.pdf'])
+        self.assertEqual(sorted(os.listdir(self.scratch)), ['out', 'source'])
+
+        folder = source / '90e4bbaa-cec6-478f-bd08-aa1fa4b58fe8.metadata'
+        meta = json.loads(folder.read_text())
+        meta['visibleName'] = '..'
+        folder.write_text(json.dumps(meta))
+        res = self.export('90e4bbaa-cec6-478f-bd08-aa1fa4b58fe8',
+                          self.scratch / 'nested' / 'out', source=source)
+        self.assertEqual(res.returncode, 32, res.stdout)
+        self.assertIn(b"can't be a file name", res.stdout)
+        self.assertEqual(os.listdir(self.scratch / 'nested'), ['out'])
+        self.assertEqual(os.listdir(self.scratch / 'nested' / 'out'), [])
+
+    def test_ambiguous(self):
+        source = self.scratch / 'source'
+        shutil.copytree(ROOT / 'conflict', source)
+        uuid = 'b3e9f0d4-71a2-4d8c-8e5f-0c4a9d2b7e02'
+        (source / (uuid + '.pdf')).rename(source / (uuid + '.epub'))
+        res = self.export('Report', self.scratch / 'out', source=source)
+        self.assertEqual(res.returncode, 31)
+        self.assertIn(b'ambiguous', res.stdout)
+        self.assertIn(uuid.encode(), res.stdout)
+        res = self.export('Report.epub', self.scratch / 'out', source=source)
+        self.assertEqual(res.returncode, 0)
+
+
+class BackupTest(CliTest):
+    def backup(self, *args, source=SRC_DIR):
+        return run([FUSERM, 'backup', *args[:-1], source, args[-1]],
+                   stdout=PIPE, stderr=STDOUT, timeout=10)
+
+    def manifest(self, dest):
+        return json.loads((dest / '.fuse-rm-manifest.json').read_text())
+
+    def copy_source(self):
+        source = self.scratch / 'source'
+        shutil.copytree(SRC_DIR, source)
+        return source
+
+    def remove_document(self, source, uuid):
+        for path in source.glob(uuid + '*'):
+            if path.is_dir():
+                shutil.rmtree(path)
+            else:
+                path.unlink()
+
+    def test_tree(self):
+        dest = self.scratch / 'out'
+        res = self.backup(dest)
+        self.assertEqual(res.returncode, 0, res.stdout)
+        self.assertIn(b'Backed up 5 document(s)', res.stdout)
+        manifest = self.manifest(dest)
+        self.assertFalse(manifest['raw'])
+        docs = {d['path']: d for d in manifest['documents']}
+        self.assertEqual(sorted(docs), [
+            'dolor/ipsum.epub', 'dolor/lorem.pdf', 'ipsum.pdf', 'lorem.epub',
+            'trash/lorem-trashed.pdf'])
+        ipsum = docs['ipsum.pdf']
+        self.assertEqual(ipsum['uuid'], 'c180a2a6-fab9-4ba5-9e7f-3a71f9a1bd09')
+        self.assertEqual(ipsum['size'], 126501)
+        data = (dest / 'ipsum.pdf').read_bytes()
+        self.assertEqual(data, (ROOT / 'ipsum.pdf').read_bytes())
+        self.assertEqual(ipsum['sha256'], hashlib.sha256(data).hexdigest())
+        meta = json.loads((SRC_DIR / (ipsum['uuid'] + '.metadata')).read_text())
+        self.assertEqual(ipsum['lastModified'], meta['lastModified'])
+
+    def test_raw(self):
+        dest = self.scratch / 'out'
+        res = self.backup('--raw', dest)
+        self.assertEqual(res.returncode, 0, res.stdout)
+        self.assertTrue(self.manifest(dest)['raw'])
+        # a library of its own, folders included
+        self.assertEqual(check_output([FUSERM, 'list', dest]),
+                         check_output([FUSERM, 'list', SRC_DIR]))
+        uuid = 'c180a2a6-fab9-4ba5-9e7f-3a71f9a1bd09'
+        self.assertEqual((dest / (uuid + '.metadata')).read_bytes(),
+                         (SRC_DIR / (uuid + '.metadata')).read_bytes())
+
+    def test_incremental(self):
+        source, dest = self.copy_source(), self.scratch / 'out'
+        self.assertEqual(self.backup(dest, source=source).returncode, 0)
+        ipsum = source / 'c180a2a6-fab9-4ba5-9e7f-3a71f9a1bd09.metadata'
+        meta = json.loads(ipsum.read_text())
+        meta.update(visibleName='renamed', lastModified='1700000000000')
+        ipsum.write_text(json.dumps(meta))
+        self.remove_document(source, 'a44b5dee-cf0f-4e06-a0d1-458317c60d5f')
+        res = self.backup('--incremental', dest, source=source)
+        self.assertEqual(res.returncode, 0, res.stdout)
+        self.assertIn(b'Backed up 1 document(s)', res.stdout)
+        self.assertIn(b'3 unchanged, 2 removed', res.stdout)
+        self.assertEqual(sorted(os.listdir(dest)),
+                         ['.fuse-rm-manifest.json', 'dolor', 'renamed.pdf',
+                          'trash'])
+        renamed = [d for d in self.manifest(dest)['documents']
+                   if d['path'] == 'renamed.pdf']
+        self.assertEqual(renamed[0]['lastModified'], '1700000000000')
+
+    def test_incremental_raw_removes(self):
+        source, dest = self.copy_source(), self.scratch / 'out'
+        self.assertEqual(self.backup('--raw', dest, source=source).returncode,
+                         0)
+        uuid = 'f27527b8-62d4-4e44-9511-43bdf275d364'
+        self.remove_document(source, uuid)
+        res = self.backup('--raw', '--incremental', dest, source=source)
+        self.assertIn(b'0 document(s)', res.stdout)
+        self.assertIn(b'4 unchanged, 1 removed', res.stdout)
+        self.assertEqual(list(dest.glob(uuid + '*')), [])
+
+    def test_incremental_needs_same_mode(self):
+        dest = self.scratch / 'out'
+        self.assertEqual(self.backup(dest).returncode, 0)
+        res = self.backup('--raw', '--incremental', dest)
+        self.assertEqual(res.returncode, 50)
+        self.assertIn(b'without --raw', res.stdout)
+
+    def test_deleted_left_out(self):
+        source, dest = self.copy_source(), self.scratch / 'out'
+        folder = source / '90e4bbaa-cec6-478f-bd08-aa1fa4b58fe8.metadata'
+        meta = json.loads(folder.read_text())
+        meta['deleted'] = True
+        folder.write_text(json.dumps(meta))
+        res = self.backup(dest, source=source)
+        self.assertEqual(res.returncode, 0, res.stdout)
+        paths = [d['path'] for d in self.manifest(dest)['documents']]
+        self.assertEqual(sorted(paths), ['ipsum.pdf', 'lorem.epub',
+                                         'trash/lorem-trashed.pdf'])
+
+    def rename(self, source, uuid, name):
+        path = source / (uuid + '.metadata')
+        meta = json.loads(path.read_text())
+        meta['visibleName'] = name
+        path.write_text(json.dumps(meta))
+
+    def test_names_stay_in_dest(self):
+        source, dest = self.copy_source(), self.scratch / 'out'
+        self.rename(source, 'c180a2a6-fab9-4ba5-9e7f-3a71f9a1bd09',
+                    '../../escape.pdf')
+        self.rename(source, '90e4bbaa-cec6-478f-bd08-aa1fa4b58fe8', '..')
+        res = self.backup(dest, source=source)
+        self.assertEqual(res.returncode, 52, res.stdout)
+        self.assertIn(b"Couldn't back up ../ipsum.epub", res.stdout)
+        self.assertIn(b"Couldn't back up ../lorem.pdf", res.stdout)
+        self.assertEqual(sorted(os.listdir(self.scratch)), ['out', 'source'])
+        self.assertEqual(sorted(os.listdir(dest)), [
+            '..\u2215..\u2215escape.pdf', '.fuse-rm-manifest.json',
+            'lorem.epub', 'trash'])
+        paths = [d['path'] for d in self.manifest(dest)['documents']]
+        self.assertIn('..\u2215..\u2215escape.pdf', paths)
+
+    def test_manifest_stays_in_dest(self):
+        dest = self.scratch / 'out'
+        self.assertEqual(self.backup(dest).returncode, 0)
+        victim = self.scratch / 'victim.pdf'
+        victim.write_bytes(b'%PDF')
+        manifest = self.manifest(dest)
+        manifest['documents'].append({
+            'uuid': '00000000-0000-4000-8000-000000000000',
+            'path': 'victim.pdf', 'file': '../victim.pdf', 'sha256': '',
+            'size': 4, 'lastModified': '0'})
+        (dest / '.fuse-rm-manifest.json').write_text(json.dumps(manifest))
+        res = self.backup('--incremental', dest)
+        self.assertEqual(res.returncode, 52, res.stdout)
+        self.assertIn(b'"../victim.pdf" of the manifest is not in the backup',
+                      res.stdout)
+        self.assertTrue(victim.exists())
+
+    def test_raw_manifest_without_uuid(self):
+        dest = self.scratch / 'out'
+        self.assertEqual(self.backup('--raw', dest).returncode, 0)
+        manifest = self.manifest(dest)
+        manifest['documents'].append({
+            'uuid': '', 'path': 'gone.pdf', 'file': '.pdf', 'sha256': '',
+            'size': 4, 'lastModified': '0'})
+        (dest / '.fuse-rm-manifest.json').write_text(json.dumps(manifest))
+        res = self.backup('--raw', '--incremental', dest)
+        self.assertEqual(res.returncode, 52, res.stdout)
+        self.assertEqual(len(self.manifest(dest)['documents']), 5)
+
+    def test_notebooks_left_out(self):
+        dest = self.scratch / 'out'
+        res = self.backup(dest, source=ROOT / 'notebook')
+        self.assertEqual(res.returncode, 0, res.stdout)
+        self.assertIn(b'Left out', res.stdout)
+        self.assertEqual(self.manifest(dest)['documents'], [])
+
+
+class RestoreTest(CliTest):
+    IPSUM = 'c180a2a6-fab9-4ba5-9e7f-3a71f9a1bd09'
+
+    def setUp(self):
+        super().setUp()
+        self.backup_dir = self.scratch / 'backup'
+        check_output([FUSERM, 'backup', SRC_DIR, self.backup_dir])
+        self.library = self.scratch / 'library'
+        self.library.mkdir()
+
+    def restore(self, *args):
+        return run([FUSERM, 'restore', *args, self.library, self.backup_dir],
+                   stdout=PIPE, stderr=STDOUT, timeout=10)
+
+    def listing(self, source):
+        return check_output([FUSERM, 'list', source])
+
+    def copy_source(self, new_uuid):
+        """The source with ipsum.pdf under another uuid"""
+        shutil.rmtree(self.library)
+        shutil.copytree(SRC_DIR, self.library)
+        for path in self.library.glob(self.IPSUM + '*'):
+            path.rename(self.library / path.name.replace(self.IPSUM, new_uuid))
+
+    def test_into_empty(self):
+        res = self.restore()
+        self.assertEqual(res.returncode, 0, res.stdout)
+        self.assertIn(b'Restored dolor/lorem.pdf', res.stdout)
+        self.assertEqual(self.listing(self.library), self.listing(SRC_DIR))
+        # under their uuids, with a content file of their type
+        data = self.library / (self.IPSUM + '.pdf')
+        self.assertEqual(data.read_bytes(), (ROOT / 'ipsum.pdf').read_bytes())
+        content = json.loads(data.with_suffix('.content').read_text())
+        self.assertEqual(content['fileType'], 'pdf')
+        meta = json.loads(data.with_suffix('.metadata').read_text())
+        self.assertEqual(meta['visibleName'], 'ipsum.pdf')
+
+    def test_dry_run(self):
+        res = self.restore('--dry-run')
+        self.assertEqual(res.returncode, 0, res.stdout)
+        self.assertIn(b'Would restore ipsum.pdf', res.stdout)
+        self.assertEqual(os.listdir(self.library), ['.fuse-rm.lock'])
+
+    def test_resumable(self):
+        self.assertEqual(self.restore().returncode, 0)
+        # as if it stopped while staging lorem.epub
+        uuid = 'a44b5dee-cf0f-4e06-a0d1-458317c60d5f'
+        for path in self.library.glob(uuid + '*'):
+            path.unlink()
+        pending = self.library / '.pending'
+        pending.mkdir(exist_ok=True)
+        (pending / uuid).write_bytes(b'PK half')
+        (pending / (uuid + '.metadata')).write_text('{}')
+        res = self.restore()
+        self.assertEqual(res.returncode, 0, res.stdout)
+        self.assertIn(b'Restored lorem.epub', res.stdout)
+        self.assertIn(b'Skipped ipsum.pdf, source has its uuid already',
+                      res.stdout)
+        self.assertEqual(res.stdout.count(b'Restored'), 1)
+        self.assertEqual(self.listing(self.library), self.listing(SRC_DIR))
+        self.assertEqual((self.library / (uuid + '.epub')).read_bytes(),
+                         (self.backup_dir / 'lorem.epub').read_bytes())
+        self.assertFalse((pending / uuid).exists())
+
+    def test_same_content_skipped(self):
+        self.copy_source('0b5a7bd2-6e0e-4a51-9f5e-0c2b6a3d4e10')
+        (self.library / 'dolor').mkdir()
+        res = self.restore()
+        self.assertEqual(res.returncode, 0, res.stdout)
+        self.assertIn(b'Skipped ipsum.pdf, source has ipsum.pdf already',
+                      res.stdout)
+        self.assertNotIn(b'Restored', res.stdout)
+
+    def test_conflict(self):
+        uuid = '0b5a7bd2-6e0e-4a51-9f5e-0c2b6a3d4e10'
+        self.copy_source(uuid)
+        other = ROOT / 'source' / 'a93bea92-3b54-462f-b9f0-858c876c936f.pdf'
+        shutil.copyfile(other, self.library / (uuid + '.pdf'))
+        res = self.restore()
+        self.assertEqual(res.returncode, 60, res.stdout)
+        self.assertIn(b'Conflict: ipsum.pdf', res.stdout)
+        self.assertEqual((self.library / (uuid + '.pdf')).read_bytes(),
+                         other.read_bytes())
+        res = self.restore('--overwrite')
+        self.assertEqual(res.returncode, 0, res.stdout)
+        self.assertIn(b'Overwrote ipsum.pdf', res.stdout)
+        self.assertEqual((self.library / (uuid + '.pdf')).read_bytes(),
+                         (ROOT / 'ipsum.pdf').read_bytes())
+
+    def test_damaged_copy(self):
+        with open(self.backup_dir / 'lorem.epub', 'r+b') as f:
+            f.write(b'XX')
+        res = self.restore()
+        self.assertEqual(res.returncode, 44, res.stdout)
+        self.assertIn(b'lorem.epub in the backup is damaged', res.stdout)
+        self.assertIn(b'Restored ipsum.pdf', res.stdout)
+
+    def test_no_manifest(self):
+        (self.backup_dir / '.fuse-rm-manifest.json').unlink()
+        res = self.restore()
+        self.assertEqual(res.returncode, 50)
+        self.assertIn(b'not found', res.stdout)
+
+    def edit_metadata(self, uuid, **changes):
+        path = self.library / (uuid + '.metadata')
+        meta = json.loads(path.read_text())
+        meta.update(changes)
+        path.write_text(json.dumps(meta))
+
+    def test_trashed_restored(self):
+        shutil.rmtree(self.library)
+        shutil.copytree(SRC_DIR, self.library)
+        self.edit_metadata(self.IPSUM, parent='trash')
+        self.edit_metadata('a44b5dee-cf0f-4e06-a0d1-458317c60d5f', deleted=True)
+        res = self.restore()
+        self.assertEqual(res.returncode, 0, res.stdout)
+        self.assertIn(b'Restored ipsum.pdf', res.stdout)
+        self.assertIn(b'Restored lorem.epub', res.stdout)
+        self.assertIn(b'Skipped trash/lorem-trashed.pdf, source has its uuid',
+                      res.stdout)
+        self.assertEqual(self.listing(self.library), self.listing(SRC_DIR))
+
+    def test_trashed_copy_is_not_kept(self):
+        shutil.rmtree(self.library)
+        shutil.copytree(SRC_DIR, self.library)
+        # the trashed document has its content
+        for path in self.library.glob('f27527b8-62d4-4e44-9511-43bdf275d364*'):
+            if path.is_dir():
+                shutil.rmtree(path)
+            else:
+                path.unlink()
+        res = self.restore()
+        self.assertEqual(res.returncode, 0, res.stdout)
+        self.assertIn(b'Restored dolor/lorem.pdf', res.stdout)
+        self.assertEqual(self.listing(self.library), self.listing(SRC_DIR))
+
+    def duplicate(self, **changes):
+        path = self.backup_dir / '.fuse-rm-manifest.json'
+        manifest = json.loads(path.read_text())
+        lorem = next(d for d in manifest['documents']
+                     if d['path'] == 'lorem.epub')
+        manifest['documents'].append(dict(lorem, **changes))
+        path.write_text(json.dumps(manifest))
+
+    def test_duplicate_path(self):
+        self.duplicate(path='ipsum.pdf',
+                       uuid='0b5a7bd2-6e0e-4a51-9f5e-0c2b6a3d4e10')
+        res = self.restore('--dry-run')
+        self.assertEqual(res.returncode, 60, res.stdout)
+        self.assertEqual(res.stdout.count(b'Would restore ipsum.pdf'), 1)
+        self.assertIn(b'Conflict: ipsum.pdf', res.stdout)
+        res = self.restore()
+        self.assertEqual(res.returncode, 60, res.stdout)
+        self.assertIn(b'Conflict: ipsum.pdf', res.stdout)
+        self.assertEqual((self.library / (self.IPSUM + '.pdf')).read_bytes(),
+                         (ROOT / 'ipsum.pdf').read_bytes())
+        self.assertEqual(self.listing(self.library), self.listing(SRC_DIR))
+
+    def test_duplicate_path_overwritten(self):
+        self.duplicate(path='ipsum.pdf',
+                       uuid='0b5a7bd2-6e0e-4a51-9f5e-0c2b6a3d4e10')
+        res = self.restore('--overwrite')
+        self.assertEqual(res.returncode, 0, res.stdout)
+        self.assertIn(b'Restored ipsum.pdf', res.stdout)
+        self.assertIn(b'Overwrote ipsum.pdf', res.stdout)
+        self.assertEqual((self.library / (self.IPSUM + '.epub')).read_bytes(),
+                         (self.backup_dir / 'lorem.epub').read_bytes())
+        self.assertFalse((self.library / (self.IPSUM + '.pdf')).exists())
+
+    def test_duplicate_uuid(self):
+        self.duplicate(path='dolor/again.epub')
+        res = self.restore()
+        self.assertEqual(res.returncode, 0, res.stdout)
+        self.assertIn(b'Skipped dolor/again.epub, source has its uuid already',
+                      res.stdout)
+        self.assertEqual(self.listing(self.library), self.listing(SRC_DIR))
+
+    def test_from_raw(self):
+        shutil.rmtree(self.backup_dir)
+        check_output([FUSERM, 'backup', '--raw', SRC_DIR, self.backup_dir])
+        res = self.restore()
+        self.assertEqual(res.returncode, 0, res.stdout)
+        self.assertEqual(self.listing(self.library), self.listing(SRC_DIR))
+
+
+class ImportTest(CliTest):
+    def setUp(self):
+        super().setUp()
+        self.source = self.scratch / 'source'
+        shutil.copytree(SRC_DIR, self.source)
+
+    def fuserm_import(self, *args):
+        return run([FUSERM, 'import', *args], stdout=PIPE, stderr=STDOUT,
+                   timeout=10)
+
+    def listing(self, parent):
+        res = {}
+        for path in self.source.glob('*.metadata'):
+            meta = json.loads(path.read_text())
+            if meta['parent'] == parent:
+                res[meta['visibleName']] = path.stem
+        return res
+
+    def test_import(self):
+        res = self.fuserm_import(self.source, ROOT / 'ipsum.epub')
+        self.assertEqual(res.returncode, 0)
+        uuid = self.listing('')['ipsum.epub']
+        self.assertEqual((self.source / (uuid + '.epub')).read_bytes(),
+                         (ROOT / 'ipsum.epub').read_bytes())
+        content = json.loads((self.source / (uuid + '.content')).read_text())
+        self.assertEqual(content['fileType'], 'epub')
+        self.assertFalse((self.source / '.pending').exists())
+
+    def test_into_folder(self):
+        for name in ['a.pdf', 'b.pdf']:
+            shutil.copy(ROOT / 'ipsum.pdf', self.scratch / name)
+        res = self.fuserm_import('--into', 'dolor', self.source,
+                                 self.scratch / 'a.pdf', self.scratch / 'b.pdf')
+        self.assertEqual(res.returncode, 0)
+        folder = self.listing('')['dolor']
+        self.assertIn('a.pdf', self.listing(folder))
+        self.assertIn('b.pdf', self.listing(folder))
+
+    def test_unsupported(self):
+        before = sorted(os.listdir(self.source))
+        res = self.fuserm_import(self.source, ROOT / 'lorem.txt')
+        self.assertEqual(res.returncode, 43)
+        self.assertEqual(sorted(os.listdir(self.source)), before)
+
+    def test_exists(self):
+        res = self.fuserm_import(self.source, ROOT / 'ipsum.pdf')
+        self.assertEqual(res.returncode, 42)
+        self.assertIn(b'--replace', res.stdout)
+
+    def test_replace(self):
+        uuid = 'a44b5dee-cf0f-4e06-a0d1-458317c60d5f'
+        res = self.fuserm_import('--replace', 'lorem.epub', self.source,
+                                 ROOT / 'ipsum.pdf')
+        self.assertEqual(res.returncode, 0)
+        self.assertEqual(self.listing('')['lorem.epub'], uuid)
+        self.assertFalse((self.source / (uuid + '.epub')).exists())
+        self.assertEqual((self.source / (uuid + '.pdf')).read_bytes(),
+                         (ROOT / 'ipsum.pdf').read_bytes())
+        content = json.loads((self.source / (uuid + '.content')).read_text())
+        self.assertEqual(content['fileType'], 'pdf')
+        self.assertIn('pageCount', content)
+
+
+class MetadataTouchTest(CliTest):
+    """Replacing a document's data rewrites its metadata"""
+    def setUp(self):
+        super().setUp()
+        self.source = self.scratch / 'source'
+        shutil.copytree(ROOT / 'metadata', self.source)
+
+    def replace(self, name, uuid):
+        before = json.loads(
+            (self.source / (uuid + '.metadata')).read_text())
+        res = run([FUSERM, 'import', '--replace', name, self.source,
+                   ROOT / 'ipsum.pdf'], stdout=PIPE, stderr=STDOUT,
+                  timeout=10)
+        self.assertEqual(res.returncode, 0, res.stdout)
+        after = json.loads((self.source / (uuid + '.metadata')).read_text())
+        return before, after
+
+    def assertSameFields(self, before, after, changed):
+        self.assertEqual(after.keys(), before.keys())
+        self.assertEqual(json_types(after), json_types(before))
+        for key in before.keys() - changed:
+            self.assertEqual(after[key], before[key], key)
+
+    def test_string_timestamp(self):
+        before, after = self.replace(
+            'v2 document.pdf', 'a1c4e2f0-1b7d-4c3e-9a5f-0d2e4b6c8a10')
+        self.assertSameFields(before, after,
+                              {'lastModified', 'modified', 'version'})
+        self.assertGreater(int(after['lastModified']),
+                           int(before['lastModified']))
+        self.assertIs(after['modified'], True)
+        self.assertEqual(after['version'], before['version'] + 1)
+
+    def test_integer_timestamp(self):
+        before, after = self.replace(
+            'v2 folder/integer timestamps.pdf',
+            'c3e6a4b2-3d9f-4e5a-9c7b-2f4a6d8e0c32')
+        self.assertSameFields(before, after,
+                              {'lastModified', 'modified', 'version'})
+        self.assertGreater(after['lastModified'], before['lastModified'])
+
+    def test_without_sync_fields(self):
+        # 3.x has no modified or version, they aren't added
+        before, after = self.replace(
+            'v3 document.pdf', 'b2d5f3a1-2c8e-4d4f-8b6a-1e3f5c7d9b21')
+        self.assertSameFields(before, after, {'lastModified'})
+        self.assertNotIn('modified', after)
+
+    def test_adds_last_modified(self):
+        # the one field written where there was none
+        uuid = 'a1c4e2f0-1b7d-4c3e-9a5f-0d2e4b6c8a10'
+        path = self.source / (uuid + '.metadata')
+        meta = json.loads(path.read_text())
+        del meta['lastModified']
+        path.write_text(json.dumps(meta))
+        before, after = self.replace('v2 document.pdf', uuid)
+        self.assertEqual(after.keys() - before.keys(), {'lastModified'})
+        self.assertIsInstance(after['lastModified'], str)
+
+
+class BenchTest(CliTest):
+    def bench(self, *args):
+        return run([FUSERM, 'bench', *args], stdout=PIPE, stderr=STDOUT,
+                   timeout=60)
+
+    def test_generated(self):
+        # debug builds carry some more baseline than the 10 MiB of a release
+        res = self.bench('--docs', '5000', '--max-rss', '16384')
+        self.assertEqual(res.returncode, 0, res.stdout)
+        self.assertIn(b'peak rss:', res.stdout)
+
+    def test_over_budget(self):
+        res = self.bench('--docs', '10', '--max-rss', '1')
+        self.assertEqual(res.returncode, 1)
+        self.assertIn(b'over the budget', res.stdout)
+
+    def test_read_buffers_reused(self):
+        res = self.bench('--docs', '10', '--read-mb', '20')
+        self.assertEqual(res.returncode, 0, res.stdout)
+        self.assertIn(b'read buffers: 1 for 160 reads', res.stdout)
+
+    def test_flat_snapshots(self):
+        res = self.bench('--docs', '10000', '--flat')
+        self.assertEqual(res.returncode, 0, res.stdout)
+        found = re.search(rb'dir snapshots: (\d+) bytes', res.stdout)
+        # the handles share one snapshot of small entries
+        self.assertLess(int(found.group(1)), 16)
+
+    def test_shared_listing(self):
+        res = self.bench('--docs', '5000')
+        self.assertEqual(res.returncode, 0, res.stdout)
+        found = re.search(rb'top level: (\d+) allocations, (\d+) with a copy',
+                          res.stdout)
+        shared, copied = int(found.group(1)), int(found.group(2))
+        # the listing has ~5000 entries, the top level ~1000: a few
+        # allocations for each entry listed and none for the rest
+        self.assertLess(shared, 5 * 1000)
+        self.assertGreater(copied - shared, 5000)
+
+    def test_write_requests(self):
+        res = self.bench('--docs', '10', '--write-mb', '8', '--write-kib',
+                         '128')
+        self.assertEqual(res.returncode, 0, res.stdout)
+        self.assertIn(b'write: 8 MiB in', res.stdout)
+
+    def test_scan_threads(self):
+        res = run([FUSERM, '--scan-threads', '4', 'bench', '--docs', '2000'],
+                  stdout=PIPE, stderr=STDOUT, timeout=60)
+        self.assertEqual(res.returncode, 0, res.stdout)
+        self.assertIn(b'scan with 4 threads:', res.stdout)
+
+    def test_source(self):
+        res = self.bench(SRC_DIR)
+        self.assertEqual(res.returncode, 0)
+        self.assertNotIn(b'Generating', res.stdout)